@@ -0,0 +1,8 @@
+CREATE TABLE pending_deletions
+(
+    commitment     BYTEA     NOT NULL,
+    group_id       BIGINT    NOT NULL,
+    created_at     TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    mined_in_block BIGINT,
+    PRIMARY KEY (group_id, commitment)
+)
@@ -0,0 +1,4 @@
+-- priority requested by the client, and the number of batches an identity has
+-- been passed over for, which gradually raises its effective priority
+ALTER TABLE pending_identities ADD COLUMN priority BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE pending_identities ADD COLUMN skipped_batches BIGINT NOT NULL DEFAULT 0;
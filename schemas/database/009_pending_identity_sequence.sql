@@ -0,0 +1,3 @@
+-- submission order of pending identities, as created_at is too coarse to order
+-- identities submitted within the same second
+ALTER TABLE pending_identities ADD COLUMN seq BIGINT NOT NULL DEFAULT 0;
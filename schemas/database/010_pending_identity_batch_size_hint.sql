@@ -0,0 +1,3 @@
+-- batch size the client would like its identity to be committed in, used to
+-- pick a smaller prover tier for lower latency
+ALTER TABLE pending_identities ADD COLUMN batch_size_hint BIGINT;
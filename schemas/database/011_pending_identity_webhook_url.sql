@@ -0,0 +1,3 @@
+-- URL the sequencer posts to when the identity is mined or fails to be
+-- submitted
+ALTER TABLE pending_identities ADD COLUMN webhook_url TEXT;
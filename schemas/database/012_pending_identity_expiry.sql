@@ -0,0 +1,3 @@
+-- Unix time after which the identity is dropped from the queue if it has not
+-- been mined yet
+ALTER TABLE pending_identities ADD COLUMN expires_at BIGINT;
@@ -0,0 +1,10 @@
+-- Links the deletion of a recovered identity to the insertion that replaces it
+CREATE TABLE identity_recoveries
+(
+    group_id       BIGINT    NOT NULL,
+    old_commitment BYTEA     NOT NULL,
+    new_commitment BYTEA     NOT NULL,
+    created_at     TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (group_id, old_commitment),
+    UNIQUE (group_id, new_commitment)
+);
@@ -0,0 +1,10 @@
+-- Insertion provers registered at runtime, restored on startup
+CREATE TABLE provers
+(
+    batch_size      BIGINT NOT NULL PRIMARY KEY,
+    url             TEXT   NOT NULL,
+    max_concurrency BIGINT,
+    -- The client certificate and key, as JSON like `--mtb-prover-tls-client`
+    tls_client      TEXT,
+    region          TEXT
+);
@@ -0,0 +1,14 @@
+-- The transactions table of the initial schema records neither the sender nor
+-- the hash of every transaction, so its rows cannot be carried over. It is
+-- kept under a new name rather than dropped, so that none are lost.
+ALTER TABLE transactions RENAME TO initial_transactions;
+
+-- Transactions sent by the sequencer that are not known to be settled, so
+-- they can be monitored again after a restart
+CREATE TABLE transactions
+(
+    tx_hash    BYTEA     NOT NULL PRIMARY KEY,
+    sender     BYTEA     NOT NULL,
+    nonce      BIGINT    NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,7 @@
+-- Keys that authenticate the mutating endpoints, stored as their hashes
+CREATE TABLE api_keys
+(
+    key_hash   BYTEA     NOT NULL PRIMARY KEY,
+    name       TEXT      NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
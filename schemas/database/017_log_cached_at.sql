@@ -0,0 +1,3 @@
+-- Unix time at which the event was confirmed and cached. Events cached before
+-- have none.
+ALTER TABLE logs ADD COLUMN cached_at BIGINT;
@@ -0,0 +1,13 @@
+-- The lifecycle of every identity, kept after it is mined so that its status
+-- can be reported. Times are unix seconds. Identities queued before have none.
+-- Its submission time is that of its batch.
+CREATE TABLE identity_audit
+(
+    group_id       BIGINT NOT NULL,
+    commitment     BYTEA  NOT NULL,
+    received_at    BIGINT NOT NULL,
+    batch_id       BIGINT,
+    tx_hash        BYTEA,
+    mined_in_block BIGINT,
+    PRIMARY KEY (group_id, commitment)
+);
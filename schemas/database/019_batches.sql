@@ -0,0 +1,17 @@
+-- Every batch submitted to the contract, to audit what was sent on-chain.
+-- Times are unix seconds. A batch is settled once it is mined or has failed.
+-- Its transactions are kept in the audits of its identities, as a batch may be
+-- submitted as several of them.
+CREATE TABLE batches
+(
+    id           BIGINT NOT NULL PRIMARY KEY,
+    pre_root     BYTEA,
+    -- The commitments of the batch in order, as a JSON array
+    commitments  TEXT   NOT NULL,
+    assembled_at BIGINT NOT NULL,
+    submitted_at BIGINT NOT NULL,
+    block_number BIGINT,
+    gas_used     BIGINT,
+    settled_at   BIGINT,
+    error        TEXT
+);
@@ -0,0 +1,7 @@
+-- The depth of the tree the database was created for, so that restarting with
+-- a different depth is refused instead of corrupting the stored identities.
+CREATE TABLE tree_metadata
+(
+    id    BIGINT NOT NULL PRIMARY KEY CHECK (id = 1),
+    depth BIGINT NOT NULL
+);
@@ -0,0 +1,34 @@
+-- The next submission order was taken as MAX(seq) + 1, which concurrent inserts
+-- could both read. It is now handed out by incrementing the single row of this
+-- table, which serializes concurrent inserts until they commit.
+CREATE TABLE pending_identity_sequence
+(
+    last BIGINT NOT NULL
+);
+
+-- Renumber the queue without ties, keeping its order.
+CREATE TABLE pending_identity_renumbering AS
+SELECT identity.group_id,
+       identity.commitment,
+       (SELECT COUNT(1)
+        FROM pending_identities AS earlier
+        WHERE earlier.seq < identity.seq
+           OR (earlier.seq = identity.seq
+               AND (earlier.group_id < identity.group_id
+                    OR (earlier.group_id = identity.group_id
+                        AND earlier.commitment <= identity.commitment)))) AS seq
+FROM pending_identities AS identity;
+
+UPDATE pending_identities
+SET seq = (SELECT renumbered.seq
+           FROM pending_identity_renumbering AS renumbered
+           WHERE renumbered.group_id = pending_identities.group_id
+             AND renumbered.commitment = pending_identities.commitment);
+
+DROP TABLE pending_identity_renumbering;
+
+INSERT INTO pending_identity_sequence (last)
+SELECT COALESCE(MAX(seq), 0)
+FROM pending_identities;
+
+CREATE UNIQUE INDEX pending_identities_seq ON pending_identities (seq);
@@ -0,0 +1,14 @@
+-- Confirmed events emptying a leaf of the tree, cached like the events adding
+-- leaves in `logs`.
+CREATE TABLE deletion_logs
+(
+    block_index       BIGINT NOT NULL,
+    transaction_index INT    NOT NULL,
+    log_index         INT    NOT NULL,
+    raw               TEXT   NOT NULL,
+    leaf              BYTEA  NOT NULL,
+    leaf_index        BIGINT NOT NULL,
+    root              BYTEA  NOT NULL,
+    cached_at         BIGINT,
+    UNIQUE (block_index, transaction_index, log_index)
+);
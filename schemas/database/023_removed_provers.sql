@@ -0,0 +1,6 @@
+-- Batch sizes whose insertion provers were removed at runtime, so that the
+-- configured provers don't come back on startup
+CREATE TABLE removed_provers
+(
+    batch_size BIGINT NOT NULL PRIMARY KEY
+);
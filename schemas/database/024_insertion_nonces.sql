@@ -0,0 +1,7 @@
+-- The last insertion nonce accepted with each API key, to reject replayed
+-- insertions
+CREATE TABLE insertion_nonces
+(
+    api_key TEXT   NOT NULL PRIMARY KEY,
+    nonce   BIGINT NOT NULL
+);
@@ -0,0 +1,3 @@
+-- The batch an identity was drained into while that batch is in flight, so
+-- that it is not dropped as expired in the meantime
+ALTER TABLE pending_identities ADD COLUMN batch_id BIGINT;
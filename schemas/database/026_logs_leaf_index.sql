@@ -0,0 +1,3 @@
+-- Looks up the batch of a mined identity by its leaf, as /identityStatus does,
+-- without scanning the event log
+CREATE INDEX logs_leaf ON logs (leaf);
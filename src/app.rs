@@ -1,39 +1,111 @@
 use crate::{
+    auth::{self, AdminKey, CreatedApiKey},
+    batch_events::BatchEvent,
+    batching_policy::{BatchingPolicy, CostModel},
     contracts,
     contracts::{
         batching::Contract as BatchingContract, legacy::Contract as LegacyContract,
-        IdentityManager, SharedIdentityManager,
+        ContractVersion, IdentityManager, SharedIdentityManager,
     },
-    database::{self, Database},
+    database::{self, BatchAuditRecord, BatchRecord, Database, RootCursor, RootRecord, RootStatus},
     ethereum::{self, Ethereum},
     ethereum_subscriber::{Error as SubscriberError, EthereumSubscriber},
     identity_committer::IdentityCommitter,
-    identity_tree::{Hash, SharedTreeState, TreeState},
-    prover,
+    identity_deleter::{IdentityDeleter, OnDeleteComplete},
+    identity_tree::{CompressedProof, Hash, HashFunction, SharedTreeState, TreeState},
+    proof_cache::{CachedProof, ProofCache},
+    prover::{
+        self, discovery, health,
+        map::{
+            add_shared_prover, make_deletion_map, make_insertion_map, remove_shared_prover,
+            InsertionProverMap, UpdateError,
+        },
+        verification, Prover, ProverConfiguration, ProverFailure,
+    },
+    receipt::{Receipt, ReceiptPayload, ReceiptSigner},
+    seen_cache::SeenCache,
     server::{Error as ServerError, ToResponseCode},
-    timed_rw_lock::TimedRwLock,
+    statsd,
+    status_changes::{self, IdentityStatus, StatusChanges, Subscriber},
+    status_coalescer::StatusCoalescer,
+    timed_rw_lock::{TimedReadGuard, TimedRwLock},
+    tree_cache,
+    tx_sitter::{
+        defender::{self, DefenderRelay},
+        nonce_manager::NonceManager,
+    },
+    webhook::Webhooks,
 };
-use anyhow::{anyhow, Result as AnyhowResult};
+use anyhow::{anyhow, Context, Result as AnyhowResult};
 use clap::Parser;
 use cli_batteries::await_shutdown;
-use ethers::types::U256;
-use futures::TryFutureExt;
+use ethers::types::{H256, U256};
 use hyper::StatusCode;
 use semaphore::{poseidon_tree::Proof, Field};
 use serde::{ser::SerializeStruct, Serialize, Serializer};
-use std::{sync::Arc, time::Duration};
-use tokio::{select, try_join};
+use std::{
+    future::Future,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    select,
+    sync::{broadcast, RwLock},
+    time::sleep,
+    try_join,
+};
 use tracing::{error, info, instrument, warn};
+use url::Url;
 
 pub enum InclusionProofResponse {
-    Proof { root: Field, proof: Proof },
+    Proof {
+        root:  Field,
+        proof: Proof,
+    },
+    /// A previously served proof whose root is still accepted on chain but may
+    /// not be the latest, returned when the tree could not be read in time.
+    /// `age` is how long ago the proof was computed.
+    StaleProof {
+        root:  Field,
+        proof: Proof,
+        age:   Duration,
+    },
+    /// A [`Self::Proof`] or [`Self::StaleProof`] without its empty siblings,
+    /// with the age of the latter.
+    CompressedProof {
+        root:  Field,
+        proof: CompressedProof,
+        stale: Option<Duration>,
+    },
     Pending,
 }
 
+impl InclusionProofResponse {
+    /// Leaves out the empty siblings of the proof, if any, in a tree
+    /// initialized with `initial_leaf` and built with `hasher`.
+    #[must_use]
+    pub fn compressed(self, initial_leaf: Field, hasher: HashFunction) -> Self {
+        let (root, proof, stale) = match self {
+            Self::Proof { root, proof } => (root, proof, None),
+            Self::StaleProof { root, proof, age } => (root, proof, Some(age)),
+            other => return other,
+        };
+        Self::CompressedProof {
+            root,
+            proof: CompressedProof::compress(&proof, initial_leaf, &hasher),
+            stale,
+        }
+    }
+}
+
 impl ToResponseCode for InclusionProofResponse {
     fn to_response_code(&self) -> StatusCode {
         match self {
-            Self::Proof { .. } => StatusCode::OK,
+            Self::Proof { .. } | Self::StaleProof { .. } | Self::CompressedProof { .. } => {
+                StatusCode::OK
+            }
             Self::Pending => StatusCode::ACCEPTED,
         }
     }
@@ -51,11 +123,249 @@ impl Serialize for InclusionProofResponse {
                 state.serialize_field("proof", proof)?;
                 state.end()
             }
+            Self::StaleProof { root, proof, age } => {
+                let mut state = serializer.serialize_struct("InclusionProof", 4)?;
+                state.serialize_field("root", root)?;
+                state.serialize_field("proof", proof)?;
+                state.serialize_field("stale", &true)?;
+                state.serialize_field("ageSecs", &age.as_secs())?;
+                state.end()
+            }
+            Self::CompressedProof { root, proof, stale } => {
+                let mut state = serializer.serialize_struct("InclusionProof", 5)?;
+                state.serialize_field("root", root)?;
+                state.serialize_field("proof", proof)?;
+                state.serialize_field("compressed", &true)?;
+                if let Some(age) = stale {
+                    state.serialize_field("stale", &true)?;
+                    state.serialize_field("ageSecs", &age.as_secs())?;
+                } else {
+                    state.skip_field("stale")?;
+                    state.skip_field("ageSecs")?;
+                }
+                state.end()
+            }
             Self::Pending => serializer.serialize_str("pending"),
         }
     }
 }
 
+/// How many identities and batches are waiting at each stage of the queue.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueStatusResponse {
+    announced:           usize,
+    awaiting_batching:   usize,
+    awaiting_submission: usize,
+    awaiting_mining:     usize,
+}
+
+impl ToResponseCode for QueueStatusResponse {
+    fn to_response_code(&self) -> StatusCode {
+        StatusCode::OK
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProverResponse {
+    batch_size: usize,
+    url:        String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    region:     Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<ProverFailure>,
+}
+
+impl From<&Prover> for ProverResponse {
+    fn from(prover: &Prover) -> Self {
+        Self {
+            batch_size: prover.batch_size(),
+            url:        prover.url().to_string(),
+            region:     prover.region().map(String::from),
+            last_error: None,
+        }
+    }
+}
+
+impl ToResponseCode for ProverResponse {
+    fn to_response_code(&self) -> StatusCode {
+        StatusCode::OK
+    }
+}
+
+impl ToResponseCode for Vec<ProverResponse> {
+    fn to_response_code(&self) -> StatusCode {
+        StatusCode::OK
+    }
+}
+
+/// The root of the tree after a batch, as listed by `/roots`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RootResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root:         Option<Field>,
+    status:       &'static str,
+    block_number: u64,
+    /// Where the next page starts when this is the last root of a page, see
+    /// [`RootCursor`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor:       Option<String>,
+    /// Unix time at which the sequencer cached the events of the batch, which
+    /// trails the time of the block.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cached_at:    Option<u64>,
+}
+
+impl From<RootRecord> for RootResponse {
+    fn from(record: RootRecord) -> Self {
+        Self {
+            root:         record.root,
+            status:       match record.status {
+                RootStatus::Pending => "pending",
+                RootStatus::Mined => "mined",
+            },
+            block_number: record.block_number,
+            cursor:       record.cursor.as_ref().map(RootCursor::to_string),
+            cached_at:    record.cached_at,
+        }
+    }
+}
+
+impl ToResponseCode for Vec<RootResponse> {
+    fn to_response_code(&self) -> StatusCode {
+        StatusCode::OK
+    }
+}
+
+/// The lifecycle of an identity, as reported by `/identityStatus`.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentityLifecycle {
+    identity_commitment: Hash,
+    status:              IdentityStatus,
+    /// Unix time at which the identity was queued, if recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    received_at:         Option<u64>,
+    /// How many identities queued earlier are waiting for a batch, while the
+    /// identity waits for one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_position:      Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    batch_id:            Option<u64>,
+    /// Unix time at which its batch was submitted to the contract.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    submitted_at:        Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transaction_hash:    Option<H256>,
+    /// The block its batch was mined in, as confirmed once it is mined.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_number:        Option<u64>,
+}
+
+/// A batch in the batch audit log, as reported by `/batches`.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchResponse {
+    id:                 u64,
+    /// Whether the batch is still being `submitted`, was `mined` or `failed`.
+    status:             &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pre_root:           Option<Hash>,
+    commitments:        Vec<Hash>,
+    assembled_at:       u64,
+    submitted_at:       u64,
+    /// The transactions that submitted its identities, in the order they were
+    /// mined.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    transaction_hashes: Vec<H256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_number:       Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gas_used:           Option<u64>,
+    /// Unix time at which the batch was mined or failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    settled_at:         Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error:              Option<String>,
+}
+
+impl From<BatchAuditRecord> for BatchResponse {
+    fn from(record: BatchAuditRecord) -> Self {
+        let status = match (&record.block_number, &record.error) {
+            (Some(_), _) => "mined",
+            (None, Some(_)) => "failed",
+            (None, None) => "submitted",
+        };
+        Self {
+            id: record.batch.id,
+            status,
+            pre_root: record.batch.pre_root,
+            commitments: record.batch.commitments,
+            assembled_at: record.batch.assembled_at,
+            submitted_at: record.batch.submitted_at,
+            transaction_hashes: record.tx_hashes,
+            block_number: record.block_number,
+            gas_used: record.gas_used,
+            settled_at: record.settled_at,
+            error: record.error,
+        }
+    }
+}
+
+impl ToResponseCode for BatchResponse {
+    fn to_response_code(&self) -> StatusCode {
+        StatusCode::OK
+    }
+}
+
+impl ToResponseCode for IdentityLifecycle {
+    fn to_response_code(&self) -> StatusCode {
+        StatusCode::OK
+    }
+}
+
+/// A page of the leaves of the tree in insertion order, as listed by
+/// `/leaves`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeavesResponse {
+    /// The root of the tree the leaves were read from.
+    root:   Field,
+    /// The number of leaves inserted so far.
+    total:  usize,
+    offset: usize,
+    leaves: Vec<Field>,
+}
+
+impl LeavesResponse {
+    #[must_use]
+    pub const fn root(&self) -> Field {
+        self.root
+    }
+}
+
+impl ToResponseCode for LeavesResponse {
+    fn to_response_code(&self) -> StatusCode {
+        StatusCode::OK
+    }
+}
+
+/// The proof of the emptied leaf of a queued deletion, against the root the
+/// tree will have once the deletion is applied.
+#[derive(Serialize)]
+pub struct DeletionResponse {
+    root:  Field,
+    proof: Proof,
+}
+
+impl ToResponseCode for DeletionResponse {
+    fn to_response_code(&self) -> StatusCode {
+        StatusCode::ACCEPTED
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Parser)]
 #[group(skip)]
 pub struct Options {
@@ -71,25 +381,329 @@ pub struct Options {
     #[clap(flatten)]
     pub prover: prover::Options,
 
+    #[clap(flatten)]
+    pub defender: defender::Options,
+
+    #[clap(flatten)]
+    pub metrics: statsd::Options,
+
     /// Block number to start syncing from
     #[clap(long, env, default_value = "0")]
     pub starting_block: u64,
 
+    /// Discard the cached events and rebuild the tree from the chain if the
+    /// cache is found to be corrupt on startup, instead of failing. Likewise,
+    /// rebuild the tree from the database if its snapshot is unusable.
+    #[clap(long, env, default_value = "true")]
+    pub rebuild_on_corrupt_cache: bool,
+
     /// Timeout for the tree lock (seconds).
     #[clap(long, env, default_value = "120")]
     pub lock_timeout: u64,
+
+    /// Record how long the tree lock is held by reads and writes, in the
+    /// `lock_hold_seconds` histogram.
+    #[clap(long, env)]
+    pub lock_hold_time_metrics: bool,
+
+    /// Log a warning whenever the tree lock is held for longer than this
+    /// (milliseconds). Zero disables the warnings.
+    #[clap(long, env, default_value = "0")]
+    pub lock_hold_warning_ms: u64,
+
+    /// Time to wait for more identities before committing a batch that is
+    /// not yet full (milliseconds). Zero commits batches immediately.
+    #[clap(long, env, default_value = "0")]
+    pub batch_linger_ms: u64,
+
+    /// Longest time identities wait for a full batch of the largest prover
+    /// tier before they are committed in the smallest tier that fits them
+    /// (milliseconds). Takes the place of the batch linger and cost-aware
+    /// batching. Zero disables the limit.
+    #[clap(long, env, default_value = "0")]
+    pub batch_max_wait_ms: u64,
+
+    /// Fixed on-chain cost of submitting a batch, in any unit, for cost-aware
+    /// batching.
+    #[clap(long, env, default_value = "0")]
+    pub batch_fixed_cost: f64,
+
+    /// On-chain cost of every identity slot of the prover tier a batch is
+    /// proven in, padding included, in the unit of `--batch-fixed-cost`.
+    #[clap(long, env, default_value = "0")]
+    pub batch_identity_cost: f64,
+
+    /// Longest time a batch is held back while a larger batch is expected to
+    /// be cheaper per identity under the batch cost model (milliseconds).
+    /// Once it elapses, the pending identities are committed in the smallest
+    /// tier that fits them. Zero disables cost-aware batching, which otherwise
+    /// replaces the batch linger.
+    #[clap(long, env, default_value = "0")]
+    pub batch_cost_max_wait_ms: u64,
+
+    /// Minimum time between two batch submissions (milliseconds). Identities
+    /// that become ready sooner wait for the next batch. Zero disables the
+    /// limit.
+    #[clap(long, env, default_value = "0")]
+    pub min_batch_interval_ms: u64,
+
+    /// Window over which the identities committed per second are averaged for
+    /// the `insertion_throughput` metric (seconds). Zero disables the metric.
+    #[clap(long, env, default_value = "0")]
+    pub insertion_throughput_window_secs: u64,
+
+    /// Collapse wake-up notifications that arrive while the committer is
+    /// already processing into that processing cycle.
+    #[clap(long, env)]
+    pub coalesce_wake_ups: bool,
+
+    /// Number of batches a pending identity must be passed over for before its
+    /// priority is raised by one. Zero disables aging, which allows low
+    /// priority identities to starve.
+    #[clap(long, env, default_value = "10")]
+    pub priority_aging_batches: u64,
+
+    /// Drain pending identities into batches oldest first, ignoring their
+    /// priority.
+    #[clap(long, env)]
+    pub drain_oldest_first: bool,
+
+    /// Order pending identities that are otherwise tied by commitment instead
+    /// of by local submission order, so that sequencers given the same
+    /// identities batch them identically.
+    #[clap(long, env)]
+    pub deterministic_tie_break: bool,
+
+    /// Check that a batch still builds on the latest cached root right before
+    /// submitting it, and reassemble it otherwise.
+    #[clap(long, env)]
+    pub validate_pre_root: bool,
+
+    /// Honour the batch size hints of authenticated insertions: a batch is
+    /// capped at the smallest prover tier that fits the hint of its first
+    /// identity.
+    #[clap(long, env)]
+    pub batch_size_hints: bool,
+
+    /// Number of times the webhook of an insertion is tried when it is
+    /// confirmed on chain or its batch fails to be submitted. Zero disables
+    /// webhooks, and insertions requesting one are rejected.
+    #[clap(long, env, default_value = "0")]
+    pub insertion_webhook_max_attempts: usize,
+
+    /// Hosts that the webhooks of insertions may be at, comma separated. Any
+    /// host is allowed while empty.
+    #[clap(long, env, value_delimiter = ',')]
+    pub insertion_webhook_allowed_hosts: Vec<String>,
+
+    /// Allow the webhooks of insertions to be at private addresses, such as
+    /// loopback and internal networks.
+    #[clap(long, env)]
+    pub insertion_webhook_private_addresses: bool,
+
+    /// Interval at which to submit the queued deletions on chain (seconds).
+    /// Zero only queues deletions, and recoveries are rejected as their
+    /// replacements would wait for the deletions forever.
+    #[clap(long, env, default_value = "0")]
+    pub deletion_batch_interval_secs: u64,
+
+    /// Maximum number of deletions submitted on chain together.
+    #[clap(long, env, default_value = "10")]
+    pub deletion_batch_size: usize,
+
+    /// Number of batch lifecycle events buffered for every subscriber before
+    /// the oldest are dropped. Zero disables the events.
+    #[clap(long, env, default_value = "0")]
+    pub batch_event_capacity: usize,
+
+    /// Time for which the identity committer keeps committing the queued
+    /// identities after a shutdown signal, instead of stopping after the batch
+    /// in progress (seconds). Zero disables draining.
+    #[clap(long, env, default_value = "0")]
+    pub shutdown_drain_secs: u64,
+
+    /// Number of identity status changes buffered for every `/subscribe`
+    /// client before the oldest are dropped. Zero disables subscriptions.
+    #[clap(long, env, default_value = "0")]
+    pub status_subscription_capacity: usize,
+
+    /// Maximum number of identities a single `/subscribe` client follows at a
+    /// time, including those of a single message.
+    #[clap(long, env, default_value = "1000")]
+    pub status_subscription_max_identities: usize,
+
+    /// Maximum number of `/subscribe` sockets open from a single IP address.
+    /// Unlimited if zero.
+    #[clap(long, env, default_value = "10")]
+    pub status_subscriptions_per_client: usize,
+
+    /// Run the identity committer on a dedicated runtime with this many worker
+    /// threads, so that request handling cannot delay batches. The committer
+    /// shares the server's runtime if unset.
+    #[clap(long, env)]
+    pub committer_threads: Option<usize>,
+
+    /// Interval at which to submit an empty batch while no identities are
+    /// pending, refreshing the on-chain root (seconds). Zero disables empty
+    /// batches.
+    #[clap(long, env, default_value = "0")]
+    pub empty_batch_interval_secs: u64,
+
+    /// Private key used to sign receipts for accepted insertions. Receipts are
+    /// only returned if set.
+    #[clap(long, env)]
+    // NOTE: We abuse `Hash` here because it has the right `FromStr` implementation.
+    pub receipt_signing_key: Option<H256>,
+
+    /// Maximum time since the tree last caught up with the chain for the
+    /// sequencer to report itself as ready (seconds). Zero disables the check.
+    #[clap(long, env, default_value = "0")]
+    pub max_staleness_secs: u64,
+
+    /// Reject new insertions while none of the provers pass their health
+    /// check.
+    #[clap(long, env)]
+    pub require_healthy_prover: bool,
+
+    /// Time for which recently inserted commitments are remembered to reject
+    /// rapid duplicates (seconds).
+    #[clap(long, env, default_value = "60")]
+    pub seen_cache_max_age: u64,
+
+    /// Maximum number of recently inserted commitments to remember. Zero
+    /// disables the cache.
+    #[clap(long, env, default_value = "10000")]
+    pub seen_cache_max_size: usize,
+
+    /// Interval at which to refresh the `pending_identities_backlog` and
+    /// `oldest_unmined_leaf_index` gauges from the database (seconds).
+    #[clap(long, env, default_value = "10")]
+    pub backlog_metric_interval_secs: u64,
+
+    /// Window within which inclusion proof requests for pending commitments
+    /// are looked up in the database together (milliseconds). Each request
+    /// is delayed by up to the window. Zero looks every commitment up alone.
+    #[clap(long, env, default_value = "0")]
+    pub status_coalesce_window_ms: u64,
+
+    /// Interval at which to drop pending identities past the expiry their
+    /// insertion asked for (seconds).
+    #[clap(long, env, default_value = "10")]
+    pub expiry_sweep_interval_secs: u64,
+
+    /// Maximum number of inclusion proofs of mined commitments to cache. Zero
+    /// disables the cache.
+    #[clap(long, env, default_value = "10000")]
+    pub proof_cache_max_size: usize,
+
+    /// Maximum memory used by the internal nodes of the merkle tree (bytes).
+    /// The lower levels of the tree are recomputed from the leaves as needed
+    /// to stay within it, trading CPU time for memory. All nodes are kept if
+    /// unset.
+    #[clap(long, env)]
+    pub tree_node_cache_max_bytes: Option<usize>,
+
+    /// Hash function the merkle tree is built with. It has to match the one
+    /// of the contract, `keccak` is only meant for test deployments.
+    #[clap(long, env, value_enum, default_value = "poseidon")]
+    pub tree_hash_function: HashFunction,
+
+    /// File to periodically save a snapshot of the merkle tree to. On start
+    /// up the tree is restored from it, and only the events since are
+    /// replayed. The tree is rebuilt from every event if unset.
+    #[clap(long, env)]
+    pub tree_snapshot_file: Option<PathBuf>,
+
+    /// Interval at which to save the tree snapshot (seconds).
+    #[clap(long, env, default_value = "300")]
+    pub tree_snapshot_interval_secs: u64,
+
+    /// Serve the newest cached proof of a commitment whose root is still
+    /// accepted on chain, flagged as `stale` along with its age in `ageSecs`,
+    /// when the tree lock times out instead of failing the request.
+    #[clap(long, env)]
+    pub degraded_proofs: bool,
+
+    /// How long an inclusion proof waits for the tree lock before it fails,
+    /// or falls back to a stale proof with `--degraded-proofs`
+    /// (milliseconds). Zero does not wait at all while the tree is being
+    /// written to. Waits for the lock timeout if unset.
+    #[clap(long, env)]
+    pub inclusion_proof_lock_wait_ms: Option<u64>,
+
+    /// Expose the insertion provers under `/admin/provers` so they can be
+    /// listed, added and removed at runtime.
+    #[clap(long, env)]
+    pub prover_admin_api: bool,
+
+    /// Hosts that the provers added at runtime may be reached at, comma
+    /// separated. No prover can be added while empty.
+    #[clap(long, env, value_delimiter = ',')]
+    pub prover_admin_allowed_hosts: Vec<String>,
+
+    /// Include the most recent error of every prover, and when it occurred, in
+    /// the prover admin API.
+    #[clap(long, env)]
+    pub expose_prover_errors: bool,
+
+    /// Require a valid API key in the `x-api-key` header to insert, delete
+    /// or recover identities.
+    #[clap(long, env)]
+    pub require_api_keys: bool,
+
+    /// Expose `/admin/apiKeys` so that API keys can be created and revoked.
+    #[clap(long, env)]
+    pub api_key_admin_api: bool,
+
+    /// The credential required in the `x-admin-key` header by the admin
+    /// routes, `/admin/*` and the batch size routes. API keys do not grant
+    /// access to them, and they reject every request while this is unset.
+    #[clap(long, env)]
+    pub admin_api_key: Option<String>,
+
+    /// Maximum number of roots returned by a single `/roots` request. Clients
+    /// page through longer histories by block number.
+    #[clap(long, env, default_value = "1000")]
+    pub max_roots_per_request: usize,
+
+    /// Maximum number of leaves returned by a single `/leaves` request.
+    #[clap(long, env, default_value = "10000")]
+    pub max_leaves_per_request: usize,
 }
 
 pub struct App {
-    database:           Arc<Database>,
+    database:               Arc<Database>,
     #[allow(dead_code)]
-    ethereum:           Ethereum,
-    identity_manager:   SharedIdentityManager,
-    identity_committer: Arc<IdentityCommitter>,
+    ethereum:               Ethereum,
+    identity_manager:       SharedIdentityManager,
+    identity_committer:     Arc<IdentityCommitter>,
+    identity_deleter:       IdentityDeleter,
+    recoveries:             bool,
     #[allow(dead_code)]
-    chain_subscriber:   EthereumSubscriber,
-    tree_state:         SharedTreeState,
-    snark_scalar_field: Hash,
+    chain_subscriber:       EthereumSubscriber,
+    tree_state:             SharedTreeState,
+    tree_hasher:            HashFunction,
+    snark_scalar_field:     Hash,
+    prover_map:             InsertionProverMap,
+    prover_options:         prover::Options,
+    prover_admin_api:       bool,
+    prover_allowed_hosts:   Vec<String>,
+    expose_prover_errors:   bool,
+    require_api_keys:       bool,
+    api_key_admin_api:      bool,
+    admin_key:              AdminKey,
+    require_healthy_prover: bool,
+    max_staleness:          Duration,
+    max_roots:              usize,
+    max_leaves:             usize,
+    seen_cache:             Arc<Mutex<SeenCache>>,
+    status_coalescer:       Option<StatusCoalescer>,
+    proof_cache:            Arc<Mutex<ProofCache>>,
+    degraded_proofs:        bool,
+    proof_lock_wait:        Option<Duration>,
+    webhooks:               Option<Webhooks>,
+    receipt_signer:         Option<ReceiptSigner>,
+    status_changes:         Option<StatusChanges>,
 }
 
 impl App {
@@ -100,49 +714,203 @@ impl App {
     #[allow(clippy::missing_panics_doc)] // TODO
     #[instrument(name = "App::new", level = "debug")]
     pub async fn new(options: Options) -> AnyhowResult<Self> {
+        statsd::start(&options.metrics);
         let refresh_rate = options.ethereum.refresh_rate;
         let cache_recovery_step_size = options.ethereum.cache_recovery_step_size;
+        let prover_map = Arc::new(RwLock::new(make_insertion_map(&options.prover)?));
+        let deletion_prover_map = Arc::new(RwLock::new(make_deletion_map(&options.prover)?));
+        if let Some(url) = &options.prover.mtb_prover_discovery_url {
+            discovery::refresh(&prover_map, url, &options.prover).await?;
+            discovery::start_refresh(
+                prover_map.clone(),
+                url.clone(),
+                options.prover.clone(),
+                Duration::from_secs(options.prover.mtb_prover_discovery_interval_secs),
+            );
+        }
+        if let Some(interval) = options.prover.mtb_prover_batch_size_check_interval_secs {
+            verification::start_verification(
+                vec![prover_map.clone(), deletion_prover_map.clone()],
+                Duration::from_secs(interval),
+            );
+        }
+        if let Some(interval) = options.prover.mtb_prover_health_check_interval_secs {
+            health::start_health_checks(
+                vec![
+                    ("insertion", prover_map.clone()),
+                    ("deletion", deletion_prover_map.clone()),
+                ],
+                Duration::from_secs(interval),
+            );
+        }
 
-        // Connect to Ethereum and Database
-        let (database, (ethereum, identity_manager)) = {
-            let db = Database::new(options.database);
-
-            let eth = Ethereum::new(options.ethereum).and_then(|ethereum| async move {
-                let identity_manager = if cfg!(feature = "batching-contract") {
-                    BatchingContract::new(options.contracts, ethereum.clone()).await?;
-                    panic!("The batching contract does not yet exist but was requested.");
-                } else {
-                    LegacyContract::new(options.contracts, ethereum.clone()).await?
-                };
-                Ok((ethereum, Arc::new(identity_manager)))
-            });
+        // Connect to Ethereum and Database in parallel
+        let (database, ethereum) = try_join!(
+            Database::new(options.database),
+            Ethereum::new(options.ethereum)
+        )?;
+        let database = Arc::new(database);
 
-            // Connect to both in parallel
-            try_join!(db, eth)?
+        // The contract waits for the transactions left in flight by the last run.
+        let mut ethereum = ethereum.with_nonce_manager(NonceManager::new(database.clone()));
+        if let Some(relay) =
+            DefenderRelay::new(options.defender, ethereum.provider().clone()).await?
+        {
+            ethereum = ethereum.with_submitter(Arc::new(relay));
+        }
+        let identity_manager = match options.contracts.contract_version {
+            ContractVersion::Legacy => {
+                LegacyContract::new(options.contracts, ethereum.clone()).await?
+            }
+            ContractVersion::Batching => {
+                BatchingContract::new(options.contracts, ethereum.clone()).await?;
+                return Err(anyhow!(
+                    "The events of the batching contract cannot be followed yet but it was \
+                     requested."
+                ));
+            }
         };
-        let database = Arc::new(database);
+        let identity_manager = Arc::new(identity_manager);
+        Self::check_tree_depth(&database, identity_manager.tree_depth()).await?;
 
         // Poseidon tree depth is one more than the contract's tree depth
-        let tree_state = Arc::new(TimedRwLock::new(
-            Duration::from_secs(options.lock_timeout),
-            TreeState::new(
-                identity_manager.tree_depth() + 1,
-                identity_manager.initial_leaf_value(),
-            ),
-        ));
+        let mut tree = TreeState::with_node_cache_limit(
+            identity_manager.tree_depth() + 1,
+            identity_manager.initial_leaf_value(),
+            options.tree_node_cache_max_bytes,
+        )
+        .with_hasher(options.tree_hash_function);
+        identity_manager
+            .assert_initial_root(tree.merkle_tree.root())
+            .await
+            .context("The configured tree does not match the contract")?;
+        if let Some(path) = &options.tree_snapshot_file {
+            if let Some(restored) =
+                tree_cache::restore(path.clone(), &tree, options.rebuild_on_corrupt_cache).await?
+            {
+                info!(
+                    next_leaf = restored.next_leaf,
+                    "Restored the tree from its snapshot."
+                );
+                tree = restored;
+            }
+        }
+        let tree_state = Arc::new(
+            TimedRwLock::new(Duration::from_secs(options.lock_timeout), tree)
+                .with_hold_time_metrics(options.lock_hold_time_metrics)
+                .with_hold_warning(
+                    (options.lock_hold_warning_ms > 0)
+                        .then(|| Duration::from_millis(options.lock_hold_warning_ms)),
+                ),
+        );
 
-        let identity_committer = Arc::new(IdentityCommitter::new(
-            database.clone(),
-            identity_manager.clone(),
-            tree_state.clone(),
-        ));
+        let empty_batch_interval = if options.empty_batch_interval_secs > 0
+            && !identity_manager.supports_empty_batches()
+        {
+            warn!("The identity manager does not support empty batches, disabling them.");
+            Duration::ZERO
+        } else {
+            Duration::from_secs(options.empty_batch_interval_secs)
+        };
+
+        let webhooks = (options.insertion_webhook_max_attempts > 0).then(|| {
+            Webhooks::new(options.insertion_webhook_max_attempts)
+                .with_allowed_hosts(options.insertion_webhook_allowed_hosts.clone())
+                .with_private_addresses(options.insertion_webhook_private_addresses)
+        });
+        let batching_policy = if options.batch_max_wait_ms > 0 {
+            BatchingPolicy::MaxWait(Duration::from_millis(options.batch_max_wait_ms))
+        } else if options.batch_cost_max_wait_ms > 0 {
+            BatchingPolicy::CostAware(CostModel {
+                fixed_cost:    options.batch_fixed_cost,
+                identity_cost: options.batch_identity_cost,
+                max_wait:      Duration::from_millis(options.batch_cost_max_wait_ms),
+            })
+        } else if options.batch_linger_ms > 0 {
+            BatchingPolicy::MaxWait(Duration::from_millis(options.batch_linger_ms))
+        } else {
+            BatchingPolicy::Immediate
+        };
+        let identity_committer = Arc::new(
+            IdentityCommitter::new(
+                database.clone(),
+                identity_manager.clone(),
+                tree_state.clone(),
+                prover_map.clone(),
+                Duration::from_millis(options.batch_linger_ms),
+                options.priority_aging_batches,
+                empty_batch_interval,
+            )
+            .with_min_batch_interval(Duration::from_millis(options.min_batch_interval_ms))
+            .with_throughput_window(Duration::from_secs(
+                options.insertion_throughput_window_secs,
+            ))
+            .with_coalesced_wake_ups(options.coalesce_wake_ups)
+            .with_oldest_first(options.drain_oldest_first)
+            .with_deterministic_ties(options.deterministic_tie_break)
+            .with_pre_root_validation(options.validate_pre_root)
+            .with_batch_size_hints(options.batch_size_hints)
+            .with_webhooks(webhooks.clone())
+            .with_dedicated_runtime(options.committer_threads)
+            .with_shutdown_drain(Duration::from_secs(options.shutdown_drain_secs))
+            // Subscriptions learn of processed identities from the batch events.
+            .with_batch_events(
+                options
+                    .batch_event_capacity
+                    .max(options.status_subscription_capacity),
+            )
+            .with_batching_policy(batching_policy),
+        );
+        let status_changes = (options.status_subscription_capacity > 0).then(|| {
+            StatusChanges::new(options.status_subscription_capacity)
+                .with_max_commitments(options.status_subscription_max_identities)
+                .with_max_sockets_per_client(options.status_subscriptions_per_client)
+        });
+        if let (Some(status_changes), Some(batch_events)) =
+            (&status_changes, identity_committer.subscribe_batch_events())
+        {
+            status_changes::forward_batch_events(batch_events, status_changes.clone());
+        }
+        let proof_cache = Arc::new(Mutex::new(ProofCache::new(options.proof_cache_max_size)));
         let chain_subscriber = EthereumSubscriber::new(
             options.starting_block,
             database.clone(),
             identity_manager.clone(),
             tree_state.clone(),
             identity_committer.clone(),
+            options.rebuild_on_corrupt_cache,
+        )
+        .with_status_changes(status_changes.clone())
+        .with_webhooks(webhooks.clone())
+        .with_proof_cache(Some(proof_cache.clone()));
+        let identity_deleter = IdentityDeleter::new(
+            database.clone(),
+            identity_manager.clone(),
+            tree_state.clone(),
+        )
+        .with_batches(
+            Duration::from_secs(options.deletion_batch_interval_secs),
+            options.deletion_batch_size,
         );
+        // Without deletion provers, deletions are submitted without a batch
+        // proof, as the legacy contract expects. A contract that does not
+        // verify proofs would only have them thrown away.
+        let identity_deleter = if deletion_prover_map.read().await.is_empty() {
+            identity_deleter
+        } else if !identity_manager.proves_batches() {
+            warn!("Deletion provers are configured, but the contract does not verify proofs.");
+            identity_deleter
+        } else {
+            identity_deleter.with_provers(deletion_prover_map)
+        };
+
+        let receipt_signer = options
+            .receipt_signing_key
+            .map(ReceiptSigner::new)
+            .transpose()?;
+        if let Some(signer) = &receipt_signer {
+            info!(address = ?signer.address(), "Signing insertion receipts.");
+        }
 
         let snark_scalar_field = Hash::from_str_radix(
             "21888242871839275222246405745257275088548364400416034343698204186575808495617",
@@ -156,33 +924,178 @@ impl App {
             ethereum,
             identity_manager,
             identity_committer,
+            identity_deleter,
+            recoveries: options.deletion_batch_interval_secs > 0,
             chain_subscriber,
             tree_state,
+            tree_hasher: options.tree_hash_function,
             snark_scalar_field,
+            prover_map,
+            prover_options: options.prover.clone(),
+            prover_admin_api: options.prover_admin_api,
+            prover_allowed_hosts: options.prover_admin_allowed_hosts,
+            expose_prover_errors: options.expose_prover_errors,
+            require_api_keys: options.require_api_keys,
+            api_key_admin_api: options.api_key_admin_api,
+            admin_key: AdminKey::new(options.admin_api_key.as_deref()),
+            require_healthy_prover: options.require_healthy_prover,
+            max_staleness: Duration::from_secs(options.max_staleness_secs),
+            max_roots: options.max_roots_per_request,
+            max_leaves: options.max_leaves_per_request,
+            seen_cache: Arc::new(Mutex::new(SeenCache::new(
+                Duration::from_secs(options.seen_cache_max_age),
+                options.seen_cache_max_size,
+            ))),
+            status_coalescer: (options.status_coalesce_window_ms > 0).then(|| {
+                StatusCoalescer::new(Duration::from_millis(options.status_coalesce_window_ms))
+            }),
+            proof_cache,
+            degraded_proofs: options.degraded_proofs,
+            proof_lock_wait: options
+                .inclusion_proof_lock_wait_ms
+                .map(Duration::from_millis),
+            webhooks,
+            receipt_signer,
+            status_changes,
         };
 
+        // Bring back the provers added at runtime
+        if app.prover_admin_api {
+            app.restore_provers().await?;
+        }
+        // Without a prover no batch could be assembled, so the committer would
+        // stall. Restored provers that are down count, as the health checks
+        // bring them back once they are up.
+        if !app
+            .prover_map
+            .read()
+            .await
+            .as_batch_size_vec()
+            .iter()
+            .any(|&batch_size| batch_size > 0)
+        {
+            return Err(anyhow!(
+                "No insertion prover is registered for a batch size above zero."
+            ));
+        }
+
         select! {
-            _ = app.load_initial_events(options.lock_timeout, options.starting_block, cache_recovery_step_size) => {},
+            _ = app.load_initial_events(options.lock_timeout, options.starting_block, cache_recovery_step_size, options.rebuild_on_corrupt_cache) => {},
             _ = await_shutdown() => return Err(anyhow!("Interrupted"))
         }
 
         // Basic sanity checks on the merkle tree
         app.chain_subscriber.check_health().await;
 
+        // Keep the snapshot of the tree recent for a fast restart
+        if let Some(path) = options.tree_snapshot_file {
+            tree_cache::start_snapshots(
+                app.tree_state.clone(),
+                path,
+                Duration::from_secs(options.tree_snapshot_interval_secs),
+            );
+        }
+
         // Listen to Ethereum events
         app.chain_subscriber.start(refresh_rate).await;
 
         // Process to push new identities to Ethereum
         app.identity_committer.start().await;
 
+        // Process to queue identities for deletion
+        app.identity_deleter.start().await;
+
+        // Keep the persisted backlog observable
+        Self::start_backlog_metric(
+            app.database.clone(),
+            Duration::from_secs(options.backlog_metric_interval_secs),
+        );
+
+        // Drop identities that expired before being mined
+        Self::start_expiry_sweep(
+            app.database.clone(),
+            app.seen_cache.clone(),
+            Duration::from_secs(options.expiry_sweep_interval_secs),
+        );
+
         Ok(app)
     }
 
+    /// Periodically refreshes the gauge of identities persisted but not yet
+    /// mined, until shutdown.
+    fn start_backlog_metric(database: Arc<Database>, interval: Duration) {
+        tokio::spawn(async move {
+            loop {
+                if let Err(error) = database.refresh_backlog_metric().await {
+                    warn!(?error, "Failed to refresh the pending backlog metric.");
+                }
+                select! {
+                    _ = sleep(interval) => {}
+                    _ = await_shutdown() => return,
+                }
+            }
+        });
+    }
+
+    /// Periodically drops pending identities past their expiry, until
+    /// shutdown. The dropped identities can be inserted again right away.
+    fn start_expiry_sweep(
+        database: Arc<Database>,
+        seen_cache: Arc<Mutex<SeenCache>>,
+        interval: Duration,
+    ) {
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    _ = sleep(interval) => {}
+                    _ = await_shutdown() => return,
+                }
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |time| time.as_secs());
+                match database.sweep_expired_identities(now).await {
+                    Ok(expired) if expired.is_empty() => {}
+                    Ok(expired) => {
+                        let mut seen_cache = seen_cache.lock().unwrap();
+                        for commitment in &expired {
+                            seen_cache.remove(commitment);
+                        }
+                        info!(count = expired.len(), "Dropped expired pending identities.");
+                    }
+                    Err(error) => warn!(?error, "Failed to sweep expired pending identities."),
+                }
+            }
+        });
+    }
+
+    /// Records the depth of the tree in a fresh database, or checks that it
+    /// matches the depth the database was created for.
+    async fn check_tree_depth(database: &Database, depth: usize) -> AnyhowResult<()> {
+        match database.get_tree_depth().await? {
+            None => {
+                info!(depth, "Recording the tree depth of the database.");
+                database.set_tree_depth(depth).await?;
+            }
+            Some(recorded) if recorded != depth => {
+                error!(
+                    depth,
+                    recorded, "The tree depth does not match the depth of the database."
+                );
+                return Err(anyhow!(
+                    "Tree depth {depth} does not match the depth {recorded} of the database"
+                ));
+            }
+            Some(_) => {}
+        }
+        Ok(())
+    }
+
     async fn load_initial_events(
         &mut self,
         lock_timeout: u64,
         starting_block: u64,
         cache_recovery_step_size: usize,
+        rebuild_on_corrupt_cache: bool,
     ) -> AnyhowResult<()> {
         let mut root_mismatch_count = 0;
         loop {
@@ -204,13 +1117,12 @@ impl App {
                     root_mismatch_count += 1;
 
                     // Create a new empty MerkleTree
-                    self.tree_state = Arc::new(TimedRwLock::new(
-                        Duration::from_secs(lock_timeout),
-                        TreeState::new(
-                            self.identity_manager.tree_depth() + 1,
-                            self.identity_manager.initial_leaf_value(),
-                        ),
-                    ));
+                    let empty_tree = self.tree_state.read_uninterruptible().await.cleared();
+                    self.tree_state = Arc::new(
+                        TimedRwLock::new(Duration::from_secs(lock_timeout), empty_tree)
+                            .with_hold_time_metrics(self.tree_state.records_hold_times())
+                            .with_hold_warning(self.tree_state.hold_warning()),
+                    );
 
                     // Retry
                     self.chain_subscriber = EthereumSubscriber::new(
@@ -219,7 +1131,14 @@ impl App {
                         self.identity_manager.clone(),
                         self.tree_state.clone(),
                         self.identity_committer.clone(),
-                    );
+                        rebuild_on_corrupt_cache,
+                    )
+                    .with_status_changes(self.status_changes.clone())
+                    .with_webhooks(self.webhooks.clone())
+                    .with_proof_cache(Some(self.proof_cache.clone()));
+                    self.identity_deleter = self
+                        .identity_deleter
+                        .with_tree_state(self.tree_state.clone());
                 }
                 Err(e) => return Err(e.into()),
                 Ok(_) => return Ok(()),
@@ -231,22 +1150,14 @@ impl App {
         commitment.lt(&self.snark_scalar_field)
     }
 
-    /// Queues an insert into the merkle tree.
-    ///
-    /// # Errors
-    ///
-    /// Will return `Err` if identity is already queued, or in the tree, or the
-    /// queue malfunctions.
-    #[instrument(level = "debug", skip_all)]
-    pub async fn insert_identity(
+    /// Checks that `commitment` can be inserted and is not queued yet. Whether
+    /// it is in the tree already is left to the caller, which must check it
+    /// afterwards.
+    async fn check_new_commitment(
         &self,
         group_id: usize,
         commitment: Hash,
     ) -> Result<(), ServerError> {
-        if U256::from(group_id) != self.identity_manager.group_id() {
-            return Err(ServerError::InvalidGroupId);
-        }
-
         if commitment == self.identity_manager.initial_leaf_value() {
             warn!(?commitment, "Attempt to insert initial leaf.");
             return Err(ServerError::InvalidCommitment);
@@ -260,6 +1171,11 @@ impl App {
             return Err(ServerError::UnreducedCommitment);
         }
 
+        if self.seen_cache.lock().unwrap().contains(&commitment) {
+            warn!(?commitment, "Commitment was inserted recently.");
+            return Err(ServerError::DuplicateCommitment);
+        }
+
         // Note the ordering of duplicate checks: since we never want to lose data,
         // pending identities are removed from the DB _after_ they are inserted into the
         // tree. Therefore this order of checks guarantees we will not insert a
@@ -273,28 +1189,168 @@ impl App {
             return Err(ServerError::DuplicateCommitment);
         }
 
-        {
-            let tree = self.tree_state.read().await?;
-            if let Some(existing) = tree
-                .merkle_tree
-                .leaves()
-                .iter()
-                .position(|&x| x == commitment)
-            {
-                warn!(?existing, ?commitment, next = %tree.next_leaf, "Commitment already exists in tree.");
-                return Err(ServerError::DuplicateCommitment);
-            }
-        }
+        Ok(())
+    }
 
-        self.database
-            .insert_pending_identity(group_id, &commitment)
-            .await?;
+    /// Checks that `commitment` could be inserted into `group_id`: that it is a
+    /// reduced field element other than the initial leaf, and neither queued
+    /// nor in the tree. Nothing is inserted.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` with the reason the insertion would be rejected.
+    #[instrument(level = "debug", skip_all)]
+    pub async fn verify_commitment(
+        &self,
+        group_id: usize,
+        commitment: Hash,
+    ) -> Result<(), ServerError> {
+        if U256::from(group_id) != self.identity_manager.group_id() {
+            return Err(ServerError::InvalidGroupId);
+        }
+        self.check_new_commitment(group_id, commitment).await?;
+        let tree = self.tree_state.read().await?;
+        if tree.leaf_index(&commitment).is_some() {
+            return Err(ServerError::DuplicateCommitment);
+        }
+        Ok(())
+    }
+
+    /// Queues an insert into the merkle tree. Identities with a higher
+    /// `priority` are committed first. A `batch_size_hint` asks for a batch no
+    /// larger than needed to fit it, if batch size hints are enabled. It is
+    /// recorded as the smallest prover tier that fits it, and dropped if none
+    /// does.
+    ///
+    /// An identity with an `expires_at` unix time is dropped from the queue if
+    /// it has not been mined by then.
+    ///
+    /// A `nonce` given with the name of the API key the request was
+    /// authenticated with must be greater than the last one accepted with the
+    /// key, so that the insertion cannot be replayed. It is used up along with
+    /// queueing the identity.
+    ///
+    /// If a receipt signing key is configured, returns a signed receipt of the
+    /// acceptance.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if identity is already queued, or in the tree, or the
+    /// nonce was replayed, or the expiry has passed, or the queue malfunctions.
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(level = "debug", skip_all)]
+    pub async fn insert_identity(
+        &self,
+        group_id: usize,
+        commitment: Hash,
+        priority: u8,
+        batch_size_hint: Option<usize>,
+        webhook_url: Option<&str>,
+        nonce: Option<(&str, u64)>,
+        expires_at: Option<u64>,
+    ) -> Result<Option<Receipt>, ServerError> {
+        if U256::from(group_id) != self.identity_manager.group_id() {
+            return Err(ServerError::InvalidGroupId);
+        }
+
+        let webhook_url = webhook_url
+            .map(|url| self.parse_webhook_url(url))
+            .transpose()?;
+
+        if let Some(expires_at) = expires_at {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|e| anyhow!(e))?
+                .as_secs();
+            if expires_at <= now {
+                return Err(ServerError::Expired);
+            }
+        }
+
+        if self.require_healthy_prover
+            && !prover::any_healthy(self.prover_map.read().await.provers()).await
+        {
+            warn!(?commitment, "Rejecting insertion as no prover is healthy.");
+            return Err(ServerError::NoHealthyProvers);
+        }
+
+        self.check_new_commitment(group_id, commitment).await?;
+
+        let batch_size_hint = match batch_size_hint {
+            Some(hint) => self
+                .prover_map
+                .read()
+                .await
+                .get(hint)
+                .map(Prover::batch_size),
+            None => None,
+        };
+
+        let root = {
+            let tree = self.tree_state.read().await?;
+            if let Some(existing) = tree
+                .merkle_tree
+                .leaves()
+                .iter()
+                .position(|&x| x == commitment)
+            {
+                warn!(?existing, ?commitment, next = %tree.next_leaf, "Commitment already exists in tree.");
+                return Err(ServerError::DuplicateCommitment);
+            }
+            if tree.next_leaf >= tree.merkle_tree.num_leaves() {
+                warn!(?commitment, "Rejecting insertion into a full tree.");
+                return Err(ServerError::TreeFull);
+            }
+            tree.merkle_tree.root()
+        };
+
+        match self
+            .database
+            .insert_pending_identity_with_options(
+                group_id,
+                &commitment,
+                priority,
+                batch_size_hint,
+                webhook_url.as_ref(),
+                nonce,
+                expires_at,
+            )
+            .await
+        {
+            Err(database::Error::ReplayedNonce) => {
+                warn!(?commitment, ?nonce, "Rejecting replayed insertion.");
+                return Err(ServerError::ReplayedNonce);
+            }
+            result => result?,
+        }
+        self.seen_cache.lock().unwrap().insert(commitment);
+        if let Some(status_changes) = &self.status_changes {
+            status_changes.emit(commitment, IdentityStatus::Pending);
+        }
 
         self.identity_committer.notify_queued().await;
 
-        Ok(())
+        let Some(signer) = &self.receipt_signer else {
+            return Ok(None);
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| anyhow!(e))?
+            .as_secs();
+        let receipt = signer
+            .sign(ReceiptPayload {
+                commitment,
+                root,
+                timestamp,
+            })
+            .await?;
+
+        Ok(Some(receipt))
     }
 
+    /// Returns the inclusion proof of `commitment`, without its empty siblings
+    /// if `compressed` is set.
+    ///
     /// # Errors
     ///
     /// Will return `Err` if the provided index is out of bounds.
@@ -303,6 +1359,27 @@ impl App {
         &self,
         group_id: usize,
         commitment: &Hash,
+        root: Option<Hash>,
+        compressed: bool,
+    ) -> Result<InclusionProofResponse, ServerError> {
+        let response = match root {
+            Some(root) => {
+                self.historical_inclusion_proof(group_id, commitment, root)
+                    .await?
+            }
+            None => self.full_inclusion_proof(group_id, commitment).await?,
+        };
+        if compressed {
+            Ok(response.compressed(self.identity_manager.initial_leaf_value(), self.tree_hasher))
+        } else {
+            Ok(response)
+        }
+    }
+
+    async fn full_inclusion_proof(
+        &self,
+        group_id: usize,
+        commitment: &Hash,
     ) -> Result<InclusionProofResponse, ServerError> {
         if U256::from(group_id) != self.identity_manager.group_id() {
             return Err(ServerError::InvalidGroupId);
@@ -313,12 +1390,28 @@ impl App {
         }
 
         {
-            let tree = self.tree_state.read().await.map_err(|e| {
-                error!(?e, "Failed to obtain tree lock in inclusion_proof.");
-                panic!("Sequencer potentially deadlocked, terminating.");
-                #[allow(unreachable_code)]
-                e
-            })?;
+            let stale = || async {
+                if self.degraded_proofs {
+                    self.valid_cached_proof(commitment).await
+                } else {
+                    None
+                }
+            };
+            let tree =
+                match read_tree_or_stale(&self.tree_state, self.proof_lock_wait, stale).await? {
+                    TreeRead::Tree(tree) => tree,
+                    TreeRead::Stale(response) => return Ok(response),
+                };
+
+            // A cached proof is served for as long as the tree has not moved
+            // on from its root, which was accepted on chain when it was cached.
+            let root = tree.merkle_tree.root();
+            if let Some(cached) = self.proof_cache.lock().unwrap().get(commitment, &root) {
+                return Ok(InclusionProofResponse::Proof {
+                    root,
+                    proof: cached.proof,
+                });
+            }
 
             if let Some(identity_index) = tree
                 .merkle_tree
@@ -330,7 +1423,6 @@ impl App {
                     .merkle_tree
                     .proof(identity_index)
                     .ok_or(ServerError::IndexOutOfBounds)?;
-                let root = tree.merkle_tree.root();
 
                 // Locally check the proof
                 // TODO: Check the leaf index / path
@@ -355,18 +1447,634 @@ impl App {
                     );
                     return Err(ServerError::RootMismatch);
                 }
+                self.proof_cache
+                    .lock()
+                    .unwrap()
+                    .insert(*commitment, root, proof.clone());
                 return Ok(InclusionProofResponse::Proof { root, proof });
             }
         }
 
-        if self
+        if self.is_pending(group_id, commitment).await? {
+            Ok(InclusionProofResponse::Pending)
+        } else {
+            Err(ServerError::IdentityCommitmentNotFound)
+        }
+    }
+
+    /// Returns the newest cached proof of `commitment` whose root is still
+    /// accepted on chain, dropping those whose root is not.
+    async fn valid_cached_proof(&self, commitment: &Hash) -> Option<CachedProof> {
+        let history = self.proof_cache.lock().unwrap().history(commitment);
+        for cached in history {
+            if self
+                .identity_manager
+                .assert_valid_root(cached.root)
+                .await
+                .is_ok()
+            {
+                return Some(cached);
+            }
+            self.proof_cache
+                .lock()
+                .unwrap()
+                .remove_root(commitment, &cached.root);
+        }
+        None
+    }
+
+    /// Returns the inclusion proof of `commitment` against the earlier `root`
+    /// of the tree, found among the cached events.
+    ///
+    /// Unlike the latest root, `root` is not checked against the contract, as
+    /// it may no longer be accepted there.
+    async fn historical_inclusion_proof(
+        &self,
+        group_id: usize,
+        commitment: &Hash,
+        root: Hash,
+    ) -> Result<InclusionProofResponse, ServerError> {
+        if U256::from(group_id) != self.identity_manager.group_id() {
+            return Err(ServerError::InvalidGroupId);
+        }
+
+        if commitment == &self.identity_manager.initial_leaf_value() {
+            return Err(ServerError::InvalidCommitment);
+        }
+
+        let size = self
             .database
-            .pending_identity_exists(group_id, commitment)
+            .count_leaves_at_root(&root)
             .await?
+            .ok_or(ServerError::RootNotFound)?;
+
+        let tree = self.tree_state.read().await?;
+        let identity_index = tree.merkle_tree.leaves()[..size.min(tree.next_leaf)]
+            .iter()
+            .position(|&x| x == *commitment)
+            .ok_or(ServerError::IdentityCommitmentNotFound)?;
+        let (computed_root, proof) = tree
+            .merkle_tree
+            .prefix_proof(identity_index, size)
+            .ok_or(ServerError::IndexOutOfBounds)?;
+        drop(tree);
+
+        // Leaves deleted since differ from the tree at that root.
+        if computed_root != root {
+            error!(
+                ?computed_root,
+                requested_root = ?root,
+                "Root mismatch between tree and cached events."
+            );
+            return Err(ServerError::RootMismatch);
+        }
+
+        Ok(InclusionProofResponse::Proof { root, proof })
+    }
+
+    /// Checks whether `commitment` is pending, together with concurrent
+    /// requests if coalescing is enabled.
+    async fn is_pending(&self, group_id: usize, commitment: &Hash) -> Result<bool, ServerError> {
+        let Some(coalescer) = &self.status_coalescer else {
+            return Ok(self
+                .database
+                .pending_identity_exists(group_id, commitment)
+                .await?);
+        };
+        let pending = coalescer
+            .contains(*commitment, |commitments| async move {
+                Ok(self
+                    .database
+                    .pending_identities_exist(group_id, &commitments)
+                    .await?)
+            })
+            .await?;
+        Ok(pending)
+    }
+
+    /// Checks whether the tree is recent enough to serve requests.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the tree has not caught up with the chain within
+    /// the maximum staleness.
+    pub fn readiness(&self) -> Result<(), ServerError> {
+        if self.max_staleness.is_zero()
+            || self
+                .chain_subscriber
+                .sync_status()
+                .is_synced_within(self.max_staleness)
         {
-            Ok(InclusionProofResponse::Pending)
+            Ok(())
         } else {
-            Err(ServerError::IdentityCommitmentNotFound)
+            Err(ServerError::Stale)
+        }
+    }
+
+    /// Returns how many identities and batches are waiting at each stage of
+    /// the queue.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the pending identities cannot be counted.
+    pub async fn queue_status(&self) -> Result<QueueStatusResponse, ServerError> {
+        let (awaiting_submission, awaiting_mining) = self.identity_committer.pending_batches();
+        Ok(QueueStatusResponse {
+            announced: self.identity_committer.announced(),
+            awaiting_batching: self.database.count_unprocessed_identities().await?,
+            awaiting_submission,
+            awaiting_mining,
+        })
+    }
+
+    /// Lists the roots of the batches mined in blocks `from_block` to
+    /// `to_block`, oldest first and at most `max_roots_per_request` of them.
+    /// The next page starts `after` the cursor of the last root listed.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the roots cannot be read.
+    pub async fn roots(
+        &self,
+        from_block: u64,
+        after: Option<RootCursor>,
+        to_block: Option<u64>,
+    ) -> Result<Vec<RootResponse>, ServerError> {
+        Ok(self
+            .database
+            .get_roots(from_block, after, to_block, self.max_roots)
+            .await?
+            .into_iter()
+            .map(RootResponse::from)
+            .collect())
+    }
+
+    /// Lists up to `limit` of the leaves inserted into the tree, starting at
+    /// the leaf `offset`, along with the root of the tree they were read
+    /// from. At most `max_leaves_per_request` leaves are listed.
+    ///
+    /// Deleted leaves are listed as the initial leaf.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the tree lock times out.
+    pub async fn leaves(
+        &self,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<LeavesResponse, ServerError> {
+        let limit = limit.map_or(self.max_leaves, |limit| limit.min(self.max_leaves));
+        let tree = self.tree_state.read().await?;
+        let start = offset.min(tree.next_leaf);
+        let end = start.saturating_add(limit).min(tree.next_leaf);
+        Ok(LeavesResponse {
+            root: tree.merkle_tree.root(),
+            total: tree.next_leaf,
+            offset,
+            leaves: tree.merkle_tree.leaves()[start..end].to_vec(),
+        })
+    }
+
+    /// Lists the registered insertion provers in ascending order of batch
+    /// size.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the prover admin API is disabled.
+    pub async fn list_provers(&self) -> Result<Vec<ProverResponse>, ServerError> {
+        self.check_prover_admin_api()?;
+        Ok(self
+            .prover_map
+            .read()
+            .await
+            .provers()
+            .map(|prover| self.prover_response(prover))
+            .collect())
+    }
+
+    /// Returns the insertion prover registered for exactly `batch_size`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the prover admin API is disabled or no prover is
+    /// registered for `batch_size`.
+    pub async fn get_prover(&self, batch_size: usize) -> Result<ProverResponse, ServerError> {
+        self.check_prover_admin_api()?;
+        let map = self.prover_map.read().await;
+        map.provers()
+            .find(|prover| prover.batch_size() == batch_size)
+            .map(|prover| self.prover_response(prover))
+            .ok_or(ServerError::ProverUpdate(UpdateError::NotFound(batch_size)))
+    }
+
+    /// Registers a new insertion prover.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the prover admin API is disabled, the
+    /// configuration is invalid, the prover is not at an allowed host, the
+    /// prover is unreachable or a prover is already registered for its batch
+    /// size.
+    pub async fn add_prover(
+        &self,
+        configuration: &ProverConfiguration,
+    ) -> Result<ProverResponse, ServerError> {
+        self.check_prover_admin_api()?;
+        check_prover_host(&configuration.url, &self.prover_allowed_hosts)?;
+        let prover = self.make_prover(configuration)?;
+        let response = ProverResponse::from(&prover);
+        add_shared_prover(&self.prover_map, prover, async {
+            Ok::<_, ServerError>(self.database.save_prover(configuration).await?)
+        })
+        .await?;
+        Ok(response)
+    }
+
+    /// Removes the insertion prover registered for `batch_size`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the prover admin API is disabled, no prover is
+    /// registered for `batch_size` or it is the last prover.
+    pub async fn remove_prover(&self, batch_size: usize) -> Result<(), ServerError> {
+        self.check_prover_admin_api()?;
+        remove_shared_prover(&self.prover_map, batch_size, async {
+            Ok::<_, ServerError>(self.database.remove_prover(batch_size).await?)
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Checks the API key a request to `endpoint` was made with, counts the
+    /// request against the key and returns its name.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if API keys are required and `api_key` is missing,
+    /// unknown or revoked.
+    pub async fn authenticate(
+        &self,
+        api_key: Option<&str>,
+        endpoint: &str,
+    ) -> Result<Option<String>, ServerError> {
+        let key_hash = api_key.map(auth::hash_key);
+        let name = match &key_hash {
+            Some(key_hash) => self.database.api_key_name(key_hash).await?,
+            None => None,
+        };
+        match name.zip(key_hash) {
+            Some((name, key_hash)) => {
+                auth::record_request(&key_hash, endpoint);
+                Ok(Some(name))
+            }
+            None if self.require_api_keys => Err(ServerError::Unauthorized),
+            None => Ok(None),
+        }
+    }
+
+    /// The credential of the admin routes.
+    #[must_use]
+    pub const fn admin_key(&self) -> &AdminKey {
+        &self.admin_key
+    }
+
+    /// Creates a new API key under `name`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the API key admin API is disabled or `name` is
+    /// already taken.
+    pub async fn create_api_key(&self, name: String) -> Result<CreatedApiKey, ServerError> {
+        self.check_api_key_admin_api()?;
+        let api_key = auth::generate_key();
+        let key_hash = auth::hash_key(&api_key);
+        if !self.database.insert_api_key(&name, &key_hash).await? {
+            return Err(ServerError::DuplicateApiKey);
+        }
+        let key_id = auth::key_id(&key_hash);
+        info!(name, key_id, "Created API key.");
+        Ok(CreatedApiKey {
+            name,
+            api_key,
+            key_id,
+        })
+    }
+
+    /// Revokes the API key of `name`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the API key admin API is disabled or there is no
+    /// API key of `name`.
+    pub async fn revoke_api_key(&self, name: &str) -> Result<(), ServerError> {
+        self.check_api_key_admin_api()?;
+        if !self.database.remove_api_key(name).await? {
+            return Err(ServerError::ApiKeyNotFound);
+        }
+        info!(name, "Revoked API key.");
+        Ok(())
+    }
+
+    /// Registers the insertion provers added through the admin API before the
+    /// last restart, replacing the configured ones of the same batch size, and
+    /// drops the configured provers removed through it.
+    ///
+    /// Restored provers are health checked like added ones, but those that fail
+    /// stay registered as unavailable until a later health check passes.
+    async fn restore_provers(&self) -> AnyhowResult<()> {
+        let removed = self.database.get_removed_provers().await?;
+        let configurations = self.database.get_provers().await?;
+        let mut provers = Vec::with_capacity(configurations.len());
+        for configuration in &configurations {
+            let prover = self.make_prover(configuration)?;
+            let healthy = match prover.health_check().await {
+                Ok(()) => true,
+                Err(error) => {
+                    warn!(batch_size = configuration.batch_size, url = %prover.url(), %error, "Restored prover failed its health check.");
+                    false
+                }
+            };
+            provers.push((prover, healthy));
+        }
+
+        let mut map = self.prover_map.write().await;
+        for batch_size in removed {
+            if map.remove(batch_size).is_some() {
+                info!(batch_size, "Dropped removed prover.");
+            }
+        }
+        for (prover, healthy) in provers {
+            let batch_size = prover.batch_size();
+            info!(batch_size, url = %prover.url(), "Restored prover.");
+            map.add(batch_size, prover);
+            map.set_available(batch_size, healthy);
+        }
+        Ok(())
+    }
+
+    fn make_prover(&self, configuration: &ProverConfiguration) -> Result<Prover, ServerError> {
+        Prover::from_options(configuration, &self.prover_options)
+            .map_err(|error| ServerError::InvalidProverConfiguration(error.to_string()))
+    }
+
+    fn prover_response(&self, prover: &Prover) -> ProverResponse {
+        ProverResponse {
+            last_error: prover.last_error().filter(|_| self.expose_prover_errors),
+            ..ProverResponse::from(prover)
+        }
+    }
+
+    /// The admin routes are not exposed at all unless enabled.
+    const fn check_prover_admin_api(&self) -> Result<(), ServerError> {
+        if self.prover_admin_api {
+            Ok(())
+        } else {
+            Err(ServerError::InvalidPath)
+        }
+    }
+
+    const fn check_api_key_admin_api(&self) -> Result<(), ServerError> {
+        if self.api_key_admin_api {
+            Ok(())
+        } else {
+            Err(ServerError::InvalidPath)
+        }
+    }
+
+    /// Parses the webhook an insertion asked to be notified at, which must be
+    /// an HTTP(S) URL that webhooks may be delivered to.
+    fn parse_webhook_url(&self, url: &str) -> Result<Url, ServerError> {
+        let Some(webhooks) = &self.webhooks else {
+            return Err(ServerError::WebhooksDisabled);
+        };
+        let url =
+            Url::parse(url).map_err(|error| ServerError::InvalidWebhookUrl(error.to_string()))?;
+        webhooks
+            .check_url(&url)
+            .map_err(|error| ServerError::InvalidWebhookUrl(error.to_string()))?;
+        Ok(url)
+    }
+
+    /// Returns a receiver of the lifecycle events of the batches committed
+    /// from now on, or `None` if batch events are disabled.
+    #[must_use]
+    pub fn subscribe_batch_events(&self) -> Option<broadcast::Receiver<BatchEvent>> {
+        self.identity_committer.subscribe_batch_events()
+    }
+
+    /// Opens a subscriber of the identity status changes from now on for
+    /// `client`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if subscriptions are disabled or the client already
+    /// has as many subscribers open as allowed.
+    pub fn subscribe_status_changes(
+        &self,
+        client: Option<IpAddr>,
+    ) -> Result<Subscriber, ServerError> {
+        self.status_changes
+            .as_ref()
+            .ok_or(ServerError::SubscriptionsDisabled)?
+            .open(client)
+            .ok_or(ServerError::TooManySubscriptions)
+    }
+
+    /// Returns the status of `commitment`, or `None` if it is neither in the
+    /// tree nor queued.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the group id is invalid, the tree lock times out
+    /// or the database query fails.
+    pub async fn identity_status(
+        &self,
+        group_id: usize,
+        commitment: &Hash,
+    ) -> Result<Option<IdentityStatus>, ServerError> {
+        if U256::from(group_id) != self.identity_manager.group_id() {
+            return Err(ServerError::InvalidGroupId);
+        }
+
+        let in_tree = self
+            .tree_state
+            .read()
+            .await?
+            .leaf_index(commitment)
+            .is_some();
+        if in_tree {
+            return Ok(Some(IdentityStatus::Mined));
+        }
+
+        let mined = self
+            .database
+            .pending_identity_mined(group_id, commitment)
+            .await?;
+        Ok(mined.map(|mined| {
+            if mined {
+                IdentityStatus::Processed
+            } else {
+                IdentityStatus::Pending
+            }
+        }))
+    }
+
+    /// Returns the lifecycle of `commitment`, from being queued to being
+    /// mined.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the commitment is neither in the tree nor queued,
+    /// the tree lock times out or the database query fails.
+    pub async fn identity_lifecycle(
+        &self,
+        commitment: &Hash,
+    ) -> Result<IdentityLifecycle, ServerError> {
+        let group_id = self.identity_manager.group_id().as_usize();
+        let status = self
+            .identity_status(group_id, commitment)
+            .await?
+            .ok_or(ServerError::IdentityCommitmentNotFound)?;
+        let audit = self
+            .database
+            .get_identity_audit(group_id, commitment)
+            .await?;
+        let queue_position = self
+            .database
+            .count_queued_before(group_id, commitment)
+            .await?;
+        // An identity requeued after a reorg waits for another batch.
+        let batch = audit
+            .as_ref()
+            .and_then(|audit| audit.batch.clone())
+            .filter(|_| status != IdentityStatus::Pending);
+        let submitted_at = audit
+            .as_ref()
+            .and_then(|audit| audit.submitted_at)
+            .filter(|_| batch.is_some());
+        let confirmed_block = match status {
+            IdentityStatus::Mined => self
+                .database
+                .get_batch_for_commitment(commitment)
+                .await?
+                .map(|batch| u64::try_from(batch.block_index).unwrap_or_default()),
+            _ => None,
+        };
+        Ok(IdentityLifecycle {
+            identity_commitment: *commitment,
+            status,
+            received_at: audit.map(|audit| audit.received_at),
+            queue_position,
+            batch_id: batch.as_ref().map(|batch| batch.batch_id),
+            submitted_at,
+            transaction_hash: batch.as_ref().map(|batch| batch.tx_hash),
+            block_number: confirmed_block.or_else(|| batch.map(|batch| batch.block_number)),
+        })
+    }
+
+    /// Returns the batch `id` from the batch audit log.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the batch was not recorded or the database query
+    /// fails.
+    pub async fn get_batch(&self, id: u64) -> Result<BatchResponse, ServerError> {
+        self.database
+            .get_batch(id)
+            .await?
+            .map(BatchResponse::from)
+            .ok_or(ServerError::BatchNotFound)
+    }
+
+    /// Returns the batch that included `commitment`, or `None` if it has not
+    /// been mined in a confirmed block yet.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the database query fails.
+    pub async fn get_batch_for_commitment(
+        &self,
+        commitment: &Hash,
+    ) -> AnyhowResult<Option<BatchRecord>> {
+        Ok(self.database.get_batch_for_commitment(commitment).await?)
+    }
+
+    /// Queues the deletion of an identity from the merkle tree.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the provided `group_id` is invalid, the identity is
+    /// not in the tree or the deleter malfunctions.
+    #[instrument(level = "debug", skip_all)]
+    pub async fn delete_identity(
+        &self,
+        group_id: usize,
+        commitment: Hash,
+    ) -> Result<DeletionResponse, ServerError> {
+        if U256::from(group_id) != self.identity_manager.group_id() {
+            return Err(ServerError::InvalidGroupId);
+        }
+
+        match self.identity_deleter.delete(group_id, commitment).await? {
+            OnDeleteComplete::NotFound => Err(ServerError::IdentityCommitmentNotFound),
+            OnDeleteComplete::AlreadyQueued => Err(ServerError::DeletionAlreadyQueued),
+            OnDeleteComplete::Deleted { root, proof } => Ok(DeletionResponse { root, proof }),
+        }
+    }
+
+    /// Queues the recovery of an identity: the deletion of `old_commitment`
+    /// and the insertion of `new_commitment` in its place. Both are recorded
+    /// together, and the new commitment is only committed once the deletion
+    /// is mined.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if deletions are not submitted on chain, the
+    /// provided `group_id` is invalid, the old identity is not in the tree or
+    /// already queued for deletion, the new identity cannot be inserted, or
+    /// the deleter malfunctions.
+    #[instrument(level = "debug", skip_all)]
+    pub async fn recover_identity(
+        &self,
+        group_id: usize,
+        old_commitment: Hash,
+        new_commitment: Hash,
+    ) -> Result<DeletionResponse, ServerError> {
+        if !self.recoveries {
+            return Err(ServerError::RecoveryDisabled);
+        }
+        if U256::from(group_id) != self.identity_manager.group_id() {
+            return Err(ServerError::InvalidGroupId);
+        }
+
+        self.check_new_commitment(group_id, new_commitment).await?;
+        if self
+            .tree_state
+            .read()
+            .await?
+            .merkle_tree
+            .leaves()
+            .contains(&new_commitment)
+        {
+            warn!(
+                ?new_commitment,
+                "Recovered commitment already exists in tree."
+            );
+            return Err(ServerError::DuplicateCommitment);
+        }
+
+        let outcome = self
+            .identity_deleter
+            .recover(group_id, old_commitment, new_commitment)
+            .await?;
+        match outcome {
+            OnDeleteComplete::NotFound => Err(ServerError::IdentityCommitmentNotFound),
+            OnDeleteComplete::AlreadyQueued => Err(ServerError::DeletionAlreadyQueued),
+            OnDeleteComplete::Deleted { root, proof } => {
+                self.seen_cache.lock().unwrap().insert(new_commitment);
+                self.identity_committer.notify_queued().await;
+                Ok(DeletionResponse { root, proof })
+            }
         }
     }
 
@@ -375,8 +2083,160 @@ impl App {
     /// Will return an Error if any of the components cannot be shut down
     /// gracefully.
     pub async fn shutdown(&self) -> AnyhowResult<()> {
-        info!("Shutting down identity committer and chain subscriber.");
+        info!("Shutting down identity committer, deleter and chain subscriber.");
         self.chain_subscriber.shutdown().await;
+        self.identity_deleter.shutdown().await?;
         self.identity_committer.shutdown().await
     }
 }
+
+/// The outcome of [`read_tree_or_stale`].
+enum TreeRead<'a> {
+    Tree(TimedReadGuard<'a, TreeState>),
+    Stale(InclusionProofResponse),
+}
+
+/// Acquires a read lock on the tree, waiting up to `wait` or the timeout of the
+/// lock, or serves the proof returned by `stale` instead if the lock times out.
+/// Its root must have been checked to still be accepted.
+///
+/// # Errors
+///
+/// Will return `Err` if the lock times out and there is no stale proof to fall
+/// back to.
+async fn read_tree_or_stale<F>(
+    tree_state: &TimedRwLock<TreeState>,
+    wait: Option<Duration>,
+    stale: impl FnOnce() -> F,
+) -> Result<TreeRead<'_>, ServerError>
+where
+    F: Future<Output = Option<CachedProof>>,
+{
+    let read = match wait {
+        None => tree_state.read().await,
+        Some(Duration::ZERO) => tree_state.try_read(),
+        Some(wait) => tree_state.read_timeout(wait).await,
+    };
+    let error = match read {
+        Ok(tree) => return Ok(TreeRead::Tree(tree)),
+        Err(error) => error,
+    };
+    let Some(CachedProof {
+        root,
+        proof,
+        cached_at,
+    }) = stale().await
+    else {
+        error!(?error, "Failed to obtain tree lock in inclusion_proof.");
+        return Err(error.into());
+    };
+    warn!(?error, ?root, "Tree lock timed out, serving a stale proof.");
+    Ok(TreeRead::Stale(InclusionProofResponse::StaleProof {
+        root,
+        proof,
+        age: cached_at.elapsed(),
+    }))
+}
+
+/// Checks that a prover added at runtime is reached over http(s) at one of the
+/// allowed hosts, so the admin API can't point the sequencer at arbitrary
+/// addresses.
+fn check_prover_host(url: &str, allowed_hosts: &[String]) -> Result<(), ServerError> {
+    let url = url::Url::parse(url)
+        .map_err(|error| ServerError::InvalidProverConfiguration(error.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(ServerError::InvalidProverConfiguration(format!(
+            "unsupported scheme {}",
+            url.scheme()
+        )));
+    }
+    let host = url.host_str().unwrap_or_default();
+    if !allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    {
+        return Err(ServerError::InvalidProverConfiguration(format!(
+            "host {host} is not allowed"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use semaphore::poseidon_tree::PoseidonTree;
+    use std::time::Instant;
+
+    #[test]
+    fn prover_host_should_be_allowlisted() {
+        let allowed = vec!["Prover.internal".to_string()];
+        assert!(check_prover_host("https://prover.internal:3001/", &allowed).is_ok());
+        assert!(check_prover_host("http://PROVER.internal", &allowed).is_ok());
+        assert!(check_prover_host("https://169.254.169.254/", &allowed).is_err());
+        assert!(check_prover_host("file://prover.internal/etc", &allowed).is_err());
+        assert!(check_prover_host("not a url", &allowed).is_err());
+        assert!(check_prover_host("https://prover.internal", &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn lock_timeout_should_serve_stale_proof() -> AnyhowResult<()> {
+        let tree_state = TimedRwLock::new(
+            Duration::from_millis(50),
+            TreeState::new(4, Hash::from(0_u64)),
+        );
+        let mut tree = PoseidonTree::new(4, Hash::from(0_u64));
+        tree.set(0, Hash::from(1_u64));
+        let stale = CachedProof {
+            root:      tree.root(),
+            proof:     tree.proof(0).unwrap(),
+            cached_at: Instant::now() - Duration::from_secs(30),
+        };
+
+        // The tree is readable, so the stale proof is not looked up.
+        let unused = || -> std::future::Ready<Option<CachedProof>> {
+            panic!("The stale proof was looked up.")
+        };
+        assert!(matches!(
+            read_tree_or_stale(&tree_state, None, unused).await,
+            Ok(TreeRead::Tree(_))
+        ));
+
+        let _writer = tree_state.write().await?;
+        let Ok(TreeRead::Stale(response)) =
+            read_tree_or_stale(&tree_state, None, || async { Some(stale.clone()) }).await
+        else {
+            panic!("Expected a stale proof.");
+        };
+        assert_eq!(
+            serde_json::to_value(&response)?,
+            serde_json::json!({
+                "root": stale.root,
+                "proof": stale.proof,
+                "stale": true,
+                "ageSecs": 30,
+            })
+        );
+        assert_eq!(response.to_response_code(), StatusCode::OK);
+
+        // Without a stale proof the request fails rather than the sequencer.
+        let Err(error) = read_tree_or_stale(&tree_state, None, || async { None }).await else {
+            panic!("Expected the lock to time out.");
+        };
+        assert_eq!(
+            error.to_response().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // Without waiting for the lock, the stale proof is served right away.
+        let started = Instant::now();
+        let fallback = read_tree_or_stale(&tree_state, Some(Duration::ZERO), || async {
+            Some(stale.clone())
+        })
+        .await;
+        assert!(matches!(fallback, Ok(TreeRead::Stale(_))));
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        Ok(())
+    }
+}
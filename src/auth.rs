@@ -0,0 +1,118 @@
+use crate::server::ToResponseCode;
+use ethers::{types::H256, utils::keccak256};
+use hyper::StatusCode;
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::Serialize;
+
+static API_KEY_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "api_key_requests",
+        "Number of authenticated requests, by API key id and endpoint.",
+        &["key_id", "endpoint"]
+    )
+    .unwrap()
+});
+
+/// Generates a new random API key.
+#[must_use]
+pub fn generate_key() -> String {
+    format!("{:x}", H256::random())
+}
+
+/// Returns the hash under which `api_key` is stored, so that a leaked
+/// database does not leak the keys.
+#[must_use]
+pub fn hash_key(api_key: &str) -> [u8; 32] {
+    keccak256(api_key.as_bytes())
+}
+
+/// The credential of the admin routes, kept hashed like the API keys.
+#[derive(Debug, Default)]
+pub struct AdminKey {
+    hash: Option<[u8; 32]>,
+}
+
+impl AdminKey {
+    #[must_use]
+    pub fn new(admin_key: Option<&str>) -> Self {
+        Self {
+            hash: admin_key.map(hash_key),
+        }
+    }
+
+    /// Returns `true` if `admin_key` is the admin credential. Nothing is
+    /// accepted while there is none.
+    #[must_use]
+    pub fn accepts(&self, admin_key: Option<&str>) -> bool {
+        match (&self.hash, admin_key) {
+            (Some(hash), Some(admin_key)) => hash_key(admin_key) == *hash,
+            _ => false,
+        }
+    }
+}
+
+/// Returns the id an API key is told apart by in the metrics, which are
+/// public: the leading bytes of its hash, so that neither the key nor its
+/// name is exposed.
+#[must_use]
+pub fn key_id(key_hash: &[u8; 32]) -> String {
+    hex::encode(&key_hash[..8])
+}
+
+/// Counts a request to `endpoint` made with the API key of `key_hash`.
+pub fn record_request(key_hash: &[u8; 32], endpoint: &str) {
+    API_KEY_REQUESTS
+        .with_label_values(&[&key_id(key_hash), endpoint])
+        .inc();
+}
+
+/// A newly created API key. The key itself is only ever shown here.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedApiKey {
+    pub name:    String,
+    pub api_key: String,
+    pub key_id:  String,
+}
+
+impl ToResponseCode for CreatedApiKey {
+    fn to_response_code(&self) -> StatusCode {
+        StatusCode::CREATED
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generated_keys_should_hash_differently() {
+        let (first, second) = (generate_key(), generate_key());
+        assert_eq!(first.len(), 64);
+        assert_ne!(first, second);
+        assert_eq!(hash_key(&first), keccak256(first.as_bytes()));
+        assert_ne!(hash_key(&first), hash_key(&second));
+    }
+
+    #[test]
+    fn key_ids_should_be_derived_from_the_hash() {
+        let api_key = generate_key();
+        let id = key_id(&hash_key(&api_key));
+        assert_eq!(id.len(), 16);
+        assert_eq!(id, hex::encode(&hash_key(&api_key)[..8]));
+        assert_ne!(id, key_id(&hash_key(&generate_key())));
+    }
+
+    #[test]
+    fn admin_key_should_only_accept_itself() {
+        let admin_key = AdminKey::new(Some("secret"));
+        assert!(admin_key.accepts(Some("secret")));
+        assert!(!admin_key.accepts(Some("guess")));
+        assert!(!admin_key.accepts(None));
+
+        // Without an admin key every request is rejected.
+        assert!(!AdminKey::default().accepts(Some("")));
+        assert!(!AdminKey::default().accepts(None));
+    }
+}
@@ -0,0 +1,81 @@
+use crate::identity_tree::Hash;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// A transition in the lifecycle of a batch of insertions.
+///
+/// Every batch is assembled, then submitted, and finally either mined or
+/// failed. A batch assembled on a stale tree is retried instead of being
+/// submitted, and its identities are assembled into a new batch. Events of the
+/// same batch carry the same `batch_id`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type")]
+pub enum BatchEvent {
+    /// The identities of the batch were picked from the queue.
+    #[serde(rename_all = "camelCase")]
+    BatchAssembled {
+        batch_id:    u64,
+        /// The root the batch builds on, which is `None` for an empty tree.
+        pre_root:    Option<Hash>,
+        commitments: Vec<Hash>,
+    },
+    /// The transactions of the batch were accepted by the contract.
+    #[serde(rename_all = "camelCase")]
+    BatchSubmitted { batch_id: u64, batch_size: usize },
+    /// The batch was mined in `block`.
+    #[serde(rename_all = "camelCase")]
+    BatchMined {
+        batch_id:   u64,
+        batch_size: usize,
+        block:      u64,
+    },
+    /// The batch failed to be submitted.
+    #[serde(rename_all = "camelCase")]
+    BatchFailed {
+        batch_id:   u64,
+        batch_size: usize,
+        error:      String,
+    },
+    /// The batch was dropped before submission as the tree was stale, and its
+    /// identities go into a new batch once the tree catches up.
+    #[serde(rename_all = "camelCase")]
+    BatchRetried {
+        batch_id:   u64,
+        batch_size: usize,
+        reason:     String,
+    },
+}
+
+/// Broadcasts [`BatchEvent`]s to every subscriber.
+///
+/// Subscribers that fall more than the capacity behind miss the oldest events,
+/// so that a slow consumer cannot hold up batches.
+#[derive(Clone, Debug)]
+pub struct BatchEvents {
+    sender: broadcast::Sender<BatchEvent>,
+}
+
+impl BatchEvents {
+    /// Creates a bus that buffers up to `capacity` events per subscriber.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    /// Returns a receiver of the events emitted from now on.
+    #[must_use]
+    pub fn subscribe(&self) -> broadcast::Receiver<BatchEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Sends `event` to the current subscribers, if there are any.
+    pub fn emit(&self, event: BatchEvent) {
+        // Without subscribers there is nobody to miss the event.
+        let _ = self.sender.send(event);
+    }
+}
@@ -0,0 +1,237 @@
+use std::time::Duration;
+
+/// What the committer should do with the identities pending at the moment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Commit up to `batch_size` identities in a batch proven in the tier of
+    /// `batch_size`, padded with empty leaves if fewer are pending.
+    Submit { batch_size: usize },
+    /// Wait up to `wait` for `target` identities to be pending.
+    Wait { target: usize, wait: Duration },
+    /// There is nothing to commit.
+    Idle,
+}
+
+/// The on-chain cost of committing a batch, used to hold back batches while a
+/// larger one is expected to be cheaper per identity.
+///
+/// A batch costs a fixed amount plus an amount per identity slot of the prover
+/// tier it is proven in, including the slots that are padded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CostModel {
+    pub fixed_cost:    f64,
+    pub identity_cost: f64,
+    /// The longest a batch is held back waiting for more identities.
+    pub max_wait:      Duration,
+}
+
+impl CostModel {
+    /// Returns the cost per identity of committing `count` identities in the
+    /// prover tier of `tier_size`.
+    #[allow(clippy::cast_precision_loss)]
+    fn amortized_cost(&self, tier_size: usize, count: usize) -> f64 {
+        self.identity_cost
+            .mul_add(tier_size as f64, self.fixed_cost)
+            / count as f64
+    }
+
+    /// Returns the number of identities to wait for, given the `pending` ones,
+    /// the `tiers` the provers are set up for and the `arrival_rate` of new
+    /// identities per second.
+    ///
+    /// Every tier is filled with the identities expected within the maximum
+    /// wait, and the one with the lowest cost per identity wins. Ties go to the
+    /// smaller batch, which waits less. No more than `pending` are waited for
+    /// if no tier is registered.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    fn target_batch_size(&self, tiers: &[usize], pending: usize, arrival_rate: f64) -> usize {
+        let expected = pending + (arrival_rate * self.max_wait.as_secs_f64()).floor() as usize;
+        let mut best: Option<(f64, usize)> = None;
+        for &tier_size in tiers {
+            let fill = tier_size.min(expected);
+            if fill == 0 {
+                continue;
+            }
+            // Tiers ascend, so on a tie the smaller batch is kept.
+            let cost = self.amortized_cost(tier_size, fill);
+            if best.map_or(true, |(best_cost, _)| cost < best_cost) {
+                best = Some((cost, fill));
+            }
+        }
+        best.map_or(pending, |(_, fill)| fill)
+    }
+}
+
+/// How long the committer holds back identities for a fuller batch.
+///
+/// Once the identities have been held back for as long as the policy allows,
+/// they are committed in the smallest tier that fits them, trading the cost
+/// of padding for latency under low traffic.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BatchingPolicy {
+    /// Commits the pending identities right away.
+    #[default]
+    Immediate,
+    /// Waits up to this long for the largest tier to fill up.
+    MaxWait(Duration),
+    /// Waits, for no longer than the `max_wait` of the cost model, while a
+    /// larger batch is expected to cost less per identity given the recent
+    /// arrival rate of identities, see [`CostModel`].
+    CostAware(CostModel),
+}
+
+impl BatchingPolicy {
+    /// Decides on the `pending` identities, which have been held back for
+    /// `waited`, given the ascending batch sizes of the registered prover
+    /// `tiers` and the `arrival_rate` of new identities per second.
+    ///
+    /// Pending identities beyond the decided batch size are left for the next
+    /// batch. Without any tier, the pending identities are submitted as they
+    /// are.
+    #[must_use]
+    pub fn decide(
+        &self,
+        tiers: &[usize],
+        pending: usize,
+        waited: Duration,
+        arrival_rate: f64,
+    ) -> Decision {
+        if pending == 0 {
+            return Decision::Idle;
+        }
+        let Some(&largest) = tiers.last() else {
+            return Decision::Submit {
+                batch_size: pending,
+            };
+        };
+        let (target, max_wait) = match self {
+            Self::Immediate => (pending, Duration::ZERO),
+            Self::MaxWait(max_wait) => (largest, *max_wait),
+            Self::CostAware(cost_model) => (
+                cost_model.target_batch_size(tiers, pending, arrival_rate),
+                cost_model.max_wait,
+            ),
+        };
+        if target > pending && waited < max_wait {
+            return Decision::Wait {
+                target,
+                wait: max_wait - waited,
+            };
+        }
+        let fill = target.min(pending).min(largest);
+        let batch_size = tiers
+            .iter()
+            .copied()
+            .find(|&tier| tier >= fill)
+            .unwrap_or(largest);
+        Decision::Submit { batch_size }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TIERS: [usize; 3] = [10, 100, 1000];
+
+    #[test]
+    fn full_batches_should_be_submitted_right_away() {
+        let policy = BatchingPolicy::MaxWait(Duration::from_secs(60));
+
+        assert_eq!(
+            policy.decide(&TIERS, 1000, Duration::ZERO, 0.0),
+            Decision::Submit { batch_size: 1000 }
+        );
+        assert_eq!(
+            policy.decide(&TIERS, 2500, Duration::ZERO, 0.0),
+            Decision::Submit { batch_size: 1000 }
+        );
+        assert_eq!(
+            policy.decide(&TIERS, 0, Duration::ZERO, 0.0),
+            Decision::Idle
+        );
+    }
+
+    #[test]
+    fn partial_batches_should_wait_up_to_max_wait() {
+        let policy = BatchingPolicy::MaxWait(Duration::from_secs(60));
+
+        assert_eq!(
+            policy.decide(&TIERS, 42, Duration::from_secs(20), 0.0),
+            Decision::Wait {
+                target: 1000,
+                wait:   Duration::from_secs(40),
+            }
+        );
+        // The smallest tier that fits is padded.
+        assert_eq!(
+            policy.decide(&TIERS, 42, Duration::from_secs(60), 0.0),
+            Decision::Submit { batch_size: 100 }
+        );
+        assert_eq!(
+            policy.decide(&TIERS, 7, Duration::from_secs(90), 0.0),
+            Decision::Submit { batch_size: 10 }
+        );
+        assert_eq!(
+            policy.decide(&[], 7, Duration::ZERO, 0.0),
+            Decision::Submit { batch_size: 7 }
+        );
+    }
+
+    #[test]
+    fn immediate_batches_should_use_smallest_fitting_tier() {
+        let policy = BatchingPolicy::Immediate;
+
+        assert_eq!(
+            policy.decide(&TIERS, 42, Duration::ZERO, 0.0),
+            Decision::Submit { batch_size: 100 }
+        );
+        assert_eq!(
+            policy.decide(&TIERS, 2500, Duration::ZERO, 0.0),
+            Decision::Submit { batch_size: 1000 }
+        );
+    }
+
+    #[test]
+    fn cost_model_should_wait_for_cheapest_batch() {
+        let cost_model = CostModel {
+            fixed_cost:    100.0,
+            identity_cost: 1.0,
+            max_wait:      Duration::from_secs(10),
+        };
+        let tiers = [10, 100];
+
+        // Without arrivals, a full small batch is cheapest.
+        assert_eq!(cost_model.target_batch_size(&tiers, 10, 0.0), 10);
+        // Too few arrivals to make up for the padding of the large tier.
+        assert_eq!(cost_model.target_batch_size(&tiers, 10, 0.5), 10);
+        // A partly filled large batch is already cheaper per identity.
+        assert_eq!(cost_model.target_batch_size(&tiers, 10, 2.0), 30);
+        // Enough arrivals to fill the large tier, but no more.
+        assert_eq!(cost_model.target_batch_size(&tiers, 10, 50.0), 100);
+        // Nothing to wait for if no prover is registered.
+        assert_eq!(cost_model.target_batch_size(&[], 7, 50.0), 7);
+
+        let policy = BatchingPolicy::CostAware(cost_model);
+        assert_eq!(
+            policy.decide(&tiers, 10, Duration::from_secs(4), 2.0),
+            Decision::Wait {
+                target: 30,
+                wait:   Duration::from_secs(6),
+            }
+        );
+        // Once the wait is up, the pending identities go in the tier that fits.
+        assert_eq!(
+            policy.decide(&tiers, 20, Duration::from_secs(10), 2.0),
+            Decision::Submit { batch_size: 100 }
+        );
+        assert_eq!(
+            policy.decide(&tiers, 15, Duration::ZERO, 0.0),
+            Decision::Submit { batch_size: 10 }
+        );
+    }
+}
@@ -19,6 +19,7 @@ abigen!(
         function registerIdentities(uint256[8] calldata insertionProof, uint256 preRoot, uint32 startIndex, uint256[] calldata identityCommitments, uint256 postRoot) public virtual
         function calculateTreeVerifierInputHash(uint32 startIndex, uint256 preRoot, uint256 postRoot, uint256[] identityCommitments) public view virtual returns (bytes32 hash)
         function latestRoot() public view virtual returns (uint256 root)
+        function getTreeDepth() public view virtual returns (uint8 initializedTreeDepth)
         function queryRoot(uint256 root) public view virtual returns ((uint256 root, uint128 supersededTimestamp, bool isValid) memory rootInfo)
         function isInputInReducedForm(uint256 input) public view virtual returns (bool isInReducedForm)
         function checkValidRoot(uint256 root) public view virtual returns (bool)
@@ -2,15 +2,16 @@ mod abi;
 
 use self::abi::BatchingContract as ContractAbi;
 use crate::{
-    contracts::{EventStream, IdentityManager, Options},
+    contracts::{
+        EventStream, IdentityManager, InsertionProof, Options, SubmissionError, Submitted,
+    },
     ethereum::{Ethereum, EventError, ProviderStack, TxError},
+    prover::proof::Proof as BatchProof,
 };
+use anyhow::anyhow;
 use async_trait::async_trait;
-use ethers::{
-    providers::Middleware,
-    types::{TransactionReceipt, U256},
-};
-use semaphore::Field;
+use ethers::{providers::Middleware, types::U256};
+use semaphore::{poseidon_tree::Proof, Field};
 use tracing::{error, info, instrument};
 
 /// A structure representing the interface to the batch-based identity manager
@@ -54,8 +55,19 @@ impl IdentityManager for Contract {
             "Connected to the WorldID Identity Manager"
         );
 
+        let tree_depth = usize::from(abi.get_tree_depth().call().await?);
+        if tree_depth != options.tree_depth {
+            error!(
+                tree_depth,
+                configured = options.tree_depth,
+                "The tree depth of the contract does not match the configured depth."
+            );
+            return Err(anyhow!(
+                "Contract tree depth {tree_depth} does not match the configured depth {}",
+                options.tree_depth
+            ));
+        }
         let initial_leaf_value = options.initial_leaf_value;
-        let tree_depth = options.tree_depth;
 
         let identity_manager = Self {
             ethereum,
@@ -96,13 +108,64 @@ impl IdentityManager for Contract {
         Ok(owner == self.ethereum.address())
     }
 
+    fn supports_empty_batches(&self) -> bool {
+        // TODO Enable once empty batches can be proven.
+        false
+    }
+
+    fn proves_batches(&self) -> bool {
+        true
+    }
+
     #[instrument(level = "debug", skip_all)]
     async fn register_identities(
         &self,
-        _identity_commitments: Vec<Field>,
-    ) -> Result<TransactionReceipt, TxError> {
-        // TODO [Ara] Assert length of merkle tree proofs.
-        todo!()
+        identity_commitments: Vec<Field>,
+        proof: Option<InsertionProof>,
+    ) -> Result<Vec<Submitted>, SubmissionError> {
+        let Some(InsertionProof {
+            proof,
+            batch_size,
+            start_index,
+            pre_root,
+            post_root,
+        }) = proof
+        else {
+            return Err(TxError::Unsupported("register_identities without a proof").into());
+        };
+        let count = identity_commitments.len();
+        // The proof covers the batch padded with empty leaves.
+        let commitments = identity_commitments
+            .into_iter()
+            .chain(std::iter::repeat(self.initial_leaf_value))
+            .take(batch_size.max(count))
+            .map(|commitment| U256::from(commitment.to_be_bytes()))
+            .collect();
+        let receipt = self
+            .ethereum
+            .send_transaction(
+                self.abi
+                    .register_identities(
+                        proof.into(),
+                        pre_root,
+                        start_index,
+                        commitments,
+                        post_root,
+                    )
+                    .tx,
+            )
+            .await?;
+        Ok(vec![Submitted { count, receipt }])
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn delete_identities(
+        &self,
+        _deletions: Vec<(Field, Proof)>,
+        _proof: Option<BatchProof>,
+    ) -> Result<Vec<Submitted>, SubmissionError> {
+        // TODO Submit once the contract supports batched deletions.
+        Err(TxError::Unsupported("delete_identities").into())
     }
 
     async fn assert_latest_root(&self, root: Field) -> anyhow::Result<()> {
@@ -115,6 +178,21 @@ impl IdentityManager for Contract {
         }
     }
 
+    #[instrument(level = "debug", skip_all)]
+    async fn assert_initial_root(&self, root: Field) -> anyhow::Result<()> {
+        // Every root the contract has held is kept in its history, which
+        // reports unknown roots as the zero root.
+        let (known_root, ..) = self.abi.query_root(root.into()).call().await?;
+        let processed_root: U256 = root.into();
+        if known_root == processed_root {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(
+                "The contract was not initialized with the empty tree root.",
+            ))
+        }
+    }
+
     #[instrument(level = "debug", skip_all)]
     async fn assert_valid_root(&self, root: Field) -> anyhow::Result<()> {
         if self.abi.check_valid_root(root.into()).call().await? {
@@ -3,7 +3,7 @@ use async_stream::try_stream;
 use core::fmt::Debug;
 use ethers::{
     providers::{LogQueryError, Middleware, ProviderError},
-    types::{Filter, Log, U64},
+    types::{BlockNumber, Filter, Log, U64},
 };
 use futures::{Stream, StreamExt};
 use std::{cmp::max, sync::Arc, time::Duration};
@@ -11,13 +11,45 @@ use thiserror::Error;
 use tokio::time::sleep;
 use tracing::{error, info};
 
+/// When blocks, and the events in them, are considered confirmed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Finality {
+    /// Once this many blocks are mined on top of them.
+    Blocks(u64),
+    /// Once the node tags them as finalized.
+    Finalized,
+}
+
+impl Finality {
+    /// Returns the number of the latest confirmed block.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the block cannot be loaded, for example because
+    /// the node does not support the `finalized` tag.
+    pub async fn confirmed_block(self, provider: &ProviderStack) -> Result<U64, ProviderError> {
+        let provider = provider.provider();
+        match self {
+            Self::Blocks(delay) => Ok(provider
+                .get_block_number()
+                .await?
+                .saturating_sub(U64::from(delay))),
+            Self::Finalized => Ok(provider
+                .get_block(BlockNumber::Finalized)
+                .await?
+                .and_then(|block| block.number)
+                .unwrap_or_default()),
+        }
+    }
+}
+
 pub struct ConfirmedLogQuery {
-    provider:                  Arc<ProviderStack>,
-    filter:                    Filter,
-    start_page_size:           u64,
-    min_page_size:             u64,
-    max_backoff_time:          Duration,
-    confirmation_blocks_delay: u64,
+    provider:         Arc<ProviderStack>,
+    filter:           Filter,
+    start_page_size:  u64,
+    min_page_size:    u64,
+    max_backoff_time: Duration,
+    finality:         Finality,
 }
 
 #[derive(Error, Debug)]
@@ -36,7 +68,7 @@ impl ConfirmedLogQuery {
             start_page_size: 10000,
             min_page_size: 1000,
             max_backoff_time: Duration::from_secs(32),
-            confirmation_blocks_delay: 0,
+            finality: Finality::Blocks(0),
         }
     }
 
@@ -56,14 +88,14 @@ impl ConfirmedLogQuery {
         self
     }
 
-    pub const fn with_blocks_delay(mut self, confirmation_blocks_delay: u64) -> Self {
-        self.confirmation_blocks_delay = confirmation_blocks_delay;
+    pub const fn with_finality(mut self, finality: Finality) -> Self {
+        self.finality = finality;
         self
     }
 
     pub fn into_stream(self) -> impl Stream<Item = Result<Log, Error<ProviderError>>> {
         try_stream! {
-            let last_block = self.get_block_number().await?;
+            let last_block = self.get_confirmed_block().await?;
 
             let mut retry_status = RetryStatus::new(self.start_page_size, self.min_page_size, self.max_backoff_time);
 
@@ -103,7 +135,7 @@ impl ConfirmedLogQuery {
                     let to_filter = self.filter.get_to_block().expect("filter's to_block must be set");
 
                     // get_logs_paginated ignores to_block filter. Check again if the block is confirmed
-                    let is_confirmed = log_block + self.confirmation_blocks_delay <= last_block && log_block <= to_filter;
+                    let is_confirmed = log_block <= last_block && log_block <= to_filter;
                     if is_confirmed {
                         yield log;
                     }
@@ -129,10 +161,9 @@ impl ConfirmedLogQuery {
         }
     }
 
-    async fn get_block_number(&self) -> Result<U64, Error<ProviderError>> {
-        let provider = self.provider.provider();
-        provider
-            .get_block_number()
+    async fn get_confirmed_block(&self) -> Result<U64, Error<ProviderError>> {
+        self.finality
+            .confirmed_block(&self.provider)
             .await
             .map_err(Error::LoadLastBlock)
     }
@@ -6,10 +6,12 @@ abigen!(
     LegacyContract,
     r#"[
         event MemberAdded(uint256 indexed groupId, uint256 identityCommitment, uint256 root)
+        event MemberRemoved(uint256 indexed groupId, uint256 identityCommitment, uint256 root)
         function manager() public view returns (address)
         function getDepth(uint256 groupId) public view returns (uint8)
         function createGroup(uint256 groupId, uint8 depth, uint256 zeroValue) public override
         function addMember(uint256 groupId, uint256 identityCommitment) public override
+        function removeMember(uint256 groupId, uint256 identityCommitment, uint256[] calldata proofSiblings, uint8[] calldata proofPathIndices) public override
         function verifyProof(uint256 root, uint256 groupId, uint256 signalHash, uint256 nullifierHash, uint256 externalNullifierHash, uint256[8] calldata proof) public view
     ]"#,
     event_derives(serde::Deserialize, serde::Serialize)
@@ -1,23 +1,30 @@
 mod abi;
 
-use self::abi::{LegacyContract as ContractAbi, MemberAddedFilter};
+use self::abi::{
+    LegacyContract as ContractAbi, LegacyContractEvents, MemberAddedFilter, MemberRemovedFilter,
+};
 use crate::{
-    contracts::{EventStream, IdentityManager, Options},
-    ethereum::{Ethereum, EventError, ProviderStack, TxError},
+    contracts::{
+        EventStream, IdentityManager, InsertionProof, Options, SubmissionError, Submitted,
+    },
+    ethereum::{Ethereum, EventError, ProviderStack},
+    prover::proof::Proof as BatchProof,
     tx_sitter::Sitter,
 };
 use anyhow::anyhow;
 use async_trait::async_trait;
 use core::future;
-use ethers::{
-    providers::Middleware,
-    types::{TransactionReceipt, U256},
-};
+use ethers::{contract::EthEvent, providers::Middleware, types::U256};
 use futures::TryStreamExt;
-use semaphore::Field;
+use semaphore::{merkle_tree::Branch, poseidon_tree::Proof, Field};
 use tracing::{error, info, instrument};
 
 pub type MemberAddedEvent = MemberAddedFilter;
+pub type MemberRemovedEvent = MemberRemovedFilter;
+
+/// An event emitted by the contract when it adds a leaf to the tree or empties
+/// one.
+pub type TreeEvent = LegacyContractEvents;
 
 /// A structure representing the interface to the legacy identity manager
 /// contract.
@@ -84,6 +91,18 @@ impl IdentityManager for Contract {
             info!(group_id = ?options.group_id, ?existing_tree_depth, "Semaphore group found.");
             usize::from(existing_tree_depth)
         };
+        if actual_tree_depth != options.tree_depth {
+            error!(
+                group_id = ?options.group_id,
+                actual_tree_depth,
+                configured = options.tree_depth,
+                "The depth of the Semaphore group does not match the configured depth."
+            );
+            return Err(anyhow!(
+                "Group depth {actual_tree_depth} does not match the configured depth {}",
+                options.tree_depth
+            ));
+        }
 
         // TODO: Some way to check the initial leaf
 
@@ -126,26 +145,71 @@ impl IdentityManager for Contract {
         Ok(manager == self.ethereum.address())
     }
 
+    fn supports_empty_batches(&self) -> bool {
+        // Identities are added one at a time, there is no batch to submit.
+        false
+    }
+
+    fn proves_batches(&self) -> bool {
+        false
+    }
+
     #[instrument(level = "debug", skip_all)]
     async fn register_identities(
         &self,
         identity_commitments: Vec<Field>,
-    ) -> Result<TransactionReceipt, TxError> {
-        // TODO Make this loop over identities if it gets multiple.
-        assert_eq!(
-            identity_commitments.len(),
-            1,
-            "The legacy identity manager can only accept single commitments."
-        );
-        let identity = identity_commitments.first().unwrap();
-
-        // Send the registration transaction
-        let commitment = U256::from(identity.to_be_bytes());
-        let receipt = self
-            .sitter
-            .send(self.abi.add_member(self.group_id, commitment).tx)
-            .await?;
-        Ok(receipt)
+        _proof: Option<InsertionProof>,
+    ) -> Result<Vec<Submitted>, SubmissionError> {
+        // The legacy contract only accepts single commitments, so we send one
+        // registration transaction per identity, each mined in its own block.
+        let mut submitted = Vec::with_capacity(identity_commitments.len());
+        for identity in identity_commitments {
+            let commitment = U256::from(identity.to_be_bytes());
+            match self
+                .sitter
+                .send(self.abi.add_member(self.group_id, commitment).tx)
+                .await
+            {
+                Ok(receipt) => submitted.push(Submitted { count: 1, receipt }),
+                Err(source) => return Err(SubmissionError { submitted, source }),
+            }
+        }
+        Ok(submitted)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn delete_identities(
+        &self,
+        deletions: Vec<(Field, Proof)>,
+        _proof: Option<BatchProof>,
+    ) -> Result<Vec<Submitted>, SubmissionError> {
+        // Like registrations, removals are sent one transaction per identity,
+        // each with its own proof, so there is no batch proof to send.
+        let mut submitted = Vec::with_capacity(deletions.len());
+        for (identity, proof) in deletions {
+            let commitment = U256::from(identity.to_be_bytes());
+            let (siblings, path_indices) = proof
+                .0
+                .iter()
+                .map(|branch| match branch {
+                    Branch::Left(sibling) => (U256::from(sibling.to_be_bytes()), 0_u8),
+                    Branch::Right(sibling) => (U256::from(sibling.to_be_bytes()), 1_u8),
+                })
+                .unzip();
+            match self
+                .sitter
+                .send(
+                    self.abi
+                        .remove_member(self.group_id, commitment, siblings, path_indices)
+                        .tx,
+                )
+                .await
+            {
+                Ok(receipt) => submitted.push(Submitted { count: 1, receipt }),
+                Err(source) => return Err(SubmissionError { submitted, source }),
+            }
+        }
+        Ok(submitted)
     }
 
     async fn assert_latest_root(&self, _: Field) -> anyhow::Result<()> {
@@ -154,6 +218,12 @@ impl IdentityManager for Contract {
         ))
     }
 
+    async fn assert_initial_root(&self, _: Field) -> anyhow::Result<()> {
+        // The contract keeps no root history to check the initial root against,
+        // the group depth is checked on creation instead.
+        Ok(())
+    }
+
     // This is a total hack due to the contract not supporting a `get_root`
     // function.
     #[instrument(level = "debug", skip_all)]
@@ -189,15 +259,30 @@ impl IdentityManager for Contract {
     }
 
     fn fetch_events(&self, starting_block: u64, end_block: Option<u64>) -> Option<EventStream<'_>> {
-        // Start the MemberAdded event stream.
-        let mut filter = self.abi.member_added_filter().from_block(starting_block);
+        // Start the stream of the MemberAdded and MemberRemoved events, which
+        // have to be applied to the tree in order.
+        let mut filter = self
+            .abi
+            .events()
+            .filter
+            .topic0(vec![
+                MemberAddedEvent::signature(),
+                MemberRemovedEvent::signature(),
+            ])
+            .from_block(starting_block);
         if let Some(end_block) = end_block {
             filter = filter.to_block(end_block);
         }
         let stream = self
             .ethereum
-            .fetch_events::<MemberAddedEvent>(&filter.filter)
-            .try_filter(|event| future::ready(event.event.group_id == self.group_id));
+            .fetch_events::<TreeEvent>(&filter)
+            .try_filter(|log| {
+                let group_id = match &log.event {
+                    TreeEvent::MemberAddedFilter(event) => event.group_id,
+                    TreeEvent::MemberRemovedFilter(event) => event.group_id,
+                };
+                future::ready(group_id == self.group_id)
+            });
         Some(Box::pin(stream))
     }
 }
@@ -4,18 +4,34 @@ pub mod confirmed_log_query;
 pub mod legacy;
 
 use crate::{
-    contracts::legacy::MemberAddedEvent,
+    contracts::legacy::TreeEvent,
     ethereum::{Ethereum, EventError, Log, TxError},
+    prover::proof::Proof as BatchProof,
 };
 use async_trait::async_trait;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use ethers::{
     prelude::{Address, U256},
     types::TransactionReceipt,
 };
 use futures::Stream;
-use semaphore::Field;
+use semaphore::{poseidon_tree::Proof, Field};
 use std::{pin::Pin, sync::Arc};
+use thiserror::Error;
+
+/// The version of the identity manager contract deployed on chain, which
+/// determines its ABI.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ContractVersion {
+    /// The Semaphore contract, which registers identities one at a time.
+    #[default]
+    Legacy,
+    /// The WorldID identity manager, which registers batches of identities
+    /// with a proof of the tree update. It cannot be selected until its events
+    /// can be followed to keep the tree in sync.
+    #[value(skip)]
+    Batching,
+}
 
 /// Configuration options for the component responsible for interacting with the
 /// contract.
@@ -26,6 +42,10 @@ pub struct Options {
     #[clap(long, env, default_value = "174ee9b5fBb5Eb68B6C61032946486dD9c2Dc4b6")]
     pub semaphore_address: Address,
 
+    /// The version of the identity manager contract at `semaphore_address`.
+    #[clap(long, env, value_enum, default_value = "legacy")]
+    pub contract_version: ContractVersion,
+
     // TODO This option should be removed.
     /// The semaphore group identifier to use.
     #[clap(long, env, default_value = "1")]
@@ -39,7 +59,8 @@ pub struct Options {
 
     /// The depth of the tree that the contract is working with. This needs to
     /// agree with the verifier in the deployed contract, and also with
-    /// `semaphore-mtb`.
+    /// `semaphore-mtb`. The sequencer refuses to start if it disagrees with
+    /// the depth read from the contract or recorded in the database.
     #[clap(long, env, default_value = "10")]
     pub tree_depth: usize,
 
@@ -84,17 +105,52 @@ pub trait IdentityManager {
     /// the on-chain contract it manages.
     async fn is_owner(&self) -> anyhow::Result<bool>;
 
+    /// Returns `true` if the contract accepts an empty batch of identities,
+    /// which leaves the tree unchanged but refreshes the on-chain root.
+    fn supports_empty_batches(&self) -> bool;
+
+    /// Returns `true` if the contract verifies a proof of every batch of
+    /// identities it registers, which then has to be requested from the
+    /// provers.
+    fn proves_batches(&self) -> bool;
+
     /// Registers the provided `identity_commitments` with the contract on
-    /// chain.
+    /// chain, together with the `proof` of the batch if the contract
+    /// [proves batches](Self::proves_batches).
+    ///
+    /// Returns the transactions that registered them, in order. A contract
+    /// that registers identities one at a time sends a transaction for each,
+    /// and stops at the first that fails, reporting the ones sent before it
+    /// in the [`SubmissionError`].
     async fn register_identities(
         &self,
         identity_commitments: Vec<Field>,
-    ) -> Result<TransactionReceipt, TxError>;
+        proof: Option<InsertionProof>,
+    ) -> Result<Vec<Submitted>, SubmissionError>;
+
+    /// Removes the provided identity commitments from the contract on chain.
+    ///
+    /// Every commitment comes with the proof of its leaf in the tree as it is
+    /// once the commitments before it have been removed. Returns the
+    /// transactions that removed them, in order, like
+    /// [`Self::register_identities`].
+    ///
+    /// `proof` is the proof of the whole batch from the deletion prover of its
+    /// size, if deletion provers are configured.
+    async fn delete_identities(
+        &self,
+        deletions: Vec<(Field, Proof)>,
+        proof: Option<BatchProof>,
+    ) -> Result<Vec<Submitted>, SubmissionError>;
 
     /// Asserts that the provided `root` is the current root held by the
     /// contract on the chain.
     async fn assert_latest_root(&self, root: Field) -> anyhow::Result<()>;
 
+    /// Asserts that the provided `root`, the root of the empty tree, is the
+    /// root the contract was initialized with.
+    async fn assert_initial_root(&self, root: Field) -> anyhow::Result<()>;
+
     /// Asserts that the provided `root` is a valid root.
     ///
     /// A valid root is one that has not expired based on the time since it was
@@ -102,18 +158,252 @@ pub trait IdentityManager {
     async fn assert_valid_root(&self, root: Field) -> anyhow::Result<()>;
 
     // TODO [Ara] Remove this once the OZ relay work is integrated.
-    /// Fetches member added events from the blockchain from a starting block to
-    /// an optionally specified end block.
+    /// Fetches the events adding leaves to the tree and emptying them from the
+    /// blockchain from a starting block to an optionally specified end block.
     ///
     /// Such functionality need not be supported by all identity managers, and
     /// these may return `None` to signify such a situation.
     fn fetch_events(&self, starting_block: u64, end_block: Option<u64>) -> Option<EventStream>;
 }
 
+/// The proof of inserting a batch of identities into the tree, together with
+/// the inputs it proves.
+///
+/// The batch is padded with empty leaves to `batch_size`, the batch size of
+/// the prover.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InsertionProof {
+    pub proof:       BatchProof,
+    pub batch_size:  usize,
+    pub start_index: u32,
+    pub pre_root:    U256,
+    pub post_root:   U256,
+}
+
+/// A mined transaction that submitted the next `count` identities of a batch,
+/// or of a batch of deletions.
+#[derive(Clone, Debug)]
+pub struct Submitted {
+    pub count:   usize,
+    pub receipt: TransactionReceipt,
+}
+
+/// A failure to submit a batch, after the transactions in `submitted` went
+/// through.
+#[derive(Debug, Error)]
+#[error("{source} (after submitting {} of the batch)", submitted_count(.submitted))]
+pub struct SubmissionError {
+    pub submitted: Vec<Submitted>,
+    #[source]
+    pub source:    TxError,
+}
+
+impl From<TxError> for SubmissionError {
+    fn from(source: TxError) -> Self {
+        Self {
+            submitted: Vec::new(),
+            source,
+        }
+    }
+}
+
+/// Returns the number of identities submitted by the transactions in
+/// `submitted`.
+pub fn submitted_count(submitted: &[Submitted]) -> usize {
+    submitted.iter().map(|submitted| submitted.count).sum()
+}
+
 /// The type of the event stream used by the contracts to receive events from on
 /// chain.
-type EventStream<'a> =
-    Pin<Box<dyn Stream<Item = Result<Log<MemberAddedEvent>, EventError>> + Send + 'a>>;
+type EventStream<'a> = Pin<Box<dyn Stream<Item = Result<Log<TreeEvent>, EventError>> + Send + 'a>>;
 
 /// A type for an identity manager object that can be sent across threads.
 pub type SharedIdentityManager = Arc<dyn IdentityManager + Send + Sync>;
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use crate::contracts::legacy::{MemberAddedEvent, MemberRemovedEvent};
+    use std::sync::Mutex;
+    use tokio::time::Instant;
+
+    /// An identity manager that records the batches it is asked to register,
+    /// and when and from which thread, instead of submitting them on chain.
+    ///
+    /// Serves `events` as `(block, commitment, root)` member added events, and
+    /// `removals` as member removed events of the same shape, each after the
+    /// additions of its block.
+    ///
+    /// If `failing_identity` is set, identities are registered one at a time
+    /// like by the legacy contract, each in the next block, until that one
+    /// fails.
+    ///
+    /// If `proves_batches` is set, the proofs of the batches are recorded in
+    /// `proofs`.
+    #[derive(Default)]
+    pub struct MockIdentityManager {
+        pub batches:          Mutex<Vec<Vec<Field>>>,
+        pub proves_batches:   bool,
+        pub proofs:           Mutex<Vec<Option<InsertionProof>>>,
+        pub failing_identity: Mutex<Option<Field>>,
+        pub deletions:        Mutex<Vec<Vec<(Field, Proof)>>>,
+        pub deletion_proofs:  Mutex<Vec<Option<BatchProof>>>,
+        pub submitted_at:     Mutex<Vec<Instant>>,
+        pub submitted_from:   Mutex<Vec<Option<String>>>,
+        pub events:           Mutex<Vec<(u64, Field, Field)>>,
+        pub removals:         Mutex<Vec<(u64, Field, Field)>>,
+    }
+
+    #[async_trait]
+    impl IdentityManager for MockIdentityManager {
+        async fn new(_: Options, _: Ethereum) -> anyhow::Result<Self> {
+            Ok(Self::default())
+        }
+
+        fn tree_depth(&self) -> usize {
+            10
+        }
+
+        fn initial_leaf_value(&self) -> Field {
+            Field::default()
+        }
+
+        fn group_id(&self) -> U256 {
+            1.into()
+        }
+
+        async fn confirmed_block_number(&self) -> Result<u64, EventError> {
+            Ok(0)
+        }
+
+        async fn is_owner(&self) -> anyhow::Result<bool> {
+            Ok(true)
+        }
+
+        fn supports_empty_batches(&self) -> bool {
+            true
+        }
+
+        fn proves_batches(&self) -> bool {
+            self.proves_batches
+        }
+
+        async fn register_identities(
+            &self,
+            identity_commitments: Vec<Field>,
+            proof: Option<InsertionProof>,
+        ) -> Result<Vec<Submitted>, SubmissionError> {
+            let count = identity_commitments.len();
+            self.batches
+                .lock()
+                .unwrap()
+                .push(identity_commitments.clone());
+            self.proofs.lock().unwrap().push(proof);
+            self.submitted_at.lock().unwrap().push(Instant::now());
+            self.submitted_from
+                .lock()
+                .unwrap()
+                .push(std::thread::current().name().map(ToOwned::to_owned));
+            let receipt = |block: u64| TransactionReceipt {
+                block_number: Some(block.into()),
+                ..TransactionReceipt::default()
+            };
+            let Some(failing_identity) = *self.failing_identity.lock().unwrap() else {
+                return Ok(vec![Submitted {
+                    count,
+                    receipt: receipt(1),
+                }]);
+            };
+            let mut submitted = Vec::new();
+            for (block, identity) in (1..).zip(identity_commitments) {
+                if identity == failing_identity {
+                    return Err(SubmissionError {
+                        submitted,
+                        source: TxError::Failed(Box::new(receipt(block))),
+                    });
+                }
+                submitted.push(Submitted {
+                    count:   1,
+                    receipt: receipt(block),
+                });
+            }
+            Ok(submitted)
+        }
+
+        async fn assert_initial_root(&self, _: Field) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn delete_identities(
+            &self,
+            deletions: Vec<(Field, Proof)>,
+            proof: Option<BatchProof>,
+        ) -> Result<Vec<Submitted>, SubmissionError> {
+            let count = deletions.len();
+            self.deletions.lock().unwrap().push(deletions);
+            self.deletion_proofs.lock().unwrap().push(proof);
+            Ok(vec![Submitted {
+                count,
+                receipt: TransactionReceipt {
+                    block_number: Some(2_u64.into()),
+                    ..TransactionReceipt::default()
+                },
+            }])
+        }
+
+        async fn assert_latest_root(&self, _: Field) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn assert_valid_root(&self, _: Field) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn fetch_events(
+            &self,
+            starting_block: u64,
+            end_block: Option<u64>,
+        ) -> Option<EventStream<'_>> {
+            let added = self.events.lock().unwrap().clone();
+            let removed = self.removals.lock().unwrap().clone();
+            let mut changes = added
+                .into_iter()
+                .map(|change| (change, true))
+                .chain(removed.into_iter().map(|change| (change, false)))
+                .filter(|((block, ..), _)| {
+                    *block >= starting_block && end_block.map_or(true, |end| *block <= end)
+                })
+                .collect::<Vec<_>>();
+            changes.sort_by_key(|((block, ..), _)| *block);
+            let events = changes
+                .into_iter()
+                .enumerate()
+                .map(|(log_index, ((block, commitment, root), is_added))| {
+                    let group_id = self.group_id();
+                    let identity_commitment = U256::from(commitment.to_be_bytes());
+                    let root = U256::from(root.to_be_bytes());
+                    Ok(Log {
+                        block_index:       block.into(),
+                        transaction_index: 0_u64.into(),
+                        log_index:         log_index.into(),
+                        raw_log:           String::new(),
+                        event:             if is_added {
+                            TreeEvent::MemberAddedFilter(MemberAddedEvent {
+                                group_id,
+                                identity_commitment,
+                                root,
+                            })
+                        } else {
+                            TreeEvent::MemberRemovedFilter(MemberRemovedEvent {
+                                group_id,
+                                identity_commitment,
+                                root,
+                            })
+                        },
+                    })
+                })
+                .collect::<Vec<_>>();
+            Some(Box::pin(futures::stream::iter(events)))
+        }
+    }
+}
@@ -1,14 +1,28 @@
-use crate::identity_tree::Hash;
+use crate::{
+    identity_tree::Hash,
+    prover::{ProverConfiguration, TlsClientConfiguration},
+};
 use anyhow::{anyhow, Context, Error as ErrReport};
 use clap::Parser;
+use ethers::types::{Address, H256};
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
 use ruint::{aliases::U256, uint};
 use semaphore::Field;
+use serde::{Deserialize, Serialize};
 use sqlx::{
-    any::AnyKind,
+    any::{AnyArguments, AnyKind, AnyRow},
     migrate::{Migrate, MigrateDatabase, Migrator},
     pool::PoolOptions,
+    query::Query,
     Any, Executor, Pool, Row,
 };
+use std::{
+    collections::HashSet,
+    fmt::{self, Display},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use thiserror::Error;
 use tracing::{error, info, instrument, warn};
 use url::Url;
@@ -16,6 +30,46 @@ use url::Url;
 // Statically link in migration files
 static MIGRATOR: Migrator = sqlx::migrate!("schemas/database");
 
+/// The most identities written by a single statement, which keeps the number
+/// of parameters well below the limits of both Sqlite and Postgres.
+const MAX_IDENTITIES_PER_STATEMENT: usize = 256;
+
+/// Matches the pending identities that can be committed, leaving out the
+/// replacements of recovered identities, which wait for their deletion to be
+/// mined.
+const UNBLOCKED: &str = r#"NOT EXISTS (
+                           SELECT 1
+                           FROM identity_recoveries r
+                           JOIN pending_deletions d
+                             ON d.group_id = r.group_id AND d.commitment = r.old_commitment
+                           WHERE r.group_id = pending_identities.group_id
+                             AND r.new_commitment = pending_identities.commitment
+                             AND d.mined_in_block IS NULL)"#;
+
+static PENDING_BACKLOG: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "pending_identities_backlog",
+        "Number of identities persisted in the database that are not mined yet."
+    )
+    .unwrap()
+});
+
+static MINING_FRONTIER: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "oldest_unmined_leaf_index",
+        "Leaf index the oldest identity that is not mined yet will take, or -1 if there is none."
+    )
+    .unwrap()
+});
+
+static REPLICA_FALLBACKS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "database_replica_fallbacks",
+        "Number of reads made from the primary because the read replica lagged behind."
+    )
+    .unwrap()
+});
+
 #[derive(Clone, Debug, PartialEq, Eq, Parser)]
 pub struct Options {
     /// Database server connection string.
@@ -32,10 +86,19 @@ pub struct Options {
     /// Maximum number of connections in the database connection pool
     #[clap(long, env, default_value = "10")]
     pub database_max_connections: u32,
+
+    /// Connection string of a read replica of the database, to serve the
+    /// heavy reads of the event cache from. The schema is migrated on the
+    /// primary only.
+    #[clap(long, env)]
+    pub database_read_replica: Option<Url>,
 }
 
 pub struct Database {
-    pool: Pool<Any>,
+    pool:    Pool<Any>,
+    /// Serves the reads of the event cache, if configured. A lagging replica
+    /// is read from only where the primary can be fallen back to.
+    replica: Option<Pool<Any>>,
 }
 
 impl Database {
@@ -56,6 +119,18 @@ impl Database {
             .connect(options.database.as_str())
             .await
             .context("error connecting to database")?;
+        let replica = match &options.database_read_replica {
+            Some(url) => {
+                let replica = PoolOptions::<Any>::new()
+                    .max_connections(options.database_max_connections)
+                    .connect(url.as_str())
+                    .await
+                    .context("error connecting to read replica")?;
+                info!(%url, kind = ?replica.any_kind(), "Connected to read replica");
+                Some(replica)
+            }
+            None => None,
+        };
 
         // Log DB version to test connection.
         let sql = match pool.any_kind() {
@@ -123,43 +198,429 @@ impl Database {
             return Err(anyhow!("Could not get database version."));
         }
 
-        Ok(Self { pool })
+        Ok(Self { pool, replica })
+    }
+
+    /// Returns the pools to read from in order, the replica first if there
+    /// is one.
+    fn read_pools(&self) -> impl Iterator<Item = &Pool<Any>> {
+        self.replica.iter().chain([&self.pool])
+    }
+
+    /// Returns the replica if it caches the same events as the primary, and
+    /// the primary otherwise.
+    async fn event_cache_pool(&self) -> Result<&Pool<Any>, Error> {
+        let Some(replica) = &self.replica else {
+            return Ok(&self.pool);
+        };
+        let query = || {
+            sqlx::query(
+                r#"SELECT block_index, transaction_index, log_index
+                   FROM logs
+                   ORDER BY block_index DESC, transaction_index DESC, log_index DESC
+                   LIMIT 1;"#,
+            )
+        };
+        let latest = |row: Option<AnyRow>| {
+            row.map(|row| {
+                (
+                    row.get::<i64, _>(0),
+                    row.get::<i32, _>(1),
+                    row.get::<i32, _>(2),
+                )
+            })
+        };
+        let primary = latest(self.pool.fetch_optional(query()).await?);
+        if latest(replica.fetch_optional(query()).await?) == primary {
+            Ok(replica)
+        } else {
+            REPLICA_FALLBACKS.inc();
+            warn!(
+                ?primary,
+                "Read replica lags behind, reading events from the primary."
+            );
+            Ok(&self.pool)
+        }
     }
 
+    /// Queues `identity` for insertion.
+    ///
+    /// Every identity is assigned the next sequence number, which records the
+    /// order of submission so that it can be restored deterministically.
     pub async fn insert_pending_identity(
         &self,
         group_id: usize,
         identity: &Hash,
+        priority: u8,
+    ) -> Result<(), Error> {
+        self.insert_pending_identity_with_options(
+            group_id, identity, priority, None, None, None, None,
+        )
+        .await
+    }
+
+    /// Queues `identity` for insertion like [`Self::insert_pending_identity`],
+    /// recording the batch size the client would like it committed in, the
+    /// webhook to notify of its status and the unix time it expires at.
+    ///
+    /// A `nonce` given with an API key must be greater than the last one
+    /// accepted with the key. It is only used up if the identity is queued.
+    ///
+    /// The time it was received at is recorded in its audit, which restarts
+    /// if it was queued before.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(Error::ReplayedNonce)` if the nonce is not greater
+    /// than the last one of the key.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn insert_pending_identity_with_options(
+        &self,
+        group_id: usize,
+        identity: &Hash,
+        priority: u8,
+        batch_size_hint: Option<usize>,
+        webhook_url: Option<&Url>,
+        nonce: Option<(&str, u64)>,
+        expires_at: Option<u64>,
     ) -> Result<(), Error> {
+        let query = insert_pending_identity_query(
+            group_id,
+            identity,
+            priority,
+            batch_size_hint,
+            webhook_url,
+            expires_at,
+        );
+        let mut tx = self.pool.begin().await?;
+        if let Some((api_key, nonce)) = nonce {
+            let query = sqlx::query(
+                r#"INSERT INTO insertion_nonces (api_key, nonce)
+                   VALUES ($1, $2)
+                   ON CONFLICT (api_key) DO UPDATE SET nonce = excluded.nonce
+                   WHERE insertion_nonces.nonce < excluded.nonce;"#,
+            )
+            .bind(api_key)
+            .bind(i64::try_from(nonce).unwrap_or(i64::MAX));
+            if tx.execute(query).await?.rows_affected() == 0 {
+                return Err(Error::ReplayedNonce);
+            }
+        }
+        tx.execute(next_seq_query()).await?;
+        tx.execute(query).await?;
+        tx.execute(audit_received_query(group_id, identity)).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Returns the batch size hint recorded for a pending identity, if any.
+    pub async fn get_batch_size_hint(
+        &self,
+        group_id: usize,
+        identity: &Hash,
+    ) -> Result<Option<usize>, Error> {
         let query = sqlx::query(
-            r#"INSERT INTO pending_identities (group_id, commitment)
-                   VALUES ($1, $2);"#,
+            r#"SELECT batch_size_hint
+                   FROM pending_identities
+                   WHERE group_id = $1 AND commitment = $2
+                   LIMIT 1;"#,
         )
         .bind(group_id as i64)
         .bind(identity);
-        self.pool.execute(query).await?;
-        Ok(())
+        let row = self.pool.fetch_optional(query).await?;
+        Ok(row
+            .and_then(|row| row.get::<Option<i64>, _>(0))
+            .map(|hint| usize::try_from(hint).unwrap_or(0)))
     }
 
-    pub async fn mark_identity_inserted(
+    /// Returns the webhook recorded for a pending identity, if any.
+    pub async fn get_webhook_url(
         &self,
         group_id: usize,
-        commitment: &Hash,
+        identity: &Hash,
+    ) -> Result<Option<Url>, Error> {
+        let query = sqlx::query(
+            r#"SELECT webhook_url
+                   FROM pending_identities
+                   WHERE group_id = $1 AND commitment = $2
+                   LIMIT 1;"#,
+        )
+        .bind(group_id as i64)
+        .bind(identity);
+        let row = self.pool.fetch_optional(query).await?;
+        Ok(row
+            .and_then(|row| row.get::<Option<String>, _>(0))
+            .and_then(|url| Url::parse(&url).ok()))
+    }
+
+    /// Fetches the group and webhook recorded for the pending `identity`, if
+    /// it asked for one.
+    pub async fn get_webhook(&self, identity: &Hash) -> Result<Option<(usize, Url)>, Error> {
+        let query = sqlx::query(
+            r#"SELECT group_id, webhook_url
+                   FROM pending_identities
+                   WHERE commitment = $1 AND webhook_url IS NOT NULL
+                   LIMIT 1;"#,
+        )
+        .bind(identity);
+        let row = self.pool.fetch_optional(query).await?;
+        Ok(row.and_then(|row| {
+            let url = Url::parse(&row.get::<String, _>(1)).ok()?;
+            Some((row.get::<i64, _>(0).try_into().unwrap(), url))
+        }))
+    }
+
+    /// Marks the identities of a batch as mined in `block_number`.
+    ///
+    /// The identities are updated by a few multi-row statements in a single
+    /// transaction, so either all of them are marked or none are.
+    pub async fn mark_identities_inserted(
+        &self,
+        identities: &[(usize, Hash)],
         block_number: usize,
     ) -> Result<(), Error> {
-        let query = sqlx::query(
-            r#"UPDATE pending_identities
+        let mut tx = self.pool.begin().await?;
+        for chunk in identities.chunks(MAX_IDENTITIES_PER_STATEMENT) {
+            let rows = (0..chunk.len())
+                .map(|row| format!("(${}, ${})", 2 * row + 2, 2 * row + 3))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let sql = format!(
+                r#"UPDATE pending_identities
                    SET mined_in_block = $1
-                   WHERE group_id = $2 AND commitment = $3;"#,
+                   WHERE (group_id, commitment) IN (VALUES {rows});"#
+            );
+            let mut query = sqlx::query(&sql).bind(block_number as i64);
+            for (group_id, commitment) in chunk {
+                query = query.bind(*group_id as i64).bind(*commitment);
+            }
+            tx.execute(query).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Records how the identities of a batch were submitted in their audit.
+    pub async fn record_batch_audit(
+        &self,
+        identities: &[(usize, Hash)],
+        batch: &BatchAudit,
+    ) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        for chunk in identities.chunks(MAX_IDENTITIES_PER_STATEMENT) {
+            let rows = (0..chunk.len())
+                .map(|row| format!("(${}, ${})", 2 * row + 4, 2 * row + 5))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let sql = format!(
+                r#"UPDATE identity_audit
+                   SET batch_id = $1, tx_hash = $2, mined_in_block = $3
+                   WHERE (group_id, commitment) IN (VALUES {rows});"#
+            );
+            let mut query = sqlx::query(&sql)
+                .bind(i64::try_from(batch.batch_id).unwrap_or(i64::MAX))
+                .bind(batch.tx_hash.as_bytes().to_vec())
+                .bind(i64::try_from(batch.block_number).unwrap_or(i64::MAX));
+            for (group_id, commitment) in chunk {
+                query = query.bind(*group_id as i64).bind(*commitment);
+            }
+            tx.execute(query).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Returns the recorded lifecycle of `identity`, or `None` if it was
+    /// never queued since audits are kept. The submission time comes from the
+    /// batch audit log.
+    pub async fn get_identity_audit(
+        &self,
+        group_id: usize,
+        identity: &Hash,
+    ) -> Result<Option<IdentityAuditRecord>, Error> {
+        let query = sqlx::query(
+            r#"SELECT audit.received_at, audit.batch_id, batch.submitted_at, audit.tx_hash,
+                      audit.mined_in_block
+                   FROM identity_audit AS audit
+                   LEFT JOIN batches AS batch ON batch.id = audit.batch_id
+                   WHERE audit.group_id = $1 AND audit.commitment = $2;"#,
         )
-        .bind(block_number as i64)
         .bind(group_id as i64)
-        .bind(commitment);
+        .bind(identity);
+        let Some(row) = self.pool.fetch_optional(query).await? else {
+            return Ok(None);
+        };
+        let unsigned = |value: i64| u64::try_from(value).unwrap_or_default();
+        let batch = match (
+            row.get::<Option<i64>, _>(1),
+            row.get::<Option<Vec<u8>>, _>(3),
+            row.get::<Option<i64>, _>(4),
+        ) {
+            (Some(batch_id), Some(tx_hash), Some(block_number))
+                if tx_hash.len() == H256::len_bytes() =>
+            {
+                Some(BatchAudit {
+                    batch_id:     unsigned(batch_id),
+                    tx_hash:      H256::from_slice(&tx_hash),
+                    block_number: unsigned(block_number),
+                })
+            }
+            _ => None,
+        };
+        Ok(Some(IdentityAuditRecord {
+            received_at: unsigned(row.get(0)),
+            submitted_at: batch
+                .as_ref()
+                .and(row.get::<Option<i64>, _>(2))
+                .map(unsigned),
+            batch,
+        }))
+    }
+
+    /// Returns how many identities waiting for a batch were queued before
+    /// `identity`, or `None` if it is not waiting for a batch.
+    pub async fn count_queued_before(
+        &self,
+        group_id: usize,
+        identity: &Hash,
+    ) -> Result<Option<usize>, Error> {
+        let query = sqlx::query(
+            r#"SELECT (SELECT COUNT(1)
+                         FROM pending_identities AS other
+                         WHERE other.mined_in_block IS NULL AND other.seq < identity.seq)
+                   FROM pending_identities AS identity
+                   WHERE group_id = $1 AND commitment = $2 AND mined_in_block IS NULL;"#,
+        )
+        .bind(group_id as i64)
+        .bind(identity);
+        let row = self.pool.fetch_optional(query).await?;
+        Ok(row.map(|row| usize::try_from(row.get::<i64, _>(0)).unwrap_or_default()))
+    }
+
+    /// Records a batch about to be submitted in the batch audit log.
+    pub async fn insert_batch(&self, batch: &NewBatch) -> Result<(), Error> {
+        let query = sqlx::query(
+            r#"INSERT INTO batches (id, pre_root, commitments, assembled_at, submitted_at)
+                   VALUES ($1, $2, $3, $4, $5);"#,
+        )
+        .bind(i64::try_from(batch.id).unwrap_or(i64::MAX))
+        .bind(batch.pre_root)
+        .bind(serde_json::to_string(&batch.commitments).map_err(Error::CorruptBatch)?)
+        .bind(i64::try_from(batch.assembled_at).unwrap_or(i64::MAX))
+        .bind(i64::try_from(batch.submitted_at).unwrap_or(i64::MAX));
+        self.pool.execute(query).await?;
+        Ok(())
+    }
+
+    /// Records that the last transaction of the batch `id` was mined in
+    /// `block_number`. The transactions themselves are recorded in the audits
+    /// of the identities they submitted.
+    pub async fn mark_batch_mined(
+        &self,
+        id: u64,
+        block_number: u64,
+        gas_used: Option<u64>,
+    ) -> Result<(), Error> {
+        let query = sqlx::query(
+            r#"UPDATE batches
+                   SET block_number = $2, gas_used = $3, settled_at = $4
+                   WHERE id = $1;"#,
+        )
+        .bind(i64::try_from(id).unwrap_or(i64::MAX))
+        .bind(i64::try_from(block_number).unwrap_or(i64::MAX))
+        .bind(gas_used.map(|gas| i64::try_from(gas).unwrap_or(i64::MAX)))
+        .bind(unix_now());
+        self.pool.execute(query).await?;
+        Ok(())
+    }
 
+    /// Records that submitting the batch `id` failed with `error`.
+    pub async fn mark_batch_failed(&self, id: u64, error: &str) -> Result<(), Error> {
+        let query = sqlx::query(
+            r#"UPDATE batches
+                   SET error = $2, settled_at = $3
+                   WHERE id = $1;"#,
+        )
+        .bind(i64::try_from(id).unwrap_or(i64::MAX))
+        .bind(error.to_owned())
+        .bind(unix_now());
         self.pool.execute(query).await?;
         Ok(())
     }
 
+    /// Returns the batch `id` from the batch audit log, if it was recorded,
+    /// with the transactions recorded for its identities in the order they
+    /// were mined.
+    pub async fn get_batch(&self, id: u64) -> Result<Option<BatchAuditRecord>, Error> {
+        let id_param = i64::try_from(id).unwrap_or(i64::MAX);
+        let query = sqlx::query(
+            r#"SELECT pre_root, commitments, assembled_at, submitted_at, block_number,
+                      gas_used, settled_at, error
+                   FROM batches
+                   WHERE id = $1;"#,
+        )
+        .bind(id_param);
+        let Some(row) = self.pool.fetch_optional(query).await? else {
+            return Ok(None);
+        };
+        let query = sqlx::query(
+            r#"SELECT tx_hash, MIN(mined_in_block)
+                   FROM identity_audit
+                   WHERE batch_id = $1 AND tx_hash IS NOT NULL
+                   GROUP BY tx_hash
+                   ORDER BY 2, 1;"#,
+        )
+        .bind(id_param);
+        let tx_hashes = self
+            .pool
+            .fetch_all(query)
+            .await?
+            .into_iter()
+            .filter_map(|row| {
+                let hash: Vec<u8> = row.get(0);
+                (hash.len() == H256::len_bytes()).then(|| H256::from_slice(&hash))
+            })
+            .collect();
+        let unsigned = |value: i64| u64::try_from(value).unwrap_or_default();
+        let commitments: String = row.get(1);
+        Ok(Some(BatchAuditRecord {
+            batch: NewBatch {
+                id,
+                pre_root: row.get(0),
+                commitments: serde_json::from_str(&commitments).map_err(Error::CorruptBatch)?,
+                assembled_at: unsigned(row.get(2)),
+                submitted_at: unsigned(row.get(3)),
+            },
+            tx_hashes,
+            block_number: row.get::<Option<i64>, _>(4).map(unsigned),
+            gas_used: row.get::<Option<i64>, _>(5).map(unsigned),
+            settled_at: row.get::<Option<i64>, _>(6).map(unsigned),
+            error: row.get(7),
+        }))
+    }
+
+    /// Returns the identifier after the last recorded batch, so that batch
+    /// identifiers stay unique across restarts.
+    pub async fn next_batch_id(&self) -> Result<u64, Error> {
+        let query = sqlx::query("SELECT MAX(id) FROM batches;");
+        let last: Option<i64> = self.pool.fetch_one(query).await?.get(0);
+        Ok(last.map_or(0, |last| u64::try_from(last).unwrap_or_default() + 1))
+    }
+
+    /// Returns the depth of the tree the database was created for, if it has
+    /// been recorded yet.
+    pub async fn get_tree_depth(&self) -> Result<Option<usize>, Error> {
+        let query = sqlx::query("SELECT depth FROM tree_metadata WHERE id = 1;");
+        let row = self.pool.fetch_optional(query).await?;
+        Ok(row.map(|row| usize::try_from(row.get::<i64, _>(0)).unwrap_or_default()))
+    }
+
+    /// Records the depth of the tree the database is used for.
+    pub async fn set_tree_depth(&self, depth: usize) -> Result<(), Error> {
+        self.pool.execute(set_tree_depth_query(depth)).await?;
+        Ok(())
+    }
+
     pub async fn delete_pending_identity(
         &self,
         group_id: usize,
@@ -180,24 +641,63 @@ impl Database {
         &self,
         commitment: &Hash,
     ) -> Result<IdentityConfirmationResult, Error> {
-        let retrigger_query = sqlx::query(
-            r#"UPDATE pending_identities
-            SET mined_in_block = NULL, created_at = CURRENT_TIMESTAMP
-            WHERE created_at < (SELECT created_at FROM pending_identities WHERE commitment = $1 LIMIT 1)"#,
-        )
-        .bind(commitment);
+        let retrigger_result = self.pool.execute(retrigger_query(commitment)).await?;
+        self.pool.execute(cleanup_query(commitment)).await?;
 
-        let retrigger_result = self.pool.execute(retrigger_query).await?;
-
-        let cleanup_query = sqlx::query(
-            r#"DELETE FROM pending_identities
-                WHERE commitment = $1;"#,
-        )
-        .bind(commitment);
+        if retrigger_result.rows_affected() > 0 {
+            Ok(IdentityConfirmationResult::RetriggerProcessing)
+        } else {
+            Ok(IdentityConfirmationResult::Done)
+        }
+    }
 
-        self.pool.execute(cleanup_query).await?;
+    /// Caches the events of a batch of confirmed identities and removes them
+    /// from the pending identities, like [`Self::save_log`] and
+    /// [`Self::confirm_identity_and_retrigger_stale_recods`] for each. The
+    /// events of confirmed `deletions` are cached too, and the deletions
+    /// removed from the pending deletions.
+    ///
+    /// This happens in a single transaction, so if any of the events fails
+    /// to be recorded, none of them are.
+    pub async fn confirm_identities(
+        &self,
+        identities: &[ConfirmedIdentityEvent],
+        deletions: &[ConfirmedDeletionEvent],
+    ) -> Result<IdentityConfirmationResult, Error> {
+        let mut tx = self.pool.begin().await?;
+        let mut retriggered = false;
+        for identity in identities {
+            tx.execute(save_log_query(identity)).await?;
+            let retrigger_result = tx.execute(retrigger_query(&identity.leaf)).await?;
+            retriggered |= retrigger_result.rows_affected() > 0;
+            tx.execute(cleanup_query(&identity.leaf)).await?;
+        }
+        for deletion in deletions {
+            tx.execute(
+                sqlx::query(
+                    r#"INSERT INTO deletion_logs (block_index, transaction_index, log_index, raw,
+                           leaf, leaf_index, root, cached_at)
+                       VALUES ($1, $2, $3, $4, $5, $6, $7, $8);"#,
+                )
+                .bind(deletion.block_index)
+                .bind(deletion.transaction_index)
+                .bind(deletion.log_index)
+                .bind(deletion.raw_log.clone())
+                .bind(deletion.leaf)
+                .bind(deletion.leaf_index)
+                .bind(deletion.root)
+                .bind(unix_now()),
+            )
+            .await?;
+            tx.execute(
+                sqlx::query(r#"DELETE FROM pending_deletions WHERE commitment = $1;"#)
+                    .bind(deletion.leaf),
+            )
+            .await?;
+        }
+        tx.commit().await?;
 
-        if retrigger_result.rows_affected() > 0 {
+        if retriggered {
             Ok(IdentityConfirmationResult::RetriggerProcessing)
         } else {
             Ok(IdentityConfirmationResult::Done)
@@ -221,150 +721,2604 @@ impl Database {
         Ok(row.is_some())
     }
 
-    pub async fn get_oldest_unprocessed_identity(&self) -> Result<Option<(usize, Hash)>, Error> {
-        let queue_size = sqlx::query("SELECT COUNT(1) FROM pending_identities");
-        let size: i64 = self.pool.fetch_one(queue_size).await?.get(0);
-        info!(size, "pending identity queue size fetched");
-
+    /// Returns whether the pending `identity` has been recorded as mined, or
+    /// `None` if it is not pending.
+    pub async fn pending_identity_mined(
+        &self,
+        group_id: usize,
+        identity: &Hash,
+    ) -> Result<Option<bool>, Error> {
         let query = sqlx::query(
-            r#"SELECT group_id, commitment
+            r#"SELECT mined_in_block
                    FROM pending_identities
-                   WHERE mined_in_block IS NULL
-                   ORDER BY created_at ASC
+                   WHERE group_id = $1 AND commitment = $2
                    LIMIT 1;"#,
-        );
+        )
+        .bind(group_id as i64)
+        .bind(identity);
         let row = self.pool.fetch_optional(query).await?;
-        Ok(row.map(|row| (row.get::<i64, _>(0).try_into().unwrap(), row.get(1))))
+        Ok(row.map(|row| row.get::<Option<i64>, _>(0).is_some()))
     }
 
-    #[allow(unused)]
-    pub async fn read(&self, _index: usize) -> Result<Hash, Error> {
-        self.pool
-            .execute(sqlx::query(
-                r#"CREATE TABLE IF NOT EXISTS hashes (
-                id SERIAL PRIMARY KEY,
-                hash TEXT NOT NULL
-            );"#,
-            ))
-            .await?;
-
-        let value = uint!(0x12356_U256);
+    /// Returns which of `identities` are pending, with one query per chunk of
+    /// identities.
+    pub async fn pending_identities_exist(
+        &self,
+        group_id: usize,
+        identities: &[Hash],
+    ) -> Result<HashSet<Hash>, Error> {
+        let mut pending = HashSet::new();
+        for chunk in identities.chunks(MAX_IDENTITIES_PER_STATEMENT) {
+            let placeholders = (2..chunk.len() + 2)
+                .map(|index| format!("${index}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let sql = format!(
+                r#"SELECT DISTINCT commitment
+                   FROM pending_identities
+                   WHERE group_id = $1 AND commitment IN ({placeholders});"#
+            );
+            let mut query = sqlx::query(&sql).bind(group_id as i64);
+            for identity in chunk {
+                query = query.bind(identity);
+            }
+            let rows = self.pool.fetch_all(query).await?;
+            pending.extend(rows.iter().map(|row| row.get::<Hash, _>(0)));
+        }
+        Ok(pending)
+    }
 
-        self.pool
-            .execute(sqlx::query(r#"INSERT INTO hashes ( hash ) VALUES ( $1 );"#).bind(value))
-            .await?;
+    /// Fetches up to `limit` unprocessed identities in the given `order`, with
+    /// ties decided by `tie_break`.
+    pub async fn get_next_unprocessed_identities(
+        &self,
+        limit: usize,
+        order: DrainOrder,
+        tie_break: TieBreak,
+    ) -> Result<Vec<(usize, Hash)>, Error> {
+        let queue_size = sqlx::query("SELECT COUNT(1) FROM pending_identities");
+        let size: i64 = self.pool.fetch_one(queue_size).await?.get(0);
+        info!(size, "pending identity queue size fetched");
 
-        let rows = self
-            .pool
-            .fetch_all(sqlx::query(r#"SELECT hash FROM hashes;"#))
-            .await?;
-        for row in rows {
-            let hash = row.get::<U256, _>(0);
-            info!(hash = ?hash, "Read hash");
+        let ties = match tie_break {
+            TieBreak::Submission => "seq ASC",
+            TieBreak::Commitment => "commitment ASC",
+        };
+        let sql = match order {
+            DrainOrder::Priority { .. } => {
+                // Submission order already follows the time of submission.
+                let waited = match tie_break {
+                    TieBreak::Submission => "",
+                    TieBreak::Commitment => "created_at ASC,",
+                };
+                format!(
+                    r#"SELECT group_id, commitment
+                       FROM pending_identities
+                       WHERE mined_in_block IS NULL AND {UNBLOCKED}
+                       ORDER BY priority + skipped_batches / $2 DESC,
+                                skipped_batches DESC,
+                                {waited} {ties}
+                       LIMIT $1;"#
+                )
+            }
+            DrainOrder::Oldest => format!(
+                r#"SELECT group_id, commitment
+                       FROM pending_identities
+                       WHERE mined_in_block IS NULL AND {UNBLOCKED}
+                       ORDER BY created_at ASC, {ties}
+                       LIMIT $1;"#
+            ),
+        };
+        let mut query = sqlx::query(&sql).bind(limit as i64);
+        if let DrainOrder::Priority { aging_batches } = order {
+            query = query.bind(if aging_batches == 0 {
+                i64::MAX
+            } else {
+                i64::try_from(aging_batches).unwrap_or(i64::MAX)
+            });
         }
+        let rows = self.pool.fetch_all(query).await?;
+        Ok(rows
+            .iter()
+            .map(|row| (row.get::<i64, _>(0).try_into().unwrap(), row.get(1)))
+            .collect())
+    }
 
-        Ok(Hash::default())
+    /// Records that all unprocessed identities have been passed over for a
+    /// batch.
+    pub async fn age_unprocessed_identities(&self) -> Result<(), Error> {
+        let query = sqlx::query(
+            r#"UPDATE pending_identities
+                   SET skipped_batches = skipped_batches + 1
+                   WHERE mined_in_block IS NULL;"#,
+        );
+        self.pool.execute(query).await?;
+        Ok(())
     }
 
-    pub async fn get_block_number(&self) -> Result<u64, Error> {
-        let row = self
-            .pool
-            .fetch_optional(sqlx::query(
-                r#"SELECT block_index FROM logs ORDER BY block_index DESC LIMIT 1;"#,
-            ))
-            .await?;
+    /// Drops the identities that expired at or before the unix time `now`
+    /// without being mined, and returns them.
+    ///
+    /// Identities drained into a batch in flight or already mined are mined
+    /// regardless.
+    pub async fn sweep_expired_identities(&self, now: u64) -> Result<Vec<Hash>, Error> {
+        let query = sqlx::query(
+            r#"DELETE FROM pending_identities
+                   WHERE mined_in_block IS NULL AND batch_id IS NULL AND expires_at <= $1
+                   RETURNING commitment;"#,
+        )
+        .bind(i64::try_from(now).unwrap_or(i64::MAX));
+        let rows = self.pool.fetch_all(query).await?;
+        Ok(rows.iter().map(|row| row.get::<Hash, _>(0)).collect())
+    }
 
-        if let Some(row) = row {
-            let block_number: i64 = row.try_get(0)?;
-            Ok(u64::try_from(block_number).unwrap_or(0))
-        } else {
-            Ok(0)
+    /// Records that `identities` were drained into the batch `batch_id`, which
+    /// keeps them from expiring while the batch is in flight.
+    pub async fn mark_identities_batched(
+        &self,
+        batch_id: u64,
+        identities: &[(usize, Hash)],
+    ) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        for chunk in identities.chunks(MAX_IDENTITIES_PER_STATEMENT) {
+            let rows = (0..chunk.len())
+                .map(|row| format!("(${}, ${})", 2 * row + 2, 2 * row + 3))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let sql = format!(
+                r#"UPDATE pending_identities
+                   SET batch_id = $1
+                   WHERE (group_id, commitment) IN (VALUES {rows});"#
+            );
+            let mut query = sqlx::query(&sql).bind(i64::try_from(batch_id).unwrap_or(i64::MAX));
+            for (group_id, commitment) in chunk {
+                query = query.bind(*group_id as i64).bind(*commitment);
+            }
+            tx.execute(query).await?;
         }
+        tx.commit().await?;
+        Ok(())
     }
 
-    pub async fn load_logs(
-        &self,
-        from_block: i64,
-        to_block: Option<i64>,
-    ) -> Result<Vec<(Field, Field)>, Error> {
-        let rows = self
-            .pool
-            .fetch_all(
-                sqlx::query(
-                r#"SELECT leaf, root FROM logs WHERE block_index >= $1 AND block_index <= $2 ORDER BY block_index, transaction_index, log_index;"#,
-                )
-                .bind(from_block)
-                .bind(to_block.unwrap_or(i64::MAX))
+    /// Lets the identities of the batch `batch_id` that were not mined expire
+    /// again, or those of every batch if `None`, once no batch is in flight.
+    pub async fn release_batched_identities(&self, batch_id: Option<u64>) -> Result<(), Error> {
+        let query = match batch_id {
+            Some(batch_id) => sqlx::query(
+                r#"UPDATE pending_identities
+                       SET batch_id = NULL
+                       WHERE mined_in_block IS NULL AND batch_id = $1;"#,
             )
-            .await?
-            .iter()
-            .map(|row| (row.try_get(0).unwrap_or_default(), row.try_get(1).unwrap_or_default()))
-            .collect();
+            .bind(i64::try_from(batch_id).unwrap_or(i64::MAX)),
+            None => sqlx::query(
+                r#"UPDATE pending_identities
+                       SET batch_id = NULL
+                       WHERE mined_in_block IS NULL;"#,
+            ),
+        };
+        self.pool.execute(query).await?;
+        Ok(())
+    }
 
-        Ok(rows)
+    /// Returns the identities recorded as mined in a block up to
+    /// `processed_block` to the queue.
+    ///
+    /// Their events would have been processed by now, so their batch must have
+    /// been reorged out of the chain. Identities whose insertion event is
+    /// confirmed nonetheless made it into the chain, in another block, and are
+    /// not requeued.
+    pub async fn requeue_reorged_identities(&self, processed_block: u64) -> Result<u64, Error> {
+        let query = sqlx::query(
+            r#"UPDATE pending_identities
+                   SET mined_in_block = NULL, batch_id = NULL
+                   WHERE mined_in_block <= $1
+                     AND NOT EXISTS (SELECT 1 FROM logs
+                                     WHERE logs.leaf = pending_identities.commitment);"#,
+        )
+        .bind(i64::try_from(processed_block).unwrap_or(i64::MAX));
+        let result = self.pool.execute(query).await?;
+        Ok(result.rows_affected())
     }
 
-    pub async fn save_log(&self, identity: &ConfirmedIdentityEvent) -> Result<(), Error> {
+    pub async fn insert_pending_deletion(
+        &self,
+        group_id: usize,
+        commitment: &Hash,
+    ) -> Result<(), Error> {
         self.pool
-            .execute(
-                sqlx::query(
-                    r#"INSERT INTO logs (block_index, transaction_index, log_index, raw, leaf, root)
-                    VALUES ($1, $2, $3, $4, $5, $6);"#,
-                )
-                .bind(identity.block_index)
-                .bind(identity.transaction_index)
-                .bind(identity.log_index)
-                .bind(identity.raw_log.clone())
-                .bind(identity.leaf)
-                .bind(identity.root),
+            .execute(insert_pending_deletion_query(group_id, commitment))
+            .await?;
+        Ok(())
+    }
+
+    /// Queues the deletion of `old` and the insertion of `new` in its place,
+    /// linked so that `new` is only batched once the deletion of `old` is
+    /// mined.
+    ///
+    /// This happens in a single transaction, so either both are queued or
+    /// neither is.
+    pub async fn insert_recovery(
+        &self,
+        group_id: usize,
+        old: &Hash,
+        new: &Hash,
+    ) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        tx.execute(insert_pending_deletion_query(group_id, old))
+            .await?;
+        tx.execute(next_seq_query()).await?;
+        tx.execute(insert_pending_identity_query(
+            group_id, new, 0, None, None, None,
+        ))
+        .await?;
+        tx.execute(audit_received_query(group_id, new)).await?;
+        tx.execute(
+            sqlx::query(
+                r#"INSERT INTO identity_recoveries (group_id, old_commitment, new_commitment)
+                       VALUES ($1, $2, $3);"#,
             )
-            .await
-            .map_err(Error::InternalError)?;
+            .bind(group_id as i64)
+            .bind(*old)
+            .bind(*new),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn pending_deletion_exists(
+        &self,
+        group_id: usize,
+        commitment: &Hash,
+    ) -> Result<bool, Error> {
+        let query = sqlx::query(
+            r#"SELECT 1
+                   FROM pending_deletions
+                   WHERE group_id = $1 AND commitment = $2
+                   LIMIT 1;"#,
+        )
+        .bind(group_id as i64)
+        .bind(commitment);
+        let row = self.pool.fetch_optional(query).await?;
+        Ok(row.is_some())
+    }
+
+    /// Returns the deletions that have been submitted on chain but whose events
+    /// are not confirmed yet, in the order they were mined.
+    pub async fn get_mined_deletions(&self) -> Result<Vec<Hash>, Error> {
+        let query = sqlx::query(
+            r#"SELECT commitment
+                   FROM pending_deletions
+                   WHERE mined_in_block IS NOT NULL
+                   ORDER BY mined_in_block ASC, created_at ASC, commitment ASC;"#,
+        );
+        let rows = self.pool.fetch_all(query).await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Fetches up to `limit` deletions that have not been submitted on chain
+    /// yet, oldest first.
+    pub async fn get_next_unmined_deletions(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<(usize, Hash)>, Error> {
+        let query = sqlx::query(
+            r#"SELECT group_id, commitment
+                   FROM pending_deletions
+                   WHERE mined_in_block IS NULL
+                   ORDER BY created_at ASC, commitment ASC
+                   LIMIT $1;"#,
+        )
+        .bind(limit as i64);
+        let rows = self.pool.fetch_all(query).await?;
+        Ok(rows
+            .iter()
+            .map(|row| (row.get::<i64, _>(0).try_into().unwrap(), row.get(1)))
+            .collect())
+    }
 
+    pub async fn mark_deletion_mined(
+        &self,
+        group_id: usize,
+        commitment: &Hash,
+        block_number: usize,
+    ) -> Result<(), Error> {
+        let query = sqlx::query(
+            r#"UPDATE pending_deletions
+                   SET mined_in_block = $1
+                   WHERE group_id = $2 AND commitment = $3;"#,
+        )
+        .bind(block_number as i64)
+        .bind(group_id as i64)
+        .bind(commitment);
+        self.pool.execute(query).await?;
         Ok(())
     }
 
-    pub async fn delete_most_recent_cached_events(
+    pub async fn delete_pending_deletion(
         &self,
-        recovery_step_size: i64,
+        group_id: usize,
+        commitment: &Hash,
     ) -> Result<(), Error> {
-        let max_block_number =
-            i64::try_from(self.get_block_number().await?).expect("block number must be i64");
-        self.pool
-            .execute(
-                sqlx::query("DELETE FROM logs WHERE block_index >= $1;")
-                    .bind(max_block_number - recovery_step_size),
+        let query = sqlx::query(
+            r#"DELETE FROM pending_deletions
+                   WHERE group_id = $1 AND commitment = $2;"#,
+        )
+        .bind(group_id as i64)
+        .bind(commitment);
+        self.pool.execute(query).await?;
+        Ok(())
+    }
+
+    /// Persists `configuration` as the insertion prover of its batch size,
+    /// replacing the one persisted or removed before, if any.
+    pub async fn save_prover(&self, configuration: &ProverConfiguration) -> Result<(), Error> {
+        let query = save_prover_query(configuration)?;
+        let mut tx = self.pool.begin().await?;
+        tx.execute(query).await?;
+        tx.execute(
+            sqlx::query(r#"DELETE FROM removed_provers WHERE batch_size = $1;"#)
+                .bind(configuration.batch_size as i64),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Removes the insertion prover of `batch_size`, remembering the removal
+    /// so that a configured prover of that size is not registered again.
+    pub async fn remove_prover(&self, batch_size: usize) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        tx.execute(
+            sqlx::query(
+                r#"DELETE FROM provers
+                   WHERE batch_size = $1;"#,
             )
-            .await
-            .map_err(Error::InternalError)?;
+            .bind(batch_size as i64),
+        )
+        .await?;
+        tx.execute(
+            sqlx::query(
+                r#"INSERT INTO removed_provers (batch_size)
+                   VALUES ($1)
+                   ON CONFLICT (batch_size) DO NOTHING;"#,
+            )
+            .bind(batch_size as i64),
+        )
+        .await?;
+        tx.commit().await?;
         Ok(())
     }
 
-    pub async fn wipe_cache(&self) -> Result<(), Error> {
-        self.pool
-            .execute(sqlx::query("DELETE FROM logs;"))
-            .await
-            .map_err(Error::InternalError)?;
+    /// Fetches the batch sizes whose insertion provers were removed, by
+    /// ascending batch size.
+    pub async fn get_removed_provers(&self) -> Result<Vec<usize>, Error> {
+        let query = sqlx::query(
+            r#"SELECT batch_size
+                   FROM removed_provers
+                   ORDER BY batch_size ASC;"#,
+        );
+        let rows = self.pool.fetch_all(query).await?;
+        Ok(rows
+            .iter()
+            .map(|row| row.get::<i64, _>(0).try_into().unwrap())
+            .collect())
+    }
+
+    /// Records that the transaction `tx_hash` was sent by `sender` with
+    /// `nonce`.
+    pub async fn save_transaction(
+        &self,
+        sender: &Address,
+        nonce: u64,
+        tx_hash: &H256,
+    ) -> Result<(), Error> {
+        let query = sqlx::query(
+            r#"INSERT INTO transactions (tx_hash, nonce, sender)
+                   VALUES ($1, $2, $3)
+                   ON CONFLICT (tx_hash) DO NOTHING;"#,
+        )
+        .bind(tx_hash.as_bytes().to_vec())
+        .bind(i64::try_from(nonce).unwrap_or(i64::MAX))
+        .bind(sender.as_bytes().to_vec());
+        self.pool.execute(query).await?;
         Ok(())
     }
-}
 
-#[derive(Debug, Error)]
-pub enum Error {
-    #[error("database error")]
-    InternalError(#[from] sqlx::Error),
-}
+    /// Forgets the transactions sent by `sender` with `nonce`, once one of
+    /// them is settled.
+    pub async fn remove_transactions(&self, sender: &Address, nonce: u64) -> Result<(), Error> {
+        let query = sqlx::query(
+            r#"DELETE FROM transactions
+                   WHERE nonce = $1 AND sender = $2;"#,
+        )
+        .bind(i64::try_from(nonce).unwrap_or(i64::MAX))
+        .bind(sender.as_bytes().to_vec());
+        self.pool.execute(query).await?;
+        Ok(())
+    }
 
-pub enum IdentityConfirmationResult {
-    Done,
-    RetriggerProcessing,
-}
+    /// Fetches the senders, nonces and hashes of the recorded transactions, by
+    /// ascending nonce.
+    pub async fn get_transactions(&self) -> Result<Vec<(Address, u64, H256)>, Error> {
+        let query = sqlx::query(
+            r#"SELECT sender, nonce, tx_hash
+                   FROM transactions
+                   ORDER BY nonce ASC, tx_hash ASC;"#,
+        );
+        let rows = self.pool.fetch_all(query).await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let sender: Vec<u8> = row.get(0);
+                let sender = (sender.len() == 20).then(|| Address::from_slice(&sender))?;
+                let nonce = u64::try_from(row.get::<i64, _>(1)).ok()?;
+                let tx_hash: Vec<u8> = row.get(2);
+                (tx_hash.len() == 32).then(|| (sender, nonce, H256::from_slice(&tx_hash)))
+            })
+            .collect())
+    }
 
-pub struct ConfirmedIdentityEvent {
-    pub block_index:       i64,
-    pub transaction_index: i32,
-    pub log_index:         i32,
-    pub raw_log:           String,
-    pub leaf:              Field,
-    pub root:              Field,
+    /// Stores the hash of a new API key under `name`, and returns whether it
+    /// was stored, which it is not if the name or key is taken.
+    pub async fn insert_api_key(&self, name: &str, key_hash: &[u8; 32]) -> Result<bool, Error> {
+        let query = sqlx::query(
+            r#"INSERT INTO api_keys (key_hash, name)
+                   VALUES ($1, $2)
+                   ON CONFLICT DO NOTHING;"#,
+        )
+        .bind(key_hash.to_vec())
+        .bind(name);
+        let result = self.pool.execute(query).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Revokes the API key of `name`, and returns whether there was one.
+    pub async fn remove_api_key(&self, name: &str) -> Result<bool, Error> {
+        let query = sqlx::query(
+            r#"DELETE FROM api_keys
+                   WHERE name = $1;"#,
+        )
+        .bind(name);
+        let result = self.pool.execute(query).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Fetches the name of the API key with `key_hash`, if it is not revoked.
+    pub async fn api_key_name(&self, key_hash: &[u8; 32]) -> Result<Option<String>, Error> {
+        let query = sqlx::query(
+            r#"SELECT name
+                   FROM api_keys
+                   WHERE key_hash = $1;"#,
+        )
+        .bind(key_hash.to_vec());
+        let row = self.pool.fetch_optional(query).await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// Fetches the persisted insertion provers, by ascending batch size.
+    pub async fn get_provers(&self) -> Result<Vec<ProverConfiguration>, Error> {
+        let query = sqlx::query(
+            r#"SELECT batch_size, url, max_concurrency, tls_client, region
+                   FROM provers
+                   ORDER BY batch_size ASC;"#,
+        );
+        let rows = self.pool.fetch_all(query).await?;
+        rows.iter().map(prover_from_row).collect()
+    }
+
+    pub async fn count_unprocessed_identities(&self) -> Result<usize, Error> {
+        let query = sqlx::query(
+            r#"SELECT COUNT(1)
+                   FROM pending_identities
+                   WHERE mined_in_block IS NULL;"#,
+        );
+        let count: i64 = self.pool.fetch_one(query).await?.get(0);
+        Ok(count.try_into().unwrap())
+    }
+
+    /// Returns the number of identities that are not mined yet and can be
+    /// committed, leaving out the replacements that wait for a deletion.
+    pub async fn count_unblocked_identities(&self) -> Result<usize, Error> {
+        let query = format!(
+            "SELECT COUNT(1) FROM pending_identities WHERE mined_in_block IS NULL AND {UNBLOCKED};"
+        );
+        let count: i64 = self.pool.fetch_one(query.as_str()).await?.get(0);
+        Ok(count.try_into().unwrap())
+    }
+
+    /// Returns how long ago the oldest identity that is not mined yet and can
+    /// be committed was queued, to the second, or `None` if there is none.
+    ///
+    /// The replacements that wait for a deletion are left out, as a batch
+    /// cannot take them.
+    pub async fn oldest_unprocessed_identity_age(&self) -> Result<Option<Duration>, Error> {
+        let age = match self.pool.any_kind() {
+            AnyKind::Postgres => {
+                "CAST(EXTRACT(EPOCH FROM LOCALTIMESTAMP - MIN(created_at)) AS BIGINT)"
+            }
+            _ => {
+                "CAST(strftime('%s', 'now') AS BIGINT) - CAST(strftime('%s', MIN(created_at)) AS \
+                 BIGINT)"
+            }
+        };
+        let query = format!(
+            "SELECT {age} FROM pending_identities WHERE mined_in_block IS NULL AND {UNBLOCKED};"
+        );
+        let age: Option<i64> = self.pool.fetch_one(query.as_str()).await?.get(0);
+        Ok(age.map(|age| Duration::from_secs(age.try_into().unwrap_or_default())))
+    }
+
+    /// Returns the leaf index the oldest identity that is not mined yet will
+    /// take, which is the number of leaves mined so far, or `None` if every
+    /// identity is mined.
+    ///
+    /// Mined leaves are those cached from confirmed events and those marked as
+    /// mined but not confirmed yet.
+    pub async fn oldest_unmined_leaf_index(&self) -> Result<Option<usize>, Error> {
+        if self.count_unprocessed_identities().await? == 0 {
+            return Ok(None);
+        }
+        let query = sqlx::query(
+            r#"SELECT (SELECT COUNT(1) FROM logs)
+                    + (SELECT COUNT(1) FROM pending_identities WHERE mined_in_block IS NOT NULL);"#,
+        );
+        let mined: i64 = self.pool.fetch_one(query).await?.get(0);
+        Ok(Some(mined.try_into().unwrap()))
+    }
+
+    /// Sets the `pending_identities_backlog` gauge to the number of persisted
+    /// identities that are not mined yet, and returns it. Also refreshes the
+    /// `oldest_unmined_leaf_index` gauge.
+    pub async fn refresh_backlog_metric(&self) -> Result<usize, Error> {
+        let count = self.count_unprocessed_identities().await?;
+        PENDING_BACKLOG.set(count.try_into().unwrap());
+        let frontier = self.oldest_unmined_leaf_index().await?;
+        MINING_FRONTIER.set(frontier.map_or(-1, |index| index.try_into().unwrap()));
+        Ok(count)
+    }
+
+    #[allow(unused)]
+    pub async fn read(&self, _index: usize) -> Result<Hash, Error> {
+        self.pool
+            .execute(sqlx::query(
+                r#"CREATE TABLE IF NOT EXISTS hashes (
+                id SERIAL PRIMARY KEY,
+                hash TEXT NOT NULL
+            );"#,
+            ))
+            .await?;
+
+        let value = uint!(0x12356_U256);
+
+        self.pool
+            .execute(sqlx::query(r#"INSERT INTO hashes ( hash ) VALUES ( $1 );"#).bind(value))
+            .await?;
+
+        let rows = self
+            .pool
+            .fetch_all(sqlx::query(r#"SELECT hash FROM hashes;"#))
+            .await?;
+        for row in rows {
+            let hash = row.get::<U256, _>(0);
+            info!(hash = ?hash, "Read hash");
+        }
+
+        Ok(Hash::default())
+    }
+
+    /// Returns the block of the most recent cached event, adding or emptying a
+    /// leaf, or zero if no events are cached.
+    pub async fn get_block_number(&self) -> Result<u64, Error> {
+        let row = self
+            .pool
+            .fetch_optional(sqlx::query(
+                r#"SELECT block_index FROM logs
+                   UNION ALL
+                   SELECT block_index FROM deletion_logs
+                   ORDER BY block_index DESC
+                   LIMIT 1;"#,
+            ))
+            .await?;
+
+        if let Some(row) = row {
+            let block_number: i64 = row.try_get(0)?;
+            Ok(u64::try_from(block_number).unwrap_or(0))
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Returns the root emitted by the most recent cached event, adding or
+    /// emptying a leaf, or `None` if no events are cached.
+    pub async fn get_latest_root(&self) -> Result<Option<Hash>, Error> {
+        self.get_latest_root_until(i64::MAX).await
+    }
+
+    /// Returns the root emitted by the most recent cached event up to
+    /// `to_block`, like [`Self::get_latest_root`].
+    pub async fn get_latest_root_until(&self, to_block: i64) -> Result<Option<Hash>, Error> {
+        let row = self
+            .pool
+            .fetch_optional(
+                sqlx::query(
+                    r#"SELECT root, block_index, transaction_index, log_index FROM logs
+                       WHERE block_index <= $1
+                       UNION ALL
+                       SELECT root, block_index, transaction_index, log_index FROM deletion_logs
+                       WHERE block_index <= $2
+                       ORDER BY block_index DESC, transaction_index DESC, log_index DESC
+                       LIMIT 1;"#,
+                )
+                .bind(to_block)
+                .bind(to_block),
+            )
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// Loads the leaves and roots of the cached events from `from_block` up to
+    /// `to_block`, in order.
+    ///
+    /// The events are read from the replica if it has caught up.
+    pub async fn load_logs(
+        &self,
+        from_block: i64,
+        to_block: Option<i64>,
+    ) -> Result<Vec<(Field, Field)>, Error> {
+        let rows = self
+            .event_cache_pool()
+            .await?
+            .fetch_all(
+                sqlx::query(
+                r#"SELECT leaf, root FROM logs WHERE block_index >= $1 AND block_index <= $2 ORDER BY block_index, transaction_index, log_index;"#,
+                )
+                .bind(from_block)
+                .bind(to_block.unwrap_or(i64::MAX))
+            )
+            .await?
+            .iter()
+            .map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+            .collect::<Result<_, sqlx::Error>>()
+            .map_err(Error::CorruptCache)?;
+
+        Ok(rows)
+    }
+
+    /// Loads the leaf indices and roots of the cached events emptying a leaf
+    /// from `from_block` up to `to_block`, in order.
+    ///
+    /// The events are read from the replica if it has caught up.
+    pub async fn load_deletion_logs(
+        &self,
+        from_block: i64,
+        to_block: Option<i64>,
+    ) -> Result<Vec<(usize, Field)>, Error> {
+        let rows = self
+            .event_cache_pool()
+            .await?
+            .fetch_all(
+                sqlx::query(
+                    r#"SELECT leaf_index, root FROM deletion_logs
+                       WHERE block_index >= $1 AND block_index <= $2
+                       ORDER BY block_index, transaction_index, log_index;"#,
+                )
+                .bind(from_block)
+                .bind(to_block.unwrap_or(i64::MAX)),
+            )
+            .await?
+            .iter()
+            .map(|row| {
+                let leaf_index: i64 = row.try_get(0)?;
+                Ok((leaf_index as usize, row.try_get(1)?))
+            })
+            .collect::<Result<_, sqlx::Error>>()
+            .map_err(Error::CorruptCache)?;
+
+        Ok(rows)
+    }
+
+    /// Returns the number of leaves the tree held when its root was `root`, or
+    /// `None` if no cached event produced that root.
+    ///
+    /// A root the replica has not caught up with yet is looked up on the
+    /// primary.
+    pub async fn count_leaves_at_root(&self, root: &Hash) -> Result<Option<usize>, Error> {
+        for pool in self.read_pools() {
+            let query = sqlx::query(
+                r#"SELECT COUNT(1)
+                   FROM logs AS leaf
+                   JOIN (SELECT block_index, transaction_index, log_index
+                         FROM logs
+                         WHERE root = $1
+                         LIMIT 1) AS event
+                     ON (leaf.block_index, leaf.transaction_index, leaf.log_index)
+                        <= (event.block_index, event.transaction_index, event.log_index);"#,
+            )
+            .bind(root);
+            let count: i64 = pool.fetch_one(query).await?.get(0);
+            if count > 0 {
+                return Ok(Some(count as usize));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the number of cached leaves that were added before
+    /// `before_block`.
+    ///
+    /// The leaves are counted on the replica if it has caught up.
+    pub async fn count_cached_leaves(&self, before_block: i64) -> Result<usize, Error> {
+        let query =
+            sqlx::query(r#"SELECT COUNT(1) FROM logs WHERE block_index < $1;"#).bind(before_block);
+        let count: i64 = self
+            .event_cache_pool()
+            .await?
+            .fetch_one(query)
+            .await?
+            .get(0);
+        Ok(count as usize)
+    }
+
+    pub async fn save_log(&self, identity: &ConfirmedIdentityEvent) -> Result<(), Error> {
+        self.pool
+            .execute(save_log_query(identity))
+            .await
+            .map_err(Error::InternalError)?;
+
+        Ok(())
+    }
+
+    /// Returns the identities, deletions, recoveries, provers and cached
+    /// events, read in a single transaction so that they are consistent with
+    /// each other while the sequencer is running.
+    pub async fn dump_state(&self) -> Result<StateDump, Error> {
+        let mut tx = self.pool.begin().await?;
+        if matches!(self.pool.any_kind(), AnyKind::Postgres) {
+            // Every statement reads the snapshot taken by the first one.
+            tx.execute(sqlx::query(
+                "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY;",
+            ))
+            .await?;
+        }
+
+        let tree_depth = tx
+            .fetch_optional(sqlx::query("SELECT depth FROM tree_metadata WHERE id = 1;"))
+            .await?
+            .map(|row| usize::try_from(row.get::<i64, _>(0)).unwrap_or_default());
+        let logs = tx
+            .fetch_all(sqlx::query(
+                r#"SELECT block_index, transaction_index, log_index, raw, leaf, root, cached_at
+                   FROM logs
+                   ORDER BY block_index, transaction_index, log_index;"#,
+            ))
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(LogRecord {
+                    block_index:       row.try_get(0)?,
+                    transaction_index: row.try_get(1)?,
+                    log_index:         row.try_get(2)?,
+                    raw:               row.try_get(3)?,
+                    leaf:              row.try_get(4)?,
+                    root:              row.try_get(5)?,
+                    cached_at:         row.try_get(6)?,
+                })
+            })
+            .collect::<Result<_, sqlx::Error>>()
+            .map_err(Error::CorruptCache)?;
+        let deletion_logs = tx
+            .fetch_all(sqlx::query(
+                r#"SELECT block_index, transaction_index, log_index, raw, leaf, leaf_index, root,
+                       cached_at
+                   FROM deletion_logs
+                   ORDER BY block_index, transaction_index, log_index;"#,
+            ))
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(DeletionLogRecord {
+                    block_index:       row.try_get(0)?,
+                    transaction_index: row.try_get(1)?,
+                    log_index:         row.try_get(2)?,
+                    raw:               row.try_get(3)?,
+                    leaf:              row.try_get(4)?,
+                    leaf_index:        row.try_get(5)?,
+                    root:              row.try_get(6)?,
+                    cached_at:         row.try_get(7)?,
+                })
+            })
+            .collect::<Result<_, sqlx::Error>>()
+            .map_err(Error::CorruptCache)?;
+        let pending_identities = tx
+            .fetch_all(sqlx::query(
+                r#"SELECT group_id, commitment, CAST(created_at AS TEXT), mined_in_block, priority,
+                       skipped_batches, seq, batch_size_hint, webhook_url, expires_at
+                   FROM pending_identities
+                   ORDER BY seq, commitment;"#,
+            ))
+            .await?
+            .iter()
+            .map(|row| PendingIdentityRecord {
+                group_id:        row.get(0),
+                commitment:      row.get(1),
+                created_at:      row.get(2),
+                mined_in_block:  row.get(3),
+                priority:        row.get(4),
+                skipped_batches: row.get(5),
+                seq:             row.get(6),
+                batch_size_hint: row.get(7),
+                webhook_url:     row.get(8),
+                expires_at:      row.get(9),
+            })
+            .collect();
+        let pending_deletions = tx
+            .fetch_all(sqlx::query(
+                r#"SELECT group_id, commitment, CAST(created_at AS TEXT), mined_in_block
+                   FROM pending_deletions
+                   ORDER BY created_at, group_id, commitment;"#,
+            ))
+            .await?
+            .iter()
+            .map(|row| PendingDeletionRecord {
+                group_id:       row.get(0),
+                commitment:     row.get(1),
+                created_at:     row.get(2),
+                mined_in_block: row.get(3),
+            })
+            .collect();
+        let recoveries = tx
+            .fetch_all(sqlx::query(
+                r#"SELECT group_id, old_commitment, new_commitment, CAST(created_at AS TEXT)
+                   FROM identity_recoveries
+                   ORDER BY created_at, group_id, old_commitment;"#,
+            ))
+            .await?
+            .iter()
+            .map(|row| RecoveryRecord {
+                group_id:       row.get(0),
+                old_commitment: row.get(1),
+                new_commitment: row.get(2),
+                created_at:     row.get(3),
+            })
+            .collect();
+        let provers = tx
+            .fetch_all(sqlx::query(
+                r#"SELECT batch_size, url, max_concurrency, tls_client, region
+                   FROM provers
+                   ORDER BY batch_size ASC;"#,
+            ))
+            .await?
+            .iter()
+            .map(prover_from_row)
+            .collect::<Result<_, Error>>()?;
+        let removed_provers = tx
+            .fetch_all(sqlx::query(
+                r#"SELECT batch_size
+                   FROM removed_provers
+                   ORDER BY batch_size ASC;"#,
+            ))
+            .await?
+            .iter()
+            .map(|row| row.get::<i64, _>(0).try_into().unwrap())
+            .collect();
+        tx.commit().await?;
+
+        Ok(StateDump {
+            tree_depth,
+            logs,
+            deletion_logs,
+            pending_identities,
+            pending_deletions,
+            recoveries,
+            provers,
+            removed_provers,
+        })
+    }
+
+    /// Writes a dumped state back, in a single transaction so that either all
+    /// of it is written or none is.
+    ///
+    /// Fails with [`Error::NotFresh`] without writing anything if the database
+    /// already holds identities, deletions or cached events, which the state
+    /// would be duplicated with.
+    pub async fn restore_state(&self, state: &StateDump) -> Result<(), Error> {
+        let timestamp = |index: usize| match self.pool.any_kind() {
+            AnyKind::Postgres => format!("CAST(${index} AS TIMESTAMP)"),
+            _ => format!("${index}"),
+        };
+        let insert_identity = format!(
+            r#"INSERT INTO pending_identities (group_id, commitment, created_at, mined_in_block,
+                   priority, skipped_batches, seq, batch_size_hint, webhook_url, expires_at)
+               VALUES ($1, $2, {}, $4, $5, $6, $7, $8, $9, $10);"#,
+            timestamp(3)
+        );
+        let insert_deletion = format!(
+            r#"INSERT INTO pending_deletions (group_id, commitment, created_at, mined_in_block)
+               VALUES ($1, $2, {}, $4);"#,
+            timestamp(3)
+        );
+        let insert_recovery = format!(
+            r#"INSERT INTO identity_recoveries (group_id, old_commitment, new_commitment,
+                   created_at)
+               VALUES ($1, $2, $3, {});"#,
+            timestamp(4)
+        );
+
+        let mut tx = self.pool.begin().await?;
+        let held: i64 = tx
+            .fetch_one(sqlx::query(
+                r#"SELECT (SELECT COUNT(1) FROM logs)
+                        + (SELECT COUNT(1) FROM deletion_logs)
+                        + (SELECT COUNT(1) FROM pending_identities)
+                        + (SELECT COUNT(1) FROM pending_deletions)
+                        + (SELECT COUNT(1) FROM identity_recoveries);"#,
+            ))
+            .await?
+            .get(0);
+        if held > 0 {
+            return Err(Error::NotFresh);
+        }
+
+        for log in &state.logs {
+            let query = sqlx::query(
+                r#"INSERT INTO logs (block_index, transaction_index, log_index, raw, leaf, root, cached_at)
+                   VALUES ($1, $2, $3, $4, $5, $6, $7);"#,
+            )
+            .bind(log.block_index)
+            .bind(log.transaction_index)
+            .bind(log.log_index)
+            .bind(log.raw.clone())
+            .bind(log.leaf)
+            .bind(log.root)
+            .bind(log.cached_at);
+            tx.execute(query).await?;
+        }
+        for log in &state.deletion_logs {
+            let query = sqlx::query(
+                r#"INSERT INTO deletion_logs (block_index, transaction_index, log_index, raw, leaf,
+                       leaf_index, root, cached_at)
+                   VALUES ($1, $2, $3, $4, $5, $6, $7, $8);"#,
+            )
+            .bind(log.block_index)
+            .bind(log.transaction_index)
+            .bind(log.log_index)
+            .bind(log.raw.clone())
+            .bind(log.leaf)
+            .bind(log.leaf_index)
+            .bind(log.root)
+            .bind(log.cached_at);
+            tx.execute(query).await?;
+        }
+        for identity in &state.pending_identities {
+            let query = sqlx::query(&insert_identity)
+                .bind(identity.group_id)
+                .bind(identity.commitment)
+                .bind(identity.created_at.clone())
+                .bind(identity.mined_in_block)
+                .bind(identity.priority)
+                .bind(identity.skipped_batches)
+                .bind(identity.seq)
+                .bind(identity.batch_size_hint)
+                .bind(identity.webhook_url.clone())
+                .bind(identity.expires_at);
+            tx.execute(query).await?;
+        }
+        // Restored identities keep their order, later ones are queued after.
+        tx.execute(sqlx::query(
+            r#"UPDATE pending_identity_sequence
+                   SET last = (SELECT COALESCE(MAX(seq), 0) FROM pending_identities)
+                   WHERE last < (SELECT COALESCE(MAX(seq), 0) FROM pending_identities);"#,
+        ))
+        .await?;
+        for deletion in &state.pending_deletions {
+            let query = sqlx::query(&insert_deletion)
+                .bind(deletion.group_id)
+                .bind(deletion.commitment)
+                .bind(deletion.created_at.clone())
+                .bind(deletion.mined_in_block);
+            tx.execute(query).await?;
+        }
+        for recovery in &state.recoveries {
+            let query = sqlx::query(&insert_recovery)
+                .bind(recovery.group_id)
+                .bind(recovery.old_commitment)
+                .bind(recovery.new_commitment)
+                .bind(recovery.created_at.clone());
+            tx.execute(query).await?;
+        }
+        for prover in &state.provers {
+            tx.execute(save_prover_query(prover)?).await?;
+        }
+        for &batch_size in &state.removed_provers {
+            tx.execute(
+                sqlx::query(
+                    r#"INSERT INTO removed_provers (batch_size)
+                       VALUES ($1)
+                       ON CONFLICT (batch_size) DO NOTHING;"#,
+                )
+                .bind(batch_size as i64),
+            )
+            .await?;
+        }
+        if let Some(depth) = state.tree_depth {
+            tx.execute(set_tree_depth_query(depth)).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Returns the roots of the batches mined in blocks `from_block` to
+    /// `to_block`, oldest first and at most `limit` of them. Only the batches
+    /// past `after` are returned, if given.
+    ///
+    /// The post-roots of batches with confirmed events come first, followed by
+    /// the batches that were marked as mined but whose events are not
+    /// confirmed yet, one per block and without a root.
+    pub async fn get_roots(
+        &self,
+        from_block: u64,
+        after: Option<RootCursor>,
+        to_block: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<RootRecord>, Error> {
+        // A batch is past the cursor if its last event is. Without a cursor,
+        // every batch of `from_block` is past the one before its first event.
+        let after = after
+            .filter(|cursor| cursor.block_index >= from_block)
+            .map_or(
+                (i64::try_from(from_block).unwrap_or(i64::MAX), -1, -1),
+                |cursor| {
+                    (
+                        i64::try_from(cursor.block_index).unwrap_or(i64::MAX),
+                        i64::from(cursor.transaction_index),
+                        i64::from(cursor.log_index),
+                    )
+                },
+            );
+        let to_block = to_block.map_or(i64::MAX, |block| i64::try_from(block).unwrap_or(i64::MAX));
+
+        let query = sqlx::query(
+            r#"SELECT batch.root, batch.block_index, batch.transaction_index, batch.log_index,
+                   batch.cached_at
+               FROM logs AS batch
+               WHERE (batch.block_index, batch.transaction_index, batch.log_index) > ($1, $2, $3)
+                   AND batch.block_index <= $4
+                   AND batch.log_index = (
+                       SELECT MAX(later.log_index) FROM logs AS later
+                       WHERE later.block_index = batch.block_index
+                           AND later.transaction_index = batch.transaction_index)
+               ORDER BY batch.block_index, batch.transaction_index
+               LIMIT $5;"#,
+        )
+        .bind(after.0)
+        .bind(after.1)
+        .bind(after.2)
+        .bind(to_block)
+        .bind(i64::try_from(limit).unwrap_or(i64::MAX));
+        let mut roots = self
+            .event_cache_pool()
+            .await?
+            .fetch_all(query)
+            .await?
+            .iter()
+            .map(|row| {
+                let block_number = row.try_get::<i64, _>(1)?.try_into().unwrap_or_default();
+                Ok(RootRecord {
+                    root: Some(row.try_get(0)?),
+                    status: RootStatus::Mined,
+                    block_number,
+                    cursor: Some(RootCursor {
+                        block_index:       block_number,
+                        transaction_index: row.try_get::<i32, _>(2)?.try_into().unwrap_or_default(),
+                        log_index:         row.try_get::<i32, _>(3)?.try_into().unwrap_or_default(),
+                    }),
+                    cached_at: row
+                        .try_get::<Option<i64>, _>(4)?
+                        .and_then(|time| time.try_into().ok()),
+                })
+            })
+            .collect::<Result<Vec<_>, sqlx::Error>>()
+            .map_err(Error::CorruptCache)?;
+
+        let remaining = limit.saturating_sub(roots.len());
+        if remaining > 0 {
+            let query = sqlx::query(
+                r#"SELECT DISTINCT mined_in_block FROM pending_identities
+                   WHERE mined_in_block >= $1 AND mined_in_block <= $2
+                   ORDER BY mined_in_block
+                   LIMIT $3;"#,
+            )
+            .bind(after.0)
+            .bind(to_block)
+            .bind(i64::try_from(remaining).unwrap_or(i64::MAX));
+            roots.extend(
+                self.pool
+                    .fetch_all(query)
+                    .await?
+                    .iter()
+                    .map(|row| RootRecord {
+                        root:         None,
+                        status:       RootStatus::Pending,
+                        block_number: row.get::<i64, _>(0).try_into().unwrap_or_default(),
+                        cursor:       None,
+                        cached_at:    None,
+                    }),
+            );
+        }
+        Ok(roots)
+    }
+
+    /// Returns the batch that included `commitment`, or `None` if the
+    /// commitment has not been mined in a confirmed block yet.
+    ///
+    /// A batch is the set of identities added by a single transaction, and its
+    /// post-root is the root emitted by the last of them. A commitment the
+    /// replica has not caught up with yet is looked up on the primary.
+    pub async fn get_batch_for_commitment(
+        &self,
+        commitment: &Hash,
+    ) -> Result<Option<BatchRecord>, Error> {
+        for pool in self.read_pools() {
+            let query = sqlx::query(
+                r#"SELECT batch.block_index, batch.transaction_index, batch.root
+                   FROM logs AS batch
+                   JOIN logs AS identity
+                       ON batch.block_index = identity.block_index
+                       AND batch.transaction_index = identity.transaction_index
+                   WHERE identity.leaf = $1
+                   ORDER BY batch.log_index DESC
+                   LIMIT 1;"#,
+            )
+            .bind(commitment);
+            if let Some(row) = pool.fetch_optional(query).await? {
+                return Ok(Some(BatchRecord {
+                    block_index:       row.try_get(0)?,
+                    transaction_index: row.try_get(1)?,
+                    post_root:         row.try_get(2)?,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn delete_most_recent_cached_events(
+        &self,
+        recovery_step_size: i64,
+    ) -> Result<(), Error> {
+        let max_block_number =
+            i64::try_from(self.get_block_number().await?).expect("block number must be i64");
+        let mut tx = self.pool.begin().await?;
+        for table in ["logs", "deletion_logs"] {
+            let sql = format!("DELETE FROM {table} WHERE block_index >= $1;");
+            tx.execute(sqlx::query(&sql).bind(max_block_number - recovery_step_size))
+                .await
+                .map_err(Error::InternalError)?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn wipe_cache(&self) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        tx.execute(sqlx::query("DELETE FROM logs;"))
+            .await
+            .map_err(Error::InternalError)?;
+        tx.execute(sqlx::query("DELETE FROM deletion_logs;"))
+            .await
+            .map_err(Error::InternalError)?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+fn save_log_query(identity: &ConfirmedIdentityEvent) -> Query<'static, Any, AnyArguments<'static>> {
+    sqlx::query(
+        r#"INSERT INTO logs (block_index, transaction_index, log_index, raw, leaf, root, cached_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7);"#,
+    )
+    .bind(identity.block_index)
+    .bind(identity.transaction_index)
+    .bind(identity.log_index)
+    .bind(identity.raw_log.clone())
+    .bind(identity.leaf)
+    .bind(identity.root)
+    .bind(unix_now())
+}
+
+/// Starts the audit of `identity`, forgetting any earlier batch.
+fn audit_received_query(
+    group_id: usize,
+    identity: &Hash,
+) -> Query<'static, Any, AnyArguments<'static>> {
+    sqlx::query(
+        r#"INSERT INTO identity_audit (group_id, commitment, received_at)
+               VALUES ($1, $2, $3)
+               ON CONFLICT (group_id, commitment) DO UPDATE
+               SET received_at = excluded.received_at,
+                   batch_id = NULL,
+                   tx_hash = NULL,
+                   mined_in_block = NULL;"#,
+    )
+    .bind(group_id as i64)
+    .bind(*identity)
+    .bind(unix_now())
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0_i64, |time| time.as_secs().try_into().unwrap_or(i64::MAX))
+}
+
+/// Takes the next submission order of a pending identity. The row stays locked
+/// until the transaction ends, so concurrent inserts take turns.
+fn next_seq_query() -> Query<'static, Any, AnyArguments<'static>> {
+    sqlx::query("UPDATE pending_identity_sequence SET last = last + 1;")
+}
+
+/// Inserts a pending identity with the submission order taken by
+/// [`next_seq_query`] in the same transaction.
+fn insert_pending_identity_query(
+    group_id: usize,
+    identity: &Hash,
+    priority: u8,
+    batch_size_hint: Option<usize>,
+    webhook_url: Option<&Url>,
+    expires_at: Option<u64>,
+) -> Query<'static, Any, AnyArguments<'static>> {
+    sqlx::query(
+        r#"INSERT INTO pending_identities (group_id, commitment, priority, seq, batch_size_hint, webhook_url, expires_at)
+               SELECT $1, $2, $3, last, $4, $5, $6
+               FROM pending_identity_sequence;"#,
+    )
+    .bind(group_id as i64)
+    .bind(*identity)
+    .bind(i64::from(priority))
+    .bind(batch_size_hint.map(|hint| i64::try_from(hint).unwrap_or(i64::MAX)))
+    .bind(webhook_url.map(Url::to_string))
+    .bind(expires_at.map(|time| i64::try_from(time).unwrap_or(i64::MAX)))
+}
+
+fn insert_pending_deletion_query(
+    group_id: usize,
+    commitment: &Hash,
+) -> Query<'static, Any, AnyArguments<'static>> {
+    sqlx::query(
+        r#"INSERT INTO pending_deletions (group_id, commitment)
+               VALUES ($1, $2);"#,
+    )
+    .bind(group_id as i64)
+    .bind(*commitment)
+}
+
+/// Requeues the identities mined in an earlier block than `commitment`, as
+/// they have been dropped. They keep their original submission order.
+fn retrigger_query(commitment: &Hash) -> Query<'static, Any, AnyArguments<'static>> {
+    sqlx::query(
+        r#"UPDATE pending_identities
+        SET mined_in_block = NULL
+        WHERE mined_in_block < (SELECT mined_in_block FROM pending_identities WHERE commitment = $1 LIMIT 1)"#,
+    )
+    .bind(*commitment)
+}
+
+fn cleanup_query(commitment: &Hash) -> Query<'static, Any, AnyArguments<'static>> {
+    sqlx::query(
+        r#"DELETE FROM pending_identities
+            WHERE commitment = $1;"#,
+    )
+    .bind(*commitment)
+}
+
+fn set_tree_depth_query(depth: usize) -> Query<'static, Any, AnyArguments<'static>> {
+    sqlx::query(
+        r#"INSERT INTO tree_metadata (id, depth) VALUES (1, $1)
+            ON CONFLICT (id) DO UPDATE SET depth = excluded.depth;"#,
+    )
+    .bind(i64::try_from(depth).unwrap_or(i64::MAX))
+}
+
+/// Persists `configuration` as the insertion prover of its batch size,
+/// replacing the one persisted before, if any.
+fn save_prover_query(
+    configuration: &ProverConfiguration,
+) -> Result<Query<'static, Any, AnyArguments<'static>>, Error> {
+    let tls_client = configuration
+        .tls_client
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(Error::CorruptProver)?;
+    Ok(sqlx::query(
+        r#"INSERT INTO provers (batch_size, url, max_concurrency, tls_client, region)
+               VALUES ($1, $2, $3, $4, $5)
+               ON CONFLICT (batch_size) DO UPDATE
+               SET url = excluded.url,
+                   max_concurrency = excluded.max_concurrency,
+                   tls_client = excluded.tls_client,
+                   region = excluded.region;"#,
+    )
+    .bind(configuration.batch_size as i64)
+    .bind(configuration.url.clone())
+    .bind(
+        configuration
+            .max_concurrency
+            .map(|limit| i64::try_from(limit).unwrap_or(i64::MAX)),
+    )
+    .bind(tls_client)
+    .bind(configuration.region.clone()))
+}
+
+/// Reads a row of `batch_size, url, max_concurrency, tls_client, region` from
+/// the `provers` table.
+fn prover_from_row(row: &AnyRow) -> Result<ProverConfiguration, Error> {
+    Ok(ProverConfiguration {
+        batch_size:      row.get::<i64, _>(0).try_into().unwrap(),
+        url:             row.get(1),
+        max_concurrency: row
+            .get::<Option<i64>, _>(2)
+            .map(|limit| limit.try_into().unwrap()),
+        tls_client:      row
+            .get::<Option<String>, _>(3)
+            .map(|tls_client| serde_json::from_str(&tls_client))
+            .transpose()
+            .map_err(Error::CorruptProver)?,
+        region:          row.get(4),
+    })
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+
+    /// Connects to a fresh in-memory database with all migrations applied.
+    pub async fn database() -> Database {
+        Database::new(Options {
+            database:                 Url::parse("sqlite::memory:").unwrap(),
+            database_migrate:         true,
+            database_max_connections: 1,
+            database_read_replica:    None,
+        })
+        .await
+        .expect("Failed to create in-memory database.")
+    }
+
+    /// Connects to a fresh in-memory database that reads from `replica`.
+    pub async fn database_with_replica(replica: &Database) -> Database {
+        Database {
+            replica: Some(replica.pool.clone()),
+            ..database().await
+        }
+    }
+
+    /// Caches an event for `block_index` whose leaf and root are missing.
+    pub async fn save_corrupt_log(database: &Database, block_index: i64) {
+        database
+            .pool
+            .execute(
+                sqlx::query(
+                    r#"INSERT INTO logs (block_index, transaction_index, log_index, raw)
+                       VALUES ($1, 0, 0, '');"#,
+                )
+                .bind(block_index),
+            )
+            .await
+            .expect("Failed to save corrupt log.");
+    }
+
+    /// Overrides the time at which `commitment` was submitted.
+    pub async fn set_created_at(database: &Database, commitment: &Hash, created_at: &str) {
+        database
+            .pool
+            .execute(
+                sqlx::query(
+                    r#"UPDATE pending_identities SET created_at = $2
+                       WHERE commitment = $1;"#,
+                )
+                .bind(commitment)
+                .bind(created_at),
+            )
+            .await
+            .expect("Failed to set creation time.");
+    }
+
+    /// Makes any update of a pending identity fail.
+    pub async fn fail_pending_identity_updates(database: &Database) {
+        database
+            .pool
+            .execute(
+                r#"CREATE TRIGGER fail_pending_identity_updates BEFORE UPDATE ON pending_identities
+                   BEGIN SELECT RAISE(ABORT, 'injected failure'); END;"#,
+            )
+            .await
+            .expect("Failed to create failing trigger.");
+    }
+
+    /// Makes caching any event for `block_index` fail.
+    pub async fn fail_log_inserts(database: &Database, block_index: i64) {
+        database
+            .pool
+            .execute(
+                format!(
+                    r#"CREATE TRIGGER fail_log_inserts BEFORE INSERT ON logs
+                       WHEN NEW.block_index = {block_index}
+                       BEGIN SELECT RAISE(ABORT, 'injected failure'); END;"#
+                )
+                .as_str(),
+            )
+            .await
+            .expect("Failed to create failing trigger.");
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("database error")]
+    InternalError(#[from] sqlx::Error),
+    #[error("cached event is corrupt: {0}")]
+    CorruptCache(#[source] sqlx::Error),
+    #[error("batch record is corrupt: {0}")]
+    CorruptBatch(#[source] serde_json::Error),
+    #[error("prover record is corrupt: {0}")]
+    CorruptProver(#[source] serde_json::Error),
+    #[error("database already holds identities")]
+    NotFresh,
+    #[error("nonce is not greater than the last one of the api key")]
+    ReplayedNonce,
+}
+
+/// The order in which pending identities are drained into batches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrainOrder {
+    /// By effective priority, which is the requested priority raised by one
+    /// for every `aging_batches` batches an identity has been passed over for,
+    /// so that low priority identities cannot be starved. Zero `aging_batches`
+    /// disables aging. Ties go to the identity that has waited longest, and
+    /// are then decided by the [`TieBreak`].
+    Priority { aging_batches: u64 },
+    /// Oldest first by time of submission, regardless of priority. Ties are
+    /// decided by the [`TieBreak`].
+    Oldest,
+}
+
+/// How pending identities that are otherwise equal in the [`DrainOrder`] are
+/// ordered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TieBreak {
+    /// The one submitted first goes first. The submission order is particular
+    /// to this sequencer.
+    #[default]
+    Submission,
+    /// The one submitted first goes first, down to the resolution of the
+    /// submission time, and then the smallest commitment. Sequencers given the
+    /// same identities thereby batch them alike.
+    Commitment,
+}
+
+pub enum IdentityConfirmationResult {
+    Done,
+    RetriggerProcessing,
+}
+
+/// The batch of identities that was mined in a single transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatchRecord {
+    pub block_index:       i64,
+    pub transaction_index: i32,
+    pub post_root:         Field,
+}
+
+/// How a batch of identities was submitted, as recorded in their audit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatchAudit {
+    /// The identifier the committer assigned to the batch.
+    pub batch_id:     u64,
+    /// The transaction that submitted the identity.
+    pub tx_hash:      H256,
+    pub block_number: u64,
+}
+
+/// A batch about to be submitted, as recorded in the batch audit log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NewBatch {
+    pub id:           u64,
+    /// The root the batch builds on, which is `None` for an empty tree.
+    pub pre_root:     Option<Hash>,
+    pub commitments:  Vec<Hash>,
+    /// Unix time at which the batch was assembled.
+    pub assembled_at: u64,
+    /// Unix time at which the batch was submitted to the contract.
+    pub submitted_at: u64,
+}
+
+/// A batch in the batch audit log, with the outcome of its submission once it
+/// is settled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatchAuditRecord {
+    pub batch:        NewBatch,
+    /// The transactions that submitted the identities of the batch, of which
+    /// there are several for contracts taking one identity per transaction.
+    pub tx_hashes:    Vec<H256>,
+    pub block_number: Option<u64>,
+    pub gas_used:     Option<u64>,
+    /// Unix time at which the batch was mined or failed.
+    pub settled_at:   Option<u64>,
+    pub error:        Option<String>,
+}
+
+/// The recorded lifecycle of an identity.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdentityAuditRecord {
+    /// Unix time at which the identity was queued.
+    pub received_at:  u64,
+    /// Unix time at which the batch that last took the identity was
+    /// submitted, if it was recorded.
+    pub submitted_at: Option<u64>,
+    /// The batch that last took the identity, if any.
+    pub batch:        Option<BatchAudit>,
+}
+
+/// Whether the events of a batch are confirmed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RootStatus {
+    /// Marked as mined, but the events are not confirmed yet.
+    Pending,
+    /// The events are confirmed and cached.
+    Mined,
+}
+
+/// The root of the tree after a batch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootRecord {
+    /// The post-root, which is only known once the events are confirmed.
+    pub root:         Option<Field>,
+    pub status:       RootStatus,
+    pub block_number: u64,
+    /// The position of the last event of the batch, which only batches with
+    /// confirmed events have.
+    pub cursor:       Option<RootCursor>,
+    /// Unix time at which the events were cached.
+    pub cached_at:    Option<u64>,
+}
+
+/// The position of an event on chain, written as `block.transaction.log`.
+///
+/// Pages of roots are cut at an event rather than at a block, as a block can
+/// hold more batches than fit in a page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RootCursor {
+    pub block_index:       u64,
+    pub transaction_index: u32,
+    pub log_index:         u32,
+}
+
+impl Display for RootCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}",
+            self.block_index, self.transaction_index, self.log_index
+        )
+    }
+}
+
+impl FromStr for RootCursor {
+    type Err = ErrReport;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('.');
+        let mut next = || parts.next().ok_or_else(|| anyhow!("Incomplete cursor."));
+        let cursor = Self {
+            block_index:       next()?.parse()?,
+            transaction_index: next()?.parse()?,
+            log_index:         next()?.parse()?,
+        };
+        if parts.next().is_some() {
+            return Err(anyhow!("Trailing parts in cursor."));
+        }
+        Ok(cursor)
+    }
+}
+
+/// A cached event, as dumped into a state snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogRecord {
+    pub block_index:       i64,
+    pub transaction_index: i32,
+    pub log_index:         i32,
+    pub raw:               String,
+    pub leaf:              Field,
+    pub root:              Field,
+    pub cached_at:         Option<i64>,
+}
+
+/// A cached deletion event, as dumped into a state snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeletionLogRecord {
+    pub block_index:       i64,
+    pub transaction_index: i32,
+    pub log_index:         i32,
+    pub raw:               String,
+    pub leaf:              Field,
+    pub leaf_index:        i64,
+    pub root:              Field,
+    pub cached_at:         Option<i64>,
+}
+
+/// A pending deletion, as dumped into a state snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingDeletionRecord {
+    pub group_id:       i64,
+    pub commitment:     Hash,
+    /// The time of submission, as formatted by the database.
+    pub created_at:     String,
+    pub mined_in_block: Option<i64>,
+}
+
+/// A recovery of an identity, as dumped into a state snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveryRecord {
+    pub group_id:       i64,
+    pub old_commitment: Hash,
+    pub new_commitment: Hash,
+    /// The time of submission, as formatted by the database.
+    pub created_at:     String,
+}
+
+/// The state held in the database, as dumped into a state snapshot.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StateDump {
+    /// The depth the database was created for, if recorded.
+    pub tree_depth:         Option<usize>,
+    /// The confirmed events, which hold the leaves of the tree in insertion
+    /// order and the root after each of them.
+    pub logs:               Vec<LogRecord>,
+    /// The confirmed events emptying leaves of the tree.
+    pub deletion_logs:      Vec<DeletionLogRecord>,
+    pub pending_identities: Vec<PendingIdentityRecord>,
+    pub pending_deletions:  Vec<PendingDeletionRecord>,
+    pub recoveries:         Vec<RecoveryRecord>,
+    /// The insertion provers registered at runtime.
+    pub provers:            Vec<ProverConfiguration>,
+    /// The batch sizes whose insertion provers were removed at runtime.
+    pub removed_provers:    Vec<usize>,
+}
+
+/// A pending identity, as dumped into a state snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingIdentityRecord {
+    pub group_id:        i64,
+    pub commitment:      Hash,
+    /// The time of submission, as formatted by the database.
+    pub created_at:      String,
+    pub mined_in_block:  Option<i64>,
+    pub priority:        i64,
+    pub skipped_batches: i64,
+    pub seq:             i64,
+    pub batch_size_hint: Option<i64>,
+    pub webhook_url:     Option<String>,
+    pub expires_at:      Option<i64>,
+}
+
+pub struct ConfirmedIdentityEvent {
+    pub block_index:       i64,
+    pub transaction_index: i32,
+    pub log_index:         i32,
+    pub raw_log:           String,
+    pub leaf:              Field,
+    pub root:              Field,
+}
+
+/// A confirmed event emptying the leaf at `leaf_index`, which held `leaf`.
+pub struct ConfirmedDeletionEvent {
+    pub block_index:       i64,
+    pub transaction_index: i32,
+    pub log_index:         i32,
+    pub raw_log:           String,
+    pub leaf:              Field,
+    pub leaf_index:        i64,
+    pub root:              Field,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(
+        block_index: i64,
+        transaction_index: i32,
+        log_index: i32,
+        leaf: u64,
+        root: u64,
+    ) -> ConfirmedIdentityEvent {
+        ConfirmedIdentityEvent {
+            block_index,
+            transaction_index,
+            log_index,
+            raw_log: String::new(),
+            leaf: Field::from(leaf),
+            root: Field::from(root),
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_size_hints_should_be_recorded() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let hinted = Hash::from(1_u64);
+        let unhinted = Hash::from(2_u64);
+        database
+            .insert_pending_identity_with_options(1, &hinted, 0, Some(3), None, None, None)
+            .await?;
+        database.insert_pending_identity(1, &unhinted, 0).await?;
+
+        assert_eq!(database.get_batch_size_hint(1, &hinted).await?, Some(3));
+        assert_eq!(database.get_batch_size_hint(1, &unhinted).await?, None);
+        assert_eq!(
+            database.get_batch_size_hint(1, &Hash::from(3_u64)).await?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replayed_nonces_should_be_rejected() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let insert = |commitment: u64, nonce: (&'static str, u64)| {
+            let database = &database;
+            async move {
+                database
+                    .insert_pending_identity_with_options(
+                        1,
+                        &Hash::from(commitment),
+                        0,
+                        None,
+                        None,
+                        Some(nonce),
+                        None,
+                    )
+                    .await
+            }
+        };
+
+        insert(1, ("alice", 5)).await?;
+        assert!(matches!(
+            insert(2, ("alice", 5)).await,
+            Err(Error::ReplayedNonce)
+        ));
+        assert!(matches!(
+            insert(2, ("alice", 4)).await,
+            Err(Error::ReplayedNonce)
+        ));
+        // A rejected insertion is not queued.
+        assert!(
+            !database
+                .pending_identity_exists(1, &Hash::from(2_u64))
+                .await?
+        );
+        insert(2, ("alice", 6)).await?;
+
+        // API keys are tracked independently.
+        insert(3, ("bob", 1)).await?;
+        assert!(matches!(
+            insert(4, ("bob", 1)).await,
+            Err(Error::ReplayedNonce)
+        ));
+        assert!(matches!(
+            insert(4, ("alice", 6)).await,
+            Err(Error::ReplayedNonce)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_submissions_should_get_distinct_order() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let commitments = (1..=20_u64).map(Hash::from).collect::<Vec<_>>();
+        futures::future::try_join_all(
+            commitments
+                .iter()
+                .map(|commitment| database.insert_pending_identity(1, commitment, 0)),
+        )
+        .await?;
+
+        let mut seqs = database
+            .dump_pending_identities()
+            .await?
+            .iter()
+            .map(|identity| identity.seq)
+            .collect::<Vec<_>>();
+        seqs.dedup();
+        assert_eq!(seqs, (1..=20).collect::<Vec<i64>>());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pending_identities_should_keep_submission_order() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let submitted = [5_u64, 3, 9, 1, 7].map(Hash::from);
+        for commitment in &submitted {
+            database.insert_pending_identity(1, commitment, 0).await?;
+        }
+
+        // Requeue the first two after they were dropped from an earlier block
+        // than a confirmed later submission.
+        database
+            .mark_identities_inserted(&[(1, submitted[0]), (1, submitted[1])], 1)
+            .await?;
+        database
+            .mark_identities_inserted(&[(1, submitted[2])], 2)
+            .await?;
+        database
+            .confirm_identity_and_retrigger_stale_recods(&submitted[2])
+            .await?;
+
+        let restored = database
+            .get_next_unprocessed_identities(
+                submitted.len(),
+                DrainOrder::Priority { aging_batches: 0 },
+                TieBreak::Submission,
+            )
+            .await?
+            .into_iter()
+            .map(|(_, commitment)| commitment)
+            .collect::<Vec<_>>();
+        assert_eq!(restored, [
+            submitted[0],
+            submitted[1],
+            submitted[3],
+            submitted[4]
+        ]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oldest_identities_should_be_drained_first() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let commitments = [1_u64, 2, 3].map(Hash::from);
+        for commitment in &commitments {
+            database.insert_pending_identity(1, commitment, 0).await?;
+        }
+        database
+            .insert_pending_identity(1, &Hash::from(4_u64), 5)
+            .await?;
+        mock::set_created_at(&database, &commitments[2], "2020-01-01 00:00:00").await;
+        mock::set_created_at(&database, &commitments[0], "2020-01-02 00:00:00").await;
+
+        let batch = database
+            .get_next_unprocessed_identities(3, DrainOrder::Oldest, TieBreak::Submission)
+            .await?
+            .into_iter()
+            .map(|(_, commitment)| commitment)
+            .collect::<Vec<_>>();
+        assert_eq!(batch, [commitments[2], commitments[0], commitments[1]]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oldest_pending_identity_age_should_be_reported() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        assert_eq!(database.oldest_unprocessed_identity_age().await?, None);
+
+        // A replacement waiting for its deletion cannot be batched.
+        let replacement = Hash::from(4_u64);
+        database
+            .insert_recovery(1, &Hash::from(3_u64), &replacement)
+            .await?;
+        mock::set_created_at(&database, &replacement, "2000-01-01 00:00:00").await;
+        assert_eq!(database.oldest_unprocessed_identity_age().await?, None);
+        assert_eq!(database.count_unblocked_identities().await?, 0);
+
+        let commitments = [1_u64, 2].map(Hash::from);
+        for commitment in &commitments {
+            database.insert_pending_identity(1, commitment, 0).await?;
+        }
+        assert!(
+            database.oldest_unprocessed_identity_age().await?.unwrap() < Duration::from_secs(5)
+        );
+
+        mock::set_created_at(&database, &commitments[1], "2020-01-01 00:00:00").await;
+        let age = database.oldest_unprocessed_identity_age().await?.unwrap();
+        assert!(age > Duration::from_secs(365 * 24 * 60 * 60));
+        assert!(age < Duration::from_secs(20 * 365 * 24 * 60 * 60));
+        assert_eq!(database.count_unblocked_identities().await?, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn commitment_tie_break_should_be_reproducible() -> anyhow::Result<()> {
+        let commitments = [7_u64, 3, 9, 1, 5].map(Hash::from);
+        let mut batches = Vec::new();
+        for submission_order in [[0, 1, 2, 3, 4], [4, 2, 0, 3, 1]] {
+            let database = mock::database().await;
+            for index in submission_order {
+                database
+                    .insert_pending_identity(1, &commitments[index], 0)
+                    .await?;
+                mock::set_created_at(&database, &commitments[index], "2020-01-01 00:00:00").await;
+            }
+            for order in [
+                DrainOrder::Priority { aging_batches: 0 },
+                DrainOrder::Oldest,
+            ] {
+                let batch = database
+                    .get_next_unprocessed_identities(5, order, TieBreak::Commitment)
+                    .await?
+                    .into_iter()
+                    .map(|(_, commitment)| commitment)
+                    .collect::<Vec<_>>();
+                batches.push(batch);
+            }
+        }
+
+        let mut sorted = commitments;
+        sorted.sort();
+        for batch in batches {
+            assert_eq!(batch, sorted);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pending_identities_should_be_looked_up_together() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let commitments = [1_u64, 2, 3].map(Hash::from);
+        database
+            .insert_pending_identity(1, &commitments[0], 0)
+            .await?;
+        database
+            .insert_pending_identity(1, &commitments[2], 0)
+            .await?;
+
+        assert_eq!(
+            database.pending_identities_exist(1, &commitments).await?,
+            HashSet::from([commitments[0], commitments[2]])
+        );
+        assert!(database
+            .pending_identities_exist(2, &commitments)
+            .await?
+            .is_empty());
+        assert!(database.pending_identities_exist(1, &[]).await?.is_empty());
+
+        // More identities than fit in one statement.
+        let many: Vec<Hash> = (1..=2 * MAX_IDENTITIES_PER_STATEMENT as u64 + 1)
+            .map(Hash::from)
+            .collect();
+        assert_eq!(
+            database.pending_identities_exist(1, &many).await?,
+            HashSet::from([commitments[0], commitments[2]])
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_identities_should_be_swept() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let expiring = Hash::from(1_u64);
+        let lasting = Hash::from(2_u64);
+        let mined = Hash::from(3_u64);
+        let batched = Hash::from(4_u64);
+        database
+            .insert_pending_identity_with_options(1, &expiring, 0, None, None, None, Some(1_000))
+            .await?;
+        database.insert_pending_identity(1, &lasting, 0).await?;
+        database
+            .insert_pending_identity_with_options(1, &mined, 0, None, None, None, Some(1_000))
+            .await?;
+        database.mark_identities_inserted(&[(1, mined)], 1).await?;
+        database
+            .insert_pending_identity_with_options(1, &batched, 0, None, None, None, Some(1_000))
+            .await?;
+        database.mark_identities_batched(7, &[(1, batched)]).await?;
+
+        assert!(database.sweep_expired_identities(999).await?.is_empty());
+        assert!(database.pending_identity_exists(1, &expiring).await?);
+
+        assert_eq!(database.sweep_expired_identities(1_000).await?, vec![
+            expiring
+        ]);
+        assert!(!database.pending_identity_exists(1, &expiring).await?);
+        assert!(database.pending_identity_exists(1, &lasting).await?);
+        assert!(database.pending_identity_exists(1, &mined).await?);
+        assert!(database.pending_identity_exists(1, &batched).await?);
+
+        // Once its batch is no longer in flight, the identity expires.
+        database.release_batched_identities(Some(8)).await?;
+        assert!(database.sweep_expired_identities(1_000).await?.is_empty());
+        database.release_batched_identities(Some(7)).await?;
+        assert_eq!(database.sweep_expired_identities(1_000).await?, vec![
+            batched
+        ]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn provers_should_be_persisted() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let prover = |batch_size, url: &str| ProverConfiguration {
+            url: url.into(),
+            batch_size,
+            max_concurrency: None,
+            tls_client: None,
+            region: None,
+        };
+        let secure = ProverConfiguration {
+            max_concurrency: Some(2),
+            tls_client: Some(TlsClientConfiguration::Pem {
+                cert_pem: "certificate".into(),
+                key_pem:  "key".into(),
+            }),
+            region: Some("eu".into()),
+            ..prover(100, "https://prover-100")
+        };
+        database.save_prover(&secure).await?;
+        database
+            .save_prover(&prover(10, "http://prover-10"))
+            .await?;
+        assert_eq!(database.get_provers().await?, vec![
+            prover(10, "http://prover-10"),
+            secure
+        ]);
+
+        // A prover replaces the one persisted for its batch size.
+        database.save_prover(&prover(10, "http://other-10")).await?;
+        database.remove_prover(100).await?;
+        assert_eq!(database.get_provers().await?, vec![prover(
+            10,
+            "http://other-10"
+        )]);
+        assert_eq!(database.get_removed_provers().await?, vec![100]);
+
+        // Adding a prover again forgets its removal.
+        database.save_prover(&secure).await?;
+        assert!(database.get_removed_provers().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn api_keys_should_be_found_until_revoked() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let (alice, bob) = ([1_u8; 32], [2_u8; 32]);
+        assert!(database.insert_api_key("alice", &alice).await?);
+        assert!(database.insert_api_key("bob", &bob).await?);
+        // Neither names nor keys can be reused.
+        assert!(!database.insert_api_key("alice", &[3_u8; 32]).await?);
+        assert!(!database.insert_api_key("carol", &bob).await?);
+
+        assert_eq!(database.api_key_name(&alice).await?, Some("alice".into()));
+        assert_eq!(database.api_key_name(&[3_u8; 32]).await?, None);
+
+        assert!(database.remove_api_key("alice").await?);
+        assert!(!database.remove_api_key("alice").await?);
+        assert_eq!(database.api_key_name(&alice).await?, None);
+        assert_eq!(database.api_key_name(&bob).await?, Some("bob".into()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transactions_should_be_recorded_until_settled() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let tx_hash = H256::from_low_u64_be;
+        let (alice, bob) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        database.save_transaction(&alice, 8, &tx_hash(1)).await?;
+        database.save_transaction(&alice, 7, &tx_hash(2)).await?;
+        database.save_transaction(&alice, 8, &tx_hash(3)).await?;
+        database.save_transaction(&alice, 8, &tx_hash(3)).await?;
+        database.save_transaction(&bob, 8, &tx_hash(4)).await?;
+        assert_eq!(database.get_transactions().await?, vec![
+            (alice, 7, tx_hash(2)),
+            (alice, 8, tx_hash(1)),
+            (alice, 8, tx_hash(3)),
+            (bob, 8, tx_hash(4)),
+        ]);
+
+        // Nonces are only settled for their sender.
+        database.remove_transactions(&alice, 8).await?;
+        assert_eq!(database.get_transactions().await?, vec![
+            (alice, 7, tx_hash(2)),
+            (bob, 8, tx_hash(4)),
+        ]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recovered_identity_should_wait_for_deletion() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let old = Hash::from(1_u64);
+        let new = Hash::from(2_u64);
+        let other = Hash::from(3_u64);
+        database.insert_recovery(1, &old, &new).await?;
+        database.insert_pending_identity(1, &other, 0).await?;
+        let next = || {
+            database.get_next_unprocessed_identities(10, DrainOrder::Oldest, TieBreak::Submission)
+        };
+
+        assert!(database.pending_deletion_exists(1, &old).await?);
+        assert_eq!(next().await?, vec![(1, other)]);
+
+        // A second recovery of the same identity records nothing.
+        let rejected = Hash::from(4_u64);
+        assert!(database.insert_recovery(1, &old, &rejected).await.is_err());
+        assert!(!database.pending_identity_exists(1, &rejected).await?);
+
+        database.mark_deletion_mined(1, &old, 5).await?;
+        assert_eq!(next().await?, vec![(1, new), (1, other)]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batch_should_be_marked_as_mined_at_once() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let batch = (0..2 * MAX_IDENTITIES_PER_STATEMENT as u64 + 1)
+            .map(|i| (1, Hash::from(i + 1)))
+            .collect::<Vec<_>>();
+        for (group_id, commitment) in &batch {
+            database
+                .insert_pending_identity(*group_id, commitment, 0)
+                .await?;
+        }
+        let other = Hash::from(0_u64);
+        database.insert_pending_identity(2, &other, 0).await?;
+
+        database.mark_identities_inserted(&batch, 7).await?;
+        for (group_id, commitment) in &batch {
+            assert_eq!(
+                database
+                    .pending_identity_mined(*group_id, commitment)
+                    .await?,
+                Some(true)
+            );
+        }
+        assert_eq!(
+            database.pending_identity_mined(2, &other).await?,
+            Some(false)
+        );
+
+        // Nothing is marked if any statement fails.
+        database.requeue_reorged_identities(7).await?;
+        mock::fail_pending_identity_updates(&database).await;
+        assert!(database.mark_identities_inserted(&batch, 8).await.is_err());
+        assert_eq!(
+            database.pending_identity_mined(1, &batch[0].1).await?,
+            Some(false)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reorged_identities_should_be_requeued() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let commitments = [1_u64, 2, 3, 4].map(Hash::from);
+        for (commitment, block) in commitments.iter().zip([4, 6, 0, 3]) {
+            database.insert_pending_identity(1, commitment, 0).await?;
+            if block > 0 {
+                database
+                    .mark_identities_inserted(&[(1, *commitment)], block)
+                    .await?;
+            }
+        }
+        // The batch of the last identity was reorged into block 5, where its
+        // event is confirmed.
+        database.save_log(&event(5, 0, 0, 4, 10)).await?;
+        let next = || {
+            database.get_next_unprocessed_identities(10, DrainOrder::Oldest, TieBreak::Submission)
+        };
+        assert_eq!(next().await?, vec![(1, commitments[2])]);
+
+        // Only the batch mined in a block processed since is requeued, unless
+        // its event is confirmed after all.
+        assert_eq!(database.requeue_reorged_identities(5).await?, 1);
+        assert_eq!(next().await?, vec![
+            (1, commitments[0]),
+            (1, commitments[2])
+        ]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn backlog_metric_should_count_unmined_identities() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let commitments = [1_u64, 2, 3].map(Hash::from);
+        for commitment in &commitments {
+            database.insert_pending_identity(1, commitment, 0).await?;
+        }
+        database
+            .mark_identities_inserted(&[(1, commitments[0])], 1)
+            .await?;
+
+        assert_eq!(database.refresh_backlog_metric().await?, 2);
+        assert_eq!(PENDING_BACKLOG.get(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mining_frontier_should_advance_as_identities_are_mined() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        assert_eq!(database.oldest_unmined_leaf_index().await?, None);
+
+        let commitments = [1_u64, 2, 3].map(Hash::from);
+        for commitment in &commitments {
+            database.insert_pending_identity(1, commitment, 0).await?;
+        }
+        assert_eq!(database.oldest_unmined_leaf_index().await?, Some(0));
+
+        database
+            .mark_identities_inserted(&[(1, commitments[0])], 1)
+            .await?;
+        assert_eq!(database.oldest_unmined_leaf_index().await?, Some(1));
+
+        // Confirming a mined identity moves it from the queue to the cache.
+        database
+            .confirm_identities(&[event(1, 0, 0, 1, 11)], &[])
+            .await?;
+        assert_eq!(database.oldest_unmined_leaf_index().await?, Some(1));
+
+        database
+            .mark_identities_inserted(&[(1, commitments[1])], 2)
+            .await?;
+        assert_eq!(database.oldest_unmined_leaf_index().await?, Some(2));
+
+        database
+            .mark_identities_inserted(&[(1, commitments[2])], 2)
+            .await?;
+        assert_eq!(database.oldest_unmined_leaf_index().await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leaves_should_be_counted_at_historical_root() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        for event in [
+            event(5, 0, 0, 1, 10),
+            event(5, 0, 1, 2, 20),
+            event(6, 2, 0, 3, 30),
+        ] {
+            database.save_log(&event).await?;
+        }
+
+        let count = |root: u64| database.count_leaves_at_root(&Hash::from(root));
+        assert_eq!(count(10).await?, Some(1));
+        assert_eq!(count(20).await?, Some(2));
+        assert_eq!(count(30).await?, Some(3));
+        assert_eq!(count(40).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn events_should_be_read_from_replica_unless_it_lags() -> anyhow::Result<()> {
+        let replica = mock::database().await;
+        let database = mock::database_with_replica(&replica).await;
+        database.save_log(&event(5, 0, 0, 1, 10)).await?;
+        database.save_log(&event(6, 0, 0, 2, 20)).await?;
+        // The replica holds a different first leaf so that reads from it can be
+        // told apart, and lags one event behind.
+        replica.save_log(&event(5, 0, 0, 3, 10)).await?;
+
+        let leaves = |events: Vec<(Field, Field)>| {
+            events.into_iter().map(|event| event.0).collect::<Vec<_>>()
+        };
+        assert_eq!(leaves(database.load_logs(0, None).await?), vec![
+            Field::from(1_u64),
+            Field::from(2_u64)
+        ]);
+        // Lookups the replica cannot answer yet fall back to the primary.
+        assert_eq!(
+            database.count_leaves_at_root(&Hash::from(20_u64)).await?,
+            Some(2)
+        );
+        assert!(database
+            .get_batch_for_commitment(&Hash::from(2_u64))
+            .await?
+            .is_some());
+
+        replica.save_log(&event(6, 0, 0, 2, 20)).await?;
+        assert_eq!(leaves(database.load_logs(0, None).await?), vec![
+            Field::from(3_u64),
+            Field::from(2_u64)
+        ]);
+        assert_eq!(database.count_cached_leaves(7).await?, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batch_should_be_resolved_for_mined_commitment() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        for event in [
+            event(5, 0, 0, 1, 10),
+            event(5, 0, 1, 2, 20),
+            event(6, 2, 0, 3, 30),
+        ] {
+            database.save_log(&event).await?;
+        }
+        database
+            .insert_pending_identity(1, &Hash::from(4_u64), 0)
+            .await?;
+
+        assert_eq!(
+            database
+                .get_batch_for_commitment(&Hash::from(1_u64))
+                .await?,
+            Some(BatchRecord {
+                block_index:       5,
+                transaction_index: 0,
+                post_root:         Field::from(20_u64),
+            })
+        );
+        assert_eq!(
+            database
+                .get_batch_for_commitment(&Hash::from(3_u64))
+                .await?,
+            Some(BatchRecord {
+                block_index:       6,
+                transaction_index: 2,
+                post_root:         Field::from(30_u64),
+            })
+        );
+        assert_eq!(
+            database
+                .get_batch_for_commitment(&Hash::from(4_u64))
+                .await?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn roots_should_be_listed_by_block() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        for event in [
+            event(5, 0, 0, 1, 10),
+            event(5, 0, 1, 2, 20),
+            event(5, 1, 0, 3, 30),
+            event(7, 0, 0, 4, 40),
+        ] {
+            database.save_log(&event).await?;
+        }
+        for commitment in [5_u64, 6].map(Hash::from) {
+            database.insert_pending_identity(1, &commitment, 0).await?;
+        }
+        database
+            .mark_identities_inserted(&[(1, Hash::from(5_u64))], 9)
+            .await?;
+
+        let roots = database.get_roots(0, None, None, 10).await?;
+        assert_eq!(
+            roots
+                .iter()
+                .map(|root| (root.root, root.status, root.block_number))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some(Field::from(20_u64)), RootStatus::Mined, 5),
+                (Some(Field::from(30_u64)), RootStatus::Mined, 5),
+                (Some(Field::from(40_u64)), RootStatus::Mined, 7),
+                (None, RootStatus::Pending, 9),
+            ]
+        );
+        assert!(roots[0].cached_at.is_some());
+
+        let roots = database.get_roots(6, None, Some(9), 1).await?;
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].root, Some(Field::from(40_u64)));
+        assert_eq!(database.get_roots(8, None, None, 10).await?.len(), 1);
+
+        // Pages are cut within a block, and the next one picks up from there.
+        let page = database.get_roots(5, None, Some(5), 1).await?;
+        assert_eq!(page[0].root, Some(Field::from(20_u64)));
+        assert_eq!(
+            page[0].cursor,
+            Some(RootCursor {
+                block_index:       5,
+                transaction_index: 0,
+                log_index:         1,
+            })
+        );
+        let page = database.get_roots(5, page[0].cursor, Some(5), 1).await?;
+        assert_eq!(page[0].root, Some(Field::from(30_u64)));
+        assert!(database
+            .get_roots(5, page[0].cursor, Some(5), 1)
+            .await?
+            .is_empty());
+        assert_eq!(
+            "5.1.0".parse::<RootCursor>()?.to_string(),
+            page[0].cursor.unwrap().to_string()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn identity_audit_should_follow_lifecycle() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let commitments = [1_u64, 2, 3].map(Hash::from);
+        for commitment in &commitments {
+            database.insert_pending_identity(1, commitment, 0).await?;
+        }
+        assert_eq!(
+            database.count_queued_before(1, &commitments[2]).await?,
+            Some(2)
+        );
+        let audit = database
+            .get_identity_audit(1, &commitments[0])
+            .await?
+            .expect("Queued identity should be audited.");
+        assert!(audit.received_at > 0);
+        assert_eq!(audit.batch, None);
+
+        database
+            .insert_batch(&NewBatch {
+                id:           4,
+                pre_root:     None,
+                commitments:  vec![commitments[0]],
+                assembled_at: 999,
+                submitted_at: 1_000,
+            })
+            .await?;
+        let batch = BatchAudit {
+            batch_id:     4,
+            tx_hash:      H256::from_low_u64_be(5),
+            block_number: 6,
+        };
+        database
+            .mark_identities_inserted(&[(1, commitments[0])], 6)
+            .await?;
+        database
+            .record_batch_audit(&[(1, commitments[0])], &batch)
+            .await?;
+        assert_eq!(
+            database.count_queued_before(1, &commitments[0]).await?,
+            None
+        );
+        assert_eq!(
+            database.count_queued_before(1, &commitments[2]).await?,
+            Some(1)
+        );
+        assert_eq!(
+            database.get_identity_audit(1, &commitments[0]).await?,
+            Some(IdentityAuditRecord {
+                received_at:  audit.received_at,
+                submitted_at: Some(1_000),
+                batch:        Some(batch),
+            })
+        );
+
+        // The audit outlives the pending identity, and restarts once queued again.
+        database.delete_pending_identity(1, &commitments[0]).await?;
+        assert!(database
+            .get_identity_audit(1, &commitments[0])
+            .await?
+            .is_some());
+        database
+            .insert_pending_identity(1, &commitments[0], 0)
+            .await?;
+        let audit = database.get_identity_audit(1, &commitments[0]).await?;
+        assert_eq!(audit.and_then(|audit| audit.batch), None);
+        assert_eq!(
+            database.get_identity_audit(1, &Hash::from(9_u64)).await?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_batches_should_be_audited() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        assert_eq!(database.next_batch_id().await?, 0);
+        let batch = NewBatch {
+            id:           7,
+            pre_root:     Some(Hash::from(1_u64)),
+            commitments:  vec![Hash::from(2_u64), Hash::from(3_u64)],
+            assembled_at: 100,
+            submitted_at: 101,
+        };
+        database.insert_batch(&batch).await?;
+        assert_eq!(database.next_batch_id().await?, 8);
+
+        let record = database
+            .get_batch(7)
+            .await?
+            .expect("Batch should be recorded.");
+        assert_eq!(record.batch, batch);
+        assert_eq!(record.settled_at, None);
+
+        database.mark_batch_failed(7, "reverted").await?;
+        let record = database
+            .get_batch(7)
+            .await?
+            .expect("Batch should be recorded.");
+        assert_eq!(record.error.as_deref(), Some("reverted"));
+        assert!(record.tx_hashes.is_empty());
+        assert!(record.settled_at.is_some());
+        assert_eq!(database.get_batch(8).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batches_should_record_every_transaction() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        let identities = [(1, Hash::from(2_u64)), (1, Hash::from(3_u64))];
+        for (group_id, commitment) in &identities {
+            database
+                .insert_pending_identity(*group_id, commitment, 0)
+                .await?;
+        }
+        database
+            .insert_batch(&NewBatch {
+                id:           7,
+                pre_root:     None,
+                commitments:  identities
+                    .iter()
+                    .map(|(_, commitment)| *commitment)
+                    .collect(),
+                assembled_at: 100,
+                submitted_at: 101,
+            })
+            .await?;
+
+        // Each identity went in its own transaction, the later one first.
+        for (identity, (tx_hash, block_number)) in identities.iter().zip([(9, 11), (8, 10)]) {
+            let audit = BatchAudit {
+                batch_id: 7,
+                tx_hash: H256::from_low_u64_be(tx_hash),
+                block_number,
+            };
+            database
+                .record_batch_audit(std::slice::from_ref(identity), &audit)
+                .await?;
+        }
+        database.mark_batch_mined(7, 11, Some(42)).await?;
+
+        let record = database
+            .get_batch(7)
+            .await?
+            .expect("Batch should be recorded.");
+        assert_eq!(record.tx_hashes, vec![
+            H256::from_low_u64_be(8),
+            H256::from_low_u64_be(9)
+        ]);
+        assert_eq!(record.block_number, Some(11));
+        assert_eq!(record.gas_used, Some(42));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tree_depth_should_be_persisted() -> anyhow::Result<()> {
+        let database = mock::database().await;
+        assert_eq!(database.get_tree_depth().await?, None);
+
+        database.set_tree_depth(20).await?;
+        assert_eq!(database.get_tree_depth().await?, Some(20));
+        database.set_tree_depth(30).await?;
+        assert_eq!(database.get_tree_depth().await?, Some(30));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,195 @@
+use core::cmp::{max, min};
+use ethers::types::{transaction::eip2718::TypedTransaction, U256};
+use std::time::Duration;
+
+/// How the fees of transactions are set, and raised for transactions that are
+/// stuck in the mempool.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GasStrategy {
+    legacy:           bool,
+    /// The highest `max_fee_per_gas`, or gas price of legacy transactions.
+    max_fee_cap:      Option<U256>,
+    /// The highest `max_priority_fee_per_gas`.
+    priority_fee_cap: Option<U256>,
+    bump_percentage:  u64,
+    bump_after:       Option<Duration>,
+    max_bumps:        usize,
+}
+
+impl GasStrategy {
+    /// Sends EIP-1559 transactions, unless `legacy`, with uncapped fees that
+    /// are never bumped.
+    #[must_use]
+    pub const fn new(legacy: bool) -> Self {
+        Self {
+            legacy,
+            max_fee_cap: None,
+            priority_fee_cap: None,
+            bump_percentage: 0,
+            bump_after: None,
+            max_bumps: 0,
+        }
+    }
+
+    /// Caps the fees of transactions at `max_fee` and `priority_fee`, if set.
+    #[must_use]
+    pub const fn with_caps(mut self, max_fee: Option<U256>, priority_fee: Option<U256>) -> Self {
+        self.max_fee_cap = max_fee;
+        self.priority_fee_cap = priority_fee;
+        self
+    }
+
+    /// Replaces transactions that are not mined within `after` with ones that
+    /// pay `percentage` percent higher fees, up to `max_bumps` times.
+    #[must_use]
+    pub const fn with_bumps(mut self, after: Duration, percentage: u64, max_bumps: usize) -> Self {
+        self.bump_after = Some(after);
+        self.bump_percentage = percentage;
+        self.max_bumps = max_bumps;
+        self
+    }
+
+    #[must_use]
+    pub const fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    /// Returns how long to wait for the transaction before its `bumps`-th
+    /// bump, or `None` if it is not bumped again.
+    #[must_use]
+    pub fn next_bump(&self, bumps: usize) -> Option<Duration> {
+        self.bump_after.filter(|_| bumps < self.max_bumps)
+    }
+
+    /// Lowers the fees of the filled `tx` to the caps.
+    pub fn cap(&self, tx: &mut TypedTransaction) {
+        match tx {
+            TypedTransaction::Eip1559(tx) => {
+                tx.max_fee_per_gas = tx.max_fee_per_gas.map(|fee| self.cap_max_fee(fee));
+                tx.max_priority_fee_per_gas = tx
+                    .max_priority_fee_per_gas
+                    .map(|fee| self.cap_priority_fee(fee));
+                // The priority fee is part of the maximum fee.
+                if let (Some(max_fee), Some(priority_fee)) =
+                    (tx.max_fee_per_gas, &mut tx.max_priority_fee_per_gas)
+                {
+                    *priority_fee = min(*priority_fee, max_fee);
+                }
+            }
+            TypedTransaction::Legacy(tx) => {
+                tx.gas_price = tx.gas_price.map(|price| self.cap_max_fee(price));
+            }
+            TypedTransaction::Eip2930(tx) => {
+                tx.tx.gas_price = tx.tx.gas_price.map(|price| self.cap_max_fee(price));
+            }
+        }
+    }
+
+    /// Returns a replacement of `tx` paying the bump percentage more in fees,
+    /// or `None` if the caps leave no room for a replacement.
+    ///
+    /// Nodes only accept a replacement that raises every fee, so fees are
+    /// raised by at least one wei.
+    #[must_use]
+    pub fn bump(&self, tx: &TypedTransaction) -> Option<TypedTransaction> {
+        let mut bumped = tx.clone();
+        match &mut bumped {
+            TypedTransaction::Eip1559(tx) => {
+                let max_fee = tx.max_fee_per_gas?;
+                let priority_fee = tx.max_priority_fee_per_gas?;
+                let new_max_fee = self.cap_max_fee(self.raise(max_fee));
+                let new_priority_fee =
+                    min(self.cap_priority_fee(self.raise(priority_fee)), new_max_fee);
+                if new_max_fee <= max_fee || new_priority_fee <= priority_fee {
+                    return None;
+                }
+                tx.max_fee_per_gas = Some(new_max_fee);
+                tx.max_priority_fee_per_gas = Some(new_priority_fee);
+            }
+            TypedTransaction::Legacy(tx) => {
+                tx.gas_price = Some(self.bump_price(tx.gas_price?)?);
+            }
+            TypedTransaction::Eip2930(tx) => {
+                tx.tx.gas_price = Some(self.bump_price(tx.tx.gas_price?)?);
+            }
+        }
+        Some(bumped)
+    }
+
+    fn bump_price(&self, price: U256) -> Option<U256> {
+        Some(self.cap_max_fee(self.raise(price))).filter(|&bumped| bumped > price)
+    }
+
+    fn raise(&self, fee: U256) -> U256 {
+        let raised = fee.saturating_mul(U256::from(100 + self.bump_percentage)) / 100;
+        max(raised, fee.saturating_add(U256::one()))
+    }
+
+    fn cap_max_fee(&self, fee: U256) -> U256 {
+        self.max_fee_cap.map_or(fee, |cap| min(fee, cap))
+    }
+
+    fn cap_priority_fee(&self, fee: U256) -> U256 {
+        self.priority_fee_cap.map_or(fee, |cap| min(fee, cap))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ethers::types::{Eip1559TransactionRequest, TransactionRequest};
+
+    fn eip1559(max_fee: u64, priority_fee: u64) -> TypedTransaction {
+        Eip1559TransactionRequest::new()
+            .max_fee_per_gas(max_fee)
+            .max_priority_fee_per_gas(priority_fee)
+            .into()
+    }
+
+    fn fees(tx: &TypedTransaction) -> (U256, U256) {
+        match tx {
+            TypedTransaction::Eip1559(tx) => (
+                tx.max_fee_per_gas.unwrap(),
+                tx.max_priority_fee_per_gas.unwrap(),
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn fees_should_be_capped() {
+        let strategy =
+            GasStrategy::new(false).with_caps(Some(U256::from(100)), Some(U256::from(10)));
+
+        let mut tx = eip1559(150, 20);
+        strategy.cap(&mut tx);
+        assert_eq!(fees(&tx), (100.into(), 10.into()));
+
+        let mut tx = eip1559(5, 8);
+        strategy.cap(&mut tx);
+        assert_eq!(fees(&tx), (5.into(), 5.into()));
+
+        let mut tx: TypedTransaction = TransactionRequest::new().gas_price(150).into();
+        strategy.cap(&mut tx);
+        assert_eq!(tx.gas_price(), Some(100.into()));
+    }
+
+    #[test]
+    fn fees_should_be_bumped_up_to_caps() {
+        let strategy = GasStrategy::new(false)
+            .with_caps(Some(U256::from(120)), None)
+            .with_bumps(Duration::from_secs(60), 10, 2);
+        assert_eq!(strategy.next_bump(1), Some(Duration::from_secs(60)));
+        assert_eq!(strategy.next_bump(2), None);
+
+        let tx = strategy.bump(&eip1559(100, 5)).unwrap();
+        assert_eq!(fees(&tx), (110.into(), 6.into()));
+        let tx = strategy.bump(&tx).unwrap();
+        assert_eq!(fees(&tx), (120.into(), 7.into()));
+        // The maximum fee cannot be raised past the cap.
+        assert!(strategy.bump(&tx).is_none());
+
+        let tx: TypedTransaction = TransactionRequest::new().gas_price(100).into();
+        assert_eq!(strategy.bump(&tx).unwrap().gas_price(), Some(110.into()));
+    }
+}
@@ -0,0 +1,153 @@
+use async_trait::async_trait;
+use ethers::{
+    signers::{AwsSigner, AwsSignerError, LocalWallet, Signer, WalletError},
+    types::{
+        transaction::{eip2718::TypedTransaction, eip712::Eip712},
+        Address, Signature,
+    },
+};
+use rusoto_kms::KmsClient;
+use std::{fmt, sync::Arc};
+use thiserror::Error;
+
+/// Where the key transactions are signed with is kept.
+#[derive(Clone, Debug)]
+pub enum KeySigner {
+    /// A private key held in memory.
+    Local(LocalWallet),
+    /// A key that never leaves AWS KMS, which signs every transaction.
+    Kms(KmsKey),
+}
+
+/// A key kept in AWS KMS, reached through a client shared with the other
+/// keys.
+#[derive(Clone)]
+pub struct KmsKey {
+    kms:      Arc<KmsClient>,
+    key_id:   String,
+    chain_id: u64,
+    address:  Address,
+}
+
+impl KmsKey {
+    /// Looks up the address of the key `key_id`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the public key cannot be fetched from KMS.
+    pub async fn new(
+        kms: Arc<KmsClient>,
+        key_id: &str,
+        chain_id: u64,
+    ) -> Result<Self, AwsSignerError> {
+        let address = AwsSigner::new(&kms, key_id, chain_id).await?.address();
+        Ok(Self {
+            kms,
+            key_id: key_id.to_owned(),
+            chain_id,
+            address,
+        })
+    }
+
+    /// The signer borrows the client, so one is set up for every signature,
+    /// at the cost of fetching the public key again.
+    async fn signer(&self) -> Result<AwsSigner<'_>, AwsSignerError> {
+        AwsSigner::new(&self.kms, &self.key_id, self.chain_id).await
+    }
+}
+
+impl fmt::Debug for KmsKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KmsKey")
+            .field("key_id", &self.key_id)
+            .field("chain_id", &self.chain_id)
+            .field("address", &self.address)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum KeySignerError {
+    #[error(transparent)]
+    Local(#[from] WalletError),
+    #[error(transparent)]
+    Kms(#[from] AwsSignerError),
+}
+
+#[async_trait]
+impl Signer for KeySigner {
+    type Error = KeySignerError;
+
+    async fn sign_message<S: Send + Sync + AsRef<[u8]>>(
+        &self,
+        message: S,
+    ) -> Result<Signature, Self::Error> {
+        match self {
+            Self::Local(signer) => Ok(signer.sign_message(message).await?),
+            Self::Kms(key) => Ok(key.signer().await?.sign_message(message).await?),
+        }
+    }
+
+    async fn sign_transaction(&self, tx: &TypedTransaction) -> Result<Signature, Self::Error> {
+        match self {
+            Self::Local(signer) => Ok(signer.sign_transaction(tx).await?),
+            Self::Kms(key) => Ok(key.signer().await?.sign_transaction(tx).await?),
+        }
+    }
+
+    async fn sign_typed_data<T: Eip712 + Send + Sync>(
+        &self,
+        payload: &T,
+    ) -> Result<Signature, Self::Error> {
+        match self {
+            Self::Local(signer) => Ok(signer.sign_typed_data(payload).await?),
+            Self::Kms(key) => Ok(key.signer().await?.sign_typed_data(payload).await?),
+        }
+    }
+
+    fn address(&self) -> Address {
+        match self {
+            Self::Local(signer) => signer.address(),
+            Self::Kms(key) => key.address,
+        }
+    }
+
+    fn chain_id(&self) -> u64 {
+        match self {
+            Self::Local(signer) => signer.chain_id(),
+            Self::Kms(key) => key.chain_id,
+        }
+    }
+
+    fn with_chain_id<T: Into<u64>>(self, chain_id: T) -> Self {
+        match self {
+            Self::Local(signer) => Self::Local(signer.with_chain_id(chain_id)),
+            Self::Kms(key) => Self::Kms(KmsKey {
+                chain_id: chain_id.into(),
+                ..key
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ethers::{core::k256::ecdsa::SigningKey, types::TransactionRequest};
+
+    #[tokio::test]
+    async fn local_key_should_sign_like_its_wallet() -> anyhow::Result<()> {
+        let signing_key = SigningKey::from_bytes(&[1; 32])?;
+        let wallet = LocalWallet::from(signing_key).with_chain_id(5_u64);
+        let signer = KeySigner::Local(wallet.clone());
+        assert_eq!(signer.address(), wallet.address());
+        assert_eq!(signer.chain_id(), 5);
+
+        let tx: TypedTransaction = TransactionRequest::new().nonce(1).gas(21000).into();
+        assert_eq!(
+            signer.sign_transaction(&tx).await?,
+            wallet.sign_transaction(&tx).await?
+        );
+        Ok(())
+    }
+}
@@ -1,21 +1,33 @@
 /// TODO: Upstream most of these to ethers-rs
 mod estimator;
 mod gas_oracle_logger;
+mod gas_strategy;
+mod key_signer;
 mod min_gas_fees;
 mod rpc_logger;
+mod signers;
 mod transport;
 
 use self::{
-    estimator::Estimator, gas_oracle_logger::GasOracleLogger, min_gas_fees::MinGasFees,
-    rpc_logger::RpcLogger, transport::Transport,
+    estimator::Estimator,
+    gas_oracle_logger::GasOracleLogger,
+    gas_strategy::GasStrategy,
+    key_signer::{KeySigner, KmsKey},
+    min_gas_fees::MinGasFees,
+    rpc_logger::RpcLogger,
+    signers::Signers,
+    transport::Transport,
 };
-use crate::contracts::confirmed_log_query::{ConfirmedLogQuery, Error as CachingLogQueryError};
-use anyhow::{anyhow, Result as AnyhowResult};
+use crate::{
+    contracts::confirmed_log_query::{ConfirmedLogQuery, Error as CachingLogQueryError, Finality},
+    tx_sitter::{nonce_manager::NonceManager, Submitter},
+};
+use anyhow::{anyhow, bail, Result as AnyhowResult};
 use chrono::{Duration as ChronoDuration, Utc};
 use clap::Parser;
 use ethers::{
     abi::{Error as AbiError, RawLog},
-    contract::EthEvent,
+    contract::EthLogDecode,
     core::k256::ecdsa::SigningKey,
     middleware::{
         gas_oracle::{
@@ -25,10 +37,11 @@ use ethers::{
         SignerMiddleware,
     },
     providers::{Middleware, Provider, ProviderError},
-    signers::{LocalWallet, Signer, Wallet},
+    signers::{LocalWallet, Signer},
     types::{
         transaction::eip2718::TypedTransaction, u256_from_f64_saturating, Address, BlockId,
-        BlockNumber, Chain, Filter, Log as EthLog, TransactionReceipt, H160, H256, U256, U64,
+        BlockNumber, Chain, Eip1559TransactionRequest, Filter, Log as EthLog, Transaction,
+        TransactionReceipt, TransactionRequest, H160, H256, U256, U64,
     },
 };
 use futures::{try_join, FutureExt, Stream, StreamExt, TryStreamExt};
@@ -38,13 +51,19 @@ use prometheus::{
     register_int_counter_vec, Counter, Gauge, Histogram, IntCounterVec,
 };
 use reqwest::Client as ReqwestClient;
+use rusoto_core::Region;
+use rusoto_kms::KmsClient;
 use std::{error::Error, num::ParseIntError, str::FromStr, sync::Arc, time::Duration};
 use thiserror::Error;
-use tokio::time::timeout;
+use tokio::time::{sleep, timeout, Instant};
 use tracing::{debug_span, error, info, info_span, instrument, warn, Instrument};
 use url::Url;
 
 const PENDING: Option<BlockId> = Some(BlockId::Number(BlockNumber::Pending));
+const LATEST: Option<BlockId> = Some(BlockId::Number(BlockNumber::Latest));
+
+/// How often the transactions left in flight by a previous run are checked.
+const RECOVERY_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 static TX_COUNT: Lazy<IntCounterVec> = Lazy::new(|| {
     register_int_counter_vec!("eth_tx_count", "The transaction count by bytes4.", &[
@@ -83,11 +102,29 @@ static TX_GAS_USED: Lazy<Counter> = Lazy::new(|| {
 static TX_WEI_USED: Lazy<Counter> = Lazy::new(|| {
     register_counter!("eth_tx_wei_used", "Cumulative wei used for transactions.").unwrap()
 });
+static TX_FEE_BUMPS: Lazy<Counter> = Lazy::new(|| {
+    register_counter!(
+        "eth_tx_fee_bumps",
+        "Number of stuck transactions replaced with higher fees."
+    )
+    .unwrap()
+});
 
 fn duration_from_str(value: &str) -> Result<Duration, ParseIntError> {
     Ok(Duration::from_secs(u64::from_str(value)?))
 }
 
+fn fee_cap_from_str(value: &str) -> Result<f64, String> {
+    let fee = f64::from_str(value).map_err(|e| e.to_string())?;
+    if fee.is_finite() && fee >= 0.0 {
+        Ok(fee)
+    } else {
+        Err(format!(
+            "fee cap must be a non-negative number of GWei, got {fee}"
+        ))
+    }
+}
+
 // TODO: Log and metrics for signer / nonces.
 #[derive(Clone, Debug, PartialEq, Parser)]
 #[group(skip)]
@@ -105,6 +142,18 @@ pub struct Options {
     // NOTE: We abuse `Hash` here because it has the right `FromStr` implementation.
     pub signing_key: H256,
 
+    /// Further private keys to send transactions from, comma separated.
+    /// Transactions are spread across them and the signing key, so they must
+    /// be allowed to call the contracts as well.
+    #[clap(long, env, value_delimiter = ',')]
+    pub extra_signing_keys: Vec<H256>,
+
+    /// Ids or ARNs of AWS KMS keys to sign transactions with instead of the
+    /// signing keys, comma separated. The region and credentials are taken
+    /// from the usual AWS environment.
+    #[clap(long, env, value_delimiter = ',')]
+    pub kms_key_ids: Vec<String>,
+
     /// Maximum number of blocks to pull events from in one request.
     #[clap(long, env, default_value = "100000")]
     pub max_log_blocks: usize,
@@ -117,10 +166,17 @@ pub struct Options {
     #[clap(long, env, value_parser=duration_from_str, default_value="32")]
     pub max_backoff_time: Duration,
 
-    /// Minimum number of blocks before events are considered confirmed.
-    #[clap(long, env, default_value = "35")]
+    /// Minimum number of blocks before events are considered confirmed, and
+    /// the roots of their batches mined.
+    #[clap(long, env, alias = "confirmation-blocks", default_value = "35")]
     pub confirmation_blocks_delay: usize,
 
+    /// Consider events confirmed once the node tags their block as finalized,
+    /// instead of after `confirmation_blocks_delay` blocks. For chains that
+    /// can reorg deeper than a fixed number of blocks.
+    #[clap(long, env)]
+    pub confirm_finalized_blocks: bool,
+
     /// The number of most recent blocks to be removed from cache on root
     /// mismatch
     #[clap(long, env, default_value = "1000")]
@@ -144,6 +200,34 @@ pub struct Options {
     #[clap(long, env, default_value = "100")]
     pub priority_fee_multiplier_percentage: u64,
 
+    /// Send legacy transactions even if the chain supports EIP-1559.
+    #[clap(long, env)]
+    pub legacy_transactions: bool,
+
+    /// Maximum `max_fee_per_gas`, or gas price of legacy transactions, to pay
+    /// in GWei, including fee bumps. Uncapped if unset.
+    #[clap(long, env, value_parser=fee_cap_from_str)]
+    pub max_fee_cap: Option<f64>,
+
+    /// Maximum `priority_fee_per_gas` to pay in GWei, including fee bumps.
+    /// Uncapped if unset.
+    #[clap(long, env, value_parser=fee_cap_from_str)]
+    pub priority_fee_cap: Option<f64>,
+
+    /// Replace transactions that are not mined within this time with ones
+    /// paying higher fees (seconds). Zero disables fee bumping.
+    #[clap(long, env, value_parser=duration_from_str, default_value="0")]
+    pub fee_bump_interval: Duration,
+
+    /// The percentage by which every fee bump raises the fees. Nodes require
+    /// at least 10 to accept a replacement.
+    #[clap(long, env, default_value = "12")]
+    pub fee_bump_percentage: u64,
+
+    /// Maximum number of times the fees of a transaction are bumped.
+    #[clap(long, env, default_value = "5")]
+    pub max_fee_bumps: usize,
+
     /// Timeout for sending transactions to mempool (seconds).
     #[clap(long, env, default_value = "30")]
     pub send_timeout: u64,
@@ -158,7 +242,7 @@ pub struct Options {
 type Provider0 = Provider<RpcLogger<Transport>>;
 type Provider1 = Estimator<Provider0>;
 type Provider2 = GasOracleMiddleware<Arc<Provider1>, Box<dyn GasOracle>>;
-type Provider3 = SignerMiddleware<Provider2, Wallet<SigningKey>>;
+type Provider3 = SignerMiddleware<Arc<Provider2>, KeySigner>;
 // type Provider4 = NonceManagerMiddleware<Provider3>;
 pub type ProviderStack = Provider3;
 
@@ -184,6 +268,9 @@ pub enum TxError {
 
     #[error("Transaction failed.")]
     Failed(Box<TransactionReceipt>),
+
+    #[error("Unsupported operation: {0}")]
+    Unsupported(&'static str),
 }
 
 #[derive(Debug, Error)]
@@ -204,15 +291,20 @@ pub enum EventError {
 
 #[derive(Clone, Debug)]
 pub struct Ethereum {
-    provider:                  Arc<ProviderStack>,
-    address:                   H160,
-    legacy:                    bool,
-    max_log_blocks:            usize,
-    min_log_blocks:            usize,
-    max_backoff_time:          Duration,
-    confirmation_blocks_delay: usize,
-    send_timeout:              Duration,
-    mine_timeout:              Duration,
+    provider:         Arc<ProviderStack>,
+    address:          H160,
+    /// The signers transactions are sent from, starting with `provider`.
+    signers:          Arc<Signers<Arc<ProviderStack>>>,
+    gas_strategy:     GasStrategy,
+    nonce_manager:    Option<NonceManager>,
+    /// Sends the transactions instead of the signers, if set.
+    submitter:        Option<Arc<dyn Submitter>>,
+    max_log_blocks:   usize,
+    min_log_blocks:   usize,
+    max_backoff_time: Duration,
+    finality:         Finality,
+    send_timeout:     Duration,
+    mine_timeout:     Duration,
 }
 
 impl Ethereum {
@@ -340,59 +432,116 @@ impl Ethereum {
             GasOracleMiddleware::new(provider, oracle)
         };
 
-        // Construct a local key signer
-        let (provider, address) = {
-            // Create signer
-            let signing_key = SigningKey::from_bytes(options.signing_key.as_bytes())?;
-            let signer = LocalWallet::from(signing_key);
-            let address = signer.address();
-
-            // Create signer middleware for provider.
-            let chain_id: u64 = chain_id.try_into().map_err(|e| anyhow!("{}", e))?;
-            let signer = signer.with_chain_id(chain_id);
-            let provider = SignerMiddleware::new(provider, signer);
-
-            // Create local nonce manager.
-            // TODO: This is state full. There may be unsettled TXs in the mempool.
-            // let provider = { NonceManagerMiddleware::new(provider, address) };
-            //
-            // // Log wallet info.
-            // let (next_nonce, balance) = try_join!(
-            //     provider.initialize_nonce(PENDING),
-            //     provider.get_balance(address, PENDING)
-            // )?;
-            // info!(?address, %next_nonce, %balance, "Constructed wallet");
-
-            // Log wallet info.
-            let (next_nonce, balance) = try_join!(
-                provider.get_transaction_count(address, PENDING),
-                provider.get_balance(address, PENDING)
-            )?;
-            info!(?address, %next_nonce, %balance, "Constructed wallet");
-
-            // Sanity check the balance
-            if balance.is_zero() {
-                // Log an error, but try proceeding anyway.
-                error!(?address, "Wallet has no funds.");
+        // Construct KMS or local key signers
+        let chain_id: u64 = chain_id.try_into().map_err(|e| anyhow!("{}", e))?;
+        let provider = Arc::new(provider);
+        let keys = if options.kms_key_ids.is_empty() {
+            let mut keys = Vec::with_capacity(1 + options.extra_signing_keys.len());
+            for signing_key in [&options.signing_key]
+                .into_iter()
+                .chain(&options.extra_signing_keys)
+            {
+                let signing_key = SigningKey::from_bytes(signing_key.as_bytes())?;
+                keys.push(KeySigner::Local(
+                    LocalWallet::from(signing_key).with_chain_id(chain_id),
+                ));
             }
-            (provider, address)
+            keys
+        } else {
+            let kms = Arc::new(KmsClient::new(Region::default()));
+            let mut keys = Vec::with_capacity(options.kms_key_ids.len());
+            for key_id in &options.kms_key_ids {
+                info!(%key_id, "Connecting to AWS KMS key");
+                keys.push(KeySigner::Kms(
+                    KmsKey::new(kms.clone(), key_id, chain_id).await?,
+                ));
+            }
+            keys
         };
+        let mut signers = Vec::with_capacity(keys.len());
+        for key in keys {
+            signers.push(Arc::new(Self::connect_signer(provider.clone(), key).await?));
+        }
+        let signers = Signers::new(signers);
+        let provider = signers.primary().clone();
+        let address = provider.address();
         // TODO: Check signer balance regularly and keep the metric as a gauge.
 
-        let provider = Arc::new(provider);
+        let gwei = |fee: f64| u256_from_f64_saturating(fee * 1e9);
+        let mut gas_strategy = GasStrategy::new(options.legacy_transactions || !eip1559).with_caps(
+            options.max_fee_cap.map(gwei),
+            options.priority_fee_cap.map(gwei),
+        );
+        if !options.fee_bump_interval.is_zero() {
+            gas_strategy = gas_strategy.with_bumps(
+                options.fee_bump_interval,
+                options.fee_bump_percentage,
+                options.max_fee_bumps,
+            );
+        }
+
         Ok(Self {
             provider,
             address,
-            legacy: !eip1559,
+            signers: Arc::new(signers),
+            gas_strategy,
+            nonce_manager: None,
+            submitter: None,
             max_log_blocks: options.max_log_blocks,
             min_log_blocks: options.min_log_blocks,
             max_backoff_time: options.max_backoff_time,
-            confirmation_blocks_delay: options.confirmation_blocks_delay,
+            finality: if options.confirm_finalized_blocks {
+                Finality::Finalized
+            } else {
+                Finality::Blocks(options.confirmation_blocks_delay as u64)
+            },
             send_timeout: Duration::from_secs(options.send_timeout),
             mine_timeout: Duration::from_secs(options.mine_timeout),
         })
     }
 
+    /// Creates a signer middleware for `signer` on `provider`.
+    async fn connect_signer(
+        provider: Arc<Provider2>,
+        signer: KeySigner,
+    ) -> AnyhowResult<ProviderStack> {
+        let address = signer.address();
+
+        // Create signer middleware for provider.
+        let provider = SignerMiddleware::new(provider, signer);
+
+        // Log wallet info.
+        let (next_nonce, balance) = try_join!(
+            provider.get_transaction_count(address, PENDING),
+            provider.get_balance(address, PENDING)
+        )?;
+        info!(?address, %next_nonce, %balance, "Constructed wallet");
+
+        // Sanity check the balance
+        if balance.is_zero() {
+            // Log an error, but try proceeding anyway.
+            error!(?address, "Wallet has no funds.");
+        }
+        Ok(provider)
+    }
+
+    /// Records the transactions in flight with `nonce_manager`, so that
+    /// [`Self::recover_transactions`] can pick them up after a crash.
+    #[must_use]
+    pub fn with_nonce_manager(mut self, nonce_manager: NonceManager) -> Self {
+        self.nonce_manager = Some(nonce_manager);
+        self
+    }
+
+    /// Sends transactions through `submitter` instead of the signers, from
+    /// its address.
+    #[must_use]
+    pub fn with_submitter(mut self, submitter: Arc<dyn Submitter>) -> Self {
+        self.address = submitter.address();
+        self.submitter = Some(submitter);
+        self
+    }
+
     #[must_use]
     pub const fn provider(&self) -> &Arc<ProviderStack> {
         &self.provider
@@ -408,7 +557,11 @@ impl Ethereum {
         &self,
         tx: TypedTransaction,
     ) -> Result<TransactionReceipt, TxError> {
-        self.send_transaction_unlogged(tx).await.map_err(|e| {
+        let result = match &self.submitter {
+            Some(submitter) => submitter.submit(tx).await,
+            None => self.send_transaction_unlogged(tx).await,
+        };
+        result.map_err(|e| {
             error!(?e, "Transaction failed");
             e
         })
@@ -417,12 +570,13 @@ impl Ethereum {
     #[instrument(level = "info", skip(self))]
     #[allow(clippy::option_if_let_else)] // Less readable
     #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::too_many_lines)]
     async fn send_transaction_unlogged(
         &self,
         tx: TypedTransaction,
     ) -> Result<TransactionReceipt, TxError> {
         // Convert to legacy transaction if required
-        let mut tx = if self.legacy {
+        let mut tx = if self.gas_strategy.is_legacy() {
             TypedTransaction::Legacy(match tx {
                 TypedTransaction::Legacy(tx) => tx,
                 TypedTransaction::Eip1559(tx) => tx.into(),
@@ -432,8 +586,13 @@ impl Ethereum {
             tx
         };
 
+        // Send from the least busy signer
+        let signer = self.signers.acquire();
+        let sender = signer.address();
+        tx.set_from(sender);
+
         // Fill in transaction
-        self.provider
+        signer
             .fill_transaction(&mut tx, None)
             .instrument(debug_span!("Fill in transaction"))
             .await
@@ -441,12 +600,20 @@ impl Ethereum {
                 error!(?error, "Failed to fill transaction");
                 TxError::Fill(Box::new(error))
             })?;
+        self.gas_strategy.cap(&mut tx);
         let nonce = tx.nonce().unwrap().as_u64();
         let gas_limit = tx.gas().unwrap().as_u128() as f64;
         let gas_price = tx.gas_price().unwrap().as_u128() as f64;
 
         // Log transaction
-        info!(?tx, ?nonce, ?gas_limit, ?gas_price, "Sending transaction.");
+        info!(
+            ?tx,
+            ?sender,
+            ?nonce,
+            ?gas_limit,
+            ?gas_price,
+            "Sending transaction."
+        );
         let bytes4: u32 = tx.data().map_or(0, |data| {
             let mut buffer = [0; 4];
             buffer.copy_from_slice(&data.as_ref()[..4]); // TODO: Don't panic.
@@ -455,44 +622,106 @@ impl Ethereum {
         let bytes4 = format!("{bytes4:8x}");
         TX_COUNT.with_label_values(&[&bytes4]).inc();
 
-        // Send TX to mempool
-        let pending = timeout(
-            self.send_timeout,
-            self.provider.send_transaction(tx.clone(), None),
-        )
-        .instrument(info_span!("Send TX to mempool"))
-        .await
-        .map_err(|elapsed| {
-            error!(?elapsed, "Send transaction timed out");
-            TxError::SendTimeout
-        })?
-        .map_err(|error| {
-            error!(?nonce, ?error, "Failed to send transaction");
-            TxError::Send(Box::new(error))
-        })?;
-        let tx_hash: H256 = *pending;
-        info!(?nonce, ?tx_hash, "Transaction in mempool");
-
-        // Wait for TX to be mined
+        // Send TX to mempool and wait for it to be mined, replacing it with
+        // higher fees whenever it is stuck.
         let timer = TX_LATENCY.start_timer();
-        let receipt = timeout(self.mine_timeout, pending)
-            .instrument(info_span!("Wait for TX to be mined"))
-            .await
-            .map_err(|elapsed| {
-                error!(?elapsed, "Waiting for transaction confirmation timed out");
-                TxError::ConfirmationTimeout
-            })?
-            .map_err(|err| {
-                error!(?nonce, ?tx_hash, ?err, "Transaction failed to confirm");
-
-                TxError::Confirmation(err)
-            })?
-            .ok_or_else(|| {
-                error!(?nonce, ?tx_hash, "Transaction dropped");
-                TxError::Dropped(tx_hash)
-            })?;
+        let deadline = Instant::now() + self.mine_timeout;
+        let mut tx_hashes = Vec::new();
+        let receipt = loop {
+            let pending =
+                match timeout(self.send_timeout, signer.send_transaction(tx.clone(), None))
+                    .instrument(info_span!("Send TX to mempool"))
+                    .await
+                {
+                    Ok(Ok(pending)) => pending,
+                    result => {
+                        // A replacement is rejected once the transaction it
+                        // replaces is mined.
+                        if let Some(receipt) = self.find_receipt(&tx_hashes).await {
+                            break receipt;
+                        }
+                        return Err(match result {
+                            Ok(Err(error)) => {
+                                error!(?nonce, ?error, "Failed to send transaction");
+                                TxError::Send(Box::new(error))
+                            }
+                            _ => {
+                                error!(?nonce, "Send transaction timed out");
+                                TxError::SendTimeout
+                            }
+                        });
+                    }
+                };
+            let tx_hash: H256 = *pending;
+            info!(?nonce, ?tx_hash, "Transaction in mempool");
+            tx_hashes.push(tx_hash);
+            if let Some(nonce_manager) = &self.nonce_manager {
+                nonce_manager.sent(sender, nonce, tx_hash).await;
+            }
+
+            // Wait for TX to be mined, or until it is due for a bump.
+            let bump = self
+                .gas_strategy
+                .next_bump(tx_hashes.len() - 1)
+                .and_then(|after| Some((after, self.gas_strategy.bump(&tx)?)));
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let wait = bump
+                .as_ref()
+                .map_or(remaining, |(after, _)| remaining.min(*after));
+            match timeout(wait, pending)
+                .instrument(info_span!("Wait for TX to be mined"))
+                .await
+            {
+                Ok(Ok(Some(receipt))) => break receipt,
+                Ok(Ok(None)) => {
+                    // An earlier transaction may have been mined instead.
+                    if let Some(receipt) = self.find_receipt(&tx_hashes).await {
+                        break receipt;
+                    }
+                    error!(?nonce, ?tx_hash, "Transaction dropped");
+                    if let Some(nonce_manager) = &self.nonce_manager {
+                        nonce_manager.settled(sender, nonce).await;
+                    }
+                    return Err(TxError::Dropped(tx_hash));
+                }
+                Ok(Err(err)) => {
+                    // The error may be about a replaced transaction, while an
+                    // earlier one was mined.
+                    if let Some(receipt) = self.find_receipt(&tx_hashes).await {
+                        break receipt;
+                    }
+                    error!(?nonce, ?tx_hash, ?err, "Transaction failed to confirm");
+                    return Err(TxError::Confirmation(err));
+                }
+                Err(elapsed) => {
+                    if let Some(receipt) = self.find_receipt(&tx_hashes).await {
+                        break receipt;
+                    }
+                    match bump {
+                        Some((_, bumped)) if Instant::now() < deadline => {
+                            warn!(
+                                ?nonce,
+                                ?tx_hash,
+                                bumps = tx_hashes.len(),
+                                "Transaction is stuck, replacing it with higher fees."
+                            );
+                            TX_FEE_BUMPS.inc();
+                            tx = bumped;
+                        }
+                        _ => {
+                            error!(?elapsed, "Waiting for transaction confirmation timed out");
+                            return Err(TxError::ConfirmationTimeout);
+                        }
+                    }
+                }
+            }
+        };
         timer.observe_duration();
-        info!(?nonce, ?tx_hash, ?receipt, "Transaction mined");
+        drop(signer);
+        info!(?nonce, tx_hash = ?receipt.transaction_hash, ?receipt, "Transaction mined");
+        if let Some(nonce_manager) = &self.nonce_manager {
+            nonce_manager.settled(sender, nonce).await;
+        }
 
         // Check receipt for gas used
         if let Some(gas_price) = receipt.effective_gas_price {
@@ -535,12 +764,130 @@ impl Ethereum {
         Ok(receipt)
     }
 
+    /// Waits for the transactions that were in flight when the sequencer last
+    /// stopped to be mined, replaced or dropped, so that their nonces are not
+    /// spent again or left as a gap.
+    ///
+    /// A transaction that is still pending after the mine timeout is replaced
+    /// by an empty transfer to its sender paying higher fees, which is then
+    /// monitored in turn.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the transactions cannot be read, or a pending one
+    /// cannot be replaced. It is then checked again on the next start up.
+    pub async fn recover_transactions(&self) -> AnyhowResult<()> {
+        let Some(nonce_manager) = &self.nonce_manager else {
+            return Ok(());
+        };
+        for ((sender, nonce), mut tx_hashes) in nonce_manager.in_flight().await? {
+            let mut deadline = Instant::now() + self.mine_timeout;
+            info!(
+                ?sender,
+                nonce,
+                ?tx_hashes,
+                "Resuming monitoring of in-flight transaction."
+            );
+            loop {
+                if let Some(receipt) = self.find_receipt(&tx_hashes).await {
+                    info!(nonce, tx_hash = ?receipt.transaction_hash, "In-flight transaction was mined.");
+                    break;
+                }
+                let mined_nonce = self.provider.get_transaction_count(sender, LATEST).await?;
+                if mined_nonce > U256::from(nonce) {
+                    warn!(
+                        nonce,
+                        "Nonce of in-flight transaction was spent by another one."
+                    );
+                    break;
+                }
+                let Some(pending) = self.latest_in_mempool(&tx_hashes).await? else {
+                    warn!(
+                        nonce,
+                        "In-flight transaction was dropped, its nonce is reused."
+                    );
+                    break;
+                };
+                if Instant::now() >= deadline {
+                    let tx_hash = self.replace_transaction(sender, nonce, &pending).await?;
+                    nonce_manager.sent(sender, nonce, tx_hash).await;
+                    tx_hashes.push(tx_hash);
+                    deadline = Instant::now() + self.mine_timeout;
+                }
+                sleep(RECOVERY_POLL_INTERVAL).await;
+            }
+            nonce_manager.settled(sender, nonce).await;
+        }
+        Ok(())
+    }
+
+    /// Returns the most recently sent of `tx_hashes` that is still in the
+    /// mempool, if any.
+    async fn latest_in_mempool(&self, tx_hashes: &[H256]) -> AnyhowResult<Option<Transaction>> {
+        for tx_hash in tx_hashes.iter().rev() {
+            if let Some(tx) = self.provider.get_transaction(*tx_hash).await? {
+                return Ok(Some(tx));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Replaces the `pending` transaction of `sender` with an empty transfer
+    /// to itself, with the fees bumped by the gas strategy, and returns its
+    /// hash.
+    async fn replace_transaction(
+        &self,
+        sender: Address,
+        nonce: u64,
+        pending: &Transaction,
+    ) -> AnyhowResult<H256> {
+        let signer = self
+            .signers
+            .find(|signer| signer.address() == sender)
+            .ok_or_else(|| anyhow!("No signer for in-flight transaction of {sender:?}."))?;
+        let mut tx: TypedTransaction =
+            match (pending.max_fee_per_gas, pending.max_priority_fee_per_gas) {
+                (Some(max_fee), Some(priority_fee)) => Eip1559TransactionRequest::new()
+                    .max_fee_per_gas(max_fee)
+                    .max_priority_fee_per_gas(priority_fee)
+                    .into(),
+                _ => TransactionRequest::new()
+                    .gas_price(pending.gas_price.unwrap_or_default())
+                    .into(),
+            };
+        tx.set_from(sender);
+        tx.set_to(sender);
+        tx.set_value(U256::zero());
+        tx.set_gas(21_000);
+        tx.set_nonce(nonce);
+        let Some(tx) = self.gas_strategy.bump(&tx) else {
+            bail!("In-flight transaction with nonce {nonce} is still pending at the fee caps.");
+        };
+        warn!(
+            nonce,
+            tx_hash = ?pending.hash,
+            "In-flight transaction is stuck, replacing it with higher fees."
+        );
+        TX_FEE_BUMPS.inc();
+        let tx_hash = *signer.send_transaction(tx, None).await?;
+        info!(nonce, ?tx_hash, "Replacement transaction in mempool");
+        Ok(tx_hash)
+    }
+
+    /// Returns the receipt of whichever of `tx_hashes` was mined, if any.
+    async fn find_receipt(&self, tx_hashes: &[H256]) -> Option<TransactionReceipt> {
+        for tx_hash in tx_hashes {
+            if let Ok(Some(receipt)) = self.provider.get_transaction_receipt(*tx_hash).await {
+                return Some(receipt);
+            }
+        }
+        None
+    }
+
     pub async fn confirmed_block_number(&self) -> Result<U64, EventError> {
-        self.provider
-            .provider()
-            .get_block_number()
+        self.finality
+            .confirmed_block(&self.provider)
             .await
-            .map(|num| num.saturating_sub(U64::from(self.confirmation_blocks_delay)))
             .map_err(|e| EventError::Fetching(CachingLogQueryError::LoadLastBlock(e)))
     }
 
@@ -552,12 +899,12 @@ impl Ethereum {
             .with_start_page_size(self.max_log_blocks as u64)
             .with_min_page_size(self.min_log_blocks as u64)
             .with_max_backoff_time(self.max_backoff_time)
-            .with_blocks_delay(self.confirmation_blocks_delay as u64)
+            .with_finality(self.finality)
             .into_stream()
             .map_err(Into::into)
     }
 
-    pub fn fetch_events<T: EthEvent>(
+    pub fn fetch_events<T: EthLogDecode>(
         &self,
         filter: &Filter,
     ) -> impl Stream<Item = Result<Log<T>, EventError>> + '_ {
@@ -582,7 +929,7 @@ impl Ethereum {
         })
     }
 }
-pub struct Log<Event: EthEvent> {
+pub struct Log<Event: EthLogDecode> {
     pub block_index:       U64,
     pub transaction_index: U64,
     pub log_index:         U256,
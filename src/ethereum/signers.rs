@@ -0,0 +1,124 @@
+use std::{
+    ops::Deref,
+    sync::{Mutex, MutexGuard},
+};
+
+/// The signers transactions are sent from.
+///
+/// Every transaction is sent from the signer with the fewest transactions in
+/// flight, taking turns among equally busy ones. A signer with a transaction
+/// stuck in the mempool is thereby passed over until it settles, and with as
+/// many signers as concurrent senders no signer is shared.
+#[derive(Debug)]
+pub struct Signers<S> {
+    signers: Vec<S>,
+    state:   Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// The transactions in flight of each signer.
+    in_flight: Vec<usize>,
+    /// The signer to start looking from.
+    next:      usize,
+}
+
+impl<S> Signers<S> {
+    /// # Panics
+    ///
+    /// Panics if `signers` is empty.
+    #[must_use]
+    pub fn new(signers: Vec<S>) -> Self {
+        assert!(!signers.is_empty(), "At least one signer is required.");
+        Self {
+            state: Mutex::new(State {
+                in_flight: vec![0; signers.len()],
+                next:      0,
+            }),
+            signers,
+        }
+    }
+
+    /// The first signer, which is the one contracts are connected with.
+    #[must_use]
+    pub fn primary(&self) -> &S {
+        &self.signers[0]
+    }
+
+    /// Returns the signer that `is_match`, if any.
+    pub fn find(&self, is_match: impl Fn(&S) -> bool) -> Option<&S> {
+        self.signers.iter().find(|signer| is_match(signer))
+    }
+
+    /// Picks the signer to send a transaction from, which counts as in flight
+    /// until the returned guard is dropped.
+    pub fn acquire(&self) -> SignerGuard<'_, S> {
+        let mut state = self.state();
+        let count = self.signers.len();
+        let index = (0..count)
+            .map(|offset| (state.next + offset) % count)
+            .min_by_key(|&index| state.in_flight[index])
+            .unwrap_or_default();
+        state.in_flight[index] += 1;
+        state.next = (index + 1) % count;
+        SignerGuard {
+            signers: self,
+            index,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// A signer that has a transaction in flight.
+#[derive(Debug)]
+pub struct SignerGuard<'a, S> {
+    signers: &'a Signers<S>,
+    index:   usize,
+}
+
+impl<S> Deref for SignerGuard<'_, S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.signers.signers[self.index]
+    }
+}
+
+impl<S> Drop for SignerGuard<'_, S> {
+    fn drop(&mut self) {
+        self.signers.state().in_flight[self.index] -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn least_busy_signer_should_be_picked_in_turns() {
+        let signers = Signers::new(vec!['a', 'b', 'c']);
+        assert_eq!(*signers.primary(), 'a');
+
+        // Idle signers take turns.
+        for expected in ['a', 'b', 'c', 'a'] {
+            assert_eq!(*signers.acquire(), expected);
+        }
+
+        // A busy signer is passed over until it is done.
+        let stuck = signers.acquire();
+        assert_eq!(*stuck, 'b');
+        let first = signers.acquire();
+        let second = signers.acquire();
+        assert_eq!((*first, *second), ('c', 'a'));
+        drop(first);
+        assert_eq!(*signers.acquire(), 'c');
+
+        let third = signers.acquire();
+        assert_eq!(*third, 'c');
+        // With every signer busy, they are shared.
+        assert_eq!(*signers.acquire(), 'a');
+    }
+}
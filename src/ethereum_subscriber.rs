@@ -1,19 +1,54 @@
 use crate::{
-    contracts::{legacy::MemberAddedEvent, SharedIdentityManager},
+    contracts::{
+        legacy::{MemberAddedEvent, MemberRemovedEvent, TreeEvent},
+        IdentityManager, SharedIdentityManager,
+    },
     database::{
-        ConfirmedIdentityEvent, Database, Error as DatabaseError, IdentityConfirmationResult,
+        ConfirmedDeletionEvent, ConfirmedIdentityEvent, Database, Error as DatabaseError,
+        IdentityConfirmationResult,
     },
     ethereum::{EventError, Log},
     identity_committer::IdentityCommitter,
     identity_tree::{SharedTreeState, TreeState},
+    proof_cache::ProofCache,
+    status_changes::{IdentityStatus, StatusChanges},
+    webhook::{Notification, Status, Webhooks},
 };
 use futures::TryStreamExt;
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
 use semaphore::Field;
-use std::{cmp::min, sync::Arc, time::Duration};
+use std::{
+    cmp::min,
+    iter::repeat,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use thiserror::Error;
 use tokio::{sync::RwLock, task::JoinHandle, time::sleep};
 use tracing::{error, info, instrument, warn};
 
+/// How long to wait before rebuilding the tree again after a rebuild from the
+/// database failed, doubled on every further failure up to the maximum.
+const MIN_RESYNC_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RESYNC_BACKOFF: Duration = Duration::from_secs(30);
+
+static TREE_LEAVES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "tree_leaves",
+        "Number of leaves inserted into the tree from mined events."
+    )
+    .unwrap()
+});
+
+static REORGED_IDENTITIES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "reorged_identities",
+        "Number of mined identities requeued after their batch was reorged out."
+    )
+    .unwrap()
+});
+
 struct RunningInstance {
     #[allow(dead_code)]
     handle: JoinHandle<eyre::Result<()>>,
@@ -26,13 +61,47 @@ impl RunningInstance {
     }
 }
 
+/// Tracks when the tree last caught up with the confirmed chain head, to tell
+/// how stale the served proofs may be.
+#[derive(Debug, Default)]
+pub struct SyncStatus {
+    last_synced: Mutex<Option<Instant>>,
+}
+
+impl SyncStatus {
+    pub fn mark_synced(&self) {
+        self.mark_synced_at(Instant::now());
+    }
+
+    /// Returns `true` if the tree has caught up with the chain within
+    /// `max_staleness`.
+    pub fn is_synced_within(&self, max_staleness: Duration) -> bool {
+        self.is_synced_within_at(max_staleness, Instant::now())
+    }
+
+    fn mark_synced_at(&self, now: Instant) {
+        *self.last_synced.lock().unwrap() = Some(now);
+    }
+
+    fn is_synced_within_at(&self, max_staleness: Duration, now: Instant) -> bool {
+        self.last_synced.lock().unwrap().map_or(false, |synced| {
+            now.saturating_duration_since(synced) <= max_staleness
+        })
+    }
+}
+
 pub struct EthereumSubscriber {
-    instance:           RwLock<Option<RunningInstance>>,
-    starting_block:     u64,
-    database:           Arc<Database>,
-    identity_manager:   SharedIdentityManager,
-    tree_state:         SharedTreeState,
-    identity_committer: Arc<IdentityCommitter>,
+    instance:                 RwLock<Option<RunningInstance>>,
+    starting_block:           u64,
+    database:                 Arc<Database>,
+    identity_manager:         SharedIdentityManager,
+    tree_state:               SharedTreeState,
+    identity_committer:       Arc<IdentityCommitter>,
+    rebuild_on_corrupt_cache: bool,
+    sync_status:              Arc<SyncStatus>,
+    status_changes:           Option<StatusChanges>,
+    webhooks:                 Option<Webhooks>,
+    proof_cache:              Option<Arc<Mutex<ProofCache>>>,
 }
 
 impl EthereumSubscriber {
@@ -42,6 +111,7 @@ impl EthereumSubscriber {
         identity_manager: SharedIdentityManager,
         tree_state: SharedTreeState,
         identity_committer: Arc<IdentityCommitter>,
+        rebuild_on_corrupt_cache: bool,
     ) -> Self {
         Self {
             instance: RwLock::new(None),
@@ -50,9 +120,42 @@ impl EthereumSubscriber {
             identity_manager,
             tree_state,
             identity_committer,
+            rebuild_on_corrupt_cache,
+            sync_status: Arc::new(SyncStatus::default()),
+            status_changes: None,
+            webhooks: None,
+            proof_cache: None,
         }
     }
 
+    /// Emits a [`IdentityStatus::Mined`] change for every identity added to
+    /// the tree to `status_changes`, if any.
+    #[must_use]
+    pub fn with_status_changes(mut self, status_changes: Option<StatusChanges>) -> Self {
+        self.status_changes = status_changes;
+        self
+    }
+
+    /// Posts to the webhooks recorded for insertions once they are added to
+    /// the tree, if set.
+    #[must_use]
+    pub fn with_webhooks(mut self, webhooks: Option<Webhooks>) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    /// Evicts the cached proofs of identities once they are deleted from the
+    /// tree, if set.
+    #[must_use]
+    pub fn with_proof_cache(mut self, proof_cache: Option<Arc<Mutex<ProofCache>>>) -> Self {
+        self.proof_cache = proof_cache;
+        self
+    }
+
+    pub fn sync_status(&self) -> &SyncStatus {
+        &self.sync_status
+    }
+
     #[instrument(level = "debug", skip_all)]
     pub async fn start(&self, refresh_rate: Duration) {
         let mut instance = self.instance.write().await;
@@ -66,8 +169,13 @@ impl EthereumSubscriber {
         let tree_state = self.tree_state.clone();
         let identity_manager = self.identity_manager.clone();
         let identity_committer = self.identity_committer.clone();
+        let sync_status = self.sync_status.clone();
+        let status_changes = self.status_changes.clone();
+        let webhooks = self.webhooks.clone();
+        let proof_cache = self.proof_cache.clone();
 
         let handle = tokio::spawn(async move {
+            let mut resync_backoff = MIN_RESYNC_BACKOFF;
             loop {
                 sleep(refresh_rate).await;
 
@@ -77,10 +185,33 @@ impl EthereumSubscriber {
                     identity_manager.clone(),
                     database.clone(),
                     identity_committer.clone(),
+                    status_changes.as_ref(),
+                    webhooks.as_ref(),
+                    proof_cache.as_deref(),
                 )
                 .await;
                 match processed_block {
-                    Ok(block_number) => starting_block = block_number + 1,
+                    Ok(block_number) => {
+                        starting_block = block_number + 1;
+                        sync_status.mark_synced();
+                    }
+                    // The tree stays out of sync until a rebuild succeeds, so
+                    // the next round runs into this again and retries it.
+                    Err(Error::TreeOutOfSync { .. }) => {
+                        warn!("Rebuilding the tree from the database before retrying.");
+                        match Self::resync_tree(&tree_state, &database, starting_block).await {
+                            Ok(()) => resync_backoff = MIN_RESYNC_BACKOFF,
+                            Err(error) => {
+                                error!(
+                                    ?error,
+                                    backoff = ?resync_backoff,
+                                    "Couldn't resync tree with database, retrying."
+                                );
+                                sleep(resync_backoff).await;
+                                resync_backoff = (resync_backoff * 2).min(MAX_RESYNC_BACKOFF);
+                            }
+                        }
+                    }
                     Err(error) => {
                         panic!("Couldn't process events update: {error:?}");
                     }
@@ -98,23 +229,42 @@ impl EthereumSubscriber {
             .await
             .map_err(Error::Event)?;
 
-        let last_db_block = Self::process_cached_events(
+        let cached = Self::process_cached_events(
             self.starting_block,
             end_block,
             self.tree_state.clone(),
             self.database.clone(),
         )
-        .await?;
+        .await;
+        let next_block = match cached {
+            Ok(last_db_block) => last_db_block + 1,
+            // The cache is read before the tree is touched, so the tree is still
+            // empty and can be rebuilt from the chain alone.
+            Err(Error::Database(DatabaseError::CorruptCache(error)))
+                if self.rebuild_on_corrupt_cache =>
+            {
+                error!(%error, "Cached events are corrupt, discarding the cache and rebuilding the tree from the chain.");
+                self.database.wipe_cache().await.map_err(Error::Database)?;
+                self.starting_block
+            }
+            Err(error) => return Err(error),
+        };
         let processed_block = Self::process_blockchain_events(
-            last_db_block + 1,
+            next_block,
             end_block,
             self.tree_state.clone(),
             self.identity_manager.clone(),
             self.database.clone(),
             self.identity_committer.clone(),
+            self.status_changes.as_ref(),
+            self.webhooks.as_ref(),
+            self.proof_cache.as_deref(),
         )
         .await?;
+        Self::requeue_reorged_identities(&self.database, &self.identity_committer, processed_block)
+            .await?;
         self.starting_block = processed_block + 1;
+        self.sync_status.mark_synced();
         Ok(())
     }
 
@@ -124,21 +274,55 @@ impl EthereumSubscriber {
         identity_manager: SharedIdentityManager,
         database: Arc<Database>,
         identity_committer: Arc<IdentityCommitter>,
+        status_changes: Option<&StatusChanges>,
+        webhooks: Option<&Webhooks>,
+        proof_cache: Option<&Mutex<ProofCache>>,
     ) -> Result<u64, Error> {
         let end_block = identity_manager
             .confirmed_block_number()
             .await
             .map_err(Error::Event)?;
 
-        Self::process_blockchain_events(
+        let processed_block = Self::process_blockchain_events(
             start_block,
             end_block,
             tree_state,
             identity_manager,
-            database,
-            identity_committer,
+            database.clone(),
+            identity_committer.clone(),
+            status_changes,
+            webhooks,
+            proof_cache,
         )
-        .await
+        .await?;
+        Self::requeue_reorged_identities(&database, &identity_committer, processed_block).await?;
+        Ok(processed_block)
+    }
+
+    /// Returns identities whose batch was mined in a block that has been
+    /// processed without their events to the queue, so they are submitted
+    /// again.
+    ///
+    /// Only confirmed blocks are processed, so the tree never holds the leaves
+    /// of a reorged batch and needs no rollback.
+    async fn requeue_reorged_identities(
+        database: &Database,
+        identity_committer: &IdentityCommitter,
+        processed_block: u64,
+    ) -> Result<(), Error> {
+        let requeued = database
+            .requeue_reorged_identities(processed_block)
+            .await
+            .map_err(Error::Database)?;
+        if requeued > 0 {
+            warn!(
+                requeued,
+                processed_block, "Batch was reorged out of the chain, requeueing its identities."
+            );
+            REORGED_IDENTITIES.inc_by(requeued);
+            identity_committer.notify_queued().await;
+        }
+        Ok(())
     }
 
     async fn process_cached_events(
@@ -165,20 +349,54 @@ impl EthereumSubscriber {
             )
             .await
             .map_err(Error::Database)?;
-        let root = events.last().map(|event| event.1);
-        let leaves = events.iter().map(|event| event.0);
-        let count = leaves.len();
+        let deletions = database
+            .load_deletion_logs(
+                i64::try_from(start_block).unwrap(),
+                Some(i64::try_from(end_block).unwrap()),
+            )
+            .await
+            .map_err(Error::Database)?;
+        let root = database
+            .get_latest_root_until(i64::try_from(end_block).unwrap())
+            .await
+            .map_err(Error::Database)?;
 
         let mut tree = tree_state.write().await.unwrap_or_else(|e| {
             error!(?e, "Failed to obtain tree lock in process_events.");
             panic!("Sequencer potentially deadlocked, terminating.");
         });
 
-        // Insert
+        // A tree restored from a snapshot already holds the first events, which
+        // only need to be checked against the root of the last of them.
         let index = tree.next_leaf;
+        if index > 0 {
+            let snapshot_root = events.get(index - 1).map(|event| event.1);
+            if snapshot_root == Some(tree.merkle_tree.root()) {
+                info!(
+                    next_leaf = index,
+                    "Replaying the events since the tree snapshot."
+                );
+            } else {
+                warn!(
+                    next_leaf = index,
+                    ?snapshot_root,
+                    "Tree snapshot does not match the cached events, replaying all of them."
+                );
+                *tree = tree.cleared();
+            }
+        }
+        let index = tree.next_leaf;
+        let leaves = events[index..].iter().map(|event| event.0);
+        let count = leaves.len();
+
+        // Insert
         tree.merkle_tree.set_range(index, leaves);
         tree.next_leaf += count;
 
+        // Every leaf is inserted before it is emptied, and never set again, so
+        // the deletions can be applied once all the leaves are in.
+        Self::empty_leaves(&mut tree, &deletions);
+
         // Check root
         if let Some(root) = root {
             if root != tree.merkle_tree.root() {
@@ -186,6 +404,7 @@ impl EthereumSubscriber {
                 return Err(Error::RootMismatch);
             }
         }
+        TREE_LEAVES.set(tree.next_leaf.try_into().unwrap_or(i64::MAX));
 
         Ok(min(end_block, last_cached_block))
     }
@@ -197,6 +416,9 @@ impl EthereumSubscriber {
         identity_manager: SharedIdentityManager,
         database: Arc<Database>,
         identity_committer: Arc<IdentityCommitter>,
+        status_changes: Option<&StatusChanges>,
+        webhooks: Option<&Webhooks>,
+        proof_cache: Option<&Mutex<ProofCache>>,
     ) -> Result<u64, Error> {
         if start_block > end_block {
             return Ok(end_block);
@@ -216,54 +438,120 @@ impl EthereumSubscriber {
             panic!("Sequencer potentially deadlocked, terminating.");
         });
 
-        let mut wake_up_committer = false;
+        // Pending identities are left untouched if the tree has drifted, so they
+        // are picked up again once it has been resynced.
+        Self::check_tree_sync(&tree, &database, start_block).await?;
+
+        let initial_leaf = identity_manager.initial_leaf_value();
+        let start_leaf = tree.next_leaf;
+        let mut emptied = Vec::new();
+        let applied = async {
+            let mut identities = Vec::new();
+            let mut deletions = Vec::new();
+            while let Some(event) = events.try_next().await.map_err(Error::Event)? {
+                let root = match TreeChange::try_from(event)? {
+                    TreeChange::Added(identity) => {
+                        Self::log_event_errors(
+                            &tree,
+                            &initial_leaf,
+                            tree.next_leaf,
+                            &identity.leaf,
+                        )?;
 
-        loop {
-            let event = match events.try_next().await.map_err(Error::Event)? {
-                Some(a) => a,
-                None => break,
-            };
+                        // Insert
+                        let index = tree.next_leaf;
+                        tree.merkle_tree.set(index, identity.leaf);
+                        tree.next_leaf += 1;
 
-            let identity = ConfirmedIdentityEvent::try_from(event)?;
+                        let root = identity.root;
+                        identities.push(identity);
+                        root
+                    }
+                    TreeChange::Removed(mut deletion) => {
+                        let Some(index) = tree.leaf_index(&deletion.leaf) else {
+                            error!(leaf = ?deletion.leaf, "Received removal of a leaf not in the tree.");
+                            return Err(Error::UnknownLeaf);
+                        };
 
-            Self::log_event_errors(
-                &tree,
-                &identity_manager.initial_leaf_value(),
-                tree.next_leaf,
-                &identity.leaf,
-            )?;
+                        // Empty
+                        tree.merkle_tree.set(index, initial_leaf);
+                        emptied.push((index, deletion.leaf));
 
-            // Insert
-            let index = tree.next_leaf;
-            tree.merkle_tree.set(index, identity.leaf);
-            tree.next_leaf += 1;
+                        deletion.leaf_index = i64::try_from(index)
+                            .map_err(|error| Error::Conversion(error.to_string()))?;
+                        let root = deletion.root;
+                        deletions.push(deletion);
+                        root
+                    }
+                };
 
-            // Check root
-            if identity.root != tree.merkle_tree.root() {
-                error!(computed_root = ?tree.merkle_tree.root(), event_root = ?identity.root, "Root mismatch between event and computed tree.");
-                return Err(Error::RootMismatch);
+                // Check root
+                if root != tree.merkle_tree.root() {
+                    error!(computed_root = ?tree.merkle_tree.root(), event_root = ?root, "Root mismatch between event and computed tree.");
+                    return Err(Error::RootMismatch);
+                }
             }
 
-            // Cache event
-            database
-                .save_log(&identity)
-                .await
-                .map_err(Error::Database)?;
+            // The webhooks are recorded with the pending identities, which are
+            // removed once confirmed.
+            let mut notifications = Vec::new();
+            if webhooks.is_some() {
+                for identity in &identities {
+                    let webhook = database
+                        .get_webhook(&identity.leaf)
+                        .await
+                        .map_err(Error::Database)?;
+                    if let Some((group_id, url)) = webhook {
+                        notifications.push((url, Notification {
+                            group_id,
+                            identity_commitment: identity.leaf,
+                            status: Status::Mined,
+                            block: u64::try_from(identity.block_index).ok(),
+                        }));
+                    }
+                }
+            }
 
-            // Remove from pending identities
+            // Cache events and remove from pending identities and deletions
             let queue_status = database
-                .confirm_identity_and_retrigger_stale_recods(&identity.leaf)
+                .confirm_identities(&identities, &deletions)
                 .await
                 .map_err(Error::Database)?;
-            if matches!(
-                queue_status,
-                IdentityConfirmationResult::RetriggerProcessing
-            ) {
-                wake_up_committer = true;
+            if let Some(status_changes) = status_changes {
+                for identity in &identities {
+                    status_changes.emit(identity.leaf, IdentityStatus::Mined);
+                }
             }
+            if let Some(webhooks) = webhooks {
+                for (url, notification) in notifications {
+                    webhooks.spawn_delivery(url, notification);
+                }
+            }
+            Ok(queue_status)
         }
+        .await;
 
-        if wake_up_committer {
+        // The database changes are rolled back on failure, so the tree must be
+        // too, or it would run ahead of the cached events.
+        let queue_status = match applied {
+            Ok(queue_status) => queue_status,
+            Err(error) => {
+                Self::rollback_tree(&mut tree, start_leaf, initial_leaf, &emptied);
+                return Err(error);
+            }
+        };
+        TREE_LEAVES.set(tree.next_leaf.try_into().unwrap_or(i64::MAX));
+        if let Some(proof_cache) = proof_cache {
+            let mut proof_cache = proof_cache.lock().unwrap();
+            for (_, leaf) in &emptied {
+                proof_cache.remove(leaf);
+            }
+        }
+
+        if matches!(
+            queue_status,
+            IdentityConfirmationResult::RetriggerProcessing
+        ) {
             error!(
                 "event sequencing inconsistent between chain and identity committer. re-org \
                  happened?"
@@ -274,6 +562,98 @@ impl EthereumSubscriber {
         Ok(end_block)
     }
 
+    /// Restores the `emptied` leaves, latest first, then empties the leaves
+    /// appended to the tree from `start_leaf` onwards.
+    fn rollback_tree(
+        tree: &mut TreeState,
+        start_leaf: usize,
+        initial_leaf: Field,
+        emptied: &[(usize, Field)],
+    ) {
+        for &(index, leaf) in emptied.iter().rev() {
+            tree.merkle_tree.set(index, leaf);
+        }
+        let appended = tree.next_leaf - start_leaf;
+        tree.merkle_tree
+            .set_range(start_leaf, repeat(initial_leaf).take(appended));
+        tree.next_leaf = start_leaf;
+    }
+
+    /// Empties the leaves of the cached `deletions`, given as leaf indices.
+    fn empty_leaves(tree: &mut TreeState, deletions: &[(usize, Field)]) {
+        let initial_leaf = tree.merkle_tree.initial_leaf();
+        for &(index, _) in deletions {
+            tree.merkle_tree.set(index, initial_leaf);
+        }
+    }
+
+    /// Checks that the tree holds exactly the leaves cached in the database for
+    /// the blocks before `start_block`.
+    async fn check_tree_sync(
+        tree: &TreeState,
+        database: &Database,
+        start_block: u64,
+    ) -> Result<(), Error> {
+        let cached_leaves = database
+            .count_cached_leaves(i64::try_from(start_block).unwrap_or(i64::MAX))
+            .await
+            .map_err(Error::Database)?;
+        if tree.next_leaf != cached_leaves {
+            error!(
+                tree_leaves = tree.next_leaf,
+                cached_leaves, "Tree is out of sync with the database."
+            );
+            return Err(Error::TreeOutOfSync {
+                tree_leaves: tree.next_leaf,
+                cached_leaves,
+            });
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the tree from the leaves cached in the database for the blocks
+    /// before `start_block`.
+    async fn resync_tree(
+        tree_state: &SharedTreeState,
+        database: &Database,
+        start_block: u64,
+    ) -> Result<(), Error> {
+        let to_block = i64::try_from(start_block).unwrap_or(i64::MAX) - 1;
+        let events = database
+            .load_logs(0, Some(to_block))
+            .await
+            .map_err(Error::Database)?;
+        let deletions = database
+            .load_deletion_logs(0, Some(to_block))
+            .await
+            .map_err(Error::Database)?;
+        let root = database
+            .get_latest_root_until(to_block)
+            .await
+            .map_err(Error::Database)?;
+
+        let mut tree = tree_state.write().await.unwrap_or_else(|e| {
+            error!(?e, "Failed to obtain tree lock in resync_tree.");
+            panic!("Sequencer potentially deadlocked, terminating.");
+        });
+        *tree = tree.cleared();
+        tree.merkle_tree
+            .set_range(0, events.iter().map(|event| event.0));
+        tree.next_leaf = events.len();
+        Self::empty_leaves(&mut tree, &deletions);
+
+        if let Some(root) = root {
+            if root != tree.merkle_tree.root() {
+                error!(computed_root = ?tree.merkle_tree.root(), cached_root = ?root, "Root mismatch between cache and rebuilt tree.");
+                return Err(Error::RootMismatch);
+            }
+        }
+        info!(leaves = tree.next_leaf, "Rebuilt tree from database.");
+        TREE_LEAVES.set(tree.next_leaf.try_into().unwrap_or(i64::MAX));
+
+        Ok(())
+    }
+
     #[allow(clippy::cognitive_complexity)]
     fn log_event_errors(
         tree: &TreeState,
@@ -311,10 +691,7 @@ impl EthereumSubscriber {
 
     #[instrument(level = "debug", skip_all)]
     pub async fn check_health(&self) {
-        let tree = self.tree_state.read().await.unwrap_or_else(|e| {
-            error!(?e, "Failed to obtain tree lock in check_leaves.");
-            panic!("Sequencer potentially deadlocked, terminating.");
-        });
+        let tree = self.tree_state.read_uninterruptible().await;
         let initial_leaf = self.identity_manager.initial_leaf_value();
 
         if tree.next_leaf > 0 {
@@ -411,6 +788,13 @@ pub enum Error {
     RootMismatch,
     #[error("Received event out of range")]
     EventOutOfRange,
+    #[error("Received removal of a leaf not in the tree")]
+    UnknownLeaf,
+    #[error("Tree has {tree_leaves} leaves but the database has {cached_leaves} cached.")]
+    TreeOutOfSync {
+        tree_leaves:   usize,
+        cached_leaves: usize,
+    },
     #[error("Event error: {0}")]
     Event(#[source] EventError),
     #[error("Database error: {0}")]
@@ -433,12 +817,27 @@ impl From<MemberAddedEvent> for IdentityCommitment {
     }
 }
 
-impl TryFrom<Log<MemberAddedEvent>> for ConfirmedIdentityEvent {
-    type Error = Error;
+impl From<MemberRemovedEvent> for IdentityCommitment {
+    fn from(value: MemberRemovedEvent) -> Self {
+        Self {
+            leaf: value.identity_commitment.into(),
+            root: value.root.into(),
+        }
+    }
+}
 
-    fn try_from(value: Log<MemberAddedEvent>) -> Result<Self, Self::Error> {
-        let commitment = IdentityCommitment::from(value.event);
+/// A confirmed event adding a leaf to the tree or emptying one.
+enum TreeChange {
+    Added(ConfirmedIdentityEvent),
+    /// The `leaf_index` of the removal is only known once its leaf is looked
+    /// up in the tree.
+    Removed(ConfirmedDeletionEvent),
+}
 
+impl TryFrom<Log<TreeEvent>> for TreeChange {
+    type Error = Error;
+
+    fn try_from(value: Log<TreeEvent>) -> Result<Self, Self::Error> {
         let block_index: i64 = value
             .block_index
             .try_into()
@@ -454,13 +853,385 @@ impl TryFrom<Log<MemberAddedEvent>> for ConfirmedIdentityEvent {
             .try_into()
             .map_err(|e: &str| Error::Conversion(e.to_owned()))?;
 
-        Ok(Self {
-            block_index,
-            transaction_index,
-            log_index,
-            raw_log: value.raw_log,
-            leaf: commitment.leaf,
-            root: commitment.root,
+        Ok(match value.event {
+            TreeEvent::MemberAddedFilter(event) => {
+                let commitment = IdentityCommitment::from(event);
+                Self::Added(ConfirmedIdentityEvent {
+                    block_index,
+                    transaction_index,
+                    log_index,
+                    raw_log: value.raw_log,
+                    leaf: commitment.leaf,
+                    root: commitment.root,
+                })
+            }
+            TreeEvent::MemberRemovedFilter(event) => {
+                let commitment = IdentityCommitment::from(event);
+                Self::Removed(ConfirmedDeletionEvent {
+                    block_index,
+                    transaction_index,
+                    log_index,
+                    raw_log: value.raw_log,
+                    leaf: commitment.leaf,
+                    leaf_index: 0,
+                    root: commitment.root,
+                })
+            }
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        contracts::mock::MockIdentityManager, database, prover::map::ProverMap,
+        timed_rw_lock::TimedRwLock, webhook,
+    };
+    use url::Url;
+
+    #[test]
+    fn sync_status_should_go_stale_beyond_bound() {
+        let status = SyncStatus::default();
+        let max_staleness = Duration::from_secs(30);
+        let start = Instant::now();
+        assert!(!status.is_synced_within_at(max_staleness, start));
+
+        status.mark_synced_at(start);
+        assert!(status.is_synced_within_at(max_staleness, start + max_staleness));
+        assert!(!status.is_synced_within_at(
+            max_staleness,
+            start + max_staleness + Duration::from_secs(1)
+        ));
+    }
+
+    #[tokio::test]
+    async fn out_of_sync_tree_should_return_resync_error() -> anyhow::Result<()> {
+        let database = Arc::new(database::mock::database().await);
+        database
+            .save_log(&ConfirmedIdentityEvent {
+                block_index:       1,
+                transaction_index: 0,
+                log_index:         0,
+                raw_log:           String::new(),
+                leaf:              Field::from(1_u64),
+                root:              Field::from(2_u64),
+            })
+            .await?;
+        database
+            .insert_pending_identity(1, &Field::from(3_u64), 0)
+            .await?;
+
+        let identity_manager: SharedIdentityManager = Arc::new(MockIdentityManager::default());
+        // The tree does not hold the cached leaf.
+        let tree_state = Arc::new(TimedRwLock::new(
+            Duration::from_secs(1),
+            TreeState::new(
+                identity_manager.tree_depth() + 1,
+                identity_manager.initial_leaf_value(),
+            ),
+        ));
+        let identity_committer = Arc::new(IdentityCommitter::new(
+            database.clone(),
+            identity_manager.clone(),
+            tree_state.clone(),
+            Arc::new(RwLock::new(ProverMap::default())),
+            Duration::ZERO,
+            0,
+            Duration::ZERO,
+        ));
+
+        let result = EthereumSubscriber::process_blockchain_events(
+            2,
+            3,
+            tree_state.clone(),
+            identity_manager,
+            database.clone(),
+            identity_committer,
+            None,
+            None,
+            None,
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(Error::TreeOutOfSync {
+                tree_leaves:   0,
+                cached_leaves: 1,
+            })
+        ));
+        assert_eq!(database.count_unprocessed_identities().await?, 1);
+
+        // The cached root does not match its leaves, so the rebuild fails and
+        // is left to be retried rather than taking the sequencer down.
+        assert!(matches!(
+            EthereumSubscriber::resync_tree(&tree_state, &database, 2).await,
+            Err(Error::RootMismatch)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_cache_should_fall_back_to_chain_rebuild() -> anyhow::Result<()> {
+        let database = Arc::new(database::mock::database().await);
+        database::mock::save_corrupt_log(&database, 0).await;
+
+        let mock = MockIdentityManager::default();
+        let commitment = Field::from(1_u64);
+        let mut expected = TreeState::new(mock.tree_depth() + 1, mock.initial_leaf_value());
+        expected.merkle_tree.set(0, commitment);
+        let expected_root = expected.merkle_tree.root();
+        mock.events
+            .lock()
+            .unwrap()
+            .push((0, commitment, expected_root));
+
+        let identity_manager: SharedIdentityManager = Arc::new(mock);
+        let tree_state = Arc::new(TimedRwLock::new(
+            Duration::from_secs(1),
+            TreeState::new(
+                identity_manager.tree_depth() + 1,
+                identity_manager.initial_leaf_value(),
+            ),
+        ));
+        let identity_committer = Arc::new(IdentityCommitter::new(
+            database.clone(),
+            identity_manager.clone(),
+            tree_state.clone(),
+            Arc::new(RwLock::new(ProverMap::default())),
+            Duration::ZERO,
+            0,
+            Duration::ZERO,
+        ));
+        let mut subscriber = EthereumSubscriber::new(
+            0,
+            database.clone(),
+            identity_manager,
+            tree_state.clone(),
+            identity_committer,
+            true,
+        );
+
+        subscriber.process_initial_events().await?;
+
+        let tree = tree_state.read().await?;
+        assert_eq!(tree.next_leaf, 1);
+        assert_eq!(tree.merkle_tree.root(), expected_root);
+        assert_eq!(database.load_logs(0, None).await?, vec![(
+            commitment,
+            expected_root
+        )]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_batch_should_leave_tree_and_database_untouched() -> anyhow::Result<()> {
+        let database = Arc::new(database::mock::database().await);
+        let mock = MockIdentityManager::default();
+        let commitments = [1_u64, 2].map(Field::from);
+        let mut expected = TreeState::new(mock.tree_depth() + 1, mock.initial_leaf_value());
+        for (index, commitment) in commitments.into_iter().enumerate() {
+            database.insert_pending_identity(1, &commitment, 0).await?;
+            expected.merkle_tree.set(index, commitment);
+            mock.events.lock().unwrap().push((
+                index as u64,
+                commitment,
+                expected.merkle_tree.root(),
+            ));
+        }
+        // Caching the second event of the batch fails.
+        database::mock::fail_log_inserts(&database, 1).await;
+
+        let identity_manager: SharedIdentityManager = Arc::new(mock);
+        let empty = TreeState::new(
+            identity_manager.tree_depth() + 1,
+            identity_manager.initial_leaf_value(),
+        );
+        let empty_root = empty.merkle_tree.root();
+        let tree_state = Arc::new(TimedRwLock::new(Duration::from_secs(1), empty));
+        let identity_committer = Arc::new(IdentityCommitter::new(
+            database.clone(),
+            identity_manager.clone(),
+            tree_state.clone(),
+            Arc::new(RwLock::new(ProverMap::default())),
+            Duration::ZERO,
+            0,
+            Duration::ZERO,
+        ));
+
+        let result = EthereumSubscriber::process_blockchain_events(
+            0,
+            1,
+            tree_state.clone(),
+            identity_manager,
+            database.clone(),
+            identity_committer,
+            None,
+            None,
+            None,
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::Database(_))));
+        let tree = tree_state.read().await?;
+        assert_eq!(tree.next_leaf, 0);
+        assert_eq!(tree.merkle_tree.root(), empty_root);
+        assert!(database.load_logs(0, None).await?.is_empty());
+        assert_eq!(database.count_unprocessed_identities().await?, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn webhook_should_be_called_when_confirmed() -> anyhow::Result<()> {
+        let receiver = webhook::mock::Receiver::new("0.0.0.0:3034")?;
+        let url: Url = "http://localhost:3034/webhook".parse()?;
+        let database = Arc::new(database::mock::database().await);
+        let mock = MockIdentityManager::default();
+        let commitment = Field::from(1_u64);
+        database
+            .insert_pending_identity_with_options(1, &commitment, 0, None, Some(&url), None, None)
+            .await?;
+        let mut expected = TreeState::new(mock.tree_depth() + 1, mock.initial_leaf_value());
+        expected.merkle_tree.set(0, commitment);
+        mock.events
+            .lock()
+            .unwrap()
+            .push((4, commitment, expected.merkle_tree.root()));
+
+        let identity_manager: SharedIdentityManager = Arc::new(mock);
+        let tree_state = Arc::new(TimedRwLock::new(
+            Duration::from_secs(1),
+            TreeState::new(
+                identity_manager.tree_depth() + 1,
+                identity_manager.initial_leaf_value(),
+            ),
+        ));
+        let identity_committer = Arc::new(IdentityCommitter::new(
+            database.clone(),
+            identity_manager.clone(),
+            tree_state.clone(),
+            Arc::new(RwLock::new(ProverMap::default())),
+            Duration::ZERO,
+            0,
+            Duration::ZERO,
+        ));
+
+        // The first delivery fails and is retried.
+        receiver.fail_next(1);
+        EthereumSubscriber::process_blockchain_events(
+            0,
+            4,
+            tree_state,
+            identity_manager,
+            database,
+            identity_committer,
+            None,
+            Some(&Webhooks::new(3).with_private_addresses(true)),
+            None,
+        )
+        .await?;
+
+        let expected = Notification {
+            group_id:            1,
+            identity_commitment: commitment,
+            status:              Status::Mined,
+            block:               Some(4),
+        };
+        for _ in 0..20 {
+            if !receiver.notifications().is_empty() {
+                break;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(receiver.notifications(), vec![expected]);
+
+        receiver.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removal_should_empty_leaf_and_be_replayed() -> anyhow::Result<()> {
+        let database = Arc::new(database::mock::database().await);
+        let mock = MockIdentityManager::default();
+        let initial_leaf = mock.initial_leaf_value();
+        let commitments = [1_u64, 2].map(Field::from);
+        let mut expected = TreeState::new(mock.tree_depth() + 1, initial_leaf);
+        for (index, commitment) in commitments.into_iter().enumerate() {
+            expected.merkle_tree.set(index, commitment);
+            mock.events
+                .lock()
+                .unwrap()
+                .push((0, commitment, expected.merkle_tree.root()));
+        }
+        expected.merkle_tree.set(0, initial_leaf);
+        let expected_root = expected.merkle_tree.root();
+        mock.removals
+            .lock()
+            .unwrap()
+            .push((1, commitments[0], expected_root));
+        database.insert_pending_deletion(1, &commitments[0]).await?;
+        database.mark_deletion_mined(1, &commitments[0], 1).await?;
+
+        let identity_manager: SharedIdentityManager = Arc::new(mock);
+        let new_tree = || {
+            Arc::new(TimedRwLock::new(
+                Duration::from_secs(1),
+                TreeState::new(identity_manager.tree_depth() + 1, initial_leaf),
+            ))
+        };
+        let tree_state = new_tree();
+        let identity_committer = Arc::new(IdentityCommitter::new(
+            database.clone(),
+            identity_manager.clone(),
+            tree_state.clone(),
+            Arc::new(RwLock::new(ProverMap::default())),
+            Duration::ZERO,
+            0,
+            Duration::ZERO,
+        ));
+
+        // Proofs of both identities were served before the removal.
+        let proof_cache = Mutex::new(ProofCache::new(10));
+        for (index, commitment) in commitments.into_iter().enumerate() {
+            let proof = expected.merkle_tree.proof(index).unwrap();
+            proof_cache
+                .lock()
+                .unwrap()
+                .insert(commitment, expected_root, proof);
+        }
+
+        EthereumSubscriber::process_blockchain_events(
+            0,
+            1,
+            tree_state.clone(),
+            identity_manager.clone(),
+            database.clone(),
+            identity_committer,
+            None,
+            None,
+            Some(&proof_cache),
+        )
+        .await?;
+
+        assert_eq!(tree_state.read().await?.merkle_tree.root(), expected_root);
+        assert_eq!(database.get_latest_root().await?, Some(expected_root));
+        assert!(!database.pending_deletion_exists(1, &commitments[0]).await?);
+        let proof_cache = proof_cache.into_inner().unwrap();
+        assert!(proof_cache.get(&commitments[0]).is_none());
+        assert!(proof_cache.get(&commitments[1]).is_some());
+
+        // A tree rebuilt from the cache empties the leaf again.
+        let replayed = new_tree();
+        EthereumSubscriber::process_cached_events(0, 1, replayed.clone(), database.clone()).await?;
+        let tree = replayed.read().await?;
+        assert_eq!(tree.next_leaf, 2);
+        assert_eq!(tree.merkle_tree.root(), expected_root);
+
+        Ok(())
+    }
+}
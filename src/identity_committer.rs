@@ -1,23 +1,207 @@
 use crate::{
-    contracts::{IdentityManager, SharedIdentityManager},
-    database::Database,
+    batch_events::{BatchEvent, BatchEvents},
+    batching_policy::{BatchingPolicy, Decision},
+    contracts::{
+        submitted_count, IdentityManager, InsertionProof, SharedIdentityManager, SubmissionError,
+        Submitted,
+    },
+    database::{self, BatchAudit, Database, DrainOrder, NewBatch, TieBreak},
     identity_tree::{Hash, SharedTreeState},
-    utils::spawn_or_abort,
+    prover::{generate_proof_from_any, identity::Identity, map::InsertionProverMap, Prover},
+    utils::spawn_or_abort_on,
+    webhook::{Notification, Status, Webhooks},
 };
-use anyhow::{anyhow, Result as AnyhowResult};
-use std::sync::Arc;
+use anyhow::{anyhow, Context as _, Result as AnyhowResult};
+use futures::future::join_all;
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_gauge, register_histogram, register_int_gauge_vec, Gauge,
+    Histogram, IntGauge, IntGaugeVec,
+};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
 use tokio::{
+    runtime::{self, Handle, Runtime},
     select,
-    sync::{mpsc, mpsc::error::TrySendError, RwLock},
+    sync::{broadcast, mpsc, mpsc::error::TrySendError, RwLock},
     task::JoinHandle,
+    time::{sleep, sleep_until, Instant},
 };
-use tracing::{debug, error, info, instrument, warn};
+use tracing::{debug, error, field, info, info_span, instrument, warn, Instrument, Span};
+
+/// Identifier assigned to the next batch, used to correlate its trace and its
+/// record in the batch audit log.
+static NEXT_BATCH_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The name of the threads of the committer's dedicated runtime, if it has one.
+const DEDICATED_THREAD_NAME: &str = "identity-committer";
+
+/// The window over which the arrival rate of identities is averaged for
+/// cost-aware batching.
+const ARRIVAL_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long to wait for the tree to catch up before reassembling a batch that
+/// was assembled on a stale tree, doubled on every further attempt up to the
+/// maximum.
+const MIN_REASSEMBLE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_REASSEMBLE_BACKOFF: Duration = Duration::from_secs(30);
+
+static INSERTION_THROUGHPUT: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(
+        "insertion_throughput",
+        "Identities committed per second, averaged over the throughput window."
+    )
+    .unwrap()
+});
+
+static BATCH_SIZE: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "batch_size",
+        "Number of identities in each mined batch.",
+        exponential_buckets(1.0, 2.0, 12).unwrap()
+    )
+    .unwrap()
+});
+
+static PENDING_BATCHES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pending_batches",
+        "Number of assembled batches awaiting each stage.",
+        &["stage"]
+    )
+    .unwrap()
+});
+
+/// The stage of a batch from its assembly until it is handed to the identity
+/// manager. Batches of contracts that take proofs are proven meanwhile.
+const SUBMISSION: &str = "submission";
+
+/// The stage of a batch handed to the identity manager until its transaction
+/// is mined.
+const MINING: &str = "mining";
+
+/// The number of assembled batches awaiting each stage, which the
+/// `pending_batches` gauge mirrors.
+#[derive(Debug, Default)]
+struct PendingBatches {
+    submission: AtomicUsize,
+    mining:     AtomicUsize,
+}
+
+/// Counts a batch as awaiting a stage until it is dropped.
+struct PendingStage<'a> {
+    count: &'a AtomicUsize,
+    gauge: IntGauge,
+}
+
+impl<'a> PendingStage<'a> {
+    fn enter(count: &'a AtomicUsize, stage: &str) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        let gauge = PENDING_BATCHES.with_label_values(&[stage]);
+        gauge.inc();
+        Self { count, gauge }
+    }
+}
+
+impl Drop for PendingStage<'_> {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+        self.gauge.dec();
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("batch pre-root {pre_root:?} is stale, the latest root is {latest_root:?}")]
+    StalePreRoot {
+        pre_root:    Option<Hash>,
+        latest_root: Option<Hash>,
+    },
+    #[error("batch starts at leaf {start_index}, but the tree only holds {next_leaf} leaves")]
+    TreeBehind {
+        start_index: usize,
+        next_leaf:   usize,
+    },
+}
+
+/// A rolling average of the number of identities committed, or queued, per
+/// second.
+struct Throughput {
+    window:  Duration,
+    batches: VecDeque<(Instant, usize)>,
+}
+
+impl Throughput {
+    const fn new(window: Duration) -> Self {
+        Self {
+            window,
+            batches: VecDeque::new(),
+        }
+    }
+
+    /// Records `count` identities committed at `now` and returns the rate over
+    /// the window ending at `now`.
+    fn record(&mut self, now: Instant, count: usize) -> f64 {
+        self.batches.push_back((now, count));
+        self.rate(now)
+    }
+
+    /// Returns the rate over the window ending at `now`.
+    #[allow(clippy::cast_precision_loss)]
+    fn rate(&mut self, now: Instant) -> f64 {
+        while let Some(&(at, _)) = self.batches.front() {
+            if now.duration_since(at) < self.window {
+                break;
+            }
+            self.batches.pop_front();
+        }
+        let committed: usize = self.batches.iter().map(|(_, count)| count).sum();
+        committed as f64 / self.window.as_secs_f64()
+    }
+}
+
+/// A runtime reserved for the committer, so that request handling cannot
+/// starve it.
+///
+/// It is shut down in the background when dropped, which is allowed from
+/// within another runtime.
+struct DedicatedRuntime(Option<Runtime>);
+
+impl DedicatedRuntime {
+    fn new(worker_threads: usize) -> AnyhowResult<Self> {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(worker_threads)
+            .thread_name(DEDICATED_THREAD_NAME)
+            .enable_all()
+            .build()
+            .context("Failed to build the identity committer runtime.")?;
+        Ok(Self(Some(runtime)))
+    }
+}
+
+impl Drop for DedicatedRuntime {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.0.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
 
 struct RunningInstance {
     #[allow(dead_code)]
     handle:          JoinHandle<()>,
     wake_up_sender:  mpsc::Sender<()>,
     shutdown_sender: mpsc::Sender<()>,
+    /// Kept alive for as long as the committer runs on it.
+    #[allow(dead_code)]
+    runtime:         Option<DedicatedRuntime>,
 }
 
 impl RunningInstance {
@@ -60,10 +244,30 @@ impl RunningInstance {
 /// a time. Spawning multiple worker threads will result in undefined behavior,
 /// including data duplication.
 pub struct IdentityCommitter {
-    instance:         RwLock<Option<RunningInstance>>,
-    database:         Arc<Database>,
-    identity_manager: SharedIdentityManager,
-    tree_state:       SharedTreeState,
+    instance:             RwLock<Option<RunningInstance>>,
+    database:             Arc<Database>,
+    identity_manager:     SharedIdentityManager,
+    tree_state:           SharedTreeState,
+    prover_map:           InsertionProverMap,
+    min_batch_interval:   Duration,
+    throughput_window:    Duration,
+    drain_order:          DrainOrder,
+    tie_break:            TieBreak,
+    empty_batch_interval: Duration,
+    coalesce_wake_ups:    bool,
+    validate_pre_root:    bool,
+    batch_size_hints:     bool,
+    webhooks:             Option<Webhooks>,
+    dedicated_threads:    Option<usize>,
+    batching_policy:      BatchingPolicy,
+    batch_events:         Option<BatchEvents>,
+    shutdown_drain:       Duration,
+    /// The identities queued per second, tracked for cost-aware batching.
+    arrivals:             Arc<Mutex<Throughput>>,
+    wake_ups:             Arc<AtomicU64>,
+    /// The identities queued since the committer last assembled a batch.
+    announced:            Arc<AtomicUsize>,
+    pending_batches:      Arc<PendingBatches>,
 }
 
 impl IdentityCommitter {
@@ -71,15 +275,185 @@ impl IdentityCommitter {
         database: Arc<Database>,
         contracts: SharedIdentityManager,
         tree_state: SharedTreeState,
+        prover_map: InsertionProverMap,
+        batch_linger: Duration,
+        priority_aging: u64,
+        empty_batch_interval: Duration,
     ) -> Self {
         Self {
             instance: RwLock::new(None),
             database,
             identity_manager: contracts,
             tree_state,
+            prover_map,
+            batching_policy: if batch_linger.is_zero() {
+                BatchingPolicy::Immediate
+            } else {
+                BatchingPolicy::MaxWait(batch_linger)
+            },
+            min_batch_interval: Duration::ZERO,
+            throughput_window: Duration::ZERO,
+            drain_order: DrainOrder::Priority {
+                aging_batches: priority_aging,
+            },
+            tie_break: TieBreak::Submission,
+            empty_batch_interval,
+            coalesce_wake_ups: false,
+            validate_pre_root: false,
+            batch_size_hints: false,
+            webhooks: None,
+            dedicated_threads: None,
+            batch_events: None,
+            shutdown_drain: Duration::ZERO,
+            arrivals: Arc::new(Mutex::new(Throughput::new(ARRIVAL_WINDOW))),
+            wake_ups: Arc::new(AtomicU64::new(0)),
+            announced: Arc::new(AtomicUsize::new(0)),
+            pending_batches: Arc::default(),
         }
     }
 
+    /// Spaces batch submissions at least `interval` apart. A batch that is
+    /// ready sooner waits, picking up identities queued in the meantime.
+    #[must_use]
+    pub const fn with_min_batch_interval(mut self, interval: Duration) -> Self {
+        self.min_batch_interval = interval;
+        self
+    }
+
+    /// Exposes the number of identities committed per second, averaged over
+    /// `window`, as the `insertion_throughput` gauge. It is updated after
+    /// every batch and whenever the committer runs out of identities. Zero
+    /// disables the gauge.
+    #[must_use]
+    pub const fn with_throughput_window(mut self, window: Duration) -> Self {
+        self.throughput_window = window;
+        self
+    }
+
+    /// Drains the oldest pending identities first, regardless of their
+    /// priority, instead of by effective priority.
+    #[must_use]
+    pub fn with_oldest_first(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.drain_order = DrainOrder::Oldest;
+        }
+        self
+    }
+
+    /// Breaks ties between identities by commitment instead of by the local
+    /// submission order, so that sequencers given the same identities batch
+    /// them alike, see [`TieBreak::Commitment`].
+    #[must_use]
+    pub fn with_deterministic_ties(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.tie_break = TieBreak::Commitment;
+        }
+        self
+    }
+
+    /// Checks that the root a batch was assembled against is still the latest
+    /// cached root right before submitting it, and reassembles it otherwise.
+    #[must_use]
+    pub fn with_pre_root_validation(mut self, enabled: bool) -> Self {
+        self.validate_pre_root = enabled;
+        self
+    }
+
+    /// Caps a batch at the smallest prover tier that can take the batch size
+    /// hinted at by the first identity to be committed, so that clients can
+    /// trade throughput for latency.
+    #[must_use]
+    pub const fn with_batch_size_hints(mut self, enabled: bool) -> Self {
+        self.batch_size_hints = enabled;
+        self
+    }
+
+    /// Posts to the webhooks recorded for insertions once their batch fails to
+    /// be submitted, if set. They are told about mined insertions by the
+    /// [`EthereumSubscriber`](crate::ethereum_subscriber::EthereumSubscriber)
+    /// once confirmed.
+    #[must_use]
+    pub fn with_webhooks(mut self, webhooks: Option<Webhooks>) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    /// Runs the committer on a dedicated runtime with `worker_threads` threads,
+    /// isolating its latency from request handling, instead of on the runtime
+    /// it is started from.
+    #[must_use]
+    pub const fn with_dedicated_runtime(mut self, worker_threads: Option<usize>) -> Self {
+        self.dedicated_threads = worker_threads;
+        self
+    }
+
+    /// Holds back pending identities for a fuller batch under the
+    /// `batching_policy`, see [`BatchingPolicy`]. This takes the place of the
+    /// batch linger.
+    #[must_use]
+    pub const fn with_batching_policy(mut self, batching_policy: BatchingPolicy) -> Self {
+        self.batching_policy = batching_policy;
+        self
+    }
+
+    /// Broadcasts a [`BatchEvent`] whenever a batch is assembled, submitted,
+    /// mined or fails, buffering up to `capacity` events per subscriber. Zero
+    /// disables the events.
+    #[must_use]
+    pub fn with_batch_events(mut self, capacity: usize) -> Self {
+        self.batch_events = (capacity > 0).then(|| BatchEvents::new(capacity));
+        self
+    }
+
+    /// Returns a receiver of the batch events emitted from now on, if they are
+    /// enabled.
+    #[must_use]
+    pub fn subscribe_batch_events(&self) -> Option<broadcast::Receiver<BatchEvent>> {
+        self.batch_events.as_ref().map(BatchEvents::subscribe)
+    }
+
+    /// Keeps committing the queued identities for up to `drain` after a
+    /// shutdown signal, without waiting for fuller batches, instead of
+    /// stopping after the batch in progress. Identities left over when it
+    /// elapses stay queued for the next start. Zero disables draining.
+    #[must_use]
+    pub const fn with_shutdown_drain(mut self, drain: Duration) -> Self {
+        self.shutdown_drain = drain;
+        self
+    }
+
+    /// Discards wake-up notifications that arrive before a batch is assembled,
+    /// as the batch picks up every identity they announce. This saves the
+    /// committer a redundant wake-up after a burst of insertions.
+    #[must_use]
+    pub fn with_coalesced_wake_ups(mut self, enabled: bool) -> Self {
+        self.coalesce_wake_ups = enabled;
+        self
+    }
+
+    /// Returns the number of identities queued since the committer last
+    /// assembled a batch, which it has yet to pick up.
+    #[must_use]
+    pub fn announced(&self) -> usize {
+        self.announced.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of batches awaiting submission to the contract and
+    /// awaiting mining.
+    #[must_use]
+    pub fn pending_batches(&self) -> (usize, usize) {
+        (
+            self.pending_batches.submission.load(Ordering::Relaxed),
+            self.pending_batches.mining.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The number of times the committer has been woken up by a request.
+    #[cfg(test)]
+    fn wake_ups(&self) -> u64 {
+        self.wake_ups.load(Ordering::Relaxed)
+    }
+
     #[instrument(level = "debug", skip_all)]
     pub async fn start(&self) {
         let mut instance = self.instance.write().await;
@@ -92,33 +466,195 @@ impl IdentityCommitter {
         let database = self.database.clone();
         let identity_manager = self.identity_manager.clone();
         let tree_state = self.tree_state.clone();
-        let handle = spawn_or_abort(async move {
+        let prover_map = self.prover_map.clone();
+        let min_batch_interval = self.min_batch_interval;
+        let throughput_window = self.throughput_window;
+        let drain_order = self.drain_order;
+        let tie_break = self.tie_break;
+        let empty_batch_interval = self.empty_batch_interval;
+        let coalesce_wake_ups = self.coalesce_wake_ups;
+        let validate_pre_root = self.validate_pre_root;
+        let batch_size_hints = self.batch_size_hints;
+        let webhooks = self.webhooks.clone();
+        let batch_events = self.batch_events.clone();
+        let batching_policy = self.batching_policy;
+        let shutdown_drain = self.shutdown_drain;
+        let arrivals = self.arrivals.clone();
+        let wake_ups = self.wake_ups.clone();
+        let announced = self.announced.clone();
+        let pending_batches = self.pending_batches.clone();
+        let runtime = self.dedicated_threads.map(|worker_threads| {
+            DedicatedRuntime::new(worker_threads).expect("Failed to start the identity committer.")
+        });
+        let runtime_handle = runtime
+            .as_ref()
+            .and_then(|runtime| runtime.0.as_ref())
+            .map_or_else(Handle::current, |runtime| runtime.handle().clone());
+        let handle = spawn_or_abort_on(&runtime_handle, async move {
+            // Batch identifiers continue after those recorded before a restart.
+            let next_batch_id = database
+                .next_batch_id()
+                .await
+                .context("Failed to fetch the last recorded batch.")?;
+            NEXT_BATCH_ID.fetch_max(next_batch_id, Ordering::Relaxed);
+            // No batch is in flight before the first one is assembled.
+            database
+                .release_batched_identities(None)
+                .await
+                .context("Failed to release the identities of interrupted batches.")?;
+            let mut last_batch: Option<Instant> = None;
+            let mut throughput =
+                (!throughput_window.is_zero()).then(|| Throughput::new(throughput_window));
+            // Set once a shutdown signal is received while draining the queue.
+            let mut drain_deadline: Option<Instant> = None;
+            // Set while pending identities are held back for a fuller batch.
+            let mut held_since: Option<Instant> = None;
+            let mut reassemble_backoff = MIN_REASSEMBLE_BACKOFF;
             loop {
-                while let Some((group_id, commitment)) =
-                    database.get_oldest_unprocessed_identity().await?
-                {
-                    if (shutdown_receiver.try_recv()).is_ok() {
+                loop {
+                    if let Some(deadline) = drain_deadline {
+                        if Instant::now() >= deadline {
+                            warn!("Shutdown drain elapsed, leaving the remaining items queued.");
+                            return Ok(());
+                        }
+                    } else if (shutdown_receiver.try_recv()).is_ok()
+                        && Self::begin_drain(&mut drain_deadline, shutdown_drain)
+                    {
                         info!("Shutdown signal received, not processing remaining items.");
                         return Ok(());
                     }
 
-                    Self::commit_identity(
+                    if let Some(last_batch) = last_batch.filter(|_| drain_deadline.is_none()) {
+                        let interrupted = Self::wait_until(
+                            &mut shutdown_receiver,
+                            last_batch + min_batch_interval,
+                        )
+                        .await;
+                        if interrupted && Self::begin_drain(&mut drain_deadline, shutdown_drain) {
+                            info!("Woke up by shutdown signal, exiting.");
+                            return Ok(());
+                        }
+                    }
+
+                    // Batches are not held back while draining.
+                    let policy = if drain_deadline.is_none() {
+                        batching_policy
+                    } else {
+                        BatchingPolicy::Immediate
+                    };
+                    let tiers = prover_map.read().await.as_batch_size_vec();
+                    let pending = database.count_unblocked_identities().await?;
+                    // The age is only known to the second, so it is rounded down
+                    // rather than cutting a short wait.
+                    let oldest_age = database
+                        .oldest_unprocessed_identity_age()
+                        .await?
+                        .unwrap_or_default()
+                        .saturating_sub(Duration::from_secs(1));
+                    let waited = held_since.map_or(oldest_age, |held_since: Instant| {
+                        oldest_age.max(held_since.elapsed())
+                    });
+                    let arrival_rate = arrivals.lock().unwrap().rate(Instant::now());
+                    let batch_size = match policy.decide(&tiers, pending, waited, arrival_rate) {
+                        Decision::Submit { batch_size } => {
+                            debug!(pending, batch_size, "Committing batch.");
+                            held_since = None;
+                            batch_size
+                        }
+                        Decision::Wait { target, wait } => {
+                            debug!(pending, target, ?wait, "Waiting for a fuller batch.");
+                            held_since.get_or_insert_with(Instant::now);
+                            let interrupted = Self::linger(
+                                &mut wake_up_receiver,
+                                &mut shutdown_receiver,
+                                &announced,
+                                pending,
+                                target,
+                                wait,
+                            )
+                            .await;
+                            if interrupted && Self::begin_drain(&mut drain_deadline, shutdown_drain)
+                            {
+                                info!("Woke up by shutdown signal, exiting.");
+                                return Ok(());
+                            }
+                            // Decide again on what is pending now.
+                            continue;
+                        }
+                        Decision::Idle => {
+                            held_since = None;
+                            0
+                        }
+                    };
+
+                    if coalesce_wake_ups {
+                        while wake_up_receiver.try_recv().is_ok() {}
+                    }
+                    announced.store(0, Ordering::Relaxed);
+
+                    let processed = match Self::commit_next_batch(
                         &database,
                         &*identity_manager,
                         &tree_state,
-                        group_id,
-                        commitment,
+                        &prover_map,
+                        batch_size,
+                        drain_order,
+                        tie_break,
+                        validate_pre_root,
+                        batch_size_hints,
+                        webhooks.as_ref(),
+                        batch_events.as_ref(),
+                        &pending_batches,
                     )
-                    .await?;
+                    .await
+                    {
+                        Err(error) if error.is::<Error>() => {
+                            warn!(%error, backoff = ?reassemble_backoff, "Reassembling batch.");
+                            let interrupted = Self::wait_until(
+                                &mut shutdown_receiver,
+                                Instant::now() + reassemble_backoff,
+                            )
+                            .await;
+                            if interrupted && Self::begin_drain(&mut drain_deadline, shutdown_drain)
+                            {
+                                info!("Woke up by shutdown signal, exiting.");
+                                return Ok(());
+                            }
+                            reassemble_backoff =
+                                (reassemble_backoff * 2).min(MAX_REASSEMBLE_BACKOFF);
+                            continue;
+                        }
+                        result => result?,
+                    };
+                    reassemble_backoff = MIN_REASSEMBLE_BACKOFF;
+                    if let Some(throughput) = &mut throughput {
+                        INSERTION_THROUGHPUT.set(throughput.record(Instant::now(), processed));
+                    }
+                    if processed == 0 {
+                        if drain_deadline.is_some() {
+                            info!("Queue drained, exiting.");
+                            return Ok(());
+                        }
+                        break;
+                    }
+                    if !min_batch_interval.is_zero() {
+                        last_batch = Some(Instant::now());
+                    }
                 }
 
                 select! {
                     _ = wake_up_receiver.recv() => {
+                        wake_ups.fetch_add(1, Ordering::Relaxed);
                         debug!("Woke up by a request.");
                     }
+                    _ = sleep(empty_batch_interval), if !empty_batch_interval.is_zero() => {
+                        Self::commit_empty_batch(&database, &*identity_manager).await?;
+                    }
                     _ = shutdown_receiver.recv() => {
-                        info!("Woke up by shutdown signal, exiting.");
-                        return Ok(());
+                        if Self::begin_drain(&mut drain_deadline, shutdown_drain) {
+                            info!("Woke up by shutdown signal, exiting.");
+                            return Ok(());
+                        }
                     }
                 }
             }
@@ -127,23 +663,403 @@ impl IdentityCommitter {
             handle,
             wake_up_sender,
             shutdown_sender,
+            runtime,
         });
     }
 
-    #[instrument(level = "info", skip_all)]
-    async fn commit_identity(
+    /// Waits up to `linger` for enough identities to be queued to fill a batch
+    /// of `target_batch_size`, so that light traffic does not result in a
+    /// stream of tiny batches.
+    ///
+    /// Rather than counting the queue again on every wake-up, the `pending`
+    /// identities counted before waiting are topped up with those `announced`
+    /// since.
+    ///
+    /// Returns `true` if a shutdown was requested while waiting.
+    async fn linger(
+        wake_up_receiver: &mut mpsc::Receiver<()>,
+        shutdown_receiver: &mut mpsc::Receiver<()>,
+        announced: &AtomicUsize,
+        pending: usize,
+        target_batch_size: usize,
+        linger: Duration,
+    ) -> bool {
+        let deadline = Instant::now() + linger;
+        let announced_before = announced.load(Ordering::Relaxed);
+        loop {
+            let pending = pending
+                + announced
+                    .load(Ordering::Relaxed)
+                    .saturating_sub(announced_before);
+            if pending == 0 || pending >= target_batch_size {
+                return false;
+            }
+
+            select! {
+                woken = wake_up_receiver.recv() => {
+                    if woken.is_none() {
+                        return false;
+                    }
+                    debug!(pending, "Woke up while waiting for a fuller batch.");
+                }
+                _ = sleep_until(deadline) => {
+                    debug!(pending, "Batch linger elapsed.");
+                    return false;
+                }
+                _ = shutdown_receiver.recv() => {
+                    return true;
+                }
+            }
+        }
+    }
+
+    /// Starts draining the queue for up to `drain` after a shutdown signal.
+    ///
+    /// Returns `true` if draining is disabled and the committer should stop
+    /// right away instead.
+    fn begin_drain(drain_deadline: &mut Option<Instant>, drain: Duration) -> bool {
+        if drain.is_zero() {
+            return true;
+        }
+        info!(?drain, "Shutdown signal received, draining the queue.");
+        *drain_deadline = Some(Instant::now() + drain);
+        false
+    }
+
+    /// Waits until `deadline`, which is used to space batches apart.
+    ///
+    /// Returns `true` if a shutdown was requested while waiting.
+    async fn wait_until(shutdown_receiver: &mut mpsc::Receiver<()>, deadline: Instant) -> bool {
+        select! {
+            _ = sleep_until(deadline) => false,
+            _ = shutdown_receiver.recv() => true,
+        }
+    }
+
+    /// Commits the next pending identities to the contract, returning the
+    /// number of pending identities that were processed.
+    ///
+    /// Identities are picked in `drain_order`, see [`DrainOrder`], with ties
+    /// decided by `tie_break`. Batches are capped at `batch_size` and at the
+    /// largest batch size that any of the provers can handle. Any remaining
+    /// identities are left in the queue for the next call.
+    ///
+    /// If `batch_size_hints` is set, the batch may be capped at a smaller tier,
+    /// see [`Self::with_batch_size_hints`].
+    ///
+    /// If `validate_pre_root` is set, the batch fails with
+    /// [`Error::StalePreRoot`] instead of being submitted if the root advanced
+    /// while it was assembled. If the contract proves batches, the batch is
+    /// proven before it is submitted, see [`Self::prove_batch`].
+    ///
+    /// Each batch is traced as a single `batch` span carrying the batch id,
+    /// with a child span for every stage of its lifecycle. The transitions of
+    /// a batch are also emitted to the `batch_events`, if any.
+    #[instrument(
+        name = "batch",
+        level = "info",
+        skip_all,
+        fields(
+            batch_id = field::Empty,
+            batch_size = field::Empty,
+            block = field::Empty,
+        )
+    )]
+    #[allow(clippy::too_many_arguments)]
+    async fn commit_next_batch(
         database: &Database,
         identity_manager: &(dyn IdentityManager + Send + Sync),
         tree_state: &SharedTreeState,
-        group_id: usize,
-        commitment: Hash,
-    ) -> AnyhowResult<()> {
-        {
-            let tree = tree_state.read().await.unwrap_or_else(|e| {
-                error!(?e, "Failed to obtain tree lock in check_leaves.");
-                panic!("Sequencer potentially deadlocked, terminating.");
+        prover_map: &InsertionProverMap,
+        batch_size: usize,
+        drain_order: DrainOrder,
+        tie_break: TieBreak,
+        validate_pre_root: bool,
+        batch_size_hints: bool,
+        webhooks: Option<&Webhooks>,
+        batch_events: Option<&BatchEvents>,
+        pending_batches: &PendingBatches,
+    ) -> AnyhowResult<usize> {
+        let batch_id = NEXT_BATCH_ID.fetch_add(1, Ordering::Relaxed);
+        Span::current().record("batch_id", batch_id);
+        let emit = |event| {
+            if let Some(batch_events) = batch_events {
+                batch_events.emit(event);
+            }
+        };
+
+        let (processed, batch, pre_root) = Self::assemble_batch(
+            database,
+            tree_state,
+            prover_map,
+            batch_size,
+            batch_size_hints,
+            drain_order,
+            tie_break,
+        )
+        .await?;
+        Span::current().record("batch_size", batch.len());
+        if batch.is_empty() {
+            return Ok(processed);
+        }
+        // Batches that are reassembled release their identities right away, the
+        // other failures stop the committer, which releases them once it is
+        // started again.
+        database
+            .mark_identities_batched(batch_id, &batch)
+            .await
+            .context("Failed to record the identities drained into the batch.")?;
+        let assembled_at = unix_time();
+        let commitments: Vec<Hash> = batch.iter().map(|(_, commitment)| *commitment).collect();
+        let awaiting_submission = PendingStage::enter(&pending_batches.submission, SUBMISSION);
+        emit(BatchEvent::BatchAssembled {
+            batch_id,
+            pre_root,
+            commitments: commitments.clone(),
+        });
+
+        if validate_pre_root {
+            if let Err(error) = Self::check_pre_root(database, pre_root).await {
+                database
+                    .release_batched_identities(Some(batch_id))
+                    .await
+                    .context("Failed to release the identities of a stale batch.")?;
+                emit(BatchEvent::BatchRetried {
+                    batch_id,
+                    batch_size: batch.len(),
+                    reason: error.to_string(),
+                });
+                return Err(error);
+            }
+        }
+
+        let proof = if identity_manager.proves_batches() {
+            let proof = Self::prove_batch(
+                database,
+                identity_manager,
+                tree_state,
+                prover_map,
+                &commitments,
+            )
+            .instrument(info_span!("prove_batch"))
+            .await;
+            match proof {
+                Ok(proof) => Some(proof),
+                Err(error) => {
+                    if error.is::<Error>() {
+                        database
+                            .release_batched_identities(Some(batch_id))
+                            .await
+                            .context("Failed to release the identities of a stale batch.")?;
+                        emit(BatchEvent::BatchRetried {
+                            batch_id,
+                            batch_size: batch.len(),
+                            reason: error.to_string(),
+                        });
+                    } else {
+                        emit(BatchEvent::BatchFailed {
+                            batch_id,
+                            batch_size: batch.len(),
+                            error: error.to_string(),
+                        });
+                    }
+                    return Err(error);
+                }
+            }
+        } else {
+            None
+        };
+
+        // Send Semaphore transaction
+        drop(awaiting_submission);
+        let awaiting_mining = PendingStage::enter(&pending_batches.mining, MINING);
+        let submitted_at = unix_time();
+        log_audit_failure(
+            batch_id,
+            database
+                .insert_batch(&NewBatch {
+                    id: batch_id,
+                    pre_root,
+                    commitments: commitments.clone(),
+                    assembled_at,
+                    submitted_at,
+                })
+                .await,
+        );
+        let submitted = identity_manager
+            .register_identities(commitments, proof)
+            .instrument(info_span!("submit_batch"))
+            .await;
+        drop(awaiting_mining);
+        let (submitted, failure) = match submitted {
+            Ok(submitted) => {
+                emit(BatchEvent::BatchSubmitted {
+                    batch_id,
+                    batch_size: batch.len(),
+                });
+                (submitted, None)
+            }
+            Err(SubmissionError { submitted, source }) => (submitted, Some(source)),
+        };
+
+        // The identities that went through are recorded even if the rest of the
+        // batch failed, so that they are not submitted again.
+        let mut remaining = batch.as_slice();
+        for Submitted { count, receipt } in &submitted {
+            let (mined, rest) = remaining.split_at((*count).min(remaining.len()));
+            remaining = rest;
+            let block = receipt
+                .block_number
+                .expect("Transaction is mined, block number must be present.");
+            info!(
+                batch_size = mined.len(),
+                "Identities submitted in block {}.", block
+            );
+            let audit = BatchAudit {
+                batch_id,
+                tx_hash: receipt.transaction_hash,
+                block_number: block.as_u64(),
+            };
+            Self::record_batch(database, mined, &audit).await?;
+        }
+
+        if let Some(e) = failure {
+            error!(
+                ?e,
+                submitted = submitted_count(&submitted),
+                "Failed to insert identities to contract."
+            );
+            log_audit_failure(
+                batch_id,
+                database.mark_batch_failed(batch_id, &e.to_string()).await,
+            );
+            emit(BatchEvent::BatchFailed {
+                batch_id,
+                batch_size: batch.len(),
+                error: e.to_string(),
             });
-            let is_duplicate = tree.merkle_tree.leaves()[..tree.next_leaf].contains(&commitment);
+            // The committer stops on this error, so the notifications are
+            // delivered before returning it.
+            if let Some(webhooks) = webhooks {
+                let deliveries =
+                    Self::notify_webhooks(database, webhooks, remaining, Status::Failed, None)
+                        .await?;
+                join_all(deliveries).await;
+            }
+            return Err(e.into());
+        }
+
+        let Some(last) = submitted.last() else {
+            return Ok(processed);
+        };
+        let block = last
+            .receipt
+            .block_number
+            .expect("Transaction is mined, block number must be present.");
+        Span::current().record("block", block.as_u64());
+        let gas_used = submitted
+            .iter()
+            .map(|submitted| submitted.receipt.gas_used.map(|gas| gas.low_u64()))
+            .sum::<Option<u64>>();
+        log_audit_failure(
+            batch_id,
+            database
+                .mark_batch_mined(batch_id, block.as_u64(), gas_used)
+                .await,
+        );
+        #[allow(clippy::cast_precision_loss)]
+        BATCH_SIZE.observe(batch.len() as f64);
+        emit(BatchEvent::BatchMined {
+            batch_id,
+            batch_size: batch.len(),
+            block: block.as_u64(),
+        });
+
+        database
+            .age_unprocessed_identities()
+            .await
+            .context("Failed to age the identities left out of the batch.")?;
+
+        // ethereum_subscriber module takes over from now. Once identities are found
+        // in a confirmed block, it'll update the merkle tree and remove jobs from
+        // pending_identities queue.
+
+        Ok(processed)
+    }
+
+    /// Submits an empty batch to refresh the on-chain root, unless identities
+    /// are waiting to be committed.
+    #[instrument(level = "info", skip_all)]
+    async fn commit_empty_batch(
+        database: &Database,
+        identity_manager: &(dyn IdentityManager + Send + Sync),
+    ) -> AnyhowResult<()> {
+        if database.count_unprocessed_identities().await? > 0 {
+            debug!("Identities are pending, skipping empty batch.");
+            return Ok(());
+        }
+
+        let submitted = identity_manager
+            .register_identities(Vec::new(), None)
+            .await
+            .map_err(|e| {
+                error!(?e, "Failed to submit empty batch to contract.");
+                e
+            })?;
+        let block = submitted
+            .last()
+            .and_then(|submitted| submitted.receipt.block_number);
+        info!(?block, "Empty batch submitted.");
+
+        Ok(())
+    }
+
+    /// Fetches up to `batch_size`, and no more than the largest batch size of
+    /// the `prover_map`, of the next pending identities and drops those that
+    /// are already in the tree.
+    ///
+    /// If `batch_size_hints` is set, the batch is capped at the smallest tier
+    /// that can take the batch size hinted at by the first identity.
+    ///
+    /// Returns the number of pending identities that were considered, together
+    /// with the batch of identities to submit and the root it builds on, which
+    /// is `None` for an empty tree.
+    #[instrument(
+        level = "info",
+        skip(database, tree_state, prover_map),
+        fields(pre_root)
+    )]
+    async fn assemble_batch(
+        database: &Database,
+        tree_state: &SharedTreeState,
+        prover_map: &InsertionProverMap,
+        batch_size: usize,
+        batch_size_hints: bool,
+        drain_order: DrainOrder,
+        tie_break: TieBreak,
+    ) -> AnyhowResult<(usize, Vec<(usize, Hash)>, Option<Hash>)> {
+        let max_batch_size = prover_map.read().await.max_batch_size().min(batch_size);
+        let mut pending = database
+            .get_next_unprocessed_identities(max_batch_size, drain_order, tie_break)
+            .await
+            .with_context(|| {
+                format!("Failed to fetch up to {max_batch_size} pending identities.")
+            })?;
+        if batch_size_hints {
+            if let Some(tier) = Self::hinted_tier(database, prover_map, &pending).await? {
+                debug!(tier, "Capping batch at the hinted tier.");
+                pending.truncate(tier);
+            }
+        }
+        let processed = pending.len();
+
+        let mut batch = Vec::with_capacity(processed);
+        let tree = tree_state.read().await.unwrap_or_else(|e| {
+            error!(?e, "Failed to obtain tree lock in check_leaves.");
+            panic!("Sequencer potentially deadlocked, terminating.");
+        });
+        for (group_id, commitment) in pending {
+            let is_duplicate = tree.leaf_index(&commitment).is_some();
             if is_duplicate {
                 warn!(
                     ?commitment,
@@ -151,37 +1067,217 @@ impl IdentityCommitter {
                 );
                 database
                     .delete_pending_identity(group_id, &commitment)
-                    .await?;
-                return Ok(());
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Failed to drop duplicate identity {commitment:?} of group {group_id}."
+                        )
+                    })?;
+            } else {
+                batch.push((group_id, commitment));
             }
         }
 
-        // Send Semaphore transaction
-        let receipt = identity_manager
-            .register_identities(vec![commitment])
-            .await
-            .map_err(|e| {
-                error!(?e, "Failed to insert identity to contract.");
-                e
-            })?;
+        Span::current().record("pre_root", field::debug(tree.merkle_tree.root()));
+        let pre_root = (tree.next_leaf > 0).then(|| tree.merkle_tree.root());
 
-        let block = receipt
-            .block_number
-            .expect("Transaction is mined, block number must be present.");
+        Ok((processed, batch, pre_root))
+    }
 
-        info!("Identity submitted in block {}.", block);
-        database
-            .mark_identity_inserted(group_id, &commitment, block.as_usize())
-            .await?;
+    /// Requests the proof of inserting `commitments` into the tree from the
+    /// healthy prover of the smallest batch size that takes them, preferring
+    /// those of the preferred region, see
+    /// [`healthy_get`](crate::prover::map::ProverMap::healthy_get), and from
+    /// its replicas at once, see [`generate_proof_from_any`]. The batch is
+    /// padded with empty leaves to that batch size.
+    ///
+    /// The proof builds on the tree, so the batch fails with
+    /// [`Error::TreeBehind`] while earlier batches are mined but their
+    /// identities are not in the tree yet.
+    async fn prove_batch(
+        database: &Database,
+        identity_manager: &(dyn IdentityManager + Send + Sync),
+        tree_state: &SharedTreeState,
+        prover_map: &InsertionProverMap,
+        commitments: &[Hash],
+    ) -> AnyhowResult<InsertionProof> {
+        let provers: Vec<Prover> = {
+            let prover_map = prover_map.read().await;
+            match prover_map.healthy_get(commitments.len()).await {
+                Some(prover) => prover_map
+                    .get_with_replicas(prover.batch_size())
+                    .into_iter()
+                    .cloned()
+                    .collect(),
+                None => Vec::new(),
+            }
+        };
+        let Some(batch_size) = provers.first().map(Prover::batch_size) else {
+            return Err(anyhow!(
+                "No prover is available for a batch of {} identities.",
+                commitments.len()
+            ));
+        };
+        let start_index = database
+            .oldest_unmined_leaf_index()
+            .await?
+            .unwrap_or_default();
 
-        // ethereum_subscriber module takes over from now. Once identity is found in a
-        // confirmed block, it'll update the merkle tree and remove job from
-        // pending_identities queue.
+        let (pre_root, post_root, identities) = {
+            let tree = tree_state.read().await.unwrap_or_else(|e| {
+                error!(?e, "Failed to obtain tree lock in prove_batch.");
+                panic!("Sequencer potentially deadlocked, terminating.");
+            });
+            if tree.next_leaf != start_index {
+                return Err(Error::TreeBehind {
+                    start_index,
+                    next_leaf: tree.next_leaf,
+                }
+                .into());
+            }
+            let updates = commitments
+                .iter()
+                .copied()
+                .chain(std::iter::repeat(identity_manager.initial_leaf_value()))
+                .take(batch_size)
+                .enumerate()
+                .map(|(offset, commitment)| (start_index + offset, commitment))
+                .collect::<Vec<_>>();
+            let (post_root, proofs) = tree
+                .merkle_tree
+                .proofs_with_updates(&updates)
+                .ok_or_else(|| anyhow!("The batch does not fit in the tree."))?;
+            let identities = updates
+                .iter()
+                .zip(&proofs)
+                .map(|(&(_, commitment), proof)| Identity::from_tree_proof(commitment, proof))
+                .collect();
+            (tree.merkle_tree.root(), post_root, identities)
+        };
 
+        let start_index = u32::try_from(start_index)?;
+        let provers = provers.iter().collect::<Vec<_>>();
+        let proof = generate_proof_from_any(
+            &provers,
+            start_index,
+            pre_root.into(),
+            post_root.into(),
+            identities,
+        )
+        .await?;
+        Ok(InsertionProof {
+            proof,
+            batch_size,
+            start_index,
+            pre_root: pre_root.into(),
+            post_root: post_root.into(),
+        })
+    }
+
+    /// Returns the smallest tier that can take the batch size hinted at by the
+    /// first of the `pending` identities, if it has a hint.
+    async fn hinted_tier(
+        database: &Database,
+        prover_map: &InsertionProverMap,
+        pending: &[(usize, Hash)],
+    ) -> AnyhowResult<Option<usize>> {
+        let Some((group_id, commitment)) = pending.first() else {
+            return Ok(None);
+        };
+        let hint = database
+            .get_batch_size_hint(*group_id, commitment)
+            .await
+            .with_context(|| format!("Failed to fetch the batch size hint of {commitment:?}."))?;
+        let Some(hint) = hint else {
+            return Ok(None);
+        };
+        Ok(prover_map.read().await.get(hint).map(Prover::batch_size))
+    }
+
+    /// Checks that `pre_root` is still the latest cached root.
+    async fn check_pre_root(database: &Database, pre_root: Option<Hash>) -> AnyhowResult<()> {
+        let latest_root = database.get_latest_root().await?;
+        if latest_root != pre_root {
+            error!(
+                ?pre_root,
+                ?latest_root,
+                "Root advanced while the batch was assembled."
+            );
+            return Err(Error::StalePreRoot {
+                pre_root,
+                latest_root,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Marks the identities of a submitted batch as mined in the block of
+    /// `audit`, all of them or none, and records how they were submitted.
+    ///
+    /// The audit is informational, so failing to record it does not fail the
+    /// batch.
+    #[instrument(level = "info", skip(database, batch))]
+    async fn record_batch(
+        database: &Database,
+        batch: &[(usize, Hash)],
+        audit: &BatchAudit,
+    ) -> AnyhowResult<()> {
+        let (Some((_, first)), Some((_, last))) = (batch.first(), batch.last()) else {
+            return Ok(());
+        };
+        let block = usize::try_from(audit.block_number).unwrap_or(usize::MAX);
+        database
+            .mark_identities_inserted(batch, block)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to mark the batch of {} identities from {first:?} to {last:?} as \
+                     mined in block {block}.",
+                    batch.len()
+                )
+            })?;
+        if let Err(error) = database.record_batch_audit(batch, audit).await {
+            warn!(%error, batch_id = audit.batch_id, "Failed to record the audit of the batch.");
+        }
         Ok(())
     }
 
+    /// Delivers `status` in the background to the webhooks recorded for the
+    /// identities in `batch`, returning the deliveries.
+    async fn notify_webhooks(
+        database: &Database,
+        webhooks: &Webhooks,
+        batch: &[(usize, Hash)],
+        status: Status,
+        block: Option<u64>,
+    ) -> AnyhowResult<Vec<JoinHandle<()>>> {
+        let mut deliveries = Vec::new();
+        for &(group_id, identity_commitment) in batch {
+            let url = database
+                .get_webhook_url(group_id, &identity_commitment)
+                .await
+                .with_context(|| {
+                    format!("Failed to fetch the webhook of {identity_commitment:?}.")
+                })?;
+            if let Some(url) = url {
+                let notification = Notification {
+                    group_id,
+                    identity_commitment,
+                    status,
+                    block,
+                };
+                deliveries.push(webhooks.spawn_delivery(url, notification));
+            }
+        }
+        Ok(deliveries)
+    }
+
     pub async fn notify_queued(&self) {
+        self.announced.fetch_add(1, Ordering::Relaxed);
+        if matches!(self.batching_policy, BatchingPolicy::CostAware(_)) {
+            self.arrivals.lock().unwrap().record(Instant::now(), 1);
+        }
         // Escalate all errors to panics. In the future could perform some
         // restart procedure here.
         self.instance
@@ -207,3 +1303,1016 @@ impl IdentityCommitter {
         Ok(())
     }
 }
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Logs a failure to record a batch in the batch audit log, which is
+/// informational and does not fail the batch.
+fn log_audit_failure(batch_id: u64, result: Result<(), database::Error>) {
+    if let Err(error) = result {
+        warn!(%error, batch_id, "Failed to record the batch in the audit log.");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        contracts::mock::MockIdentityManager,
+        database::{self, ConfirmedIdentityEvent},
+        identity_tree::{Hash, TreeState},
+        prover::{self, map::ProverMap, Options as ProverOptions, Prover},
+        timed_rw_lock::TimedRwLock,
+        webhook,
+    };
+    use ethers::types::{H256, U256};
+    use std::sync::Mutex;
+    use tracing::{
+        span::{Attributes, Id},
+        Subscriber,
+    };
+    use tracing_subscriber::{
+        layer::{Context, SubscriberExt},
+        registry::LookupSpan,
+        Layer, Registry,
+    };
+    use url::Url;
+
+    /// Records the name of every span created, together with the name of its
+    /// parent.
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        spans: Arc<Mutex<Vec<(String, Option<String>)>>>,
+    }
+
+    impl<S> Layer<S> for SpanRecorder
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(&self, _: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).expect("Span must exist.");
+            let parent = span.parent().map(|parent| parent.name().to_owned());
+            self.spans
+                .lock()
+                .unwrap()
+                .push((span.name().to_owned(), parent));
+        }
+    }
+
+    fn tree_state(identity_manager: &MockIdentityManager) -> SharedTreeState {
+        Arc::new(TimedRwLock::new(
+            Duration::from_secs(1),
+            TreeState::new(
+                identity_manager.tree_depth() + 1,
+                identity_manager.initial_leaf_value(),
+            ),
+        ))
+    }
+
+    fn prover_map(batch_size: usize) -> AnyhowResult<InsertionProverMap> {
+        tiered_prover_map(&[batch_size])
+    }
+
+    fn tiered_prover_map(batch_sizes: &[usize]) -> AnyhowResult<InsertionProverMap> {
+        let mut prover_map = ProverMap::default();
+        for &batch_size in batch_sizes {
+            prover_map.add(
+                batch_size,
+                Prover::new(&ProverOptions {
+                    batch_size,
+                    ..ProverOptions::default()
+                })?,
+            );
+        }
+        Ok(Arc::new(RwLock::new(prover_map)))
+    }
+
+    fn batch_sizes(identity_manager: &MockIdentityManager) -> Vec<usize> {
+        identity_manager
+            .batches
+            .lock()
+            .unwrap()
+            .iter()
+            .map(Vec::len)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn batches_should_be_capped_at_max_prover_batch_size() -> AnyhowResult<()> {
+        const MAX_BATCH_SIZE: usize = 3;
+
+        let database = database::mock::database().await;
+        let identity_manager = MockIdentityManager::default();
+        let tree_state = tree_state(&identity_manager);
+        let prover_map = prover_map(MAX_BATCH_SIZE)?;
+
+        for i in 1..=(2 * MAX_BATCH_SIZE + 1) {
+            database
+                .insert_pending_identity(1, &Hash::from(i), 0)
+                .await?;
+        }
+
+        while IdentityCommitter::commit_next_batch(
+            &database,
+            &identity_manager,
+            &tree_state,
+            &prover_map,
+            usize::MAX,
+            DrainOrder::Priority { aging_batches: 0 },
+            TieBreak::Submission,
+            false,
+            false,
+            None,
+            None,
+            &PendingBatches::default(),
+        )
+        .await?
+            > 0
+        {}
+
+        assert_eq!(batch_sizes(&identity_manager), vec![
+            MAX_BATCH_SIZE,
+            MAX_BATCH_SIZE,
+            1
+        ]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batches_should_be_proven_by_any_replica() -> AnyhowResult<()> {
+        let failing_service = prover::mock::Service::new("0.0.0.0:3036".into()).await?;
+        let service = prover::mock::Service::new("0.0.0.0:3037".into()).await?;
+        failing_service.fail_next_proofs(1);
+        let database = database::mock::database().await;
+        let identity_manager = MockIdentityManager {
+            proves_batches: true,
+            ..MockIdentityManager::default()
+        };
+        let tree_state = tree_state(&identity_manager);
+        let initial_leaf = identity_manager.initial_leaf_value();
+        let prover = |port: u16| {
+            Prover::new(&ProverOptions {
+                mtb_prover_url: format!("http://localhost:{port}"),
+                batch_size: 2,
+                ..ProverOptions::default()
+            })
+        };
+        let mut prover_map = ProverMap::default();
+        prover_map.add_replica(2, prover(3036)?);
+        prover_map.add_replica(2, prover(3037)?);
+        let prover_map = Arc::new(RwLock::new(prover_map));
+
+        // The mock prover only proves batches with an odd post root.
+        let empty_root = tree_state.read().await?.merkle_tree.root();
+        let (identity, post_root) = (1_u64..)
+            .map(|identity| {
+                let mut tree = TreeState::new(identity_manager.tree_depth() + 1, initial_leaf);
+                tree.merkle_tree.set(0, Hash::from(identity));
+                (Hash::from(identity), tree.merkle_tree.root())
+            })
+            .find(|(_, post_root)| U256::from(*post_root).bit(0))
+            .unwrap();
+        database.insert_pending_identity(1, &identity, 0).await?;
+
+        let commit = || {
+            IdentityCommitter::commit_next_batch(
+                &database,
+                &identity_manager,
+                &tree_state,
+                &prover_map,
+                usize::MAX,
+                DrainOrder::Oldest,
+                TieBreak::Submission,
+                false,
+                false,
+                None,
+                None,
+                &PendingBatches::default(),
+            )
+        };
+        assert_eq!(commit().await?, 1);
+        let proofs = identity_manager.proofs.lock().unwrap().clone();
+        let [Some(proof)] = &proofs[..] else {
+            panic!("The batch should be submitted with its proof.");
+        };
+        assert_eq!(proof.batch_size, 2);
+        assert_eq!(proof.start_index, 0);
+        assert_eq!(proof.pre_root, U256::from(empty_root));
+        assert_eq!(proof.post_root, U256::from(post_root));
+
+        // The next batch waits for the tree to hold the mined one.
+        database
+            .insert_pending_identity(1, &Hash::from(0xff_u64), 0)
+            .await?;
+        let error = commit().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::TreeBehind {
+                start_index: 1,
+                next_leaf:   0,
+            })
+        ));
+
+        failing_service.stop();
+        service.stop();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batch_size_hint_should_bias_tier_selection() -> AnyhowResult<()> {
+        async fn commit_all(
+            database: &Database,
+            identity_manager: &MockIdentityManager,
+            tree_state: &SharedTreeState,
+            prover_map: &InsertionProverMap,
+        ) -> AnyhowResult<()> {
+            while IdentityCommitter::commit_next_batch(
+                database,
+                identity_manager,
+                tree_state,
+                prover_map,
+                usize::MAX,
+                DrainOrder::Oldest,
+                TieBreak::Submission,
+                false,
+                true,
+                None,
+                None,
+                &PendingBatches::default(),
+            )
+            .await?
+                > 0
+            {}
+            Ok(())
+        }
+
+        let database = database::mock::database().await;
+        let identity_manager = MockIdentityManager::default();
+        let tree_state = tree_state(&identity_manager);
+        let prover_map = tiered_prover_map(&[3, 10])?;
+
+        // The first identity asks for a small batch, which pending allows.
+        database
+            .insert_pending_identity_with_options(
+                1,
+                &Hash::from(1_u64),
+                0,
+                Some(2),
+                None,
+                None,
+                None,
+            )
+            .await?;
+        for i in 2..=8_u64 {
+            database
+                .insert_pending_identity(1, &Hash::from(i), 0)
+                .await?;
+        }
+        commit_all(&database, &identity_manager, &tree_state, &prover_map).await?;
+        assert_eq!(batch_sizes(&identity_manager), vec![3, 5]);
+
+        // No tier is large enough for the hint, so it is ignored.
+        database
+            .insert_pending_identity_with_options(
+                1,
+                &Hash::from(9_u64),
+                0,
+                Some(50),
+                None,
+                None,
+                None,
+            )
+            .await?;
+        for i in 10..=12_u64 {
+            database
+                .insert_pending_identity(1, &Hash::from(i), 0)
+                .await?;
+        }
+        commit_all(&database, &identity_manager, &tree_state, &prover_map).await?;
+        assert_eq!(batch_sizes(&identity_manager), vec![3, 5, 4]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn low_priority_identities_should_not_starve() -> AnyhowResult<()> {
+        const PRIORITY_AGING: u64 = 2;
+
+        let database = database::mock::database().await;
+        let identity_manager = MockIdentityManager::default();
+        let tree_state = tree_state(&identity_manager);
+        let prover_map = prover_map(1)?;
+
+        let low_priority = Hash::from(1_u64);
+        database
+            .insert_pending_identity(1, &low_priority, 0)
+            .await?;
+
+        // Keep a fresh high priority identity waiting in every round.
+        let mut batched_in_round = None;
+        for round in 0..10_u64 {
+            database
+                .insert_pending_identity(1, &Hash::from(100 + round), 1)
+                .await?;
+            IdentityCommitter::commit_next_batch(
+                &database,
+                &identity_manager,
+                &tree_state,
+                &prover_map,
+                usize::MAX,
+                DrainOrder::Priority {
+                    aging_batches: PRIORITY_AGING,
+                },
+                TieBreak::Submission,
+                false,
+                false,
+                None,
+                None,
+                &PendingBatches::default(),
+            )
+            .await?;
+
+            let batches = identity_manager.batches.lock().unwrap();
+            if batches.last() == Some(&vec![low_priority]) {
+                batched_in_round = Some(round);
+                break;
+            }
+        }
+
+        assert_eq!(batched_in_round, Some(PRIORITY_AGING));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn identities_within_linger_should_be_coalesced() {
+        let (wake_up_sender, mut wake_up_receiver) = mpsc::channel(1);
+        let (_shutdown_sender, mut shutdown_receiver) = mpsc::channel(1);
+        let announced = Arc::new(AtomicUsize::new(0));
+        let start = Instant::now();
+
+        let notifier = {
+            let announced = announced.clone();
+            tokio::spawn(async move {
+                sleep(Duration::from_millis(100)).await;
+                for _ in 0..2 {
+                    announced.fetch_add(1, Ordering::Relaxed);
+                    let _ = wake_up_sender.try_send(());
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        let interrupted = IdentityCommitter::linger(
+            &mut wake_up_receiver,
+            &mut shutdown_receiver,
+            &announced,
+            1,
+            3,
+            Duration::from_secs(60),
+        )
+        .await;
+        notifier.await.unwrap();
+
+        // The batch filled up as soon as the last identity was announced.
+        assert!(!interrupted);
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn linger_should_elapse_without_enough_identities() {
+        let (_wake_up_sender, mut wake_up_receiver) = mpsc::channel(1);
+        let (shutdown_sender, mut shutdown_receiver) = mpsc::channel(1);
+        let announced = AtomicUsize::new(0);
+        let start = Instant::now();
+
+        let interrupted = IdentityCommitter::linger(
+            &mut wake_up_receiver,
+            &mut shutdown_receiver,
+            &announced,
+            1,
+            3,
+            Duration::from_secs(60),
+        )
+        .await;
+        assert!(!interrupted);
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+
+        shutdown_sender.send(()).await.unwrap();
+        let interrupted = IdentityCommitter::linger(
+            &mut wake_up_receiver,
+            &mut shutdown_receiver,
+            &announced,
+            1,
+            3,
+            Duration::from_secs(60),
+        )
+        .await;
+        assert!(interrupted);
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn queue_should_be_drained_on_shutdown() -> AnyhowResult<()> {
+        let database = Arc::new(database::mock::database().await);
+        let identity_manager = Arc::new(MockIdentityManager::default());
+        let committer = IdentityCommitter::new(
+            database.clone(),
+            identity_manager.clone(),
+            tree_state(&identity_manager),
+            prover_map(10)?,
+            Duration::from_secs(60),
+            0,
+            Duration::ZERO,
+        )
+        .with_shutdown_drain(Duration::from_secs(10));
+        committer.start().await;
+
+        for i in 1..=3_u64 {
+            database
+                .insert_pending_identity(1, &Hash::from(i), 0)
+                .await?;
+        }
+        committer.notify_queued().await;
+        sleep(Duration::from_millis(100)).await;
+        assert!(batch_sizes(&identity_manager).is_empty());
+
+        // The lingering batch is committed right away instead of being left.
+        committer.shutdown().await?;
+        assert_eq!(batch_sizes(&identity_manager), vec![3]);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rapid_notifications_should_be_coalesced() -> AnyhowResult<()> {
+        const IDENTITIES: usize = 50;
+
+        let database = Arc::new(database::mock::database().await);
+        let identity_manager = Arc::new(MockIdentityManager::default());
+        let committer = IdentityCommitter::new(
+            database.clone(),
+            identity_manager.clone(),
+            tree_state(&identity_manager),
+            prover_map(10)?,
+            Duration::ZERO,
+            0,
+            Duration::ZERO,
+        )
+        .with_coalesced_wake_ups(true);
+        committer.start().await;
+
+        for i in 1..=IDENTITIES {
+            database
+                .insert_pending_identity(1, &Hash::from(i), 0)
+                .await?;
+        }
+        for _ in 0..IDENTITIES {
+            committer.notify_queued().await;
+        }
+
+        for _ in 0..1000 {
+            if batch_sizes(&identity_manager).iter().sum::<usize>() == IDENTITIES {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        committer.shutdown().await?;
+
+        assert_eq!(
+            batch_sizes(&identity_manager).iter().sum::<usize>(),
+            IDENTITIES
+        );
+        assert_eq!(committer.wake_ups(), 1);
+        // Every announced identity was picked up.
+        assert_eq!(committer.announced(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batches_should_be_spaced_by_min_interval() -> AnyhowResult<()> {
+        const MIN_INTERVAL: Duration = Duration::from_secs(10);
+
+        let database = Arc::new(database::mock::database().await);
+        let identity_manager = Arc::new(MockIdentityManager::default());
+        let committer = IdentityCommitter::new(
+            database.clone(),
+            identity_manager.clone(),
+            tree_state(&identity_manager),
+            prover_map(2)?,
+            Duration::ZERO,
+            0,
+            Duration::ZERO,
+        )
+        .with_min_batch_interval(MIN_INTERVAL);
+        committer.start().await;
+
+        for i in 1..=5_u64 {
+            database
+                .insert_pending_identity(1, &Hash::from(i), 0)
+                .await?;
+        }
+        committer.notify_queued().await;
+
+        for _ in 0..1000 {
+            if batch_sizes(&identity_manager).len() == 3 {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        committer.shutdown().await?;
+
+        assert_eq!(batch_sizes(&identity_manager), vec![2, 2, 1]);
+        let submitted_at = identity_manager.submitted_at.lock().unwrap().clone();
+        for pair in submitted_at.windows(2) {
+            assert!(pair[1] - pair[0] >= MIN_INTERVAL);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dedicated_runtime_should_run_committer() -> AnyhowResult<()> {
+        let database = Arc::new(database::mock::database().await);
+        let identity_manager = Arc::new(MockIdentityManager::default());
+        let committer = IdentityCommitter::new(
+            database.clone(),
+            identity_manager.clone(),
+            tree_state(&identity_manager),
+            prover_map(2)?,
+            Duration::ZERO,
+            0,
+            Duration::ZERO,
+        )
+        .with_dedicated_runtime(Some(1));
+        committer.start().await;
+
+        for i in 1..=3_u64 {
+            database
+                .insert_pending_identity(1, &Hash::from(i), 0)
+                .await?;
+        }
+        committer.notify_queued().await;
+
+        for _ in 0..100 {
+            if batch_sizes(&identity_manager).len() == 2 {
+                break;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        committer.shutdown().await?;
+
+        assert_eq!(batch_sizes(&identity_manager), vec![2, 1]);
+        let submitted_from = identity_manager.submitted_from.lock().unwrap().clone();
+        assert_eq!(submitted_from, vec![
+            Some(DEDICATED_THREAD_NAME.to_owned());
+            2
+        ]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn empty_batches_should_be_submitted_when_idle() -> AnyhowResult<()> {
+        let database = Arc::new(database::mock::database().await);
+        let identity_manager = Arc::new(MockIdentityManager::default());
+        let committer = IdentityCommitter::new(
+            database.clone(),
+            identity_manager.clone(),
+            tree_state(&identity_manager),
+            prover_map(10)?,
+            Duration::ZERO,
+            0,
+            Duration::from_millis(200),
+        );
+        committer.start().await;
+
+        sleep(Duration::from_millis(500)).await;
+        committer.shutdown().await?;
+
+        let sizes = batch_sizes(&identity_manager);
+        assert!(!sizes.is_empty(), "No empty batch was submitted.");
+        assert!(sizes.iter().all(|&size| size == 0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_pre_root_should_fail_batch() -> AnyhowResult<()> {
+        let database = database::mock::database().await;
+        let identity_manager = MockIdentityManager::default();
+        let tree_state = tree_state(&identity_manager);
+
+        database
+            .insert_pending_identity(1, &Hash::from(1_u64), 0)
+            .await?;
+        let (_, batch, pre_root) = IdentityCommitter::assemble_batch(
+            &database,
+            &tree_state,
+            &prover_map(10)?,
+            usize::MAX,
+            false,
+            DrainOrder::Oldest,
+            TieBreak::Submission,
+        )
+        .await?;
+        assert_eq!(batch.len(), 1);
+        IdentityCommitter::check_pre_root(&database, pre_root).await?;
+
+        // The root advances before the batch is submitted.
+        database
+            .save_log(&ConfirmedIdentityEvent {
+                block_index:       1,
+                transaction_index: 0,
+                log_index:         0,
+                raw_log:           String::new(),
+                leaf:              Hash::from(2_u64),
+                root:              Hash::from(3_u64),
+            })
+            .await?;
+
+        let error = IdentityCommitter::check_pre_root(&database, pre_root)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::StalePreRoot {
+                pre_root:    None,
+                latest_root: Some(_),
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn batches_should_be_pending_until_their_stage_is_left() {
+        let pending_batches = PendingBatches::default();
+        let submission = PendingStage::enter(&pending_batches.submission, SUBMISSION);
+        let mining = PendingStage::enter(&pending_batches.mining, MINING);
+        assert_eq!(pending_batches.submission.load(Ordering::Relaxed), 1);
+        assert_eq!(pending_batches.mining.load(Ordering::Relaxed), 1);
+
+        drop(submission);
+        assert_eq!(pending_batches.submission.load(Ordering::Relaxed), 0);
+        drop(mining);
+        assert_eq!(pending_batches.mining.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn database_errors_should_name_the_identity() -> AnyhowResult<()> {
+        let database = database::mock::database().await;
+        let commitment = Hash::from(7_u64);
+        database.insert_pending_identity(1, &commitment, 0).await?;
+        database::mock::fail_pending_identity_updates(&database).await;
+
+        let audit = BatchAudit {
+            batch_id:     0,
+            tx_hash:      H256::zero(),
+            block_number: 12,
+        };
+        let error = IdentityCommitter::record_batch(&database, &[(1, commitment)], &audit)
+            .await
+            .unwrap_err();
+        let message = format!("{error:#}");
+        assert!(message.contains(&format!("{commitment:?}")), "{message}");
+        assert!(message.contains("block 12"), "{message}");
+        assert!(message.contains("injected failure"), "{message}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn webhook_should_not_be_called_before_confirmation() -> AnyhowResult<()> {
+        let receiver = webhook::mock::Receiver::new("0.0.0.0:3018")?;
+        let url: Url = "http://localhost:3018/webhook".parse()?;
+        let database = database::mock::database().await;
+        let identity_manager = MockIdentityManager::default();
+        let tree_state = tree_state(&identity_manager);
+        let prover_map = prover_map(3)?;
+
+        database
+            .insert_pending_identity_with_options(
+                1,
+                &Hash::from(1_u64),
+                0,
+                None,
+                Some(&url),
+                None,
+                None,
+            )
+            .await?;
+        database
+            .insert_pending_identity(1, &Hash::from(2_u64), 0)
+            .await?;
+        IdentityCommitter::commit_next_batch(
+            &database,
+            &identity_manager,
+            &tree_state,
+            &prover_map,
+            usize::MAX,
+            DrainOrder::Oldest,
+            TieBreak::Submission,
+            false,
+            false,
+            Some(&Webhooks::new(3).with_private_addresses(true)),
+            None,
+            &PendingBatches::default(),
+        )
+        .await?;
+
+        // The subscriber notifies the webhook once the insertion is confirmed.
+        sleep(Duration::from_millis(200)).await;
+        assert!(receiver.notifications().is_empty());
+
+        receiver.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batch_events_should_follow_lifecycle() -> AnyhowResult<()> {
+        let database = database::mock::database().await;
+        let identity_manager = MockIdentityManager::default();
+        let tree_state = tree_state(&identity_manager);
+        let prover_map = prover_map(3)?;
+        let batch_events = BatchEvents::new(16);
+        let mut receiver = batch_events.subscribe();
+
+        let commitments = [1_u64, 2].map(Hash::from);
+        for commitment in &commitments {
+            database.insert_pending_identity(1, commitment, 0).await?;
+        }
+        IdentityCommitter::commit_next_batch(
+            &database,
+            &identity_manager,
+            &tree_state,
+            &prover_map,
+            usize::MAX,
+            DrainOrder::Oldest,
+            TieBreak::Submission,
+            false,
+            false,
+            None,
+            Some(&batch_events),
+            &PendingBatches::default(),
+        )
+        .await?;
+
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            events.push(event);
+        }
+        let Some(&BatchEvent::BatchAssembled { batch_id, .. }) = events.first() else {
+            panic!("The batch should be assembled first, got {events:?}.");
+        };
+        assert_eq!(events, vec![
+            BatchEvent::BatchAssembled {
+                batch_id,
+                pre_root: None,
+                commitments: commitments.to_vec(),
+            },
+            BatchEvent::BatchSubmitted {
+                batch_id,
+                batch_size: 2,
+            },
+            BatchEvent::BatchMined {
+                batch_id,
+                batch_size: 2,
+                block: 1,
+            },
+        ]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_batches_should_be_retried() -> AnyhowResult<()> {
+        let database = database::mock::database().await;
+        let identity_manager = MockIdentityManager::default();
+        let tree_state = tree_state(&identity_manager);
+        let batch_events = BatchEvents::new(16);
+        let mut receiver = batch_events.subscribe();
+        database
+            .insert_pending_identity(1, &Hash::from(1_u64), 0)
+            .await?;
+        // The cache holds a root the tree has not caught up with.
+        database
+            .save_log(&ConfirmedIdentityEvent {
+                block_index:       1,
+                transaction_index: 0,
+                log_index:         0,
+                raw_log:           String::new(),
+                leaf:              Hash::from(2_u64),
+                root:              Hash::from(3_u64),
+            })
+            .await?;
+
+        let error = IdentityCommitter::commit_next_batch(
+            &database,
+            &identity_manager,
+            &tree_state,
+            &prover_map(3)?,
+            usize::MAX,
+            DrainOrder::Oldest,
+            TieBreak::Submission,
+            true,
+            false,
+            None,
+            Some(&batch_events),
+            &PendingBatches::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.is::<Error>());
+
+        let Ok(BatchEvent::BatchAssembled { batch_id, .. }) = receiver.try_recv() else {
+            panic!("The batch should be assembled.");
+        };
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(BatchEvent::BatchRetried { batch_id: retried, batch_size: 1, .. })
+                if retried == batch_id
+        ));
+        assert!(receiver.try_recv().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn submitted_batches_should_be_audited() -> AnyhowResult<()> {
+        let database = database::mock::database().await;
+        let identity_manager = MockIdentityManager::default();
+        let tree_state = tree_state(&identity_manager);
+        let prover_map = prover_map(3)?;
+        let batch_events = BatchEvents::new(16);
+        let mut receiver = batch_events.subscribe();
+
+        let commitments = [1_u64, 2].map(Hash::from);
+        for commitment in &commitments {
+            database.insert_pending_identity(1, commitment, 0).await?;
+        }
+        IdentityCommitter::commit_next_batch(
+            &database,
+            &identity_manager,
+            &tree_state,
+            &prover_map,
+            usize::MAX,
+            DrainOrder::Oldest,
+            TieBreak::Submission,
+            false,
+            false,
+            None,
+            Some(&batch_events),
+            &PendingBatches::default(),
+        )
+        .await?;
+        let Ok(BatchEvent::BatchAssembled { batch_id, .. }) = receiver.try_recv() else {
+            panic!("The batch should be assembled.");
+        };
+
+        let record = database
+            .get_batch(batch_id)
+            .await?
+            .expect("Submitted batch should be recorded.");
+        assert_eq!(record.batch.commitments, commitments.to_vec());
+        assert_eq!(record.batch.pre_root, None);
+        assert_eq!(record.block_number, Some(1));
+        assert_eq!(record.tx_hashes, vec![H256::zero()]);
+        assert_eq!(record.error, None);
+        assert!(record.settled_at >= Some(record.batch.submitted_at));
+
+        let audit = database
+            .get_identity_audit(1, &commitments[0])
+            .await?
+            .and_then(|audit| audit.batch);
+        assert_eq!(audit.map(|audit| audit.batch_id), Some(batch_id));
+        assert!(database.next_batch_id().await? > batch_id);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn partially_submitted_batch_should_record_what_went_through() -> AnyhowResult<()> {
+        let database = database::mock::database().await;
+        let identity_manager = MockIdentityManager::default();
+        let tree_state = tree_state(&identity_manager);
+        let commitments = [1_u64, 2, 3].map(Hash::from);
+        for commitment in &commitments {
+            database.insert_pending_identity(1, commitment, 0).await?;
+        }
+        *identity_manager.failing_identity.lock().unwrap() = Some(commitments[2]);
+        let batch_events = BatchEvents::new(16);
+        let mut receiver = batch_events.subscribe();
+
+        let result = IdentityCommitter::commit_next_batch(
+            &database,
+            &identity_manager,
+            &tree_state,
+            &prover_map(3)?,
+            usize::MAX,
+            DrainOrder::Oldest,
+            TieBreak::Submission,
+            false,
+            false,
+            None,
+            Some(&batch_events),
+            &PendingBatches::default(),
+        )
+        .await;
+        assert!(result.is_err());
+
+        // The batch is reported as failed, never as submitted.
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            events.push(event);
+        }
+        assert!(matches!(events.as_slice(), [
+            BatchEvent::BatchAssembled { .. },
+            BatchEvent::BatchFailed { batch_size: 3, .. },
+        ]));
+
+        // Every identity that went through is recorded in its own block, and
+        // only the failed one is left to be submitted again.
+        for (block, commitment) in (1..).zip(&commitments[..2]) {
+            let audit = database
+                .get_identity_audit(1, commitment)
+                .await?
+                .and_then(|audit| audit.batch);
+            assert_eq!(audit.map(|audit| audit.block_number), Some(block));
+        }
+        assert_eq!(database.count_unprocessed_identities().await?, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batch_lifecycle_should_be_a_single_trace() -> AnyhowResult<()> {
+        let recorder = SpanRecorder::default();
+        let _guard = tracing::subscriber::set_default(Registry::default().with(recorder.clone()));
+
+        let database = database::mock::database().await;
+        let identity_manager = MockIdentityManager::default();
+        let tree_state = tree_state(&identity_manager);
+        let prover_map = prover_map(3)?;
+
+        database
+            .insert_pending_identity(1, &Hash::from(1_u64), 0)
+            .await?;
+        IdentityCommitter::commit_next_batch(
+            &database,
+            &identity_manager,
+            &tree_state,
+            &prover_map,
+            usize::MAX,
+            DrainOrder::Priority { aging_batches: 0 },
+            TieBreak::Submission,
+            false,
+            false,
+            None,
+            None,
+            &PendingBatches::default(),
+        )
+        .await?;
+
+        let spans = recorder.spans.lock().unwrap().clone();
+        let batch = ("batch".to_owned(), None);
+        let stages = ["assemble_batch", "submit_batch", "record_batch"]
+            .map(|stage| (stage.to_owned(), Some("batch".to_owned())));
+        let lifecycle = spans
+            .into_iter()
+            .filter(|span| span == &batch || stages.contains(span))
+            .collect::<Vec<_>>();
+        assert_eq!(lifecycle, [vec![batch], stages.to_vec()].concat());
+
+        Ok(())
+    }
+
+    #[test]
+    fn throughput_should_average_over_window() {
+        let mut throughput = Throughput::new(Duration::from_secs(30));
+        let start = Instant::now();
+
+        // 10 identities every 2 seconds, for a minute.
+        let mut rate = 0.0;
+        for batch in 0..30 {
+            rate = throughput.record(start + Duration::from_secs(batch * 2), 10);
+        }
+        assert!((rate - 5.0).abs() < 0.5, "rate {rate}");
+
+        // Half the rate once the window only holds the slower batches.
+        for batch in 30..60 {
+            rate = throughput.record(start + Duration::from_secs(batch * 2), 5);
+        }
+        assert!((rate - 2.5).abs() < 0.25, "rate {rate}");
+
+        // Idle checks let the rate decay to zero.
+        rate = throughput.record(start + Duration::from_secs(200), 0);
+        assert!(rate.abs() < f64::EPSILON, "rate {rate}");
+    }
+}
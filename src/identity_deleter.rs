@@ -0,0 +1,684 @@
+use crate::{
+    contracts::{submitted_count, IdentityManager, SharedIdentityManager, Submitted},
+    database::Database,
+    identity_tree::{Hash, SharedTreeState, TreeHasher},
+    prover::{identity::Identity, map::DeletionProverMap},
+    utils::spawn_or_abort,
+};
+use anyhow::{anyhow, Context as _, Result as AnyhowResult};
+use semaphore::poseidon_tree::Proof;
+use std::{future, sync::Arc, time::Duration};
+use tokio::{
+    select,
+    sync::{mpsc, oneshot, RwLock},
+    task::JoinHandle,
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{error, info, instrument, warn};
+
+/// Number of deletion requests that may be waiting for the deleter before
+/// callers are made to wait.
+const DELETE_QUEUE_SIZE: usize = 100;
+
+/// A request to delete an identity, together with the channel on which the
+/// outcome is reported.
+pub struct IdentityDelete {
+    pub group_id:    usize,
+    pub identity:    Hash,
+    /// The identity to insert in place of the deleted one, for a recovery.
+    pub replacement: Option<Hash>,
+    pub on_complete: oneshot::Sender<OnDeleteComplete>,
+}
+
+/// The outcome of an [`IdentityDelete`] request.
+#[derive(Debug)]
+pub enum OnDeleteComplete {
+    /// The identity is not in the tree.
+    NotFound,
+    /// The identity is already queued for deletion, so it cannot be
+    /// recovered.
+    AlreadyQueued,
+    /// The identity has been queued for deletion. Carries the inclusion proof
+    /// of the emptied leaf against the root the tree will have once the
+    /// deletion is applied.
+    Deleted { root: Hash, proof: Proof },
+}
+
+struct RunningInstance {
+    #[allow(dead_code)]
+    handle:          JoinHandle<()>,
+    request_sender:  mpsc::Sender<IdentityDelete>,
+    shutdown_sender: mpsc::Sender<()>,
+}
+
+impl RunningInstance {
+    async fn shutdown(self) -> AnyhowResult<()> {
+        info!("Sending a shutdown signal to the deleter.");
+        // Ignoring errors here, since the channel can only be closed if the deleter
+        // thread is already dead.
+        let _ = self.shutdown_sender.send(()).await;
+        info!("Awaiting deleter shutdown.");
+        self.handle.await?;
+        Ok(())
+    }
+}
+
+/// A worker that queues identities for deletion, and submits the queued
+/// deletions on chain in batches.
+///
+/// Requests are processed one at a time, in the order they are received, so
+/// that the lookup of an identity's leaf and the recording of its deletion
+/// cannot interleave with another request. Like the
+/// [`IdentityCommitter`](crate::identity_committer::IdentityCommitter), it
+/// assumes that there's only one such worker spawned at a time.
+pub struct IdentityDeleter {
+    instance:         RwLock<Option<RunningInstance>>,
+    database:         Arc<Database>,
+    identity_manager: SharedIdentityManager,
+    tree_state:       SharedTreeState,
+    initial_leaf:     Hash,
+    batch_interval:   Duration,
+    max_batch_size:   usize,
+    prover_map:       Option<DeletionProverMap>,
+}
+
+impl IdentityDeleter {
+    pub fn new(
+        database: Arc<Database>,
+        identity_manager: SharedIdentityManager,
+        tree_state: SharedTreeState,
+    ) -> Self {
+        let initial_leaf = identity_manager.initial_leaf_value();
+        Self {
+            instance: RwLock::new(None),
+            database,
+            identity_manager,
+            tree_state,
+            initial_leaf,
+            batch_interval: Duration::ZERO,
+            max_batch_size: 0,
+            prover_map: None,
+        }
+    }
+
+    /// Submits up to `max_batch_size` queued deletions on chain every
+    /// `interval`. Deletions are only queued while either is zero.
+    #[must_use]
+    pub const fn with_batches(mut self, interval: Duration, max_batch_size: usize) -> Self {
+        self.batch_interval = interval;
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Has every batch of deletions proven by the prover of its size in
+    /// `prover_map` before it is submitted. A prover proves batches of exactly
+    /// its size, so deletions stay queued until they fill one.
+    #[must_use]
+    pub fn with_provers(mut self, prover_map: DeletionProverMap) -> Self {
+        self.prover_map = Some(prover_map);
+        self
+    }
+
+    /// Returns a deleter configured like this one that works on `tree_state`.
+    #[must_use]
+    pub fn with_tree_state(&self, tree_state: SharedTreeState) -> Self {
+        Self {
+            prover_map: self.prover_map.clone(),
+            ..Self::new(
+                self.database.clone(),
+                self.identity_manager.clone(),
+                tree_state,
+            )
+            .with_batches(self.batch_interval, self.max_batch_size)
+        }
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    pub async fn start(&self) {
+        let mut instance = self.instance.write().await;
+        if instance.is_some() {
+            warn!("Identity deleter already running");
+            return;
+        }
+        let (shutdown_sender, mut shutdown_receiver) = mpsc::channel(1);
+        let (request_sender, mut request_receiver) =
+            mpsc::channel::<IdentityDelete>(DELETE_QUEUE_SIZE);
+        let database = self.database.clone();
+        let identity_manager = self.identity_manager.clone();
+        let tree_state = self.tree_state.clone();
+        let initial_leaf = self.initial_leaf;
+        let max_batch_size = self.max_batch_size;
+        let prover_map = self.prover_map.clone();
+        let mut batch_timer = (!self.batch_interval.is_zero() && max_batch_size > 0).then(|| {
+            let mut timer = interval(self.batch_interval);
+            timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            timer
+        });
+        let handle = spawn_or_abort(async move {
+            loop {
+                let next_batch = async {
+                    match &mut batch_timer {
+                        Some(timer) => {
+                            timer.tick().await;
+                        }
+                        None => future::pending().await,
+                    }
+                };
+                select! {
+                    request = request_receiver.recv() => {
+                        let Some(request) = request else {
+                            info!("Deletion queue closed, exiting.");
+                            return Ok(());
+                        };
+                        let outcome = Self::delete_identity(
+                            &database,
+                            &tree_state,
+                            initial_leaf,
+                            request.group_id,
+                            &request.identity,
+                            request.replacement.as_ref(),
+                        )
+                        .await?;
+                        // The requester may have given up waiting, in which case there is
+                        // nobody left to tell.
+                        let _ = request.on_complete.send(outcome);
+                    }
+                    _ = next_batch => {
+                        Self::submit_deletions(
+                            &database,
+                            identity_manager.as_ref(),
+                            &tree_state,
+                            prover_map.as_ref(),
+                            initial_leaf,
+                            max_batch_size,
+                        )
+                        .await?;
+                    }
+                    _ = shutdown_receiver.recv() => {
+                        info!("Woke up by shutdown signal, exiting.");
+                        return Ok(());
+                    }
+                }
+            }
+        });
+        *instance = Some(RunningInstance {
+            handle,
+            request_sender,
+            shutdown_sender,
+        });
+    }
+
+    /// Hands `identity` to the deleter and waits for the outcome.
+    ///
+    /// # Errors
+    ///
+    /// Will return an Error if the deleter is not running or terminates before
+    /// the request is processed.
+    pub async fn delete(&self, group_id: usize, identity: Hash) -> AnyhowResult<OnDeleteComplete> {
+        self.request(group_id, identity, None).await
+    }
+
+    /// Hands the recovery of `identity` to the deleter and waits for the
+    /// outcome. The deletion of `identity` and the insertion of `replacement`
+    /// are queued together, and `replacement` is only committed once the
+    /// deletion is mined.
+    ///
+    /// # Errors
+    ///
+    /// Will return an Error if the deleter is not running or terminates before
+    /// the request is processed.
+    pub async fn recover(
+        &self,
+        group_id: usize,
+        identity: Hash,
+        replacement: Hash,
+    ) -> AnyhowResult<OnDeleteComplete> {
+        self.request(group_id, identity, Some(replacement)).await
+    }
+
+    async fn request(
+        &self,
+        group_id: usize,
+        identity: Hash,
+        replacement: Option<Hash>,
+    ) -> AnyhowResult<OnDeleteComplete> {
+        let (on_complete, outcome) = oneshot::channel();
+        self.instance
+            .read()
+            .await
+            .as_ref()
+            .ok_or_else(|| anyhow!("Deleter not running."))?
+            .request_sender
+            .send(IdentityDelete {
+                group_id,
+                identity,
+                replacement,
+                on_complete,
+            })
+            .await
+            .map_err(|_| anyhow!("Deleter thread terminated unexpectedly."))?;
+        outcome
+            .await
+            .map_err(|_| anyhow!("Deleter dropped the request."))
+    }
+
+    /// Looks up the leaf holding `identity` and records its deletion, together
+    /// with the insertion of its `replacement` if it is recovered.
+    ///
+    /// The tree itself is left untouched: it mirrors the chain, so the leaf is
+    /// only emptied once the deletion is submitted there.
+    #[instrument(level = "info", skip(database, tree_state, initial_leaf))]
+    async fn delete_identity(
+        database: &Database,
+        tree_state: &SharedTreeState,
+        initial_leaf: Hash,
+        group_id: usize,
+        identity: &Hash,
+        replacement: Option<&Hash>,
+    ) -> AnyhowResult<OnDeleteComplete> {
+        let (proof, hasher) = {
+            let tree = tree_state.read().await.unwrap_or_else(|e| {
+                error!(?e, "Failed to obtain tree lock in delete_identity.");
+                panic!("Sequencer potentially deadlocked, terminating.");
+            });
+            let Some(leaf_index) = tree.leaf_index(identity) else {
+                return Ok(OnDeleteComplete::NotFound);
+            };
+            let proof = tree
+                .merkle_tree
+                .proof(leaf_index)
+                .ok_or_else(|| anyhow!("Leaf index {leaf_index} out of bounds."))?;
+            (proof, tree.merkle_tree.hasher())
+        };
+
+        let queued = database.pending_deletion_exists(group_id, identity).await?;
+        match replacement {
+            Some(_) if queued => {
+                warn!(
+                    ?identity,
+                    "Identity already queued for deletion, not recovering it."
+                );
+                return Ok(OnDeleteComplete::AlreadyQueued);
+            }
+            Some(replacement) => {
+                database
+                    .insert_recovery(group_id, identity, replacement)
+                    .await?;
+            }
+            None if queued => warn!(?identity, "Identity already queued for deletion."),
+            None => database.insert_pending_deletion(group_id, identity).await?,
+        }
+
+        // Emptying a leaf leaves its siblings unchanged, so the existing proof
+        // path also proves the empty leaf against the post-deletion root.
+        let root = hasher.proof_root(initial_leaf, &proof);
+        Ok(OnDeleteComplete::Deleted { root, proof })
+    }
+
+    /// Submits up to `max_batch_size` queued deletions on chain, and returns
+    /// the number of deletions submitted.
+    ///
+    /// Each proof is taken against the tree as it is once the deletions before
+    /// it have been applied, starting with those already mined whose events
+    /// are not confirmed yet. The tree itself is left untouched, its leaves
+    /// are only emptied by the confirmed events. If the submission fails, the
+    /// deletions that did not go through stay queued for the next batch.
+    ///
+    /// With a `prover_map`, the batch is cut to the largest batch size it
+    /// fills and proven by the prover of that size. The deletions stay queued
+    /// if the batch fills no batch size or cannot be proven.
+    #[instrument(level = "info", skip_all)]
+    async fn submit_deletions(
+        database: &Database,
+        identity_manager: &(dyn IdentityManager + Send + Sync),
+        tree_state: &SharedTreeState,
+        prover_map: Option<&DeletionProverMap>,
+        initial_leaf: Hash,
+        max_batch_size: usize,
+    ) -> AnyhowResult<usize> {
+        let queued = database.get_next_unmined_deletions(max_batch_size).await?;
+        if queued.is_empty() {
+            return Ok(0);
+        }
+
+        let mined = database.get_mined_deletions().await?;
+
+        // The leaf indices stay valid while the tree lock is released to pick
+        // a prover, since insertions only append leaves and deletions only
+        // empty them.
+        let mut batch = Vec::with_capacity(queued.len());
+        let mut missing = Vec::new();
+        let emptied = {
+            let tree = tree_state.read().await.unwrap_or_else(|e| {
+                error!(?e, "Failed to obtain tree lock in submit_deletions.");
+                panic!("Sequencer potentially deadlocked, terminating.");
+            });
+            for (group_id, identity) in queued {
+                match tree.leaf_index(&identity) {
+                    Some(leaf_index) => batch.push((group_id, identity, leaf_index)),
+                    None => missing.push((group_id, identity)),
+                }
+            }
+            // The leaves of mined deletions are only emptied once confirmed.
+            mined.iter().filter_map(leaf_index).collect::<Vec<_>>()
+        };
+        for (group_id, identity) in missing {
+            warn!(
+                ?identity,
+                "Queued deletion is no longer in the tree, dropping it."
+            );
+            database
+                .delete_pending_deletion(group_id, &identity)
+                .await?;
+        }
+        if batch.is_empty() {
+            return Ok(0);
+        }
+
+        let prover = match prover_map {
+            Some(prover_map) => {
+                let prover_map = prover_map.read().await;
+                match prover_map.get_largest_fitting(batch.len()) {
+                    Some((batch_size, prover)) if batch_size <= batch.len() => {
+                        batch.truncate(batch_size);
+                        Some(prover.clone())
+                    }
+                    _ => {
+                        info!(
+                            queued = batch.len(),
+                            "Not enough deletions queued to fill a deletion prover batch."
+                        );
+                        return Ok(0);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let (pre_root, post_root, proofs) = {
+            let tree = tree_state.read().await.unwrap_or_else(|e| {
+                error!(?e, "Failed to obtain tree lock in submit_deletions.");
+                panic!("Sequencer potentially deadlocked, terminating.");
+            });
+            let updates = emptied
+                .iter()
+                .chain(batch.iter().map(|(_, _, leaf_index)| leaf_index))
+                .map(|&leaf_index| (leaf_index, initial_leaf))
+                .collect::<Vec<_>>();
+            let (post_root, mut proofs) = tree
+                .merkle_tree
+                .proofs_with_updates(&updates)
+                .ok_or_else(|| anyhow!("Leaf index out of bounds."))?;
+            let proofs = proofs.split_off(emptied.len());
+            let pre_root = tree.merkle_tree.hasher().proof_root(batch[0].1, &proofs[0]);
+            (pre_root, post_root, proofs)
+        };
+
+        let batch_proof = match prover {
+            Some(prover) => {
+                let deletion_indices = batch
+                    .iter()
+                    .map(|(_, _, leaf_index)| u32::try_from(*leaf_index))
+                    .collect::<Result<_, _>>()?;
+                let identities = batch
+                    .iter()
+                    .zip(&proofs)
+                    .map(|((_, identity, _), proof)| Identity::from_tree_proof(*identity, proof))
+                    .collect();
+                match prover
+                    .generate_deletion_proof(
+                        pre_root.into(),
+                        post_root.into(),
+                        deletion_indices,
+                        identities,
+                    )
+                    .await
+                {
+                    Ok(proof) => Some(proof),
+                    Err(error) => {
+                        error!(
+                            ?error,
+                            batch_size = batch.len(),
+                            "Failed to prove deletions, keeping them queued."
+                        );
+                        return Ok(0);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let deletions = batch
+            .iter()
+            .zip(proofs)
+            .map(|((_, identity, _), proof)| (*identity, proof))
+            .collect();
+        let submitted = match identity_manager
+            .delete_identities(deletions, batch_proof)
+            .await
+        {
+            Ok(submitted) => submitted,
+            Err(e) => {
+                error!(
+                    error = %e.source,
+                    submitted = submitted_count(&e.submitted),
+                    "Failed to delete identities from contract."
+                );
+                e.submitted
+            }
+        };
+
+        // Mark the deletions each transaction submitted as mined in its block.
+        let mut deleted = batch.iter();
+        for Submitted { count, receipt } in &submitted {
+            let block = receipt
+                .block_number
+                .expect("Transaction is mined, block number must be present.");
+            info!(count, "Deletions submitted in block {}.", block);
+            for (group_id, identity, _) in deleted.by_ref().take(*count) {
+                database
+                    .mark_deletion_mined(*group_id, identity, block.as_usize())
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Failed to mark deletion of {identity:?} as mined in block {block}."
+                        )
+                    })?;
+            }
+        }
+        Ok(submitted_count(&submitted))
+    }
+
+    /// # Errors
+    ///
+    /// Will return an Error if the deleter thread cannot be shut down
+    /// gracefully.
+    pub async fn shutdown(&self) -> AnyhowResult<()> {
+        let mut instance = self.instance.write().await;
+        if let Some(instance) = instance.take() {
+            instance.shutdown().await?;
+        } else {
+            info!("Deleter not running.");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        contracts::mock::MockIdentityManager,
+        database,
+        identity_tree::TreeState,
+        prover::{self, map::ProverMap, Options as ProverOptions, Prover},
+        timed_rw_lock::TimedRwLock,
+    };
+    use ethers::types::U256;
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn deletion_should_report_outcome() -> anyhow::Result<()> {
+        let database = Arc::new(database::mock::database().await);
+        let initial_leaf = Hash::default();
+        let identity = Hash::from(42_u64);
+
+        let mut tree = TreeState::new(11, initial_leaf);
+        tree.merkle_tree.set(0, identity);
+        tree.next_leaf = 1;
+        let pre_root = tree.merkle_tree.root();
+        let tree_state = Arc::new(TimedRwLock::new(Duration::from_secs(1), tree));
+
+        let identity_manager = Arc::new(MockIdentityManager::default());
+        let deleter = IdentityDeleter::new(database.clone(), identity_manager, tree_state);
+        deleter.start().await;
+
+        let missing = deleter.delete(1, Hash::from(7_u64)).await?;
+        assert!(matches!(missing, OnDeleteComplete::NotFound));
+
+        let OnDeleteComplete::Deleted { root, proof } = deleter.delete(1, identity).await? else {
+            panic!("Identity in the tree should be deleted.");
+        };
+        assert_eq!(proof.leaf_index(), 0);
+        assert_eq!(proof.root(identity), pre_root);
+        assert_ne!(root, pre_root);
+        assert_eq!(root, TreeState::new(11, initial_leaf).merkle_tree.root());
+        assert!(database.pending_deletion_exists(1, &identity).await?);
+
+        deleter.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn queued_deletions_should_be_submitted_in_batches() -> anyhow::Result<()> {
+        let database = Arc::new(database::mock::database().await);
+        let initial_leaf = Hash::default();
+        let identities = [Hash::from(42_u64), Hash::from(43_u64), Hash::from(44_u64)];
+
+        let mut tree = TreeState::new(11, initial_leaf);
+        tree.merkle_tree.set_range(0, identities);
+        tree.next_leaf = identities.len();
+        let pre_root = tree.merkle_tree.root();
+        let tree_state = Arc::new(TimedRwLock::new(Duration::from_secs(1), tree));
+
+        let identity_manager = Arc::new(MockIdentityManager::default());
+        let deleter = IdentityDeleter::new(
+            database.clone(),
+            identity_manager.clone(),
+            tree_state.clone(),
+        )
+        .with_batches(Duration::from_millis(50), 10);
+        deleter.start().await;
+
+        deleter.delete(1, identities[0]).await?;
+        deleter.delete(1, identities[2]).await?;
+        sleep(Duration::from_millis(200)).await;
+
+        let batches = identity_manager.deletions.lock().unwrap().clone();
+        assert_eq!(batches.len(), 1);
+        let [(first, first_proof), (second, second_proof)] = &batches[0][..] else {
+            panic!("Both deletions should be in the batch.");
+        };
+        assert_eq!((*first, *second), (identities[0], identities[2]));
+        assert_eq!(first_proof.root(identities[0]), pre_root);
+        // The second proof is taken once the first leaf is emptied.
+        let mut deleted = TreeState::new(11, initial_leaf);
+        deleted
+            .merkle_tree
+            .set_range(0, [initial_leaf, identities[1]]);
+        assert_eq!(second_proof.root(identities[2]), deleted.merkle_tree.root());
+        deleted.merkle_tree.set(2, initial_leaf);
+        assert_eq!(second_proof.root(initial_leaf), deleted.merkle_tree.root());
+
+        // The tree is left to the confirmed events.
+        assert_eq!(tree_state.read().await?.merkle_tree.root(), pre_root);
+        assert!(database.get_next_unmined_deletions(10).await?.is_empty());
+
+        // Proofs of later deletions account for the unconfirmed ones.
+        deleter.delete(1, identities[1]).await?;
+        sleep(Duration::from_millis(200)).await;
+        let batches = identity_manager.deletions.lock().unwrap().clone();
+        let [(third, third_proof)] = &batches[1][..] else {
+            panic!("The third deletion should be in its own batch.");
+        };
+        assert_eq!(*third, identities[1]);
+        assert_eq!(
+            third_proof.root(initial_leaf),
+            TreeState::new(11, initial_leaf).merkle_tree.root()
+        );
+
+        deleter.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn deletions_should_be_proven_in_prover_batches() -> anyhow::Result<()> {
+        let service = prover::mock::Service::new("0.0.0.0:3035".into()).await?;
+        let database = Arc::new(database::mock::database().await);
+        let initial_leaf = Hash::default();
+        // The mock prover only proves batches with an odd post root, the root
+        // once the first two leaves are emptied.
+        let last = (44_u64..)
+            .map(Hash::from)
+            .find(|&last| {
+                let mut deleted = TreeState::new(11, initial_leaf);
+                deleted.merkle_tree.set(2, last);
+                U256::from(deleted.merkle_tree.root()).bit(0)
+            })
+            .unwrap();
+        let identities = [Hash::from(42_u64), Hash::from(43_u64), last];
+
+        let mut tree = TreeState::new(11, initial_leaf);
+        tree.merkle_tree.set_range(0, identities);
+        tree.next_leaf = identities.len();
+        let pre_root = tree.merkle_tree.root();
+        let tree_state = Arc::new(TimedRwLock::new(Duration::from_secs(1), tree));
+
+        let mut prover_map = ProverMap::default();
+        prover_map.add(
+            2,
+            Prover::new(&ProverOptions {
+                mtb_prover_url: "http://localhost:3035".into(),
+                batch_size: 2,
+                ..ProverOptions::default()
+            })?,
+        );
+        let identity_manager = Arc::new(MockIdentityManager::default());
+        let deleter = IdentityDeleter::new(database.clone(), identity_manager.clone(), tree_state)
+            .with_batches(Duration::from_millis(50), 10)
+            .with_provers(Arc::new(RwLock::new(prover_map)));
+        deleter.start().await;
+
+        // A single deletion fills no prover batch.
+        deleter.delete(1, identities[0]).await?;
+        sleep(Duration::from_millis(200)).await;
+        assert!(identity_manager.deletions.lock().unwrap().is_empty());
+
+        // Three deletions are cut to the prover batch size.
+        deleter.delete(1, identities[1]).await?;
+        deleter.delete(1, identities[2]).await?;
+        sleep(Duration::from_millis(200)).await;
+
+        let batches = identity_manager.deletions.lock().unwrap().clone();
+        let [batch] = &batches[..] else {
+            panic!("The first two deletions should be submitted in one batch.");
+        };
+        let deleted = batch
+            .iter()
+            .map(|(identity, _)| *identity)
+            .collect::<Vec<_>>();
+        assert_eq!(deleted, identities[..2]);
+        assert!(identity_manager.deletion_proofs.lock().unwrap()[0].is_some());
+
+        let (_, body) = service.last_request().expect("Deletions should be proven.");
+        let request: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(request["deletionIndices"], serde_json::json!([0, 1]));
+        assert_eq!(
+            request["preRoot"],
+            serde_json::to_value(U256::from(pre_root))?
+        );
+        assert_eq!(database.get_next_unmined_deletions(10).await?.len(), 1);
+
+        deleter.shutdown().await?;
+        service.stop();
+        Ok(())
+    }
+}
@@ -1,26 +1,828 @@
 use crate::timed_rw_lock::TimedRwLock;
+use clap::ValueEnum;
+use ethers::{types::U64, utils::keccak256};
 use semaphore::{
-    merkle_tree::Hasher,
-    poseidon_tree::{PoseidonHash, PoseidonTree},
+    merkle_tree::{Branch, Hasher, Proof as MerkleProof},
+    poseidon_tree::{PoseidonHash, Proof},
     Field,
 };
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    iter::successors,
+    mem::{replace, size_of},
+    sync::Arc,
+};
 
 pub type Hash = <PoseidonHash as Hasher>::Hash;
 
 pub struct TreeState {
     pub next_leaf:   usize,
-    pub merkle_tree: PoseidonTree,
+    pub merkle_tree: CappedTree,
 }
 
 pub type SharedTreeState = Arc<TimedRwLock<TreeState>>;
 
+/// Hashes two sibling nodes of a merkle tree into their parent.
+pub trait TreeHasher {
+    fn hash_node(&self, left: &Hash, right: &Hash) -> Hash;
+
+    /// Returns the root that `proof` connects `leaf` to.
+    fn proof_root(&self, leaf: Hash, proof: &Proof) -> Hash {
+        proof.0.iter().fold(leaf, |node, branch| match branch {
+            Branch::Left(sibling) => self.hash_node(&node, sibling),
+            Branch::Right(sibling) => self.hash_node(sibling, &node),
+        })
+    }
+}
+
+/// The hash functions a tree can be built with. It has to match the one of
+/// the contract.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum HashFunction {
+    /// Poseidon over the scalar field, as used by Semaphore.
+    #[default]
+    Poseidon,
+    /// Keccak-256 of the concatenated big endian children, for test trees.
+    Keccak,
+}
+
+impl TreeHasher for HashFunction {
+    fn hash_node(&self, left: &Hash, right: &Hash) -> Hash {
+        match self {
+            Self::Poseidon => PoseidonHash::hash_node(left, right),
+            Self::Keccak => {
+                let mut children = [0_u8; 64];
+                children[..32].copy_from_slice(&left.to_be_bytes::<32>());
+                children[32..].copy_from_slice(&right.to_be_bytes::<32>());
+                Hash::from_be_bytes(keccak256(children))
+            }
+        }
+    }
+}
+
 impl TreeState {
     #[must_use]
     pub fn new(tree_depth: usize, initial_leaf: Field) -> Self {
+        Self::with_node_cache_limit(tree_depth, initial_leaf, None)
+    }
+
+    /// Creates an empty tree that keeps at most `max_node_bytes` of internal
+    /// nodes in memory, see [`CappedTree`].
+    #[must_use]
+    pub fn with_node_cache_limit(
+        tree_depth: usize,
+        initial_leaf: Field,
+        max_node_bytes: Option<usize>,
+    ) -> Self {
+        Self {
+            next_leaf:   0,
+            merkle_tree: CappedTree::new(tree_depth, initial_leaf, max_node_bytes),
+        }
+    }
+
+    /// Builds the tree with `hasher` instead of Poseidon.
+    #[must_use]
+    pub fn with_hasher(self, hasher: HashFunction) -> Self {
+        Self {
+            next_leaf:   self.next_leaf,
+            merkle_tree: self.merkle_tree.with_hasher(hasher),
+        }
+    }
+
+    /// Returns an empty tree of the same depth and node cache limit.
+    #[must_use]
+    pub fn cleared(&self) -> Self {
         Self {
             next_leaf:   0,
-            merkle_tree: PoseidonTree::new(tree_depth, initial_leaf),
+            merkle_tree: self.merkle_tree.cleared(),
+        }
+    }
+
+    /// Returns the index of the inserted leaf set to `hash`, without scanning
+    /// the leaves.
+    #[must_use]
+    pub fn leaf_index(&self, hash: &Hash) -> Option<usize> {
+        self.merkle_tree
+            .leaf_index(hash)
+            .filter(|&index| index < self.next_leaf)
+    }
+}
+
+/// A Poseidon merkle tree with a bound on the memory used by its internal
+/// nodes.
+///
+/// Leaves are filled in from the left, so the tree is stored as a dense prefix
+/// holding every leaf up to the last one set, and a sparse suffix of initial
+/// leaves. Nothing is stored for the suffix, whose nodes are the roots of
+/// empty subtrees and only depend on the height, so the memory used grows with
+/// the leaves inserted rather than with the depth.
+///
+/// Internal nodes above the prefix are kept from the root down for as many
+/// levels as would fit into `max_node_bytes` in a full tree; the nodes of the
+/// levels below are recomputed from the leaves whenever they are needed. Those
+/// are the cheapest nodes to recompute and each of them is needed by the
+/// fewest proofs. The root is always kept. Without a limit every node is kept,
+/// like in [`semaphore::poseidon_tree::PoseidonTree`], whose roots and proofs
+/// this tree reproduces exactly with the default [`HashFunction::Poseidon`].
+pub struct CappedTree {
+    depth:          usize,
+    initial_leaf:   Hash,
+    max_node_bytes: Option<usize>,
+    hasher:         HashFunction,
+    /// The root of an empty subtree at each height, starting with the initial
+    /// leaf.
+    empty:          Vec<Hash>,
+    /// The kept internal levels above the prefix, starting at the root. Level
+    /// `l` has up to `2^l` nodes.
+    nodes:          Vec<Vec<Hash>>,
+    /// The prefix of the leaves, every leaf after it is initial.
+    leaves:         Vec<Hash>,
+    /// The index of every leaf in the prefix that is not initial.
+    indices:        HashMap<Hash, usize>,
+}
+
+impl CappedTree {
+    /// Creates a tree of `depth` levels, including the leaves, with every leaf
+    /// set to `initial_leaf`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `depth` is zero.
+    #[must_use]
+    pub fn new(depth: usize, initial_leaf: Hash, max_node_bytes: Option<usize>) -> Self {
+        assert!(depth > 0, "A tree needs at least one level.");
+
+        let internal_levels = depth - 1;
+        let mut kept_levels = internal_levels.min(1);
+        while kept_levels < internal_levels
+            && max_node_bytes.map_or(true, |max| {
+                ((1 << (kept_levels + 1)) - 1) * size_of::<Hash>() <= max
+            })
+        {
+            kept_levels += 1;
+        }
+
+        Self {
+            depth,
+            initial_leaf,
+            max_node_bytes,
+            hasher: HashFunction::Poseidon,
+            empty: empty_nodes(&HashFunction::Poseidon, initial_leaf, depth),
+            nodes: vec![Vec::new(); kept_levels],
+            leaves: Vec::new(),
+            indices: HashMap::new(),
+        }
+    }
+
+    /// Returns an empty tree of the same depth and node limit that is built
+    /// with `hasher`.
+    #[must_use]
+    pub fn with_hasher(&self, hasher: HashFunction) -> Self {
+        Self {
+            hasher,
+            empty: empty_nodes(&hasher, self.initial_leaf, self.depth),
+            ..Self::new(self.depth, self.initial_leaf, self.max_node_bytes)
+        }
+    }
+
+    /// Returns an empty tree of the same depth, node limit and hash function.
+    #[must_use]
+    pub fn cleared(&self) -> Self {
+        self.with_hasher(self.hasher)
+    }
+
+    #[must_use]
+    pub const fn depth(&self) -> usize {
+        self.depth
+    }
+
+    #[must_use]
+    pub const fn initial_leaf(&self) -> Hash {
+        self.initial_leaf
+    }
+
+    #[must_use]
+    pub const fn hasher(&self) -> HashFunction {
+        self.hasher
+    }
+
+    /// The capacity of the tree.
+    #[must_use]
+    pub const fn num_leaves(&self) -> usize {
+        1 << (self.depth - 1)
+    }
+
+    /// The dense prefix of the leaves, up to at least the last one set. Every
+    /// leaf after it is initial.
+    #[must_use]
+    pub fn leaves(&self) -> &[Hash] {
+        &self.leaves
+    }
+
+    /// Returns the index of the leaf set to `hash`, or `None` if there is no
+    /// such leaf or `hash` is the initial leaf. If several leaves are set to
+    /// `hash`, the lowest index is returned.
+    #[must_use]
+    pub fn leaf_index(&self, hash: &Hash) -> Option<usize> {
+        self.indices.get(hash).copied()
+    }
+
+    #[must_use]
+    pub fn root(&self) -> Hash {
+        self.node(0, 0)
+    }
+
+    /// The number of kept internal levels.
+    #[must_use]
+    pub fn kept_levels(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The kept nodes above the first `leaves` leaves, level by level from
+    /// the root. If the leaves after them are initial, every other node is
+    /// the root of an empty subtree.
+    #[must_use]
+    pub fn kept_nodes(&self, leaves: usize) -> Vec<&[Hash]> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(level, nodes)| {
+                let covering = covering(leaves, self.depth - 1 - level);
+                &nodes[..covering.min(nodes.len())]
+            })
+            .collect()
+    }
+
+    /// Returns a tree of the same depth and node limit with the first `leaves`
+    /// of another such tree and its kept `nodes` above them, without
+    /// rehashing. The remaining leaves are initial.
+    ///
+    /// Returns `None` if `nodes` or `leaves` do not have the shape of such a
+    /// tree.
+    #[must_use]
+    pub fn with_parts(&self, nodes: Vec<Vec<Hash>>, leaves: &[Hash]) -> Option<Self> {
+        let mut tree = self.cleared();
+        if nodes.len() != tree.nodes.len()
+            || leaves.len() > tree.num_leaves()
+            || nodes
+                .iter()
+                .enumerate()
+                .any(|(level, nodes)| nodes.len() != covering(leaves.len(), self.depth - 1 - level))
+        {
+            return None;
+        }
+        tree.nodes = nodes;
+        tree.leaves = leaves.to_vec();
+        for (index, leaf) in leaves.iter().enumerate() {
+            tree.index_leaf(*leaf, index);
+        }
+        Some(tree)
+    }
+
+    /// The memory taken up by the kept internal nodes.
+    #[must_use]
+    pub fn node_bytes(&self) -> usize {
+        self.nodes.iter().map(Vec::len).sum::<usize>() * size_of::<Hash>()
+    }
+
+    pub fn set(&mut self, leaf: usize, hash: Hash) {
+        self.set_range(leaf, [hash]);
+    }
+
+    /// Sets consecutive leaves starting at `start`, extending the prefix up to
+    /// them, then updates the kept nodes above them.
+    pub fn set_range<I: IntoIterator<Item = Hash>>(&mut self, start: usize, hashes: I) {
+        let mut end = start;
+        for hash in hashes
+            .into_iter()
+            .take(self.num_leaves().saturating_sub(start))
+        {
+            let previous = if let Some(leaf) = self.leaves.get_mut(end) {
+                replace(leaf, hash)
+            } else {
+                self.leaves.resize(end, self.initial_leaf);
+                self.leaves.push(hash);
+                self.initial_leaf
+            };
+            if previous != hash {
+                self.unindex_leaf(previous, end);
+                self.index_leaf(hash, end);
+            }
+            end += 1;
+        }
+        if end == start {
+            return;
+        }
+
+        for level in (0..self.nodes.len()).rev() {
+            let height = self.depth - 1 - level;
+            let covering = covering(self.leaves.len(), height);
+            self.nodes[level].resize(covering, self.empty[height]);
+            for index in (start >> height)..=((end - 1) >> height) {
+                let node = self.hash_children(level, index);
+                self.nodes[level][index] = node;
+            }
+        }
+    }
+
+    /// Records that the leaf at `index` is set to `hash`, unless it is initial
+    /// or a lower leaf is set to it too.
+    fn index_leaf(&mut self, hash: Hash, index: usize) {
+        if hash != self.initial_leaf {
+            self.indices
+                .entry(hash)
+                .and_modify(|lowest| *lowest = (*lowest).min(index))
+                .or_insert(index);
+        }
+    }
+
+    /// Records that the leaf at `index` is no longer set to `hash`, falling
+    /// back to a higher leaf that is still set to it, if any.
+    fn unindex_leaf(&mut self, hash: Hash, index: usize) {
+        if self.indices.get(&hash) != Some(&index) {
+            return;
+        }
+        match self.leaves[index + 1..]
+            .iter()
+            .position(|leaf| *leaf == hash)
+        {
+            Some(offset) => self.indices.insert(hash, index + 1 + offset),
+            None => self.indices.remove(&hash),
+        };
+    }
+
+    /// Returns the inclusion proof of `leaf`, or `None` if it is out of
+    /// bounds.
+    #[must_use]
+    pub fn proof(&self, leaf: usize) -> Option<Proof> {
+        if leaf >= self.num_leaves() {
+            return None;
+        }
+
+        let mut index = leaf;
+        let path = (1..self.depth)
+            .rev()
+            .map(|level| {
+                let branch = if index & 1 == 0 {
+                    Branch::Left(self.node(level, index + 1))
+                } else {
+                    Branch::Right(self.node(level, index - 1))
+                };
+                index >>= 1;
+                branch
+            })
+            .collect();
+        Some(MerkleProof(path))
+    }
+
+    /// Returns the root and the inclusion proof of `leaf` in the tree as it
+    /// was while it only held its first `size` leaves, or `None` if `leaf` is
+    /// not among them.
+    ///
+    /// Only the nodes on the path of the last of those leaves differ from the
+    /// current tree, so this takes a few hashes per level.
+    #[must_use]
+    pub fn prefix_proof(&self, leaf: usize, size: usize) -> Option<(Hash, Proof)> {
+        if leaf >= size || size > self.num_leaves() {
+            return None;
+        }
+
+        let mut index = leaf;
+        let path = (1..self.depth)
+            .rev()
+            .map(|level| {
+                let branch = if index & 1 == 0 {
+                    Branch::Left(self.prefix_node(level, index + 1, size))
+                } else {
+                    Branch::Right(self.prefix_node(level, index - 1, size))
+                };
+                index >>= 1;
+                branch
+            })
+            .collect();
+        Some((self.prefix_node(0, 0, size), MerkleProof(path)))
+    }
+
+    /// Returns the inclusion proof of the leaf of each of `updates` in the
+    /// tree as it is once the updates before it have been applied, together
+    /// with the root once all of them are, or `None` if a leaf is out of
+    /// bounds.
+    ///
+    /// The tree itself is left unchanged, the nodes on the paths of the
+    /// updated leaves are kept aside instead.
+    #[must_use]
+    pub fn proofs_with_updates(&self, updates: &[(usize, Hash)]) -> Option<(Hash, Vec<Proof>)> {
+        let mut updated = HashMap::new();
+        let node = |updated: &HashMap<(usize, usize), Hash>, level: usize, index: usize| {
+            updated
+                .get(&(level, index))
+                .copied()
+                .unwrap_or_else(|| self.node(level, index))
+        };
+
+        let mut proofs = Vec::with_capacity(updates.len());
+        for &(leaf, hash) in updates {
+            if leaf >= self.num_leaves() {
+                return None;
+            }
+
+            let mut index = leaf;
+            let mut current = hash;
+            let mut path = Vec::with_capacity(self.depth - 1);
+            updated.insert((self.depth - 1, index), current);
+            for level in (1..self.depth).rev() {
+                if index & 1 == 0 {
+                    let sibling = node(&updated, level, index + 1);
+                    current = self.hasher.hash_node(&current, &sibling);
+                    path.push(Branch::Left(sibling));
+                } else {
+                    let sibling = node(&updated, level, index - 1);
+                    current = self.hasher.hash_node(&sibling, &current);
+                    path.push(Branch::Right(sibling));
+                }
+                index >>= 1;
+                updated.insert((level - 1, index), current);
+            }
+            proofs.push(MerkleProof(path));
+        }
+        Some((node(&updated, 0, 0), proofs))
+    }
+
+    #[must_use]
+    pub fn verify(&self, hash: Hash, proof: &Proof) -> bool {
+        self.hasher.proof_root(hash, proof) == self.root()
+    }
+
+    fn node(&self, level: usize, index: usize) -> Hash {
+        let height = self.depth - 1 - level;
+        if index << height >= self.leaves.len() {
+            self.empty[height]
+        } else if height == 0 {
+            self.leaves[index]
+        } else if let Some(nodes) = self.nodes.get(level) {
+            nodes.get(index).copied().unwrap_or(self.empty[height])
+        } else {
+            self.hash_children(level, index)
+        }
+    }
+
+    /// Returns a node of the tree holding only the first `size` leaves.
+    fn prefix_node(&self, level: usize, index: usize, size: usize) -> Hash {
+        let height = self.depth - 1 - level;
+        let start = index << height;
+        if start + (1 << height) <= size {
+            self.node(level, index)
+        } else if start >= size {
+            self.empty[height]
+        } else {
+            self.hasher.hash_node(
+                &self.prefix_node(level + 1, 2 * index, size),
+                &self.prefix_node(level + 1, 2 * index + 1, size),
+            )
+        }
+    }
+
+    fn hash_children(&self, level: usize, index: usize) -> Hash {
+        self.hasher.hash_node(
+            &self.node(level + 1, 2 * index),
+            &self.node(level + 1, 2 * index + 1),
+        )
+    }
+}
+
+/// Returns the number of nodes at `height` above the first `leaves` leaves.
+pub const fn covering(leaves: usize, height: usize) -> usize {
+    (leaves + (1 << height) - 1) >> height
+}
+
+/// Returns the value of an empty node at each of the first `count` heights
+/// above the leaves, starting with `initial_leaf` itself.
+fn empty_nodes(hasher: &impl TreeHasher, initial_leaf: Hash, count: usize) -> Vec<Hash> {
+    successors(Some(initial_leaf), |node| {
+        Some(hasher.hash_node(node, node))
+    })
+    .take(count)
+    .collect()
+}
+
+/// An inclusion proof without the siblings that are roots of empty subtrees,
+/// for clients on constrained links.
+///
+/// The left out siblings only depend on the initial leaf, so they can be
+/// recomputed on decompression. This shrinks proofs of the recently inserted
+/// leaves considerably, as most of their siblings are empty.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressedProof {
+    /// The number of siblings in the full proof.
+    pub length:   usize,
+    /// Bit `i` is set if the `i`-th node from the leaf up is a right child.
+    pub path:     U64,
+    /// Bit `i` is set if the sibling of the `i`-th node is empty and left out.
+    pub empty:    U64,
+    /// The siblings that are not left out, from the leaf up.
+    pub siblings: Vec<Hash>,
+}
+
+impl CompressedProof {
+    /// Leaves out the empty siblings of `proof` in a tree initialized with
+    /// `initial_leaf` and built with `hasher`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the proof has more than 64 siblings.
+    #[must_use]
+    pub fn compress(proof: &Proof, initial_leaf: Hash, hasher: &impl TreeHasher) -> Self {
+        assert!(proof.0.len() <= 64, "Proof is too long to compress.");
+
+        let mut path = 0_u64;
+        let mut empty = 0_u64;
+        let mut siblings = Vec::new();
+        for ((height, branch), empty_node) in
+            proof
+                .0
+                .iter()
+                .enumerate()
+                .zip(empty_nodes(hasher, initial_leaf, proof.0.len()))
+        {
+            let sibling = match branch {
+                Branch::Left(sibling) => sibling,
+                Branch::Right(sibling) => {
+                    path |= 1 << height;
+                    sibling
+                }
+            };
+            if *sibling == empty_node {
+                empty |= 1 << height;
+            } else {
+                siblings.push(*sibling);
+            }
+        }
+
+        Self {
+            length: proof.0.len(),
+            path: path.into(),
+            empty: empty.into(),
+            siblings,
+        }
+    }
+
+    /// Restores the full proof in a tree initialized with `initial_leaf` and
+    /// built with `hasher`.
+    ///
+    /// Returns `None` if the compressed proof is malformed.
+    #[must_use]
+    pub fn decompress(&self, initial_leaf: Hash, hasher: &impl TreeHasher) -> Option<Proof> {
+        if self.length > 64 {
+            return None;
         }
+
+        let mut siblings = self.siblings.iter();
+        let path = empty_nodes(hasher, initial_leaf, self.length)
+            .into_iter()
+            .enumerate()
+            .map(|(height, empty_node)| {
+                let sibling = if self.empty.bit(height) {
+                    empty_node
+                } else {
+                    *siblings.next()?
+                };
+                Some(if self.path.bit(height) {
+                    Branch::Right(sibling)
+                } else {
+                    Branch::Left(sibling)
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if siblings.next().is_some() {
+            return None;
+        }
+        Some(MerkleProof(path))
+    }
+
+    /// Returns the root that the proof connects `leaf` to, or `None` if it is
+    /// malformed.
+    #[must_use]
+    pub fn root(&self, leaf: Hash, initial_leaf: Hash, hasher: &impl TreeHasher) -> Option<Hash> {
+        Some(hasher.proof_root(leaf, &self.decompress(initial_leaf, hasher)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use semaphore::poseidon_tree::PoseidonTree;
+
+    const DEPTH: usize = 6;
+
+    fn assert_matches_reference(max_node_bytes: Option<usize>) {
+        let mut tree = CappedTree::new(DEPTH, Hash::from(0_u64), max_node_bytes);
+        let mut reference = PoseidonTree::new(DEPTH, Hash::from(0_u64));
+        if let Some(max) = max_node_bytes {
+            assert!(tree.node_bytes() <= max.max(size_of::<Hash>()));
+        }
+
+        tree.set_range(0, (1..=5_u64).map(Hash::from));
+        reference.set_range(0, (1..=5_u64).map(Hash::from));
+        tree.set(20, Hash::from(42_u64));
+        reference.set(20, Hash::from(42_u64));
+
+        assert_eq!(tree.root(), reference.root());
+        assert_eq!(tree.leaves(), &reference.leaves()[..21]);
+        for leaf in 0..tree.num_leaves() {
+            let proof = tree.proof(leaf).unwrap();
+            assert_eq!(proof, reference.proof(leaf).unwrap());
+            let hash = tree
+                .leaves()
+                .get(leaf)
+                .copied()
+                .unwrap_or(Hash::from(0_u64));
+            assert!(tree.verify(hash, &proof));
+        }
+        assert!(tree.proof(tree.num_leaves()).is_none());
+    }
+
+    #[test]
+    fn unlimited_tree_should_keep_every_node() {
+        let mut tree = CappedTree::new(DEPTH, Hash::from(0_u64), None);
+        tree.set_range(0, (1..=32_u64).map(Hash::from));
+        assert_eq!(
+            tree.node_bytes(),
+            ((1 << (DEPTH - 1)) - 1) * size_of::<Hash>()
+        );
+
+        assert_matches_reference(None);
+    }
+
+    #[test]
+    fn empty_suffix_should_not_be_stored() {
+        const DEPTH: usize = 40;
+        let mut tree = CappedTree::new(DEPTH, Hash::from(0_u64), None);
+        let empty_root = tree.root();
+        assert_eq!(tree.node_bytes(), 0);
+
+        tree.set_range(0, (1..=5_u64).map(Hash::from));
+        assert_eq!(tree.leaves().len(), 5);
+        // Three nodes above the five leaves, then two, then one on each level
+        // up to the root.
+        assert_eq!(tree.node_bytes(), (3 + 2 + (DEPTH - 3)) * size_of::<Hash>());
+        assert_ne!(tree.root(), empty_root);
+
+        for leaf in [0, 4, 5, tree.num_leaves() - 1] {
+            let proof = tree.proof(leaf).unwrap();
+            let hash = tree
+                .leaves()
+                .get(leaf)
+                .copied()
+                .unwrap_or(Hash::from(0_u64));
+            assert!(tree.verify(hash, &proof));
+        }
+
+        // Emptying the leaves again restores the empty root.
+        tree.set_range(0, [Hash::from(0_u64); 5]);
+        assert_eq!(tree.root(), empty_root);
+    }
+
+    #[test]
+    fn leaves_should_be_found_by_hash() {
+        let mut tree = CappedTree::new(DEPTH, Hash::from(0_u64), None);
+        tree.set_range(0, (1..=5_u64).map(Hash::from));
+        tree.set(9, Hash::from(3_u64));
+        assert_eq!(tree.leaf_index(&Hash::from(3_u64)), Some(2));
+        assert_eq!(tree.leaf_index(&Hash::from(6_u64)), None);
+        assert_eq!(tree.leaf_index(&Hash::from(0_u64)), None);
+
+        // A duplicate takes over once the lower leaf is emptied.
+        tree.set(2, Hash::from(0_u64));
+        assert_eq!(tree.leaf_index(&Hash::from(3_u64)), Some(9));
+        tree.set(9, Hash::from(0_u64));
+        assert_eq!(tree.leaf_index(&Hash::from(3_u64)), None);
+
+        let restored = tree
+            .with_parts(
+                tree.kept_nodes(tree.leaves().len())
+                    .into_iter()
+                    .map(<[Hash]>::to_vec)
+                    .collect(),
+                tree.leaves(),
+            )
+            .unwrap();
+        assert_eq!(restored.leaf_index(&Hash::from(5_u64)), Some(4));
+    }
+
+    #[test]
+    fn prefix_proof_should_match_tree_of_prefix() {
+        for max_node_bytes in [None, Some(3 * size_of::<Hash>())] {
+            let mut tree = CappedTree::new(DEPTH, Hash::from(0_u64), max_node_bytes);
+            tree.set_range(0, (1..=20_u64).map(Hash::from));
+            let mut prefix = tree.cleared();
+            prefix.set_range(0, (1..=13_u64).map(Hash::from));
+
+            for leaf in [0, 7, 12] {
+                let (root, proof) = tree.prefix_proof(leaf, 13).unwrap();
+                assert_eq!(root, prefix.root());
+                assert_eq!(proof, prefix.proof(leaf).unwrap());
+            }
+            assert!(tree.prefix_proof(13, 13).is_none());
+            assert_eq!(
+                tree.prefix_proof(3, 20).map(|(root, _)| root),
+                Some(tree.root())
+            );
+        }
+    }
+
+    #[test]
+    fn proofs_with_updates_should_match_updated_tree() {
+        for max_node_bytes in [None, Some(3 * size_of::<Hash>())] {
+            let mut tree = CappedTree::new(DEPTH, Hash::from(0_u64), max_node_bytes);
+            tree.set_range(0, (1..=20_u64).map(Hash::from));
+            let updates = [
+                (3, Hash::from(0_u64)),
+                (7, Hash::from(0_u64)),
+                (2, Hash::from(30_u64)),
+                (25, Hash::from(40_u64)),
+            ];
+
+            let (root, proofs) = tree.proofs_with_updates(&updates).unwrap();
+            let mut updated = tree.cleared();
+            updated.set_range(0, tree.leaves().iter().copied());
+            for (&(leaf, hash), proof) in updates.iter().zip(&proofs) {
+                assert_eq!(proof, &updated.proof(leaf).unwrap());
+                updated.set(leaf, hash);
+            }
+            assert_eq!(root, updated.root());
+            assert_eq!(tree.leaves()[3], Hash::from(4_u64));
+
+            assert!(tree
+                .proofs_with_updates(&[(tree.num_leaves(), Hash::from(0_u64))])
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn compressed_proof_should_decode_and_verify_like_full_proof() {
+        const DEPTH: usize = 12;
+        let initial_leaf = Hash::from(0_u64);
+        let mut tree = CappedTree::new(DEPTH, initial_leaf, None);
+        tree.set_range(0, (1..=3_u64).map(Hash::from));
+
+        for leaf in [0, 2, 100] {
+            let proof = tree.proof(leaf).unwrap();
+            let compressed = CompressedProof::compress(&proof, initial_leaf, &tree.hasher());
+            assert!(compressed.siblings.len() < proof.0.len());
+
+            let json = serde_json::to_string(&compressed).unwrap();
+            let compressed: CompressedProof = serde_json::from_str(&json).unwrap();
+            assert_eq!(
+                compressed.decompress(initial_leaf, &tree.hasher()),
+                Some(proof)
+            );
+            let hash = tree.leaves().get(leaf).copied().unwrap_or(initial_leaf);
+            assert_eq!(
+                compressed.root(hash, initial_leaf, &tree.hasher()),
+                Some(tree.root())
+            );
+        }
+
+        let mut truncated =
+            CompressedProof::compress(&tree.proof(0).unwrap(), initial_leaf, &tree.hasher());
+        truncated.siblings.pop();
+        assert_eq!(truncated.decompress(initial_leaf, &tree.hasher()), None);
+    }
+
+    #[test]
+    fn keccak_tree_should_hash_concatenated_children() {
+        let initial_leaf = Hash::from(0_u64);
+        let keccak = |left: Hash, right: Hash| {
+            Hash::from_be_bytes(keccak256(
+                [left.to_be_bytes::<32>(), right.to_be_bytes::<32>()].concat(),
+            ))
+        };
+        let mut tree = CappedTree::new(3, initial_leaf, None).with_hasher(HashFunction::Keccak);
+        tree.set_range(0, (1..=3_u64).map(Hash::from));
+
+        let expected = keccak(
+            keccak(Hash::from(1_u64), Hash::from(2_u64)),
+            keccak(Hash::from(3_u64), initial_leaf),
+        );
+        assert_eq!(tree.root(), expected);
+        let mut poseidon = CappedTree::new(3, initial_leaf, None);
+        poseidon.set_range(0, (1..=3_u64).map(Hash::from));
+        assert_ne!(tree.root(), poseidon.root());
+        for leaf in 0..tree.num_leaves() {
+            let hash = tree.leaves().get(leaf).copied().unwrap_or(initial_leaf);
+            assert!(tree.verify(hash, &tree.proof(leaf).unwrap()));
+        }
+        // The hash function is kept when the tree is cleared.
+        assert_eq!(tree.cleared().hasher(), HashFunction::Keccak);
+    }
+
+    #[test]
+    fn proofs_should_verify_after_eviction_under_tight_cap() {
+        // Only the root and its children fit.
+        assert_matches_reference(Some(3 * size_of::<Hash>()));
+        // Not even the root fits, which is kept anyway.
+        assert_matches_reference(Some(0));
     }
 }
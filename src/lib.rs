@@ -2,22 +2,36 @@
 #![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
 
 pub mod app;
+mod auth;
+pub mod batch_events;
+mod batching_policy;
 mod contracts;
 mod database;
 mod ethereum;
 mod ethereum_subscriber;
 mod identity_committer;
+mod identity_deleter;
 pub mod identity_tree;
+mod proof_cache;
 mod prover;
+mod rate_limiter;
+mod receipt;
+mod seen_cache;
 pub mod server;
+mod state_snapshot;
+mod statsd;
+mod status_changes;
+mod status_coalescer;
 mod timed_rw_lock;
+mod tree_cache;
 mod tx_sitter;
 mod utils;
+mod webhook;
 
-use crate::app::App;
+use crate::{app::App, database::Database};
 use anyhow::Result as AnyhowResult;
-use clap::Parser;
-use std::sync::Arc;
+use clap::{Parser, Subcommand};
+use std::{path::PathBuf, sync::Arc};
 use tracing::info;
 
 #[derive(Clone, Debug, PartialEq, Parser)]
@@ -28,6 +42,31 @@ pub struct Options {
 
     #[clap(flatten)]
     pub server: server::Options,
+
+    /// Apply the pending database migrations and exit, without starting the
+    /// sequencer. Lets migrations run as a separate deployment step.
+    #[clap(long, env)]
+    pub migrate_only: bool,
+
+    /// Run an admin command against the database instead of the sequencer.
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Write the identities, root history and tree metadata in the database
+    /// to a versioned snapshot file.
+    ExportState {
+        /// The snapshot file to write.
+        file: PathBuf,
+    },
+    /// Restore a snapshot file written by `export-state` into a fresh
+    /// database.
+    ImportState {
+        /// The snapshot file to read.
+        file: PathBuf,
+    },
 }
 
 /// ```
@@ -35,6 +74,22 @@ pub struct Options {
 /// ```
 #[allow(clippy::missing_errors_doc, clippy::missing_panics_doc)]
 pub async fn main(options: Options) -> AnyhowResult<()> {
+    if options.migrate_only {
+        let mut database = options.app.database;
+        database.database_migrate = true;
+        Database::new(database).await?;
+        info!("Migrations applied, exiting");
+        return Ok(());
+    }
+
+    if let Some(command) = options.command {
+        let database = Database::new(options.app.database).await?;
+        return match command {
+            Command::ExportState { file } => state_snapshot::export(&database, &file).await,
+            Command::ImportState { file } => state_snapshot::import(&database, &file).await,
+        };
+    }
+
     // Create App struct
     let app = Arc::new(App::new(options.app).await?);
     let app_for_server = app.clone();
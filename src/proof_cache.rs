@@ -0,0 +1,236 @@
+use crate::identity_tree::Hash;
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
+use semaphore::poseidon_tree::Proof;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Instant,
+};
+
+/// The number of proofs against distinct roots kept for each commitment.
+const HISTORY_LEN: usize = 4;
+
+static HITS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "proof_cache_hits",
+        "Number of inclusion proofs served from the proof cache."
+    )
+    .unwrap()
+});
+static EVICTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proof_cache_evictions",
+        "Number of entries evicted from the proof cache.",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// A proof of a commitment against `root`, computed at `cached_at`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedProof {
+    pub root:      Hash,
+    pub proof:     Proof,
+    pub cached_at: Instant,
+}
+
+/// A bounded cache of inclusion proofs for mined commitments.
+///
+/// A mined commitment's proof against a given root never changes, so it is
+/// served for as long as that root is the root of the tree. The last few
+/// proofs of a commitment are kept, newest first, to fall back to as stale
+/// proofs while their roots are still accepted on chain. Entries are dropped
+/// once their root is no longer accepted or, oldest first, when the cache
+/// holds more than `max_size` commitments. Pending commitments have no proof
+/// yet and are always looked up afresh. A `max_size` of zero disables the
+/// cache.
+#[derive(Debug)]
+pub struct ProofCache {
+    max_size: usize,
+    entries:  HashMap<Hash, VecDeque<CachedProof>>,
+    order:    VecDeque<Hash>,
+}
+
+impl ProofCache {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns the cached proof of `commitment` against `root`, if any.
+    pub fn get(&self, commitment: &Hash, root: &Hash) -> Option<CachedProof> {
+        let hit = self
+            .entries
+            .get(commitment)
+            .and_then(|history| history.iter().find(|cached| &cached.root == root))
+            .cloned();
+        if hit.is_some() {
+            HITS.inc();
+        }
+        hit
+    }
+
+    /// Returns the cached proofs for `commitment`, newest first.
+    pub fn history(&self, commitment: &Hash) -> Vec<CachedProof> {
+        self.entries
+            .get(commitment)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Caches the proof of the mined `commitment` against `root`.
+    pub fn insert(&mut self, commitment: Hash, root: Hash, proof: Proof) {
+        if self.max_size == 0 {
+            return;
+        }
+
+        if !self.entries.contains_key(&commitment) {
+            while self.entries.len() >= self.max_size {
+                let Some(oldest) = self.order.pop_front() else {
+                    break;
+                };
+                self.entries.remove(&oldest);
+                EVICTIONS.with_label_values(&["capacity"]).inc();
+            }
+            self.order.push_back(commitment);
+        }
+
+        let history = self.entries.entry(commitment).or_default();
+        history.retain(|cached| cached.root != root);
+        history.push_front(CachedProof {
+            root,
+            proof,
+            cached_at: Instant::now(),
+        });
+        history.truncate(HISTORY_LEN);
+    }
+
+    /// Drops every proof of `commitment` once it is deleted from the tree, as
+    /// they would still verify against roots accepted on chain.
+    pub fn remove(&mut self, commitment: &Hash) {
+        if self.entries.remove(commitment).is_some() {
+            self.order.retain(|entry| entry != commitment);
+            EVICTIONS.with_label_values(&["deleted"]).inc();
+        }
+    }
+
+    /// Drops the proof of `commitment` against `root` after the root has
+    /// expired.
+    pub fn remove_root(&mut self, commitment: &Hash, root: &Hash) {
+        let Some(history) = self.entries.get_mut(commitment) else {
+            return;
+        };
+        let before = history.len();
+        history.retain(|cached| &cached.root != root);
+        if history.len() < before {
+            EVICTIONS.with_label_values(&["expired_root"]).inc();
+        }
+        if history.is_empty() {
+            self.entries.remove(commitment);
+            self.order.retain(|entry| entry != commitment);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use semaphore::poseidon_tree::PoseidonTree;
+
+    fn tree(leaves: &[u64]) -> PoseidonTree {
+        let mut tree = PoseidonTree::new(4, Hash::from(0_u64));
+        for (index, &leaf) in leaves.iter().enumerate() {
+            tree.set(index, Hash::from(leaf));
+        }
+        tree
+    }
+
+    #[test]
+    fn mined_proofs_should_be_served_from_cache() {
+        let mut cache = ProofCache::new(10);
+        let mined = Hash::from(1_u64);
+        let pending = Hash::from(2_u64);
+
+        let before = tree(&[1]);
+        let proof = before.proof(0).unwrap();
+        cache.insert(mined, before.root(), proof.clone());
+
+        let cached = cache.get(&mined, &before.root()).unwrap();
+        assert_eq!((cached.root, cached.proof), (before.root(), proof));
+
+        // Once the tree has moved on the proof is computed afresh.
+        let after = tree(&[1, 3]);
+        assert_ne!(after.proof(0).unwrap(), proof);
+        assert_eq!(cache.get(&mined, &after.root()), None);
+
+        // Nothing is ever cached for a pending commitment.
+        assert_eq!(cache.get(&pending, &before.root()), None);
+
+        cache.remove_root(&mined, &before.root());
+        assert_eq!(cache.get(&mined, &before.root()), None);
+        assert!(cache.order.is_empty());
+    }
+
+    #[test]
+    fn older_proofs_should_outlive_expired_ones() {
+        let mut cache = ProofCache::new(10);
+        let mined = Hash::from(1_u64);
+        let trees = [tree(&[1]), tree(&[1, 2]), tree(&[1, 2, 3])];
+
+        for tree in &trees {
+            cache.insert(mined, tree.root(), tree.proof(0).unwrap());
+        }
+        // Caching a proof against a known root again does not duplicate it.
+        cache.insert(mined, trees[2].root(), trees[2].proof(0).unwrap());
+        let roots = |cache: &ProofCache| {
+            cache
+                .history(&mined)
+                .iter()
+                .map(|cached| cached.root)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(roots(&cache), vec![
+            trees[2].root(),
+            trees[1].root(),
+            trees[0].root()
+        ]);
+
+        cache.remove_root(&mined, &trees[2].root());
+        assert_eq!(roots(&cache), vec![trees[1].root(), trees[0].root()]);
+        assert!(cache.get(&mined, &trees[1].root()).is_some());
+        assert_eq!(cache.order.len(), 1);
+
+        // A deleted commitment has no proof against any root.
+        cache.remove(&mined);
+        assert!(cache.history(&mined).is_empty());
+        assert!(cache.order.is_empty());
+    }
+
+    #[test]
+    fn entries_should_be_evicted_by_capacity() {
+        let mut cache = ProofCache::new(2);
+        let tree = tree(&[1, 2, 3]);
+        let commitments = [1_u64, 2, 3].map(Hash::from);
+
+        for (index, commitment) in commitments.into_iter().enumerate() {
+            cache.insert(commitment, tree.root(), tree.proof(index).unwrap());
+        }
+
+        assert!(cache.get(&commitments[0], &tree.root()).is_none());
+        assert!(cache.get(&commitments[1], &tree.root()).is_some());
+        assert!(cache.get(&commitments[2], &tree.root()).is_some());
+    }
+
+    #[test]
+    fn zero_size_should_disable_cache() {
+        let mut cache = ProofCache::new(0);
+        let tree = tree(&[1]);
+        let commitment = Hash::from(1_u64);
+
+        cache.insert(commitment, tree.root(), tree.proof(0).unwrap());
+        assert!(cache.get(&commitment, &tree.root()).is_none());
+    }
+}
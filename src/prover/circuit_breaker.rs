@@ -0,0 +1,176 @@
+use std::{sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+/// Takes a prover out of rotation after `threshold` consecutive failed
+/// requests, for `cooldown`.
+///
+/// Once the cooldown has passed, a single trial request is let through. If it
+/// succeeds the prover is back in rotation, if it fails the breaker opens for
+/// another cooldown. A zero `threshold` disables the breaker.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: usize,
+    cooldown:  Duration,
+    state:     Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    failures:  usize,
+    opened_at: Option<Instant>,
+    /// Set while the trial request after a cooldown is in flight.
+    trial:     bool,
+}
+
+impl CircuitBreaker {
+    #[must_use]
+    pub fn new(threshold: usize, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::default(),
+        }
+    }
+
+    /// A breaker that never opens.
+    #[must_use]
+    pub fn disabled() -> Self {
+        Self::new(0, Duration::ZERO)
+    }
+
+    /// Returns whether the prover is out of rotation, without claiming the
+    /// trial request.
+    pub fn is_open(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.opened_at.map_or(false, |opened_at| {
+            opened_at.elapsed() < self.cooldown || state.trial
+        })
+    }
+
+    /// Returns whether a request may be sent now. After a cooldown, the first
+    /// caller claims the trial request.
+    ///
+    /// The outcome of the request is recorded through the returned
+    /// [`Attempt`]. Dropping it unrecorded, like when the request is abandoned,
+    /// gives the trial up for the next caller.
+    pub fn allows_request(&self) -> Option<Attempt<'_>> {
+        let mut state = self.state.lock().unwrap();
+        match state.opened_at {
+            None => Some(Attempt {
+                breaker: self,
+                trial:   false,
+            }),
+            Some(opened_at) if opened_at.elapsed() >= self.cooldown && !state.trial => {
+                state.trial = true;
+                Some(Attempt {
+                    breaker: self,
+                    trial:   true,
+                })
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// Closes the breaker.
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = State::default();
+    }
+
+    /// Counts a failed request, and returns whether it opened the breaker.
+    pub fn record_failure(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        state.trial = false;
+        if self.threshold == 0 || state.failures < self.threshold {
+            return false;
+        }
+        let was_closed = state.opened_at.is_none();
+        state.opened_at = Some(Instant::now());
+        was_closed
+    }
+}
+
+/// A request let through by a [`CircuitBreaker`], which may be its trial
+/// request.
+#[derive(Debug)]
+#[must_use]
+pub struct Attempt<'a> {
+    breaker: &'a CircuitBreaker,
+    trial:   bool,
+}
+
+impl Attempt<'_> {
+    /// Records that the request succeeded, see
+    /// [`CircuitBreaker::record_success`].
+    pub fn succeeded(mut self) {
+        self.trial = false;
+        self.breaker.record_success();
+    }
+
+    /// Records that the request failed, see
+    /// [`CircuitBreaker::record_failure`].
+    pub fn failed(mut self) -> bool {
+        self.trial = false;
+        self.breaker.record_failure()
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if self.trial {
+            self.breaker.state.lock().unwrap().trial = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::advance;
+
+    #[tokio::test(start_paused = true)]
+    async fn breaker_should_open_and_let_a_trial_through() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(10));
+        assert!(!breaker.record_failure());
+        assert!(breaker.allows_request().unwrap().failed());
+        assert!(breaker.is_open());
+        assert!(breaker.allows_request().is_none());
+
+        // A single trial request after the cooldown, which fails.
+        advance(Duration::from_secs(10)).await;
+        assert!(!breaker.is_open());
+        let trial = breaker.allows_request().unwrap();
+        assert!(breaker.allows_request().is_none());
+        assert!(!trial.failed());
+        assert!(breaker.is_open());
+
+        // The next trial succeeds.
+        advance(Duration::from_secs(10)).await;
+        breaker.allows_request().unwrap().succeeded();
+        assert!(!breaker.is_open());
+        assert!(breaker.allows_request().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn abandoned_trial_should_be_given_up() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(10));
+        assert!(breaker.record_failure());
+        advance(Duration::from_secs(10)).await;
+
+        let trial = breaker.allows_request().unwrap();
+        assert!(breaker.is_open());
+        drop(trial);
+
+        assert!(!breaker.is_open());
+        assert!(breaker.allows_request().is_some());
+    }
+
+    #[test]
+    fn disabled_breaker_should_never_open() {
+        let breaker = CircuitBreaker::disabled();
+        for _ in 0..10 {
+            assert!(!breaker.record_failure());
+        }
+        assert!(breaker.allows_request().is_some());
+    }
+}
@@ -0,0 +1,167 @@
+use crate::prover::{
+    map::{update_shared_provers, InsertionProverMap, ProverUpdate},
+    Options, Prover, ProverConfiguration,
+};
+use cli_batteries::await_shutdown;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+use tokio::{select, task::JoinHandle, time::sleep};
+use tracing::{info, warn};
+use url::Url;
+
+/// Fetches the provers listed by the discovery service at `url`, giving up
+/// after `timeout`.
+///
+/// The service is expected to answer with a JSON array of prover
+/// configurations, in the same format as `--deletion-provers`.
+///
+/// # Errors
+///
+/// Will return `Err` if the service cannot be reached in time or answers with
+/// anything but a list of provers.
+pub async fn fetch_configurations(
+    url: &Url,
+    timeout: Duration,
+) -> anyhow::Result<Vec<ProverConfiguration>> {
+    let client = reqwest::Client::builder().timeout(timeout).build()?;
+    let configurations = client
+        .get(url.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(configurations)
+}
+
+/// Replaces the provers in `map` with the ones currently listed by the
+/// discovery service at `url`.
+///
+/// # Errors
+///
+/// Will return `Err` if the service cannot be reached within the prover
+/// timeout, lists no provers, or lists a prover that cannot be set up. The map
+/// is left unchanged in that case.
+pub async fn refresh(
+    map: &InsertionProverMap,
+    url: &Url,
+    options: &Options,
+) -> anyhow::Result<ProverUpdate> {
+    let desired = fetch_configurations(url, Duration::from_secs(options.mtb_prover_timeout_secs))
+        .await?
+        .iter()
+        .map(|configuration| {
+            let prover = Prover::from_options(configuration, options)?;
+            Ok((configuration.batch_size, prover))
+        })
+        .collect::<anyhow::Result<BTreeMap<_, _>>>()?;
+
+    // Proofs are requested while holding a read lock on the map, so no batch
+    // can be in flight while the update holds the write lock.
+    Ok(update_shared_provers(map, desired, &BTreeSet::new()).await?)
+}
+
+/// Spawns a task that refreshes the provers in `map` from the discovery
+/// service at `url` every `interval`, until shutdown.
+///
+/// Failed refreshes are logged and the current provers kept.
+pub fn start_refresh(
+    map: InsertionProverMap,
+    url: Url,
+    options: Options,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            select! {
+                _ = sleep(interval) => {}
+                _ = await_shutdown() => {
+                    info!("Stopping prover discovery.");
+                    return;
+                }
+            }
+            if let Err(error) = refresh(&map, &url, &options).await {
+                warn!(%error, %url, "Failed to refresh provers from discovery service.");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prover::map::ProverMap;
+    use axum::{routing::get, Router};
+    use axum_server::Handle;
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+    use tokio::sync::RwLock;
+
+    /// Serves the JSON held in `listing` as the prover list.
+    fn discovery_service(addr: &str, listing: Arc<Mutex<String>>) -> anyhow::Result<Handle> {
+        let addr: SocketAddr = addr.parse()?;
+        let app = Router::new().route(
+            "/provers",
+            get(move || {
+                let listing = listing.lock().unwrap().clone();
+                async move { listing }
+            }),
+        );
+        let server = Handle::new();
+        let serverside_handle = server.clone();
+        tokio::spawn(async move {
+            axum_server::bind(addr)
+                .handle(serverside_handle)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        });
+        Ok(server)
+    }
+
+    #[tokio::test]
+    async fn discovered_provers_should_be_refreshed() -> anyhow::Result<()> {
+        let listing = Arc::new(Mutex::new(
+            r#"[{"url": "http://localhost:3020", "batch_size": 3}]"#.to_string(),
+        ));
+        let server = discovery_service("0.0.0.0:3009", listing.clone())?;
+        let url: Url = "http://localhost:3009/provers".parse()?;
+        let options = Options {
+            batch_size: 3,
+            mtb_prover_discovery_url: Some(url.clone()),
+            ..Options::default()
+        };
+        let map: InsertionProverMap = Arc::new(RwLock::new(ProverMap::default()));
+
+        refresh(&map, &url, &options).await?;
+        assert_eq!(map.read().await.as_batch_size_vec(), vec![3]);
+
+        *listing.lock().unwrap() = r#"[
+            {"url": "http://localhost:3020", "batch_size": 3},
+            {"url": "http://localhost:3021", "batch_size": 5}
+        ]"#
+        .to_string();
+        let task = start_refresh(
+            map.clone(),
+            url.clone(),
+            options.clone(),
+            Duration::from_millis(50),
+        );
+        sleep(Duration::from_millis(500)).await;
+        assert_eq!(map.read().await.as_batch_size_vec(), vec![3, 5]);
+
+        // An empty listing is rejected and the current provers kept.
+        *listing.lock().unwrap() = "[]".to_string();
+        assert!(refresh(&map, &url, &options).await.is_err());
+        assert_eq!(map.read().await.as_batch_size_vec(), vec![3, 5]);
+
+        task.abort();
+        server.shutdown();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use crate::prover::{map::ProverMap, Prover};
+use cli_batteries::await_shutdown;
+use futures::future::join_all;
+use once_cell::sync::Lazy;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use std::{sync::Arc, time::Duration};
+use tokio::{select, sync::RwLock, task::JoinHandle, time::sleep};
+use tracing::{info, warn};
+
+static HEALTHY_PROVERS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "provers_healthy",
+        "Number of provers that passed their last health check, by kind.",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+static TOTAL_PROVERS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "provers_total",
+        "Number of registered provers, by kind.",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+/// Health checks every prover in `map`, marks those that fail unavailable
+/// and those that pass available again, and records the number of healthy and
+/// registered provers under `kind`.
+///
+/// Returns the number of healthy provers.
+pub async fn check_health(kind: &str, map: &RwLock<ProverMap>) -> usize {
+    let outcomes: Vec<(usize, bool)> = {
+        let map = map.read().await;
+        let results = join_all(map.provers().map(Prover::health_check)).await;
+        map.provers()
+            .zip(results)
+            .map(|(prover, result)| {
+                if let Err(error) = &result {
+                    warn!(kind, batch_size = prover.batch_size(), url = %prover.url(), %error, "Prover failed its health check.");
+                }
+                (prover.batch_size(), result.is_ok())
+            })
+            .collect()
+    };
+
+    // The probes run without blocking batches, so the map may have changed in
+    // the meantime. Provers removed since are skipped.
+    let mut map = map.write().await;
+    for &(batch_size, healthy) in &outcomes {
+        if map.set_available(batch_size, healthy) {
+            if healthy {
+                info!(
+                    kind,
+                    batch_size, "Prover is healthy again, routing batches to it."
+                );
+            } else {
+                warn!(kind, batch_size, "Prover marked unavailable.");
+            }
+        }
+    }
+
+    let healthy = outcomes.iter().filter(|(_, healthy)| *healthy).count();
+    HEALTHY_PROVERS
+        .with_label_values(&[kind])
+        .set(healthy.try_into().unwrap_or(i64::MAX));
+    TOTAL_PROVERS
+        .with_label_values(&[kind])
+        .set(map.len().try_into().unwrap_or(i64::MAX));
+    healthy
+}
+
+/// Spawns a task that checks the health of the provers in each of `maps`
+/// every `interval`, until shutdown. Each map is labeled with its kind.
+pub fn start_health_checks(
+    maps: Vec<(&'static str, Arc<RwLock<ProverMap>>)>,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            join_all(maps.iter().map(|(kind, map)| check_health(kind, map))).await;
+            select! {
+                _ = sleep(interval) => {}
+                _ = await_shutdown() => {
+                    info!("Stopping prover health checks.");
+                    return;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prover::{mock, ProverConfiguration};
+
+    #[tokio::test]
+    async fn gauges_should_follow_prover_health() -> anyhow::Result<()> {
+        let first_service = mock::Service::new("0.0.0.0:3023".into()).await?;
+        let second_service = mock::Service::new("0.0.0.0:3024".into()).await?;
+        let mut map = ProverMap::default();
+        for (url, batch_size) in [("http://localhost:3023", 3), ("http://localhost:3024", 5)] {
+            let prover = Prover::from_configuration(
+                &ProverConfiguration {
+                    url: url.into(),
+                    batch_size,
+                    max_concurrency: None,
+                    tls_client: None,
+                    region: None,
+                },
+                Duration::from_secs(30),
+                3,
+            )?;
+            map.add(batch_size, prover);
+        }
+        let map = Arc::new(RwLock::new(map));
+        let healthy = HEALTHY_PROVERS.with_label_values(&["test"]);
+        let total = TOTAL_PROVERS.with_label_values(&["test"]);
+
+        let task = start_health_checks(vec![("test", map.clone())], Duration::from_millis(50));
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!((healthy.get(), total.get()), (2, 2));
+
+        first_service.set_healthy(false);
+        second_service.set_healthy(false);
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!((healthy.get(), total.get()), (0, 2));
+        assert!(map.read().await.get(1).is_none());
+
+        second_service.set_healthy(true);
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!((healthy.get(), total.get()), (1, 2));
+        // Batches skip the unavailable prover for the next larger one.
+        assert_eq!(map.read().await.get(1).map(Prover::batch_size), Some(5));
+        assert_eq!(map.read().await.max_batch_size(), 5);
+
+        first_service.set_healthy(true);
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!((healthy.get(), total.get()), (2, 2));
+
+        task.abort();
+        first_service.stop();
+        second_service.stop();
+
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 use ethers::types::U256;
+use semaphore::{merkle_tree::Branch, poseidon_tree::Proof, Field};
 
 /// A representation of an identity insertion into the merkle tree as used for
 /// the prover endpoint.
@@ -20,6 +21,20 @@ impl Identity {
             merkle_proof,
         }
     }
+
+    /// Constructs the identity value of `commitment` from the `proof` of its
+    /// leaf in the tree.
+    #[must_use]
+    pub fn from_tree_proof(commitment: Field, proof: &Proof) -> Self {
+        let merkle_proof = proof
+            .0
+            .iter()
+            .map(|branch| match branch {
+                Branch::Left(sibling) | Branch::Right(sibling) => U256::from(sibling.to_be_bytes()),
+            })
+            .collect();
+        Self::new(U256::from(commitment.to_be_bytes()), merkle_proof)
+    }
 }
 
 /// A merkle proof is a list of values for the nodes in the merkle tree once the
@@ -0,0 +1,709 @@
+use crate::prover::{Options, Prover, ProverConfiguration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    ops::RangeBounds,
+    sync::Arc,
+};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+/// A prover map that can be shared between the components that need to select
+/// a prover for a batch of insertions.
+pub type InsertionProverMap = Arc<RwLock<ProverMap>>;
+
+/// A prover map that can be shared between the components that need to select
+/// a prover for a batch of deletions.
+pub type DeletionProverMap = Arc<RwLock<ProverMap>>;
+
+/// The batch sizes added and removed by [`ProverMap::update_provers`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ProverUpdate {
+    pub added:   Vec<usize>,
+    pub removed: Vec<usize>,
+}
+
+/// Reasons for rejecting [`ProverMap::update_provers`], [`add_shared_prover`]
+/// and [`remove_shared_prover`].
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum UpdateError {
+    #[error("The update would leave no provers.")]
+    Empty,
+    #[error("Batch size {0} is needed by an in-flight batch.")]
+    InUse(usize),
+    #[error("A prover for batch size {0} is already registered.")]
+    Duplicate(usize),
+    #[error("No prover is registered for batch size {0}.")]
+    NotFound(usize),
+    #[error("The prover for batch size {0} failed its health check.")]
+    Unreachable(usize),
+}
+
+/// A map from batch sizes to the provers that are set up to prove batches of
+/// that size.
+///
+/// The provers are kept in ascending order of their batch size. Provers
+/// marked unavailable by the health checks stay registered, but batches are
+/// not routed to them.
+///
+/// Further provers for a registered batch size can be added as replicas, which
+/// are asked for the same proofs in parallel.
+#[derive(Debug)]
+pub struct ProverMap<P = Prover> {
+    map:              BTreeMap<usize, P>,
+    /// The further provers of the registered batch sizes.
+    replicas:         BTreeMap<usize, Vec<P>>,
+    /// The batch sizes whose provers failed their last health check.
+    unavailable:      BTreeSet<usize>,
+    /// The region whose provers [`ProverMap::healthy_get`] tries first.
+    preferred_region: Option<String>,
+}
+
+impl<P> Default for ProverMap<P> {
+    fn default() -> Self {
+        Self {
+            map:              BTreeMap::new(),
+            replicas:         BTreeMap::new(),
+            unavailable:      BTreeSet::new(),
+            preferred_region: None,
+        }
+    }
+}
+
+impl<P> ProverMap<P> {
+    /// Routes batches to the provers of `region` first, if set.
+    #[must_use]
+    pub fn with_preferred_region(mut self, region: Option<String>) -> Self {
+        self.preferred_region = region;
+        self
+    }
+
+    /// Returns the smallest available prover that is able to handle a batch
+    /// of `batch_size` identities.
+    pub fn get(&self, batch_size: usize) -> Option<&P> {
+        self.available_range(batch_size..)
+            .next()
+            .map(|(_, prover)| prover)
+    }
+
+    /// Returns the smallest available prover that is able to handle a batch
+    /// of `batch_size` identities, followed by its replicas.
+    ///
+    /// Empty if no available prover can handle the batch.
+    pub fn get_with_replicas(&self, batch_size: usize) -> Vec<&P> {
+        self.available_range(batch_size..)
+            .next()
+            .map(|(size, prover)| {
+                std::iter::once(prover)
+                    .chain(self.replicas.get(size).into_iter().flatten())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the largest batch size that can be filled from `available`
+    /// identities, together with its prover, among the available provers.
+    ///
+    /// Falls back to the smallest prover if `available` is below every
+    /// registered batch size, so that small batches are never stalled.
+    pub fn get_largest_fitting(&self, available: usize) -> Option<(usize, &P)> {
+        self.available_range(..=available)
+            .next_back()
+            .or_else(|| self.available_range(..).next())
+            .map(|(&batch_size, prover)| (batch_size, prover))
+    }
+
+    /// Registers `prover` for batches of `batch_size`, returning the prover
+    /// that was previously registered for that size, if any. A new prover is
+    /// available until a health check fails.
+    pub fn add(&mut self, batch_size: usize, prover: P) -> Option<P> {
+        self.unavailable.remove(&batch_size);
+        self.map.insert(batch_size, prover)
+    }
+
+    /// Registers `prover` as a replica of the prover for batches of
+    /// `batch_size`, or as that prover if there is none yet.
+    pub fn add_replica(&mut self, batch_size: usize, prover: P) {
+        if self.map.contains_key(&batch_size) {
+            self.replicas.entry(batch_size).or_default().push(prover);
+        } else {
+            self.add(batch_size, prover);
+        }
+    }
+
+    /// Removes the prover registered for batches of `batch_size`, along with
+    /// its replicas.
+    pub fn remove(&mut self, batch_size: usize) -> Option<P> {
+        self.unavailable.remove(&batch_size);
+        self.replicas.remove(&batch_size);
+        self.map.remove(&batch_size)
+    }
+
+    /// Marks the prover registered for `batch_size` as available or not,
+    /// returning whether its availability changed.
+    pub fn set_available(&mut self, batch_size: usize, available: bool) -> bool {
+        if !self.map.contains_key(&batch_size) {
+            return false;
+        }
+        if available {
+            self.unavailable.remove(&batch_size)
+        } else {
+            self.unavailable.insert(batch_size)
+        }
+    }
+
+    /// Returns `true` if the prover registered for `batch_size` passed its
+    /// last health check, or has not been checked yet.
+    #[cfg(test)]
+    pub fn is_available(&self, batch_size: usize) -> bool {
+        self.map.contains_key(&batch_size) && !self.unavailable.contains(&batch_size)
+    }
+
+    /// Returns the largest batch size that any of the available provers can
+    /// handle, or zero if none are.
+    pub fn max_batch_size(&self) -> usize {
+        self.available_range(..)
+            .next_back()
+            .map_or(0, |(&batch_size, _)| batch_size)
+    }
+
+    fn available_range(
+        &self,
+        range: impl RangeBounds<usize>,
+    ) -> impl DoubleEndedIterator<Item = (&usize, &P)> {
+        self.map
+            .range(range)
+            .filter(|(batch_size, _)| !self.unavailable.contains(batch_size))
+    }
+
+    /// Returns `true` if a prover is registered for exactly `batch_size`.
+    pub fn batch_size_exists(&self, batch_size: usize) -> bool {
+        self.map.contains_key(&batch_size)
+    }
+
+    /// Returns `true` if no provers are registered.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns the number of registered provers.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns the registered batch sizes in ascending order.
+    pub fn as_batch_size_vec(&self) -> Vec<usize> {
+        self.map.keys().copied().collect()
+    }
+
+    /// Replaces the registered provers with `desired`.
+    ///
+    /// The update is rejected, leaving the map untouched, if it would leave
+    /// the map empty or remove any of the `in_flight` batch sizes. Provers for
+    /// batch sizes in both maps are replaced by the desired ones, and replicas
+    /// of removed batch sizes are dropped.
+    pub fn update_provers(
+        &mut self,
+        desired: BTreeMap<usize, P>,
+        in_flight: &BTreeSet<usize>,
+    ) -> Result<ProverUpdate, UpdateError> {
+        if desired.is_empty() {
+            return Err(UpdateError::Empty);
+        }
+        if let Some(&batch_size) = in_flight.iter().find(|size| !desired.contains_key(size)) {
+            return Err(UpdateError::InUse(batch_size));
+        }
+
+        let update = ProverUpdate {
+            added:   desired
+                .keys()
+                .filter(|size| !self.map.contains_key(size))
+                .copied()
+                .collect(),
+            removed: self
+                .map
+                .keys()
+                .filter(|size| !desired.contains_key(size))
+                .copied()
+                .collect(),
+        };
+        self.map = desired;
+        self.replicas
+            .retain(|batch_size, _| self.map.contains_key(batch_size));
+        self.unavailable
+            .retain(|batch_size| self.map.contains_key(batch_size));
+
+        Ok(update)
+    }
+
+    /// Iterates over the registered provers in ascending order of batch size.
+    pub fn provers(&self) -> impl Iterator<Item = &P> {
+        self.map.values()
+    }
+}
+
+impl ProverMap<Prover> {
+    /// Returns the smallest healthy prover that is able to handle a batch of
+    /// `batch_size` identities.
+    ///
+    /// Provers that fail their health check are skipped in favour of the next
+    /// larger one, but are kept in the map. Provers marked unavailable are not
+    /// tried at all. If a region is preferred, its
+    /// provers are tried first and those of other regions only once none of
+    /// them is healthy.
+    pub async fn healthy_get(&self, batch_size: usize) -> Option<&Prover> {
+        let (preferred, others): (Vec<_>, Vec<_>) =
+            self.available_range(batch_size..).partition(|(_, prover)| {
+                self.preferred_region.is_some()
+                    && prover.region() == self.preferred_region.as_deref()
+            });
+        for (&size, prover) in preferred.into_iter().chain(others) {
+            match prover.health_check().await {
+                Ok(()) => return Some(prover),
+                Err(error) => {
+                    warn!(batch_size = size, %error, "Prover is unhealthy, trying the next one.");
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Replaces the provers in a shared `map`, see [`ProverMap::update_provers`].
+///
+/// The write lock is held for the whole update, so readers see either the old
+/// or the new set of provers.
+pub async fn update_shared_provers(
+    map: &RwLock<ProverMap>,
+    desired: BTreeMap<usize, Prover>,
+    in_flight: &BTreeSet<usize>,
+) -> Result<ProverUpdate, UpdateError> {
+    let update = map.write().await.update_provers(desired, in_flight)?;
+    info!(added = ?update.added, removed = ?update.removed, "Updated provers.");
+    Ok(update)
+}
+
+/// Registers `prover` in a shared `map`.
+///
+/// The prover must pass its health check and no other prover may be
+/// registered for its batch size. The health check is done before taking the
+/// write lock, so that a slow prover does not hold up batches.
+///
+/// `persist` is awaited under the write lock once the prover is accepted, and
+/// the prover is only registered if it succeeds, so the map never diverges
+/// from what was persisted.
+pub async fn add_shared_prover<E: From<UpdateError>>(
+    map: &RwLock<ProverMap>,
+    prover: Prover,
+    persist: impl Future<Output = Result<(), E>> + Send,
+) -> Result<(), E> {
+    let batch_size = prover.batch_size;
+    if let Err(error) = prover.health_check().await {
+        warn!(batch_size, %error, url = %prover.target_url, "Rejected unreachable prover.");
+        return Err(UpdateError::Unreachable(batch_size).into());
+    }
+
+    let mut map = map.write().await;
+    if map.batch_size_exists(batch_size) {
+        return Err(UpdateError::Duplicate(batch_size).into());
+    }
+    persist.await?;
+    info!(batch_size, url = %prover.target_url, "Added prover.");
+    map.add(batch_size, prover);
+    Ok(())
+}
+
+/// Removes the prover for `batch_size` from a shared `map`, refusing to remove
+/// the last one.
+///
+/// Like in [`add_shared_prover`], the prover is only removed if `persist`
+/// succeeds.
+pub async fn remove_shared_prover<E: From<UpdateError>>(
+    map: &RwLock<ProverMap>,
+    batch_size: usize,
+    persist: impl Future<Output = Result<(), E>> + Send,
+) -> Result<(), E> {
+    let mut map = map.write().await;
+    if !map.batch_size_exists(batch_size) {
+        return Err(UpdateError::NotFound(batch_size).into());
+    }
+    if map.len() == 1 {
+        return Err(UpdateError::Empty.into());
+    }
+    persist.await?;
+    if let Some(prover) = map.remove(batch_size) {
+        info!(batch_size, url = %prover.target_url, "Removed prover.");
+    }
+    Ok(())
+}
+
+/// Builds the map of insertion provers from the provided `options`.
+pub fn make_insertion_map(options: &Options) -> anyhow::Result<ProverMap> {
+    make_map(
+        &[ProverConfiguration {
+            url:             options.mtb_prover_url.clone(),
+            batch_size:      options.batch_size,
+            max_concurrency: options.mtb_prover_max_concurrency,
+            tls_client:      options.mtb_prover_tls_client.clone(),
+            region:          None,
+        }],
+        options,
+    )
+}
+
+/// Builds the map of deletion provers from the provided `options`.
+pub fn make_deletion_map(options: &Options) -> anyhow::Result<ProverMap> {
+    make_map(&options.deletion_provers.0, options)
+}
+
+fn make_map(
+    configurations: &[ProverConfiguration],
+    options: &Options,
+) -> anyhow::Result<ProverMap> {
+    let mut map =
+        ProverMap::default().with_preferred_region(options.mtb_prover_preferred_region.clone());
+    // Provers configured for the same batch size prove its batches in parallel.
+    for configuration in configurations {
+        map.add_replica(
+            configuration.batch_size,
+            Prover::from_options(configuration, options)?,
+        );
+    }
+
+    Ok(map)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prover::mock;
+    use std::time::Duration;
+
+    fn test_map() -> ProverMap<usize> {
+        let mut map = ProverMap::default();
+        for batch_size in [3, 5, 7] {
+            map.add(batch_size, batch_size);
+        }
+        map
+    }
+
+    #[test]
+    fn prover_map_tests() {
+        let map = test_map();
+
+        assert_eq!(map.get(1), Some(&3));
+        assert_eq!(map.get(3), Some(&3));
+        assert_eq!(map.get(4), Some(&5));
+        assert_eq!(map.get(7), Some(&7));
+        assert_eq!(map.get(8), None);
+    }
+
+    #[test]
+    fn prover_map_largest_fitting_tests() {
+        let map = test_map();
+
+        assert_eq!(map.get_largest_fitting(2), Some((3, &3)));
+        assert_eq!(map.get_largest_fitting(5), Some((5, &5)));
+        assert_eq!(map.get_largest_fitting(6), Some((5, &5)));
+        assert_eq!(map.get_largest_fitting(100), Some((7, &7)));
+        assert_eq!(ProverMap::<usize>::default().get_largest_fitting(5), None);
+    }
+
+    #[test]
+    fn unavailable_provers_should_be_skipped() {
+        let mut map = test_map();
+
+        assert!(map.set_available(5, false));
+        assert!(!map.set_available(5, false));
+        assert!(!map.set_available(4, false));
+        assert!(!map.is_available(5));
+        assert_eq!(map.get(4), Some(&7));
+        assert_eq!(map.get_largest_fitting(6), Some((3, &3)));
+        assert_eq!(map.as_batch_size_vec(), vec![3, 5, 7]);
+
+        assert!(map.set_available(7, false));
+        assert_eq!(map.max_batch_size(), 3);
+        assert_eq!(map.get(4), None);
+
+        // A replaced prover starts out available.
+        map.add(7, 7);
+        assert_eq!(map.get(4), Some(&7));
+        assert!(map.set_available(5, true));
+        assert_eq!(map.get(4), Some(&5));
+    }
+
+    #[test]
+    fn deletion_map_should_have_entry_per_prover() -> anyhow::Result<()> {
+        let options = Options {
+            batch_size: 3,
+            deletion_provers: r#"[
+                {"url": "http://localhost:3010", "batch_size": 10},
+                {"url": "http://localhost:3011", "batch_size": 4}
+            ]"#
+            .parse()?,
+            ..Options::default()
+        };
+
+        let map = make_deletion_map(&options)?;
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.as_batch_size_vec(), vec![4, 10]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn healthy_get_should_fall_through_unhealthy_provers() -> anyhow::Result<()> {
+        let small_service = mock::Service::new("0.0.0.0:3006".into()).await?;
+        let large_service = mock::Service::new("0.0.0.0:3007".into()).await?;
+        let options = Options {
+            mtb_prover_url: "http://localhost:3006".into(),
+            batch_size: 3,
+            deletion_provers: r#"[
+                {"url": "http://localhost:3006", "batch_size": 3},
+                {"url": "http://localhost:3007", "batch_size": 5}
+            ]"#
+            .parse()?,
+            ..Options::default()
+        };
+        let map = make_deletion_map(&options)?;
+
+        assert_eq!(map.healthy_get(2).await.map(|p| p.batch_size), Some(3));
+
+        small_service.set_healthy(false);
+        assert_eq!(map.healthy_get(2).await.map(|p| p.batch_size), Some(5));
+        assert_eq!(map.len(), 2);
+
+        large_service.set_healthy(false);
+        assert!(map.healthy_get(2).await.is_none());
+
+        small_service.set_healthy(true);
+        assert_eq!(map.healthy_get(2).await.map(|p| p.batch_size), Some(3));
+
+        small_service.stop();
+        large_service.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn healthy_get_should_prefer_region_and_fail_over() -> anyhow::Result<()> {
+        let near_service = mock::Service::new("0.0.0.0:3026".into()).await?;
+        let far_service = mock::Service::new("0.0.0.0:3027".into()).await?;
+        let options = Options {
+            mtb_prover_url: "http://localhost:3026".into(),
+            batch_size: 3,
+            mtb_prover_preferred_region: Some("eu".into()),
+            deletion_provers: r#"[
+                {"url": "http://localhost:3027", "batch_size": 3, "region": "us"},
+                {"url": "http://localhost:3026", "batch_size": 5, "region": "eu"}
+            ]"#
+            .parse()?,
+            ..Options::default()
+        };
+        let map = make_deletion_map(&options)?;
+
+        // The smaller prover is skipped for the preferred region.
+        assert_eq!(map.healthy_get(2).await.map(|p| p.batch_size), Some(5));
+
+        near_service.set_healthy(false);
+        assert_eq!(map.healthy_get(2).await.map(|p| p.batch_size), Some(3));
+
+        near_service.set_healthy(true);
+        assert_eq!(map.healthy_get(2).await.map(|p| p.batch_size), Some(5));
+
+        near_service.stop();
+        far_service.stop();
+
+        Ok(())
+    }
+
+    fn desired(batch_sizes: &[usize]) -> BTreeMap<usize, usize> {
+        batch_sizes.iter().map(|&size| (size, size)).collect()
+    }
+
+    #[test]
+    fn update_provers_should_swap_provers() {
+        let mut map = test_map();
+
+        let update = map.update_provers(desired(&[5, 9]), &BTreeSet::from([5]));
+
+        assert_eq!(
+            update,
+            Ok(ProverUpdate {
+                added:   vec![9],
+                removed: vec![3, 7],
+            })
+        );
+        assert_eq!(map.as_batch_size_vec(), vec![5, 9]);
+    }
+
+    #[test]
+    fn update_provers_should_reject_invalid_updates() {
+        let mut map = test_map();
+
+        assert_eq!(
+            map.update_provers(desired(&[]), &BTreeSet::new()),
+            Err(UpdateError::Empty)
+        );
+        assert_eq!(
+            map.update_provers(desired(&[3, 5]), &BTreeSet::from([7])),
+            Err(UpdateError::InUse(7))
+        );
+        assert_eq!(map.as_batch_size_vec(), vec![3, 5, 7]);
+    }
+
+    #[test]
+    fn update_provers_should_allow_noop() {
+        let mut map = test_map();
+
+        assert_eq!(
+            map.update_provers(desired(&[3, 5, 7]), &BTreeSet::new()),
+            Ok(ProverUpdate::default())
+        );
+        assert_eq!(map.as_batch_size_vec(), vec![3, 5, 7]);
+    }
+
+    fn persisted() -> std::future::Ready<Result<(), UpdateError>> {
+        std::future::ready(Ok(()))
+    }
+
+    fn test_prover(url: &str, batch_size: usize) -> anyhow::Result<Prover> {
+        Prover::from_configuration(
+            &ProverConfiguration {
+                url: url.into(),
+                batch_size,
+                max_concurrency: None,
+                tls_client: None,
+                region: None,
+            },
+            Duration::from_secs(30),
+            3,
+        )
+    }
+
+    #[tokio::test]
+    async fn shared_provers_should_be_added_and_removed() -> anyhow::Result<()> {
+        let service = mock::Service::new("0.0.0.0:3012".into()).await?;
+        let map = RwLock::new(ProverMap::default());
+
+        add_shared_prover(&map, test_prover("http://localhost:3012", 3)?, persisted()).await?;
+        add_shared_prover(&map, test_prover("http://localhost:3012", 5)?, persisted()).await?;
+        assert_eq!(map.read().await.as_batch_size_vec(), vec![3, 5]);
+
+        assert_eq!(
+            add_shared_prover(&map, test_prover("http://localhost:3012", 5)?, persisted()).await,
+            Err(UpdateError::Duplicate(5))
+        );
+
+        remove_shared_prover(&map, 3, persisted()).await?;
+        assert_eq!(map.read().await.as_batch_size_vec(), vec![5]);
+        assert_eq!(
+            remove_shared_prover(&map, 3, persisted()).await,
+            Err(UpdateError::NotFound(3))
+        );
+
+        service.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn last_shared_prover_should_not_be_removed() -> anyhow::Result<()> {
+        let service = mock::Service::new("0.0.0.0:3013".into()).await?;
+        let map = RwLock::new(ProverMap::default());
+        add_shared_prover(&map, test_prover("http://localhost:3013", 3)?, persisted()).await?;
+
+        assert_eq!(
+            remove_shared_prover(&map, 3, persisted()).await,
+            Err(UpdateError::Empty)
+        );
+        assert_eq!(map.read().await.as_batch_size_vec(), vec![3]);
+
+        service.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_persistence_should_leave_map_untouched() -> anyhow::Result<()> {
+        let service = mock::Service::new("0.0.0.0:3032".into()).await?;
+        let map = RwLock::new(ProverMap::default());
+        add_shared_prover(&map, test_prover("http://localhost:3032", 3)?, persisted()).await?;
+        add_shared_prover(&map, test_prover("http://localhost:3032", 5)?, persisted()).await?;
+
+        let failed = std::future::ready(Err(UpdateError::InUse(7)));
+        assert_eq!(
+            add_shared_prover(&map, test_prover("http://localhost:3032", 7)?, failed).await,
+            Err(UpdateError::InUse(7))
+        );
+        let failed = std::future::ready(Err(UpdateError::InUse(3)));
+        assert_eq!(
+            remove_shared_prover(&map, 3, failed).await,
+            Err(UpdateError::InUse(3))
+        );
+        assert_eq!(map.read().await.as_batch_size_vec(), vec![3, 5]);
+
+        service.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unreachable_provers_should_not_be_added() -> anyhow::Result<()> {
+        let service = mock::Service::new("0.0.0.0:3014".into()).await?;
+        service.set_healthy(false);
+        let map = RwLock::new(ProverMap::default());
+
+        assert_eq!(
+            add_shared_prover(&map, test_prover("http://localhost:3014", 3)?, persisted()).await,
+            Err(UpdateError::Unreachable(3))
+        );
+        // Nothing is listening on this port at all.
+        assert_eq!(
+            add_shared_prover(&map, test_prover("http://localhost:3015", 5)?, persisted()).await,
+            Err(UpdateError::Unreachable(5))
+        );
+        assert!(map.read().await.is_empty());
+
+        service.stop();
+
+        Ok(())
+    }
+
+    #[test]
+    fn replicas_should_follow_their_batch_size() {
+        let mut map = test_map();
+        map.add_replica(5, 50);
+        map.add_replica(5, 51);
+        map.add_replica(9, 9);
+
+        assert_eq!(map.get_with_replicas(4), vec![&5, &50, &51]);
+        assert_eq!(map.get_with_replicas(8), vec![&9]);
+        assert_eq!(map.as_batch_size_vec(), vec![3, 5, 7, 9]);
+
+        // Replicas are only routed to along with their available prover.
+        map.set_available(5, false);
+        assert_eq!(map.get_with_replicas(4), vec![&7]);
+        map.set_available(5, true);
+
+        map.update_provers(desired(&[5, 7]), &BTreeSet::new())
+            .unwrap();
+        assert_eq!(map.get_with_replicas(4), vec![&5, &50, &51]);
+        map.remove(5);
+        map.add(5, 5);
+        assert_eq!(map.get_with_replicas(4), vec![&5]);
+        assert!(map.get_with_replicas(10).is_empty());
+    }
+
+    #[test]
+    fn max_batch_size_should_track_largest_prover() {
+        let mut map = test_map();
+        assert_eq!(map.max_batch_size(), 7);
+
+        map.remove(7);
+        assert_eq!(map.max_batch_size(), 5);
+
+        assert_eq!(ProverMap::<usize>::default().max_batch_size(), 0);
+    }
+}
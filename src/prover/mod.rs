@@ -1,22 +1,123 @@
-#![allow(unused_variables, dead_code)] // TODO [AA] Remove when this is used outside of tests.
-mod identity;
-mod proof;
+pub mod circuit_breaker;
+pub mod discovery;
+pub mod health;
+pub mod identity;
+pub mod map;
+pub mod proof;
+pub mod verification;
 
-use crate::prover::{identity::Identity, proof::Proof};
+use crate::prover::{circuit_breaker::CircuitBreaker, identity::Identity, proof::Proof};
+use anyhow::Context;
 use clap::Parser;
-use ethers::{types::U256, utils::keccak256};
-use reqwest;
+use ethers::{
+    core::k256::ecdsa::SigningKey,
+    signers::{LocalWallet, Signer},
+    types::{H256, U256},
+    utils::keccak256,
+};
+use futures::future::{join_all, select_ok};
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+    HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+};
+use reqwest::{self, header::HeaderValue};
 use serde::{Deserialize, Serialize};
 use std::{
-    fmt::{Display, Formatter},
+    fmt::{Debug, Display, Formatter},
+    fs,
+    future::Future,
     mem::size_of,
-    time::Duration,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use tokio::{
+    sync::{Semaphore, SemaphorePermit},
+    time::sleep,
+};
+use tracing::{error, info, warn};
 use url::Url;
 
 /// The endpoint used for proving operations.
 const MTB_PROVE_ENDPOINT: &str = "prove";
 
+/// The header carrying the signature of a proof request, if signing is
+/// enabled.
+pub const REQUEST_SIGNATURE_HEADER: &str = "x-sequencer-signature";
+
+/// The endpoint used for checking the health of the prover service.
+const MTB_HEALTH_ENDPOINT: &str = "health";
+
+/// The endpoint at which the prover service reports its configuration.
+const MTB_INFO_ENDPOINT: &str = "info";
+
+/// The maximum amount of time to wait for a prover to answer a health check.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The delay before the first retry of a failed proof request. It doubles with
+/// every further retry.
+const PROVE_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+static PROOF_RETRIES_EXHAUSTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prover_proof_retries_exhausted",
+        "Number of proofs that failed on every attempt, by prover.",
+        &["prover"]
+    )
+    .unwrap()
+});
+
+static CIRCUIT_OPEN: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "prover_circuit_open",
+        "Whether a prover is out of rotation after consecutive failed proof requests, by prover.",
+        &["prover"]
+    )
+    .unwrap()
+});
+
+static PROOF_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prover_proof_requests",
+        "Number of proofs requested, by batch size and prover.",
+        &["batch_size", "prover"]
+    )
+    .unwrap()
+});
+
+static PROOF_REQUESTS_INFLIGHT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "prover_proof_requests_inflight",
+        "Number of proof requests currently awaiting an answer, by batch size and prover.",
+        &["batch_size", "prover"]
+    )
+    .unwrap()
+});
+
+static PROOF_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "prover_proof_seconds",
+        "Time taken to generate a proof in seconds, by batch size and prover.",
+        &["batch_size", "prover"],
+        exponential_buckets(0.5, 2.0, 12).unwrap()
+    )
+    .unwrap()
+});
+
+static BATCH_SIZE_MISMATCH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "prover_batch_size_mismatch",
+        "Whether a prover reports a batch size other than its registered one, by prover.",
+        &["prover"]
+    )
+    .unwrap()
+});
+
 /// Configuration options for the component responsible for interacting with the
 /// prover service.
 #[derive(Clone, Debug, PartialEq, Eq, Parser)]
@@ -27,23 +128,228 @@ pub struct Options {
     #[clap(long, env, default_value = "http://localhost:3001")]
     pub mtb_prover_url: String,
 
-    /// The number of seconds to wait before timing out the transaction.
+    /// The number of seconds to wait for a connection to a prover, and for the
+    /// answer of the discovery service.
     #[clap(long, env, default_value = "30")]
     pub mtb_prover_timeout_secs: u64,
 
+    /// The number of times a proof is requested from a prover before giving up
+    /// and raising an alert.
+    #[clap(long, env, default_value = "3")]
+    pub mtb_prover_max_attempts: usize,
+
     // TODO Add and query a prover `info` endpoint instead.
     /// The batch size that the prover is set up to work with. This must match
     /// the deployed prover.
     #[clap(long, env, default_value = "50")]
     pub batch_size: usize,
+
+    /// The maximum number of proofs to request from the prover at once.
+    /// Unlimited if unset.
+    #[clap(long, env)]
+    pub mtb_prover_max_concurrency: Option<usize>,
+
+    /// The client certificate to present to the prover if it requires mutual
+    /// TLS, as a JSON `{"cert": "...", "key": "..."}` object of paths to PEM
+    /// files.
+    #[clap(long, env)]
+    pub mtb_prover_tls_client: Option<TlsClientConfiguration>,
+
+    /// The URL of a service listing the insertion provers, as a JSON array in
+    /// the format of `--deletion-provers`. If set, the insertion provers are
+    /// fetched from it on startup and periodically refreshed, instead of using
+    /// `--mtb-prover-url`.
+    #[clap(long, env)]
+    pub mtb_prover_discovery_url: Option<Url>,
+
+    /// The interval at which to refresh the insertion provers from the
+    /// discovery service (seconds).
+    #[clap(long, env, default_value = "60")]
+    pub mtb_prover_discovery_interval_secs: u64,
+
+    /// Private key used to sign proof requests, for provers that only accept
+    /// requests from a known sequencer. The EIP-191 signature of the request
+    /// body is sent in the `x-sequencer-signature` header. Requests are not
+    /// signed if unset.
+    #[clap(long, env)]
+    // NOTE: We abuse `Hash` here because it has the right `FromStr` implementation.
+    pub mtb_prover_signing_key: Option<H256>,
+
+    /// The interval at which to check that every prover still reports the
+    /// batch size it is registered for (seconds). Provers that report another
+    /// one are considered unhealthy until they match again. Not checked if
+    /// unset.
+    #[clap(long, env)]
+    pub mtb_prover_batch_size_check_interval_secs: Option<u64>,
+
+    /// The interval at which to health check every prover and update the
+    /// `provers_healthy` and `provers_total` gauges (seconds). Not checked if
+    /// unset.
+    #[clap(long, env)]
+    pub mtb_prover_health_check_interval_secs: Option<u64>,
+
+    /// The region whose provers are tried first when selecting a healthy
+    /// prover, before failing over to the provers of other regions. Provers
+    /// are tagged with a `region` in `--deletion-provers` and the discovery
+    /// service. Regions are ignored if unset.
+    #[clap(long, env)]
+    pub mtb_prover_preferred_region: Option<String>,
+
+    /// The number of seconds a proof request may take, including the proof
+    /// generation, before it is abandoned as failed. Zero waits indefinitely.
+    #[clap(long, env, default_value = "0")]
+    pub mtb_prover_request_timeout_secs: u64,
+
+    /// The number of consecutive proof requests that fail to reach a prover
+    /// after which the prover is taken out of rotation. Zero disables the
+    /// circuit breaker.
+    #[clap(long, env, default_value = "0")]
+    pub mtb_prover_circuit_breaker_threshold: usize,
+
+    /// The number of seconds a prover stays out of rotation once its circuit
+    /// breaker opens, before a single request is let through to probe it.
+    #[clap(long, env, default_value = "60")]
+    pub mtb_prover_circuit_breaker_cooldown_secs: u64,
+
+    /// The provers set up to prove deletion batches, as a JSON array of
+    /// `{"url": "...", "batch_size": ..., "max_concurrency": ..., "tls_client":
+    /// ..., "region": "..."}` objects, where all but `url` and `batch_size` are
+    /// optional. They are ignored by a contract that does not verify proofs.
+    #[clap(long, env, default_value = "[]")]
+    pub deletion_provers: ProverConfigurations,
+}
+
+impl Default for Options {
+    /// The options as they are parsed from an empty command line.
+    fn default() -> Self {
+        Self {
+            mtb_prover_url: "http://localhost:3001".into(),
+            mtb_prover_timeout_secs: 30,
+            mtb_prover_max_attempts: 3,
+            batch_size: 50,
+            mtb_prover_max_concurrency: None,
+            mtb_prover_tls_client: None,
+            mtb_prover_discovery_url: None,
+            mtb_prover_discovery_interval_secs: 60,
+            mtb_prover_signing_key: None,
+            mtb_prover_batch_size_check_interval_secs: None,
+            mtb_prover_health_check_interval_secs: None,
+            mtb_prover_preferred_region: None,
+            mtb_prover_request_timeout_secs: 0,
+            mtb_prover_circuit_breaker_threshold: 0,
+            mtb_prover_circuit_breaker_cooldown_secs: 60,
+            deletion_provers: ProverConfigurations::default(),
+        }
+    }
+}
+
+/// The location of a prover service and the batch size it is set up to work
+/// with.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ProverConfiguration {
+    pub url:             String,
+    pub batch_size:      usize,
+    /// The maximum number of proofs to request from the prover at once.
+    /// Unlimited if unset.
+    #[serde(default)]
+    pub max_concurrency: Option<usize>,
+    /// The client certificate to present if the prover requires mutual TLS.
+    #[serde(default)]
+    pub tls_client:      Option<TlsClientConfiguration>,
+    /// The region the prover runs in, for routing batches to the preferred
+    /// region.
+    #[serde(default)]
+    pub region:          Option<String>,
+}
+
+/// The client certificate and key to present to a prover behind mutual TLS,
+/// in PEM format. The key must be in PKCS #8 format.
+///
+/// Both are redacted from the debug output, which ends up in logs.
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum TlsClientConfiguration {
+    /// Paths to the PEM files, for provers configured by the operator.
+    Files { cert: PathBuf, key: PathBuf },
+    /// The PEM contents themselves, for provers added through the admin API,
+    /// which must not get the sequencer to read its own files.
+    #[serde(rename_all = "camelCase")]
+    Pem { cert_pem: String, key_pem: String },
+}
+
+impl Debug for TlsClientConfiguration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Files { .. } => "Files",
+            Self::Pem { .. } => "Pem",
+        };
+        f.debug_struct(name)
+            .field("cert", &"<redacted>")
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
+
+impl FromStr for TlsClientConfiguration {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+    }
+}
+
+impl TlsClientConfiguration {
+    fn load_identity(&self) -> anyhow::Result<reqwest::Identity> {
+        match self {
+            Self::Files { cert, key } => {
+                let cert = fs::read(cert).context("Failed to read TLS client certificate.")?;
+                let key = fs::read(key).context("Failed to read TLS client key.")?;
+                Ok(reqwest::Identity::from_pkcs8_pem(&cert, &key)?)
+            }
+            Self::Pem { cert_pem, key_pem } => Ok(reqwest::Identity::from_pkcs8_pem(
+                cert_pem.as_bytes(),
+                key_pem.as_bytes(),
+            )?),
+        }
+    }
+}
+
+/// A list of prover configurations, parsed from a JSON array.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProverConfigurations(pub Vec<ProverConfiguration>);
+
+impl FromStr for ProverConfigurations {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s).map(Self)
+    }
 }
 
 /// A representation of the connection to the MTB prover service.
 #[derive(Clone, Debug)]
 pub struct Prover {
-    target_url: Url,
-    client:     reqwest::Client,
-    batch_size: usize,
+    target_url:        Url,
+    client:            reqwest::Client,
+    batch_size:        usize,
+    concurrency_limit: Option<Arc<Semaphore>>,
+    max_attempts:      usize,
+    request_timeout:   Option<Duration>,
+    breaker:           Arc<CircuitBreaker>,
+    signer:            Option<LocalWallet>,
+    region:            Option<String>,
+    /// Set while the prover reports a batch size other than `batch_size`.
+    mismatched:        Arc<AtomicBool>,
+    last_error:        Arc<Mutex<Option<ProverFailure>>>,
+}
+
+/// The most recent failure of a prover to generate a proof.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProverFailure {
+    pub message:   String,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
 }
 
 impl Prover {
@@ -51,23 +357,231 @@ impl Prover {
     ///
     /// # Arguments
     /// - `options`: The prover configuration options.
+    #[cfg(test)]
     pub fn new(options: &Options) -> anyhow::Result<Self> {
-        let target_url = Url::parse(&options.mtb_prover_url)?;
-        let timeout_duration = Duration::from_secs(options.mtb_prover_timeout_secs);
-        let batch_size = options.batch_size;
-        let client = reqwest::Client::builder()
+        Self::from_options(
+            &ProverConfiguration {
+                url:             options.mtb_prover_url.clone(),
+                batch_size:      options.batch_size,
+                max_concurrency: options.mtb_prover_max_concurrency,
+                tls_client:      options.mtb_prover_tls_client.clone(),
+                region:          None,
+            },
+            options,
+        )
+    }
+
+    /// Constructs a new instance of the Merkle Tree Batcher (or Mtb) from the
+    /// configuration of a single prover, with the timeouts, retries, circuit
+    /// breaker and request signing of `options`.
+    pub fn from_options(
+        configuration: &ProverConfiguration,
+        options: &Options,
+    ) -> anyhow::Result<Self> {
+        let request_timeout = (options.mtb_prover_request_timeout_secs > 0)
+            .then(|| Duration::from_secs(options.mtb_prover_request_timeout_secs));
+        Ok(Self::from_configuration(
+            configuration,
+            Duration::from_secs(options.mtb_prover_timeout_secs),
+            options.mtb_prover_max_attempts,
+        )?
+        .with_request_signing(options.mtb_prover_signing_key)?
+        .with_resilience(
+            request_timeout,
+            CircuitBreaker::new(
+                options.mtb_prover_circuit_breaker_threshold,
+                Duration::from_secs(options.mtb_prover_circuit_breaker_cooldown_secs),
+            ),
+        ))
+    }
+
+    /// Constructs a new instance of the Merkle Tree Batcher (or Mtb) from the
+    /// configuration of a single prover.
+    ///
+    /// # Arguments
+    /// - `configuration`: The location and batch size of the prover.
+    /// - `timeout_duration`: The time to wait for a connection to the prover.
+    /// - `max_attempts`: The number of times a proof is requested before giving
+    ///   up.
+    pub fn from_configuration(
+        configuration: &ProverConfiguration,
+        timeout_duration: Duration,
+        max_attempts: usize,
+    ) -> anyhow::Result<Self> {
+        let target_url = Url::parse(&configuration.url)?;
+        let batch_size = configuration.batch_size;
+        let concurrency_limit = configuration
+            .max_concurrency
+            .map(|permits| Arc::new(Semaphore::new(permits)));
+        let mut client = reqwest::Client::builder()
             .connect_timeout(timeout_duration)
-            .https_only(false)
-            .build()?;
+            .https_only(false);
+        if let Some(tls_client) = &configuration.tls_client {
+            client = client.identity(tls_client.load_identity()?);
+        }
+        let client = client.build()?;
         let mtb = Self {
             target_url,
             client,
             batch_size,
+            concurrency_limit,
+            max_attempts,
+            request_timeout: None,
+            breaker: Arc::new(CircuitBreaker::disabled()),
+            signer: None,
+            region: configuration.region.clone(),
+            mismatched: Arc::new(AtomicBool::new(false)),
+            last_error: Arc::default(),
         };
 
         Ok(mtb)
     }
 
+    /// Signs every proof request with `signing_key`, if set.
+    ///
+    /// The signature is an EIP-191 personal message signature over the exact
+    /// request body, sent in the [`REQUEST_SIGNATURE_HEADER`] header.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `signing_key` is not a valid secp256k1 key.
+    pub fn with_request_signing(mut self, signing_key: Option<H256>) -> anyhow::Result<Self> {
+        if let Some(signing_key) = signing_key {
+            let signing_key = SigningKey::from_bytes(signing_key.as_bytes())?;
+            self.signer = Some(LocalWallet::from(signing_key));
+        }
+        Ok(self)
+    }
+
+    /// Abandons proof requests that take longer than `request_timeout`, and
+    /// takes the prover out of rotation while `breaker` is open.
+    #[must_use]
+    pub fn with_resilience(
+        mut self,
+        request_timeout: Option<Duration>,
+        breaker: CircuitBreaker,
+    ) -> Self {
+        self.request_timeout = request_timeout;
+        self.breaker = Arc::new(breaker);
+        self
+    }
+
+    /// Returns the batch size the prover is set up to work with.
+    pub const fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Returns the URL of the prover service.
+    pub const fn url(&self) -> &Url {
+        &self.target_url
+    }
+
+    /// Returns the region the prover runs in, if it is tagged with one.
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    /// Checks that the prover service is reachable and reports itself as
+    /// healthy.
+    ///
+    /// A prover whose last [`Self::verify_batch_size`] found a mismatch, or
+    /// whose circuit breaker is open, is unhealthy without being asked.
+    pub async fn health_check(&self) -> anyhow::Result<()> {
+        if self.mismatched.load(Ordering::SeqCst) {
+            return Err(anyhow::Error::msg(format!(
+                "Prover does not report its registered batch size {}.",
+                self.batch_size
+            )));
+        }
+        if self.breaker.is_open() {
+            return Err(anyhow::Error::msg(
+                "Prover is out of rotation after consecutive failed requests.",
+            ));
+        }
+
+        self.client
+            .get(self.target_url.join(MTB_HEALTH_ENDPOINT)?)
+            .timeout(HEALTH_CHECK_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Asks the prover service for the batch size it is set up to work with.
+    pub async fn reported_batch_size(&self) -> anyhow::Result<usize> {
+        let info: ProverInfo = self
+            .client
+            .get(self.target_url.join(MTB_INFO_ENDPOINT)?)
+            .timeout(HEALTH_CHECK_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(info.batch_size)
+    }
+
+    /// Checks that the prover service still reports the batch size it is
+    /// registered for, and returns whether it does.
+    ///
+    /// A mismatch marks the prover unhealthy until a later check finds it
+    /// matching again. It is logged as an error and raises the
+    /// `prover_batch_size_mismatch` gauge for alerting.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the prover does not report its batch size. Its
+    /// health is left unchanged in that case.
+    pub async fn verify_batch_size(&self) -> anyhow::Result<bool> {
+        let reported = self.reported_batch_size().await?;
+        let matches = reported == self.batch_size;
+        let was_mismatched = self.mismatched.swap(!matches, Ordering::SeqCst);
+        if !matches && !was_mismatched {
+            error!(
+                batch_size = self.batch_size,
+                reported,
+                url = %self.target_url,
+                "Prover reports a different batch size, marking it unhealthy."
+            );
+        } else if matches && was_mismatched {
+            info!(batch_size = self.batch_size, url = %self.target_url, "Prover reports its batch size again.");
+        }
+        BATCH_SIZE_MISMATCH
+            .with_label_values(&[self.target_url.as_str()])
+            .set(i64::from(!matches));
+
+        Ok(matches)
+    }
+
+    /// Returns the most recent failure to generate a proof, if any.
+    pub fn last_error(&self) -> Option<ProverFailure> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    fn record_error(&self, error: &anyhow::Error) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        *self.last_error.lock().unwrap() = Some(ProverFailure {
+            message: format!("{error:#}"),
+            timestamp,
+        });
+    }
+
+    /// Waits until the prover has capacity for another proof, if its
+    /// concurrency is limited.
+    ///
+    /// The returned permit must be held for the duration of the request.
+    async fn acquire_permit(&self) -> anyhow::Result<Option<SemaphorePermit<'_>>> {
+        match &self.concurrency_limit {
+            Some(limit) => Ok(Some(limit.acquire().await?)),
+            None => Ok(None),
+        }
+    }
+
     /// Generates a proof term for the provided identity insertions into the
     /// merkle tree.
     ///
@@ -80,12 +594,99 @@ impl Prover {
     ///   were inserted.
     /// - `identities`: A list of identity insertions, ordered in the order the
     ///   identities were inserted into the merkle tree.
+    ///
+    /// A failure is recorded as the prover's [`Self::last_error`]. Requests are
+    /// counted in `prover_proof_requests` and tracked in
+    /// `prover_proof_requests_inflight` until they are answered.
     pub async fn generate_proof(
         &self,
         start_index: u32,
         pre_root: U256,
         post_root: U256,
         identities: Vec<Identity>,
+    ) -> anyhow::Result<Proof> {
+        self.track_request(self.request_proof(start_index, pre_root, post_root, identities))
+            .await
+    }
+
+    /// Generates a proof term for the provided identity deletions from the
+    /// merkle tree.
+    ///
+    /// # Arguments
+    /// - `pre_root`: The value of the merkle tree's root before identities were
+    ///   deleted.
+    /// - `post_root`: The value of the merkle tree's root after the identities
+    ///   were deleted.
+    /// - `deletion_indices`: The leaf indices of the deleted identities.
+    /// - `identities`: The deleted identities, each with its commitment before
+    ///   the deletion and its merkle proof, in the order of `deletion_indices`.
+    ///
+    /// Failures and requests are recorded like for [`Self::generate_proof`].
+    pub async fn generate_deletion_proof(
+        &self,
+        pre_root: U256,
+        post_root: U256,
+        deletion_indices: Vec<u32>,
+        identities: Vec<Identity>,
+    ) -> anyhow::Result<Proof> {
+        self.track_request(self.request_deletion_proof(
+            pre_root,
+            post_root,
+            deletion_indices,
+            identities,
+        ))
+        .await
+    }
+
+    /// Counts `request` in the proof request metrics and records its failure
+    /// as the prover's [`Self::last_error`].
+    ///
+    /// The request is not sent while the circuit breaker is open. Failures to
+    /// reach the prover count towards opening it, while any answer closes it.
+    async fn track_request(
+        &self,
+        request: impl Future<Output = anyhow::Result<Proof>>,
+    ) -> anyhow::Result<Proof> {
+        let Some(attempt) = self.breaker.allows_request() else {
+            return Err(anyhow::Error::msg(
+                "Prover is out of rotation after consecutive failed requests.",
+            ));
+        };
+        let batch_size = self.batch_size.to_string();
+        let labels = [batch_size.as_str(), self.target_url.as_str()];
+        PROOF_REQUESTS.with_label_values(&labels).inc();
+        let _inflight = Inflight::new(PROOF_REQUESTS_INFLIGHT.with_label_values(&labels));
+        let timer = PROOF_DURATION.with_label_values(&labels).start_timer();
+        let result = request.await;
+        match &result {
+            Ok(_) => timer.observe_duration(),
+            Err(error) => {
+                timer.stop_and_discard();
+                self.record_error(error);
+            }
+        }
+        match &result {
+            Err(error) if is_transient(error) => {
+                if attempt.failed() {
+                    warn!(url = %self.target_url, "Prover failed repeatedly, taking it out of rotation.");
+                }
+            }
+            _ => attempt.succeeded(),
+        }
+        CIRCUIT_OPEN
+            .with_label_values(&[self.target_url.as_str()])
+            .set(i64::from(self.breaker.is_open()));
+        result
+    }
+
+    /// Requests a proof term from the prover service, see
+    /// [`Self::generate_proof`].
+    async fn request_proof(
+        &self,
+        start_index: u32,
+        pre_root: U256,
+        post_root: U256,
+        identities: Vec<Identity>,
     ) -> anyhow::Result<Proof> {
         if identities.len() != self.batch_size {
             return Err(anyhow::Error::msg(
@@ -110,24 +711,216 @@ impl Prover {
             merkle_proofs,
         };
 
-        let request = self
+        self.send_proof_request(&proof_input).await
+    }
+
+    /// Requests a deletion proof term from the prover service, see
+    /// [`Self::generate_deletion_proof`].
+    async fn request_deletion_proof(
+        &self,
+        pre_root: U256,
+        post_root: U256,
+        deletion_indices: Vec<u32>,
+        identities: Vec<Identity>,
+    ) -> anyhow::Result<Proof> {
+        if identities.len() != self.batch_size {
+            return Err(anyhow::Error::msg(
+                "Provided batch does not match prover batch size.",
+            ));
+        }
+        if deletion_indices.len() != identities.len() {
+            return Err(anyhow::Error::msg(
+                "Provided deletion indices do not match the deleted identities.",
+            ));
+        }
+
+        let identity_commitments: Vec<U256> = identities.iter().map(|id| id.commitment).collect();
+        let input_hash = compute_deletion_input_hash(
+            &deletion_indices,
+            pre_root,
+            post_root,
+            &identity_commitments,
+        );
+        let merkle_proofs = identities
+            .iter()
+            .map(|id| id.merkle_proof.clone())
+            .collect();
+
+        let proof_input = DeletionProofInput {
+            input_hash,
+            deletion_indices,
+            pre_root,
+            post_root,
+            identity_commitments,
+            merkle_proofs,
+        };
+
+        self.send_proof_request(&proof_input).await
+    }
+
+    /// Sends `input` to the prover service, signed if the prover has a
+    /// signing key, and parses the proof term it responds with.
+    async fn send_proof_request(&self, input: &impl Serialize) -> anyhow::Result<Proof> {
+        let mut request = self
             .client
             .post(self.target_url.join(MTB_PROVE_ENDPOINT)?)
             .body("OH MY GOD")
-            .json(&proof_input)
+            .json(input)
             .build()?;
+        if let Some(signer) = &self.signer {
+            let body = request
+                .body()
+                .and_then(reqwest::Body::as_bytes)
+                .context("Proof request has no body to sign.")?;
+            let signature = signer.sign_message(body).await?;
+            request.headers_mut().insert(
+                REQUEST_SIGNATURE_HEADER,
+                HeaderValue::from_str(&signature.to_string())?,
+            );
+        }
+        if let Some(request_timeout) = self.request_timeout {
+            *request.timeout_mut() = Some(request_timeout);
+        }
+        let _permit = self.acquire_permit().await?;
         let proof_term = self.client.execute(request).await?;
+        let status_error = proof_term.error_for_status_ref().err();
         let json = proof_term.text().await?;
 
         let Ok(proof) = serde_json::from_str::<Proof>(&json) else {
-            let error: ProverError = serde_json::from_str(&json)?;
-            return Err(anyhow::Error::msg(format!("{error}")))
+            return Err(
+                match (serde_json::from_str::<ProverError>(&json), status_error) {
+                    (Ok(error), _) => error.into(),
+                    (Err(_), Some(status_error)) => status_error.into(),
+                    (Err(error), None) => error.into(),
+                },
+            );
         };
 
         Ok(proof)
     }
 }
 
+/// Counts a request in an inflight gauge for as long as it is held, so that
+/// abandoned requests are not counted forever.
+struct Inflight(IntGauge);
+
+impl Inflight {
+    fn new(gauge: IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+impl Drop for Inflight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Prover {
+    /// Generates a proof term like [`Self::generate_proof`], retrying failed
+    /// requests with exponential backoff.
+    ///
+    /// Only transient failures, such as timeouts, connection errors and server
+    /// errors, are retried. Once the last attempt fails, the failure is logged
+    /// as an error and counted in `prover_proof_retries_exhausted` for
+    /// alerting.
+    pub async fn generate_proof_with_retry(
+        &self,
+        start_index: u32,
+        pre_root: U256,
+        post_root: U256,
+        identities: Vec<Identity>,
+    ) -> anyhow::Result<Proof> {
+        let mut backoff = PROVE_INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match self
+                .generate_proof(start_index, pre_root, post_root, identities.clone())
+                .await
+            {
+                Err(error) if attempt < self.max_attempts && is_transient(&error) => {
+                    warn!(%error, attempt, url = %self.target_url, "Proof request failed, retrying.");
+                    sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(error) if !is_transient(&error) => {
+                    error!(%error, url = %self.target_url, "Proof request rejected, not retrying.");
+                    return Err(error);
+                }
+                Err(error) => {
+                    error!(%error, attempts = attempt, url = %self.target_url, "Proof request failed on every attempt.");
+                    PROOF_RETRIES_EXHAUSTED
+                        .with_label_values(&[self.target_url.as_str()])
+                        .inc();
+                    return Err(error);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Returns whether `error` is a failure to reach the prover or to get an
+/// answer from it in time, which may well succeed on another attempt.
+///
+/// An error answered by the prover, or a batch it cannot take, would fail the
+/// same way again.
+fn is_transient(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .map_or(false, |error| {
+            error.is_timeout()
+                || error.is_connect()
+                || error.is_request()
+                || error.is_body()
+                || error.status().map_or(false, |status| {
+                    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                })
+        })
+}
+
+/// Requests the same proof from every one of `provers` at once, as returned
+/// by [`map::ProverMap::get_with_replicas`], and returns the first that
+/// succeeds.
+///
+/// The outstanding requests are dropped once a proof is in, so a slow prover
+/// only delays the batch if all others fail.
+///
+/// # Errors
+///
+/// Will return `Err` if `provers` is empty, or with the last failure if every
+/// prover fails.
+pub async fn generate_proof_from_any(
+    provers: &[&Prover],
+    start_index: u32,
+    pre_root: U256,
+    post_root: U256,
+    identities: Vec<Identity>,
+) -> anyhow::Result<Proof> {
+    anyhow::ensure!(!provers.is_empty(), "No prover to request the proof from.");
+    let requests = provers.iter().map(|prover| {
+        Box::pin(prover.generate_proof_with_retry(
+            start_index,
+            pre_root,
+            post_root,
+            identities.clone(),
+        ))
+    });
+    let (proof, _) = select_ok(requests).await?;
+    Ok(proof)
+}
+
+/// Returns `true` if at least one of the provided `provers` passes its health
+/// check.
+pub async fn any_healthy<'a>(provers: impl IntoIterator<Item = &'a Prover>) -> bool {
+    join_all(provers.into_iter().map(Prover::health_check))
+        .await
+        .iter()
+        .any(Result::is_ok)
+}
+
 /// Computes the input hash to the prover.
 ///
 /// The input hash is specified as the `keccak256` hash of the inputs arranged
@@ -174,6 +967,45 @@ pub fn compute_input_hash(
     keccak256(bytes).into()
 }
 
+/// Computes the input hash to the prover for a batch of deletions.
+///
+/// The input hash is specified as the `keccak256` hash of the inputs arranged
+/// as follows:
+///
+/// ```md
+/// DeletionIndices[0] || ... || DeletionIndices[batchSize-1] || PreRoot || PostRoot || IdComms[0] || ... || IdComms[batchSize-1]
+///         32         || ... ||             32               ||   256   ||   256    ||    256     || ... ||      256 bits
+/// ```
+///
+/// where `DeletionIndices` is `deletion_indices`, the leaf indices of the
+/// deleted identities, and the remaining inputs are as in
+/// [`compute_input_hash`], with `IdComms` holding the commitments before they
+/// were deleted.
+///
+/// The result is computed using the inputs in _big-endian_ byte ordering.
+pub fn compute_deletion_input_hash(
+    deletion_indices: &[u32],
+    pre_root: U256,
+    post_root: U256,
+    identity_commitments: &[U256],
+) -> U256 {
+    let mut bytes: Vec<u8> = vec![];
+    for index in deletion_indices {
+        bytes.extend_from_slice(&index.to_be_bytes());
+    }
+
+    for value in [pre_root, post_root]
+        .iter()
+        .chain(identity_commitments.iter())
+    {
+        let mut value_bytes: [u8; size_of::<U256>()] = Default::default();
+        value.to_big_endian(value_bytes.as_mut_slice());
+        bytes.extend(value_bytes.iter());
+    }
+
+    keccak256(bytes).into()
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ProverError {
@@ -191,6 +1023,15 @@ impl Display for ProverError {
     }
 }
 
+impl std::error::Error for ProverError {}
+
+/// The configuration reported by a prover service.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProverInfo {
+    batch_size: usize,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ProofInput {
@@ -202,9 +1043,109 @@ struct ProofInput {
     merkle_proofs:        Vec<Vec<U256>>,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeletionProofInput {
+    input_hash:           U256,
+    deletion_indices:     Vec<u32>,
+    pre_root:             U256,
+    post_root:            U256,
+    identity_commitments: Vec<U256>,
+    merkle_proofs:        Vec<Vec<U256>>,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use ethers::types::Signature;
+
+    #[test]
+    fn default_options_should_match_empty_command_line() {
+        assert_eq!(Options::try_parse_from([""]).unwrap(), Options::default());
+    }
+
+    #[tokio::test]
+    async fn concurrency_should_be_limited_per_prover() -> anyhow::Result<()> {
+        let prover = |batch_size, max_concurrency| {
+            Prover::from_configuration(
+                &ProverConfiguration {
+                    url: "http://localhost:3001".into(),
+                    batch_size,
+                    max_concurrency,
+                    tls_client: None,
+                    region: None,
+                },
+                Duration::from_secs(30),
+                3,
+            )
+        };
+        let serial = prover(3, Some(1))?;
+        let parallel = prover(10, Some(2))?;
+        let wait = Duration::from_millis(50);
+
+        let _first = serial.acquire_permit().await?;
+        assert!(tokio::time::timeout(wait, serial.acquire_permit())
+            .await
+            .is_err());
+
+        let _first = parallel.acquire_permit().await?;
+        let _second = tokio::time::timeout(wait, parallel.acquire_permit()).await??;
+
+        Ok(())
+    }
+
+    #[test]
+    fn tls_client_certificate_should_be_applied() {
+        let fixture = |name: &str| {
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures")
+                .join(name)
+        };
+        let prover = |tls_client| {
+            Prover::from_configuration(
+                &ProverConfiguration {
+                    url: "https://localhost:3001".into(),
+                    batch_size: 3,
+                    max_concurrency: None,
+                    tls_client,
+                    region: None,
+                },
+                Duration::from_secs(30),
+                3,
+            )
+        };
+        let tls_client = TlsClientConfiguration::Files {
+            cert: fixture("prover-client.crt"),
+            key:  fixture("prover-client.key"),
+        };
+
+        assert!(tls_client.load_identity().is_ok());
+        assert!(prover(Some(tls_client.clone())).is_ok());
+        assert!(prover(Some(TlsClientConfiguration::Files {
+            cert: fixture("prover-client.crt"),
+            key:  fixture("missing.key"),
+        }))
+        .is_err());
+
+        let debug = format!("{tls_client:?}");
+        assert!(!debug.contains("prover-client"));
+
+        // The same identity is accepted as PEM contents.
+        let pem = TlsClientConfiguration::Pem {
+            cert_pem: fs::read_to_string(fixture("prover-client.crt")).unwrap(),
+            key_pem:  fs::read_to_string(fixture("prover-client.key")).unwrap(),
+        };
+        assert!(prover(Some(pem.clone())).is_ok());
+        assert!(!format!("{pem:?}").contains("BEGIN"));
+        assert_eq!(
+            serde_json::from_str::<TlsClientConfiguration>(r#"{"certPem": "a", "keyPem": "b"}"#)
+                .unwrap(),
+            TlsClientConfiguration::Pem {
+                cert_pem: "a".into(),
+                key_pem:  "b".into(),
+            }
+        );
+    }
 
     #[tokio::test]
     async fn mtb_should_generate_proof_with_correct_inputs() -> anyhow::Result<()> {
@@ -212,9 +1153,8 @@ mod test {
         let mock_service = mock::Service::new(mock_url.clone()).await?;
 
         let options = Options {
-            mtb_prover_url:          "http://localhost:3001".into(),
-            mtb_prover_timeout_secs: 30,
-            batch_size:              3,
+            batch_size: 3,
+            ..Options::default()
         };
         let mtb = Prover::new(&options).unwrap();
         let input_data = get_default_proof_input();
@@ -237,15 +1177,365 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn proof_should_be_retried_until_prover_recovers() -> anyhow::Result<()> {
+        let mock_service = mock::Service::new("0.0.0.0:3005".into()).await?;
+        let mtb = Prover::new(&Options {
+            mtb_prover_url: "http://localhost:3005".into(),
+            batch_size: 3,
+            ..Options::default()
+        })?;
+        let input_data = get_default_proof_input();
+        let identities: Vec<Identity> = extract_identities_from(&input_data);
+        let prove = || {
+            mtb.generate_proof_with_retry(
+                input_data.start_index,
+                input_data.pre_root,
+                input_data.post_root,
+                identities.clone(),
+            )
+        };
+
+        mock_service.fail_next_proofs(mtb.max_attempts - 1);
+        assert_eq!(prove().await?, get_default_proof_output());
+
+        mock_service.fail_next_proofs(mtb.max_attempts);
+        assert!(prove().await.is_err());
+
+        mock_service.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn first_proof_of_replicas_should_be_used() -> anyhow::Result<()> {
+        let slow_service = mock::Service::new("0.0.0.0:3029".into()).await?;
+        let failing_service = mock::Service::new("0.0.0.0:3030".into()).await?;
+        slow_service.set_proof_delay(Duration::from_millis(200));
+        failing_service.fail_next_proofs(1);
+        let prover = |url: &str| {
+            Prover::from_configuration(
+                &ProverConfiguration {
+                    url:             url.into(),
+                    batch_size:      3,
+                    max_concurrency: None,
+                    tls_client:      None,
+                    region:          None,
+                },
+                Duration::from_secs(30),
+                1,
+            )
+        };
+        let slow = prover("http://localhost:3029")?;
+        let failing = prover("http://localhost:3030")?;
+        let input_data = get_default_proof_input();
+        let identities: Vec<Identity> = extract_identities_from(&input_data);
+        let (start_index, pre_root, post_root) = (
+            input_data.start_index,
+            input_data.pre_root,
+            input_data.post_root,
+        );
+        let prove = |provers: Vec<&Prover>| {
+            let identities = identities.clone();
+            async move {
+                generate_proof_from_any(&provers, start_index, pre_root, post_root, identities)
+                    .await
+            }
+        };
+
+        // The failure of one prover is covered by the slower one.
+        assert_eq!(
+            prove(vec![&failing, &slow]).await?,
+            get_default_proof_output()
+        );
+
+        // The faster prover does not wait for the slower one.
+        let started = std::time::Instant::now();
+        assert_eq!(
+            prove(vec![&slow, &failing]).await?,
+            get_default_proof_output()
+        );
+        assert!(started.elapsed() < Duration::from_millis(200));
+
+        assert!(prove(vec![]).await.is_err());
+
+        slow_service.stop();
+        failing_service.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proof_requests_should_be_signed() -> anyhow::Result<()> {
+        let mock_service = mock::Service::new("0.0.0.0:3016".into()).await?;
+        let signing_key = H256::from_low_u64_be(1);
+        let signer = LocalWallet::from(SigningKey::from_bytes(signing_key.as_bytes())?);
+        let mtb = Prover::new(&Options {
+            mtb_prover_url: "http://localhost:3016".into(),
+            batch_size: 3,
+            mtb_prover_signing_key: Some(signing_key),
+            ..Options::default()
+        })?;
+        let input_data = get_default_proof_input();
+        let identities: Vec<Identity> = extract_identities_from(&input_data);
+
+        mtb.generate_proof(
+            input_data.start_index,
+            input_data.pre_root,
+            input_data.post_root,
+            identities,
+        )
+        .await?;
+        let (headers, body) = mock_service
+            .last_request()
+            .context("No request received.")?;
+        let signature: Signature = headers
+            .get(REQUEST_SIGNATURE_HEADER)
+            .context("Request is not signed.")?
+            .to_str()?
+            .parse()?;
+
+        signature.verify(body.as_ref(), signer.address())?;
+        let other = LocalWallet::from(SigningKey::from_bytes(H256::from_low_u64_be(2).as_bytes())?);
+        assert!(signature.verify(body.as_ref(), other.address()).is_err());
+
+        mock_service.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exhausted_retries_should_alert_once() -> anyhow::Result<()> {
+        let mock_service = mock::Service::new("0.0.0.0:3008".into()).await?;
+        let mtb = Prover::new(&Options {
+            mtb_prover_url: "http://localhost:3008".into(),
+            mtb_prover_max_attempts: 4,
+            batch_size: 3,
+            ..Options::default()
+        })?;
+        let alerts = PROOF_RETRIES_EXHAUSTED.with_label_values(&["http://localhost:3008/"]);
+        let input_data = get_default_proof_input();
+        let identities: Vec<Identity> = extract_identities_from(&input_data);
+        let prove = || {
+            mtb.generate_proof_with_retry(
+                input_data.start_index,
+                input_data.pre_root,
+                input_data.post_root,
+                identities.clone(),
+            )
+        };
+
+        mock_service.fail_next_proofs(3);
+        assert!(prove().await.is_ok());
+        assert_eq!(alerts.get(), 0);
+
+        mock_service.fail_next_proofs(4);
+        assert!(prove().await.is_err());
+        assert_eq!(alerts.get(), 1);
+
+        mock_service.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_proofs_should_be_recorded_as_last_error() -> anyhow::Result<()> {
+        let mock_service = mock::Service::new("0.0.0.0:3022".into()).await?;
+        let mtb = Prover::from_configuration(
+            &ProverConfiguration {
+                url:             "http://localhost:3022".into(),
+                batch_size:      3,
+                max_concurrency: None,
+                tls_client:      None,
+                region:          None,
+            },
+            Duration::from_secs(30),
+            1,
+        )?;
+        let input_data = get_default_proof_input();
+        let identities = extract_identities_from(&input_data);
+        let prove = || {
+            mtb.generate_proof(
+                input_data.start_index,
+                input_data.pre_root,
+                input_data.post_root,
+                identities.clone(),
+            )
+        };
+
+        prove().await?;
+        assert_eq!(mtb.last_error(), None);
+
+        mock_service.fail_next_proofs(1);
+        assert!(prove().await.is_err());
+        let last_error = mtb.last_error().expect("Failure should be recorded.");
+        assert!(!last_error.message.is_empty());
+        assert!(last_error.timestamp > 0);
+
+        // A later success keeps the last error around for diagnostics.
+        prove().await?;
+        assert_eq!(mtb.last_error(), Some(last_error));
+
+        mock_service.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failing_prover_should_be_taken_out_of_rotation() -> anyhow::Result<()> {
+        let mock_service = mock::Service::new("0.0.0.0:3031".into()).await?;
+        let mtb = Prover::from_configuration(
+            &ProverConfiguration {
+                url:             "http://localhost:3031".into(),
+                batch_size:      3,
+                max_concurrency: None,
+                tls_client:      None,
+                region:          None,
+            },
+            Duration::from_secs(30),
+            1,
+        )?
+        .with_resilience(
+            Some(Duration::from_secs(10)),
+            CircuitBreaker::new(2, Duration::from_secs(60)),
+        );
+        let input_data = get_default_proof_input();
+        let identities = extract_identities_from(&input_data);
+        let prove = || {
+            mtb.generate_proof(
+                input_data.start_index,
+                input_data.pre_root,
+                input_data.post_root,
+                identities.clone(),
+            )
+        };
+
+        mock_service.fail_next_proofs(2);
+        assert!(prove().await.is_err());
+        mtb.health_check().await?;
+        assert!(prove().await.is_err());
+        assert!(mtb.health_check().await.is_err());
+
+        // Requests fail fast without reaching the recovered prover.
+        assert!(prove().await.is_err());
+
+        mock_service.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deletion_proof_should_be_requested_with_deletion_inputs() -> anyhow::Result<()> {
+        let mock_service = mock::Service::new("0.0.0.0:3028".into()).await?;
+        let mtb = Prover::from_configuration(
+            &ProverConfiguration {
+                url:             "http://localhost:3028".into(),
+                batch_size:      3,
+                max_concurrency: None,
+                tls_client:      None,
+                region:          None,
+            },
+            Duration::from_secs(30),
+            1,
+        )?;
+        let input_data = get_default_proof_input();
+        let identities = extract_identities_from(&input_data);
+        let deletion_indices = vec![0, 1, 2];
+
+        let proof = mtb
+            .generate_deletion_proof(
+                input_data.pre_root,
+                input_data.post_root,
+                deletion_indices.clone(),
+                identities.clone(),
+            )
+            .await?;
+        assert_eq!(proof, get_default_proof_output());
+
+        let (_, body) = mock_service
+            .last_request()
+            .expect("Request should be received.");
+        let request: DeletionProofInput = serde_json::from_slice(&body)?;
+        assert_eq!(request.deletion_indices, deletion_indices);
+        assert_eq!(
+            request.input_hash,
+            compute_deletion_input_hash(
+                &deletion_indices,
+                input_data.pre_root,
+                input_data.post_root,
+                &input_data.identity_commitments,
+            )
+        );
+        assert_ne!(request.input_hash, input_data.input_hash);
+
+        // Every deleted identity needs its index.
+        assert!(mtb
+            .generate_deletion_proof(
+                input_data.pre_root,
+                input_data.post_root,
+                vec![0, 1],
+                identities,
+            )
+            .await
+            .is_err());
+
+        mock_service.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn inflight_gauge_should_follow_slow_request() -> anyhow::Result<()> {
+        let mock_service = mock::Service::new("0.0.0.0:3025".into()).await?;
+        mock_service.set_proof_delay(Duration::from_millis(300));
+        let mtb = Prover::from_configuration(
+            &ProverConfiguration {
+                url:             "http://localhost:3025".into(),
+                batch_size:      3,
+                max_concurrency: None,
+                tls_client:      None,
+                region:          None,
+            },
+            Duration::from_secs(30),
+            1,
+        )?;
+        let labels = ["3", "http://localhost:3025/"];
+        let requests = PROOF_REQUESTS.with_label_values(&labels);
+        let inflight = PROOF_REQUESTS_INFLIGHT.with_label_values(&labels);
+        let input_data = get_default_proof_input();
+        let identities = extract_identities_from(&input_data);
+
+        let request = tokio::spawn(async move {
+            mtb.generate_proof(
+                input_data.start_index,
+                input_data.pre_root,
+                input_data.post_root,
+                identities,
+            )
+            .await
+        });
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(requests.get(), 1);
+        assert_eq!(inflight.get(), 1);
+
+        request.await??;
+        assert_eq!(requests.get(), 1);
+        assert_eq!(inflight.get(), 0);
+
+        mock_service.stop();
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn mtb_should_respond_with_error_if_inputs_incorrect() -> anyhow::Result<()> {
         let mock_url: String = "0.0.0.0:3002".into();
         let mock_service = mock::Service::new(mock_url.clone()).await?;
 
         let options = Options {
-            mtb_prover_url:          "http://localhost:3002".into(),
-            mtb_prover_timeout_secs: 30,
-            batch_size:              3,
+            mtb_prover_url: "http://localhost:3002".into(),
+            batch_size: 3,
+            ..Options::default()
         };
         let mtb = Prover::new(&options).unwrap();
         let mut input_data = get_default_proof_input();
@@ -270,9 +1560,9 @@ mod test {
     #[tokio::test]
     async fn prover_should_error_if_batch_size_wrong() -> anyhow::Result<()> {
         let options = Options {
-            mtb_prover_url:          "http://localhost:3002".into(),
-            mtb_prover_timeout_secs: 30,
-            batch_size:              10,
+            mtb_prover_url: "http://localhost:3002".into(),
+            batch_size: 10,
+            ..Options::default()
         };
         let mtb = Prover::new(&options).unwrap();
         let input_data = get_default_proof_input();
@@ -296,6 +1586,37 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn any_healthy_should_follow_prover_health() -> anyhow::Result<()> {
+        let first_service = mock::Service::new("0.0.0.0:3003".into()).await?;
+        let second_service = mock::Service::new("0.0.0.0:3004".into()).await?;
+
+        let provers = ["http://localhost:3003", "http://localhost:3004"]
+            .into_iter()
+            .map(|url| {
+                Prover::new(&Options {
+                    mtb_prover_url: url.into(),
+                    batch_size: 3,
+                    ..Options::default()
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        assert!(any_healthy(&provers).await);
+
+        first_service.set_healthy(false);
+        second_service.set_healthy(false);
+        assert!(!any_healthy(&provers).await);
+
+        second_service.set_healthy(true);
+        assert!(any_healthy(&provers).await);
+
+        first_service.stop();
+        second_service.stop();
+
+        Ok(())
+    }
+
     #[test]
     fn compute_input_hash_should_succeed() {
         let input = get_default_proof_input();
@@ -454,12 +1775,31 @@ mod test {
 #[cfg(test)]
 pub mod mock {
     use super::*;
-    use axum::{routing::post, Json, Router};
+    use axum::{
+        body::Bytes,
+        http::{HeaderMap, StatusCode},
+        routing::{get, post},
+        Json, Router,
+    };
     use axum_server::Handle;
-    use std::net::SocketAddr;
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    /// The headers and body of the last proof request received.
+    type LastRequest = Arc<Mutex<Option<(HeaderMap, Bytes)>>>;
 
     pub struct Service {
-        server: Handle,
+        server:              Handle,
+        healthy:             Arc<AtomicBool>,
+        failing_proofs:      Arc<AtomicUsize>,
+        last_request:        LastRequest,
+        reported_batch_size: Arc<AtomicUsize>,
+        proof_delay_ms:      Arc<AtomicU64>,
     }
 
     #[derive(Serialize, Deserialize)]
@@ -472,21 +1812,81 @@ pub mod mock {
 
     impl Service {
         pub async fn new(url: String) -> anyhow::Result<Self> {
-            let prove = |Json(payload): Json<ProofInput>| async move {
-                match payload.post_root.div_mod(U256::from(2)) {
-                    (_, y) if y != U256::zero() => {
-                        Json(ProveResponse::ProofSuccess(test::get_default_proof_output()))
+            let failing_proofs = Arc::new(AtomicUsize::new(0));
+            let last_request: LastRequest = Arc::default();
+            let proof_delay_ms = Arc::new(AtomicU64::new(0));
+            let prove = {
+                let failing_proofs = failing_proofs.clone();
+                let last_request = last_request.clone();
+                let proof_delay_ms = proof_delay_ms.clone();
+                move |headers: HeaderMap, body: Bytes| {
+                    let failing_proofs = failing_proofs.clone();
+                    let last_request = last_request.clone();
+                    let proof_delay_ms = proof_delay_ms.clone();
+                    async move {
+                        // Insertion and deletion proofs are answered alike.
+                        let post_root = serde_json::from_slice::<ProofInput>(&body)
+                            .map(|input| input.post_root)
+                            .or_else(|_| {
+                                serde_json::from_slice::<DeletionProofInput>(&body)
+                                    .map(|input| input.post_root)
+                            })
+                            .map_err(|_| StatusCode::BAD_REQUEST)?;
+                        *last_request.lock().unwrap() = Some((headers, body));
+                        sleep(Duration::from_millis(proof_delay_ms.load(Ordering::SeqCst))).await;
+                        let failing = failing_proofs
+                            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                            .is_ok();
+                        if failing {
+                            return Err(StatusCode::SERVICE_UNAVAILABLE);
+                        }
+                        match post_root.div_mod(U256::from(2)) {
+                            (_, y) if y != U256::zero() => Ok(Json(ProveResponse::ProofSuccess(
+                                test::get_default_proof_output(),
+                            ))),
+                            _ => {
+                                let error = ProverError {
+                                    code:    "Oh no!".into(),
+                                    message: "Things went wrong.".into(),
+                                };
+                                Ok(Json(ProveResponse::ProofFailure(error)))
+                            }
+                        }
+                    }
+                }
+            };
+            let healthy = Arc::new(AtomicBool::new(true));
+            let health = {
+                let healthy = healthy.clone();
+                move || {
+                    let healthy = healthy.clone();
+                    async move {
+                        if healthy.load(Ordering::SeqCst) {
+                            StatusCode::OK
+                        } else {
+                            StatusCode::SERVICE_UNAVAILABLE
+                        }
                     }
-                    _ => {
-                        let error = ProverError {
-                            code:    "Oh no!".into(),
-                            message: "Things went wrong.".into(),
-                        };
-                        Json(ProveResponse::ProofFailure(error))
+                }
+            };
+            // Zero while no batch size is reported.
+            let reported_batch_size = Arc::new(AtomicUsize::new(0));
+            let info = {
+                let reported_batch_size = reported_batch_size.clone();
+                move || {
+                    let reported_batch_size = reported_batch_size.clone();
+                    async move {
+                        match reported_batch_size.load(Ordering::SeqCst) {
+                            0 => Err(StatusCode::NOT_FOUND),
+                            batch_size => Ok(Json(ProverInfo { batch_size })),
+                        }
                     }
                 }
             };
-            let app = Router::new().route("/prove", post(prove));
+            let app = Router::new()
+                .route("/prove", post(prove))
+                .route("/health", get(health))
+                .route("/info", get(info));
 
             let addr: SocketAddr = url.parse()?;
             let server = Handle::new();
@@ -501,10 +1901,43 @@ pub mod mock {
                     .unwrap();
             });
 
-            let service = Self { server };
+            let service = Self {
+                server,
+                healthy,
+                failing_proofs,
+                last_request,
+                reported_batch_size,
+                proof_delay_ms,
+            };
             Ok(service)
         }
 
+        pub fn set_healthy(&self, healthy: bool) {
+            self.healthy.store(healthy, Ordering::SeqCst);
+        }
+
+        /// Makes the service report `batch_size` as the batch size it is set
+        /// up to work with.
+        pub fn set_reported_batch_size(&self, batch_size: usize) {
+            self.reported_batch_size.store(batch_size, Ordering::SeqCst);
+        }
+
+        /// Makes the next `count` proof requests fail as unavailable.
+        pub fn fail_next_proofs(&self, count: usize) {
+            self.failing_proofs.store(count, Ordering::SeqCst);
+        }
+
+        /// Makes every proof request take at least `delay` to be answered.
+        pub fn set_proof_delay(&self, delay: Duration) {
+            let delay_ms = u64::try_from(delay.as_millis()).unwrap_or(u64::MAX);
+            self.proof_delay_ms.store(delay_ms, Ordering::SeqCst);
+        }
+
+        /// Returns the headers and body of the last proof request received.
+        pub fn last_request(&self) -> Option<(HeaderMap, Bytes)> {
+            self.last_request.lock().unwrap().clone()
+        }
+
         pub fn stop(self) {
             self.server.shutdown();
         }
@@ -0,0 +1,85 @@
+use crate::prover::{map::ProverMap, Prover};
+use cli_batteries::await_shutdown;
+use futures::future::join_all;
+use std::{sync::Arc, time::Duration};
+use tokio::{select, sync::RwLock, task::JoinHandle, time::sleep};
+use tracing::{info, warn};
+
+/// Checks that every prover in `map` still reports the batch size it is
+/// registered for, see [`Prover::verify_batch_size`].
+///
+/// Provers that do not report their batch size are logged and keep their
+/// current health.
+pub async fn verify_batch_sizes(map: &RwLock<ProverMap>) {
+    let map = map.read().await;
+    let results = join_all(map.provers().map(Prover::verify_batch_size)).await;
+    for (prover, result) in map.provers().zip(results) {
+        if let Err(error) = result {
+            warn!(batch_size = prover.batch_size(), url = %prover.url(), %error, "Failed to verify prover batch size.");
+        }
+    }
+}
+
+/// Spawns a task that verifies the batch sizes of the provers in each of
+/// `maps` every `interval`, until shutdown.
+pub fn start_verification(maps: Vec<Arc<RwLock<ProverMap>>>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            select! {
+                _ = sleep(interval) => {}
+                _ = await_shutdown() => {
+                    info!("Stopping prover batch size verification.");
+                    return;
+                }
+            }
+            join_all(maps.iter().map(|map| verify_batch_sizes(map))).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prover::{mock, ProverConfiguration, BATCH_SIZE_MISMATCH};
+
+    #[tokio::test]
+    async fn drifting_batch_size_should_mark_prover_unhealthy() -> anyhow::Result<()> {
+        let service = mock::Service::new("0.0.0.0:3017".into()).await?;
+        service.set_reported_batch_size(3);
+        let prover = Prover::from_configuration(
+            &ProverConfiguration {
+                url:             "http://localhost:3017".into(),
+                batch_size:      3,
+                max_concurrency: None,
+                tls_client:      None,
+                region:          None,
+            },
+            Duration::from_secs(30),
+            3,
+        )?;
+        let alert = BATCH_SIZE_MISMATCH.with_label_values(&["http://localhost:3017/"]);
+        let mut map = ProverMap::default();
+        map.add(3, prover);
+        let map = Arc::new(RwLock::new(map));
+
+        let task = start_verification(vec![map.clone()], Duration::from_millis(50));
+        sleep(Duration::from_millis(200)).await;
+        assert!(map.read().await.healthy_get(3).await.is_some());
+        assert_eq!(alert.get(), 0);
+
+        service.set_reported_batch_size(5);
+        sleep(Duration::from_millis(200)).await;
+        assert!(map.read().await.healthy_get(3).await.is_none());
+        assert_eq!(alert.get(), 1);
+
+        service.set_reported_batch_size(3);
+        sleep(Duration::from_millis(200)).await;
+        assert!(map.read().await.healthy_get(3).await.is_some());
+        assert_eq!(alert.get(), 0);
+
+        task.abort();
+        service.stop();
+
+        Ok(())
+    }
+}
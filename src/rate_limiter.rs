@@ -0,0 +1,167 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+/// The requests a caller has left, and when they were last topped up.
+#[derive(Debug)]
+struct Bucket {
+    tokens:  f64,
+    updated: Instant,
+    tick:    u64,
+}
+
+/// Limits every caller to `per_minute` requests per minute, allowing bursts
+/// of up to as many.
+///
+/// Each caller has a bucket of requests that is topped up continuously. At
+/// most `max_callers` callers are tracked, the least recently active one is
+/// forgotten to make room for a new one, starting again from a full bucket. A
+/// `per_minute` of zero disables the limit.
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_minute:  u32,
+    max_callers: usize,
+    buckets:     HashMap<String, Bucket>,
+    /// The callers by the tick of their last request.
+    activity:    BTreeMap<u64, String>,
+    tick:        u64,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32, max_callers: usize) -> Self {
+        Self {
+            per_minute,
+            max_callers,
+            buckets: HashMap::new(),
+            activity: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub const fn is_enabled(&self) -> bool {
+        self.per_minute > 0 && self.max_callers > 0
+    }
+
+    /// Takes a request from the bucket of `caller`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` with the time until the next request is allowed if
+    /// the caller is over the limit.
+    pub fn check(&mut self, caller: &str) -> Result<(), Duration> {
+        self.check_at(caller, Instant::now())
+    }
+
+    fn check_at(&mut self, caller: &str, now: Instant) -> Result<(), Duration> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let capacity = f64::from(self.per_minute);
+        let per_second = capacity / 60.0;
+        if !self.buckets.contains_key(caller) && self.buckets.len() >= self.max_callers {
+            if let Some((_, idle)) = self.activity.pop_first() {
+                self.buckets.remove(&idle);
+            }
+        }
+
+        self.tick += 1;
+        let bucket = self.buckets.entry(caller.to_owned()).or_insert(Bucket {
+            tokens:  capacity,
+            updated: now,
+            tick:    self.tick,
+        });
+        self.activity.remove(&bucket.tick);
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = elapsed
+            .as_secs_f64()
+            .mul_add(per_second, bucket.tokens)
+            .min(capacity);
+        bucket.updated = now;
+        bucket.tick = self.tick;
+        self.activity.insert(self.tick, caller.to_owned());
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+
+    /// Gives back the request last taken from the bucket of `caller`, for a
+    /// request that was rejected for another reason.
+    pub fn refund(&mut self, caller: &str) {
+        let capacity = f64::from(self.per_minute);
+        if let Some(bucket) = self.buckets.get_mut(caller) {
+            bucket.tokens = (bucket.tokens + 1.0).min(capacity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn callers_should_be_limited_independently() {
+        let mut limiter = RateLimiter::new(2, 10);
+        let start = Instant::now();
+
+        assert!(limiter.check_at("alice", start).is_ok());
+        assert!(limiter.check_at("alice", start).is_ok());
+        assert_eq!(
+            limiter.check_at("alice", start),
+            Err(Duration::from_secs(30))
+        );
+        assert!(limiter.check_at("bob", start).is_ok());
+
+        // A request is topped up every 30 seconds.
+        let later = start + Duration::from_secs(20);
+        assert_eq!(
+            limiter.check_at("alice", later),
+            Err(Duration::from_secs(10))
+        );
+        assert!(limiter
+            .check_at("alice", start + Duration::from_secs(30))
+            .is_ok());
+    }
+
+    #[test]
+    fn refunded_request_should_be_allowed_again() {
+        let mut limiter = RateLimiter::new(1, 10);
+        let now = Instant::now();
+
+        assert!(limiter.check_at("alice", now).is_ok());
+        limiter.refund("alice");
+        assert!(limiter.check_at("alice", now).is_ok());
+        assert!(limiter.check_at("alice", now).is_err());
+    }
+
+    #[test]
+    fn least_recently_active_caller_should_be_forgotten() {
+        let mut limiter = RateLimiter::new(1, 2);
+        let now = Instant::now();
+
+        assert!(limiter.check_at("alice", now).is_ok());
+        assert!(limiter.check_at("bob", now).is_ok());
+        assert!(limiter.check_at("carol", now).is_ok());
+
+        assert_eq!(limiter.buckets.len(), 2);
+        assert!(limiter.check_at("carol", now).is_err());
+        // Alice was forgotten to make room for Carol.
+        assert!(limiter.check_at("alice", now).is_ok());
+    }
+
+    #[test]
+    fn zero_rate_should_disable_limit() {
+        let mut limiter = RateLimiter::new(0, 10);
+        let now = Instant::now();
+
+        assert!(!limiter.is_enabled());
+        for _ in 0..100 {
+            assert!(limiter.check_at("alice", now).is_ok());
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{identity_tree::Hash, server::ToResponseCode};
+use anyhow::Result as AnyhowResult;
+use ethers::{
+    core::k256::ecdsa::SigningKey,
+    signers::{LocalWallet, Signer},
+    types::{Address, Signature, H256},
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// The statement a receipt attests to: that the sequencer accepted
+/// `commitment` at `timestamp`, while the tree had the given `root`.
+///
+/// The leaf index is only assigned once the commitment is mined, so it is not
+/// part of the receipt.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptPayload {
+    pub commitment: Hash,
+    pub root:       Hash,
+    /// Seconds since the Unix epoch.
+    pub timestamp:  u64,
+}
+
+impl ReceiptPayload {
+    /// The bytes covered by the signature: the commitment, the root and the
+    /// timestamp, each big-endian.
+    fn to_message(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(72);
+        message.extend_from_slice(&self.commitment.to_be_bytes::<32>());
+        message.extend_from_slice(&self.root.to_be_bytes::<32>());
+        message.extend_from_slice(&self.timestamp.to_be_bytes());
+        message
+    }
+}
+
+/// A signed [`ReceiptPayload`].
+///
+/// The signature is an EIP-191 personal message signature over the payload,
+/// so it can be checked with standard Ethereum tooling against the signer's
+/// address.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Receipt {
+    #[serde(flatten)]
+    pub payload:   ReceiptPayload,
+    pub signer:    Address,
+    pub signature: Signature,
+}
+
+impl Receipt {
+    /// Checks that the receipt was signed by `signer`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the signature does not match the payload or was
+    /// made by a different key.
+    pub fn verify(&self, signer: Address) -> AnyhowResult<()> {
+        self.signature
+            .verify(self.payload.to_message(), signer)
+            .map_err(Into::into)
+    }
+}
+
+impl ToResponseCode for Option<Receipt> {
+    fn to_response_code(&self) -> StatusCode {
+        StatusCode::OK
+    }
+}
+
+/// Signs receipts with a configured key.
+#[derive(Debug)]
+pub struct ReceiptSigner {
+    wallet: LocalWallet,
+}
+
+impl ReceiptSigner {
+    /// # Errors
+    ///
+    /// Will return `Err` if `signing_key` is not a valid secp256k1 key.
+    pub fn new(signing_key: H256) -> AnyhowResult<Self> {
+        let signing_key = SigningKey::from_bytes(signing_key.as_bytes())?;
+        Ok(Self {
+            wallet: LocalWallet::from(signing_key),
+        })
+    }
+
+    /// The address receipts can be verified against.
+    pub fn address(&self) -> Address {
+        self.wallet.address()
+    }
+
+    /// # Errors
+    ///
+    /// Will return `Err` if signing fails.
+    pub async fn sign(&self, payload: ReceiptPayload) -> AnyhowResult<Receipt> {
+        let signature = self.wallet.sign_message(payload.to_message()).await?;
+        Ok(Receipt {
+            payload,
+            signer: self.address(),
+            signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn signer(key: u64) -> ReceiptSigner {
+        ReceiptSigner::new(H256::from_low_u64_be(key)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn receipt_should_verify_against_signer() -> AnyhowResult<()> {
+        let signer = signer(1);
+        let receipt = signer
+            .sign(ReceiptPayload {
+                commitment: Hash::from(42_u64),
+                root:       Hash::from(7_u64),
+                timestamp:  1_700_000_000,
+            })
+            .await?;
+
+        receipt.verify(signer.address())?;
+        assert!(receipt.verify(self::signer(2).address()).is_err());
+
+        let mut tampered = receipt;
+        tampered.payload.timestamp += 1;
+        assert!(tampered.verify(signer.address()).is_err());
+
+        Ok(())
+    }
+}
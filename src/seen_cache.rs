@@ -0,0 +1,167 @@
+use crate::identity_tree::Hash;
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+static HITS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "seen_cache_hits",
+        "Number of insertions rejected by the recently seen commitments cache."
+    )
+    .unwrap()
+});
+static EVICTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "seen_cache_evictions",
+        "Number of entries evicted from the recently seen commitments cache.",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// A bounded cache of recently inserted commitments.
+///
+/// This catches clients rapidly resubmitting the same commitment without
+/// having to consult the database or the tree. Entries are evicted once they
+/// are older than `max_age`, or, oldest first, once the cache holds more than
+/// `max_size` entries. A `max_size` of zero disables the cache.
+#[derive(Debug)]
+pub struct SeenCache {
+    max_age:  Duration,
+    max_size: usize,
+    entries:  HashMap<Hash, Instant>,
+    order:    VecDeque<(Hash, Instant)>,
+}
+
+impl SeenCache {
+    pub fn new(max_age: Duration, max_size: usize) -> Self {
+        Self {
+            max_age,
+            max_size,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns `true` if `commitment` has been inserted within the maximum age.
+    pub fn contains(&mut self, commitment: &Hash) -> bool {
+        self.contains_at(commitment, Instant::now())
+    }
+
+    /// Records `commitment` as inserted.
+    pub fn insert(&mut self, commitment: Hash) {
+        self.insert_at(commitment, Instant::now());
+    }
+
+    /// Forgets `commitment`, so that it can be inserted again.
+    ///
+    /// Its place in the eviction order is left behind and skipped once it
+    /// comes up.
+    pub fn remove(&mut self, commitment: &Hash) {
+        self.entries.remove(commitment);
+    }
+
+    fn contains_at(&mut self, commitment: &Hash, now: Instant) -> bool {
+        self.evict_expired(now);
+        let hit = self.entries.contains_key(commitment);
+        if hit {
+            HITS.inc();
+        }
+        hit
+    }
+
+    fn insert_at(&mut self, commitment: Hash, now: Instant) {
+        if self.max_size == 0 {
+            return;
+        }
+
+        self.evict_expired(now);
+        while self.entries.len() >= self.max_size {
+            let Some((oldest, seen_at)) = self.order.pop_front() else {
+                break;
+            };
+            if self.entries.get(&oldest) == Some(&seen_at) {
+                self.entries.remove(&oldest);
+                EVICTIONS.with_label_values(&["capacity"]).inc();
+            }
+        }
+
+        // An earlier place of the commitment in the eviction order is skipped.
+        self.entries.insert(commitment, now);
+        self.order.push_back((commitment, now));
+    }
+
+    fn evict_expired(&mut self, now: Instant) {
+        while let Some(&(commitment, seen_at)) = self.order.front() {
+            if now.saturating_duration_since(seen_at) < self.max_age {
+                break;
+            }
+            self.order.pop_front();
+            if self.entries.get(&commitment) == Some(&seen_at) {
+                self.entries.remove(&commitment);
+                EVICTIONS.with_label_values(&["age"]).inc();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn entries_should_be_evicted_by_age() {
+        let mut cache = SeenCache::new(Duration::from_secs(10), 100);
+        let start = Instant::now();
+        let commitment = Hash::from(1_u64);
+
+        cache.insert_at(commitment, start);
+        assert!(cache.contains_at(&commitment, start + Duration::from_secs(9)));
+        assert!(!cache.contains_at(&commitment, start + Duration::from_secs(10)));
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn entries_should_be_evicted_by_capacity() {
+        let mut cache = SeenCache::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+        let commitments = [1_u64, 2, 3].map(Hash::from);
+
+        for commitment in commitments {
+            cache.insert_at(commitment, now);
+        }
+
+        assert!(!cache.contains_at(&commitments[0], now));
+        assert!(cache.contains_at(&commitments[1], now));
+        assert!(cache.contains_at(&commitments[2], now));
+    }
+
+    #[test]
+    fn removed_entries_should_be_insertable_again() {
+        let mut cache = SeenCache::new(Duration::from_secs(10), 100);
+        let start = Instant::now();
+        let commitment = Hash::from(1_u64);
+
+        cache.insert_at(commitment, start);
+        cache.remove(&commitment);
+        assert!(!cache.contains_at(&commitment, start));
+
+        // The entry inserted again is not evicted along with the removed one.
+        cache.insert_at(commitment, start + Duration::from_secs(5));
+        assert!(cache.contains_at(&commitment, start + Duration::from_secs(12)));
+        assert!(!cache.contains_at(&commitment, start + Duration::from_secs(15)));
+    }
+
+    #[test]
+    fn zero_size_should_disable_cache() {
+        let mut cache = SeenCache::new(Duration::from_secs(10), 0);
+        let now = Instant::now();
+        let commitment = Hash::from(1_u64);
+
+        cache.insert_at(commitment, now);
+        assert!(!cache.contains_at(&commitment, now));
+    }
+}
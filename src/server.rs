@@ -1,27 +1,50 @@
-use crate::{app::App, database, identity_tree::Hash};
-use ::prometheus::{opts, register_counter, register_histogram, Counter, Histogram};
+use crate::{
+    app::App,
+    auth::AdminKey,
+    database,
+    identity_tree::Hash,
+    prover::{map::UpdateError, ProverConfiguration, TlsClientConfiguration},
+    rate_limiter::RateLimiter,
+    status_changes::{StatusChange, Subscriber, Subscription},
+};
+use ::prometheus::{
+    opts, register_counter, register_histogram, Counter, Encoder, Histogram, TextEncoder,
+};
 use anyhow::{bail, ensure, Context, Error as EyreError, Result as AnyhowResult};
 use clap::Parser;
 use cli_batteries::{await_shutdown, trace_from_headers};
-use futures::Future;
+use futures::{Future, SinkExt, StreamExt};
 use hyper::{
-    body::Buf,
-    header,
+    body::HttpBody as _,
+    header::{self, HeaderValue},
+    server::conn::AddrStream,
     service::{make_service_fn, service_fn},
     Body, Method, Request, Response, Server, StatusCode,
 };
 use once_cell::sync::Lazy;
+use percent_encoding::percent_decode_str;
 use prometheus::{register_int_counter_vec, IntCounterVec};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
-    sync::Arc,
+    str::FromStr,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 use thiserror::Error;
-use tokio::time::timeout;
-use tracing::{error, info, instrument, trace};
-use url::{Host, Url};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    select,
+    sync::broadcast::error::RecvError,
+    time::timeout,
+};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+use tracing::{error, info, instrument, trace, warn};
+use url::{form_urlencoded, Host, Url};
 
 #[derive(Clone, Debug, PartialEq, Eq, Parser)]
 #[group(skip)]
@@ -33,6 +56,33 @@ pub struct Options {
     /// Request handling timeout (seconds)
     #[clap(long, env, default_value = "300")]
     pub serve_timeout: u64,
+
+    /// Maximum size of a JSON request body (bytes). Larger requests, such as
+    /// insertions of oversized commitments, are rejected before they are
+    /// parsed.
+    #[clap(long, env, default_value = "4096")]
+    pub max_request_bytes: usize,
+
+    /// Maximum number of `/insertIdentity` requests per minute from a single
+    /// IP address. Unlimited if zero.
+    #[clap(long, env, default_value = "0")]
+    pub insert_rate_limit_per_ip: u32,
+
+    /// Maximum number of `/insertIdentity` requests per minute with a single
+    /// valid API key, sent in the `x-api-key` header. Unlimited if zero.
+    #[clap(long, env, default_value = "0")]
+    pub insert_rate_limit_per_api_key: u32,
+
+    /// Maximum number of IP addresses and of API keys whose request rate is
+    /// tracked. The least recently active ones are forgotten beyond it.
+    #[clap(long, env, default_value = "10000")]
+    pub rate_limit_max_callers: usize,
+
+    /// Addresses of the load balancers in front of the sequencer, comma
+    /// separated. Requests from them are rate limited by the client address
+    /// they append to `X-Forwarded-For`.
+    #[clap(long, env, value_delimiter = ',')]
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 static REQUESTS: Lazy<Counter> =
@@ -45,10 +95,218 @@ static STATUS: Lazy<IntCounterVec> = Lazy::new(|| {
     )
     .unwrap()
 });
+static RATE_LIMITED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "api_rate_limited",
+        "Number of requests rejected for exceeding a rate limit, by limit.",
+        &["limit"]
+    )
+    .unwrap()
+});
 static LATENCY: Lazy<Histogram> = Lazy::new(|| {
     register_histogram!("api_latency_seconds", "The API latency in seconds.").unwrap()
 });
+static INSERT_LATENCY: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "api_insert_latency_seconds",
+        "The latency of identity insertions in seconds."
+    )
+    .unwrap()
+});
 const CONTENT_JSON: &str = "application/json";
+const ADMIN_PROVERS_PREFIX: &str = "/admin/provers/";
+const ADMIN_API_KEYS_PREFIX: &str = "/admin/apiKeys/";
+const IDENTITY_STATUS_PREFIX: &str = "/identityStatus/";
+const BATCHES_PREFIX: &str = "/batches/";
+const API_KEY_HEADER: &str = "x-api-key";
+const ADMIN_KEY_HEADER: &str = "x-admin-key";
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// The rate limits on insertions, by IP address and by API key.
+#[derive(Debug)]
+pub struct InsertRateLimits {
+    per_ip:          Mutex<RateLimiter>,
+    per_api_key:     Mutex<RateLimiter>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl InsertRateLimits {
+    #[must_use]
+    pub fn new(options: &Options) -> Self {
+        Self {
+            per_ip:          Mutex::new(RateLimiter::new(
+                options.insert_rate_limit_per_ip,
+                options.rate_limit_max_callers,
+            )),
+            per_api_key:     Mutex::new(RateLimiter::new(
+                options.insert_rate_limit_per_api_key,
+                options.rate_limit_max_callers,
+            )),
+            trusted_proxies: options.trusted_proxies.clone(),
+        }
+    }
+
+    /// Unlimited insertions.
+    #[must_use]
+    pub fn disabled() -> Self {
+        Self {
+            per_ip:          Mutex::new(RateLimiter::new(0, 0)),
+            per_api_key:     Mutex::new(RateLimiter::new(0, 0)),
+            trusted_proxies: Vec::new(),
+        }
+    }
+
+    /// Counts `request` against the limit of the client address it came
+    /// from, returning that address.
+    fn check_address(&self, request: &Request<Body>) -> Result<Option<IpAddr>, Error> {
+        let Some(address) = client_address(request, &self.trusted_proxies) else {
+            return Ok(None);
+        };
+        self.per_ip
+            .lock()
+            .unwrap()
+            .check(&address.to_string())
+            .map_err(|retry_after| rate_limited("ip", retry_after))?;
+        Ok(Some(address))
+    }
+
+    /// Counts a request against the limit of the API key `name` it was
+    /// authenticated with. If it is over the limit, the request is not
+    /// counted against the limit of its client `address`.
+    fn check_api_key(&self, name: &str, address: Option<IpAddr>) -> Result<(), Error> {
+        let checked = self.per_api_key.lock().unwrap().check(name);
+        checked.map_err(|retry_after| {
+            if let Some(address) = address {
+                self.per_ip.lock().unwrap().refund(&address.to_string());
+            }
+            rate_limited("api_key", retry_after)
+        })
+    }
+}
+
+fn rate_limited(limit: &str, retry_after: Duration) -> Error {
+    RATE_LIMITED.with_label_values(&[limit]).inc();
+    Error::RateLimited(retry_after)
+}
+
+/// The address of the client that sent `request`: the peer address, or if the
+/// peer is one of the `trusted_proxies`, the last address in
+/// `X-Forwarded-For` that was not appended by one of them.
+fn client_address(request: &Request<Body>, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = request.extensions().get::<SocketAddr>()?.ip();
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+    let forwarded = request
+        .headers()
+        .get_all(FORWARDED_FOR_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|address| address.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+    // A malformed entry can't be traced further back, so the peer is used.
+    let client = forwarded
+        .into_iter()
+        .rev()
+        .find(|address| address.map_or(true, |address| !trusted_proxies.contains(&address)));
+    Some(client.flatten().unwrap_or(peer))
+}
+
+/// The API key `request` was made with, if any.
+fn api_key(request: &Request<Body>) -> Option<&str> {
+    request
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|api_key| api_key.to_str().ok())
+}
+
+/// The admin key `request` was made with, if any.
+fn admin_key(request: &Request<Body>) -> Option<&str> {
+    request
+        .headers()
+        .get(ADMIN_KEY_HEADER)
+        .and_then(|key| key.to_str().ok())
+}
+
+/// Returns `true` if `path` is an admin route, which takes the admin key.
+fn is_admin_path(path: &str) -> bool {
+    path.starts_with("/admin/")
+        || matches!(
+            path,
+            "/addBatchSize" | "/removeBatchSize" | "/listBatchSizes"
+        )
+}
+
+/// Rejects a request to an admin route that does not carry the admin key.
+fn authorize_admin(request: &Request<Body>, admin_key: &AdminKey) -> Result<(), Error> {
+    if is_admin_path(request.uri().path()) && !admin_key.accepts(self::admin_key(request)) {
+        return Err(Error::Unauthorized);
+    }
+    Ok(())
+}
+
+/// Checks the API key of a request to a mutating endpoint, returning the name
+/// of the key if it has one.
+async fn authorize(request: &Request<Body>, app: &App) -> Result<Option<String>, Error> {
+    app.authenticate(api_key(request), request.uri().path())
+        .await
+}
+
+/// Checks an insertion against the rate limit of its address before its API
+/// key, so that guessing keys is rate limited too, then against the rate
+/// limit of the key it was authenticated with. Returns the name of the key.
+async fn check_insertion(
+    request: &Request<Body>,
+    app: &App,
+    rate_limits: &InsertRateLimits,
+) -> Result<Option<String>, Error> {
+    let address = rate_limits.check_address(request)?;
+    let name = authorize(request, app).await?;
+    if let Some(name) = &name {
+        rate_limits.check_api_key(name, address)?;
+    }
+    Ok(name)
+}
+
+/// Returns the API key of a request to an endpoint that removes identities,
+/// or `None` if it carries the admin key. Unlike the other mutating endpoints
+/// these always take a credential, whether or not API keys are required.
+fn removal_credential<'a>(
+    request: &'a Request<Body>,
+    admin_key: &AdminKey,
+) -> Result<Option<&'a str>, Error> {
+    if admin_key.accepts(self::admin_key(request)) {
+        return Ok(None);
+    }
+    api_key(request).map(Some).ok_or(Error::Unauthorized)
+}
+
+/// Checks a deletion or recovery against the rate limit of its address, then
+/// requires the admin key or a known API key, which is rate limited in turn.
+async fn check_deletion(
+    request: &Request<Body>,
+    app: &App,
+    rate_limits: &InsertRateLimits,
+) -> Result<(), Error> {
+    let address = rate_limits.check_address(request)?;
+    let Some(api_key) = removal_credential(request, app.admin_key())? else {
+        return Ok(());
+    };
+    let name = app
+        .authenticate(Some(api_key), request.uri().path())
+        .await?
+        .ok_or(Error::Unauthorized)?;
+    rate_limits.check_api_key(&name, address)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct VerifyCommitmentRequest {
+    group_id:            usize,
+    identity_commitment: Hash,
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -56,6 +314,129 @@ const CONTENT_JSON: &str = "application/json";
 pub struct InsertCommitmentRequest {
     group_id:            usize,
     identity_commitment: Hash,
+    #[serde(default)]
+    priority:            u8,
+    #[serde(default)]
+    batch_size_hint:     Option<usize>,
+    #[serde(default)]
+    webhook_url:         Option<String>,
+    #[serde(default)]
+    nonce:               Option<u64>,
+    #[serde(default)]
+    expires_at:          Option<u64>,
+}
+
+impl InsertCommitmentRequest {
+    /// Returns the nonce of the request together with the name of the API
+    /// key it was authenticated with, which the nonce is tracked under.
+    fn keyed_nonce<'a>(&self, api_key: Option<&'a str>) -> Result<Option<(&'a str, u64)>, Error> {
+        match (api_key, self.nonce) {
+            (Some(api_key), Some(nonce)) => Ok(Some((api_key, nonce))),
+            (None, Some(_)) => Err(Error::Unauthorized),
+            (_, None) => Ok(None),
+        }
+    }
+}
+
+/// The client certificate and key of a prover behind mutual TLS, given to the
+/// admin API as PEM contents rather than as files on the sequencer.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct TlsClientPem {
+    cert_pem: String,
+    key_pem:  String,
+}
+
+impl From<TlsClientPem> for TlsClientConfiguration {
+    fn from(pem: TlsClientPem) -> Self {
+        Self::Pem {
+            cert_pem: pem.cert_pem,
+            key_pem:  pem.key_pem,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct AddProverRequest {
+    url:             String,
+    #[serde(default)]
+    max_concurrency: Option<usize>,
+    #[serde(default)]
+    tls_client:      Option<TlsClientPem>,
+    #[serde(default)]
+    region:          Option<String>,
+}
+
+impl AddProverRequest {
+    fn into_configuration(self, batch_size: usize) -> ProverConfiguration {
+        ProverConfiguration {
+            url: self.url,
+            batch_size,
+            max_concurrency: self.max_concurrency,
+            tls_client: self.tls_client.map(Into::into),
+            region: self.region,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct AddBatchSizeRequest {
+    batch_size:      usize,
+    url:             String,
+    #[serde(default)]
+    max_concurrency: Option<usize>,
+    #[serde(default)]
+    tls_client:      Option<TlsClientPem>,
+    #[serde(default)]
+    region:          Option<String>,
+}
+
+impl From<AddBatchSizeRequest> for ProverConfiguration {
+    fn from(request: AddBatchSizeRequest) -> Self {
+        Self {
+            url:             request.url,
+            batch_size:      request.batch_size,
+            max_concurrency: request.max_concurrency,
+            tls_client:      request.tls_client.map(Into::into),
+            region:          request.region,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct RemoveBatchSizeRequest {
+    batch_size: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct CreateApiKeyRequest {
+    name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct DeleteCommitmentRequest {
+    group_id:            usize,
+    identity_commitment: Hash,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct RecoveryRequest {
+    group_id:                     usize,
+    previous_identity_commitment: Hash,
+    new_identity_commitment:      Hash,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -64,6 +445,18 @@ pub struct InsertCommitmentRequest {
 pub struct InclusionProofRequest {
     pub group_id:            usize,
     pub identity_commitment: Hash,
+    #[serde(default)]
+    pub compressed:          bool,
+}
+
+/// A message of a `/subscribe` client, following the status of its
+/// identities.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct SubscribeRequest {
+    group_id:             usize,
+    identity_commitments: Vec<Hash>,
 }
 
 pub trait ToResponseCode {
@@ -84,20 +477,68 @@ pub enum Error {
     InvalidPath,
     #[error("invalid content type")]
     InvalidContentType,
+    #[error("request body exceeds the limit of {0} bytes")]
+    PayloadTooLarge(usize),
     #[error("invalid group id")]
     InvalidGroupId,
     #[error("provided identity index out of bounds")]
     IndexOutOfBounds,
     #[error("provided identity commitment not found")]
     IdentityCommitmentNotFound,
+    #[error("provided identity commitment is already queued for deletion")]
+    DeletionAlreadyQueued,
     #[error("provided identity commitment is invalid")]
     InvalidCommitment,
     #[error("provided identity commitment is already included")]
     DuplicateCommitment,
     #[error("provided identity commitment is not reduced into SNARK_SCALAR_FIELD")]
     UnreducedCommitment,
+    #[error("the tree is full")]
+    TreeFull,
     #[error("Root mismatch between tree and contract.")]
     RootMismatch,
+    #[error("invalid root")]
+    InvalidRoot,
+    #[error("invalid query parameter: {0}")]
+    InvalidQuery(&'static str),
+    #[error("provided root not found")]
+    RootNotFound,
+    #[error("no healthy provers available")]
+    NoHealthyProvers,
+    #[error("tree is not in sync with the chain")]
+    Stale,
+    #[error("invalid webhook url: {0}")]
+    InvalidWebhookUrl(String),
+    #[error("webhooks are disabled")]
+    WebhooksDisabled,
+    #[error("nonce is not greater than the last one of the api key")]
+    ReplayedNonce,
+    #[error("expiry is in the past")]
+    Expired,
+    #[error("status subscriptions are disabled")]
+    SubscriptionsDisabled,
+    #[error("too many status subscriptions open from this client")]
+    TooManySubscriptions,
+    #[error("too many identities followed, at most {0} are allowed")]
+    TooManyCommitments(usize),
+    #[error("recoveries are disabled while deletions are not submitted")]
+    RecoveryDisabled,
+    #[error("expected a websocket upgrade")]
+    InvalidUpgrade,
+    #[error("missing or invalid api key")]
+    Unauthorized,
+    #[error("api key not found")]
+    ApiKeyNotFound,
+    #[error("batch not found")]
+    BatchNotFound,
+    #[error("an api key of this name already exists")]
+    DuplicateApiKey,
+    #[error("too many requests, retry after {}s", retry_after_secs(.0))]
+    RateLimited(Duration),
+    #[error("invalid prover configuration: {0}")]
+    InvalidProverConfiguration(String),
+    #[error(transparent)]
+    ProverUpdate(#[from] UpdateError),
     #[error("invalid JSON request: {0}")]
     InvalidSerialization(#[from] serde_json::Error),
     #[error(transparent)]
@@ -116,7 +557,129 @@ pub enum Error {
     Other(#[from] EyreError),
 }
 
+/// The stable kind of an error response, for clients to branch on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiError {
+    InvalidMethod,
+    InvalidPath,
+    InvalidContentType,
+    PayloadTooLarge,
+    InvalidJson,
+    InvalidGroupId,
+    IndexOutOfBounds,
+    CommitmentNotFound,
+    DeletionAlreadyQueued,
+    InvalidCommitment,
+    DuplicateCommitment,
+    InvalidFieldElement,
+    TreeFull,
+    InvalidRoot,
+    InvalidQuery,
+    RootNotFound,
+    RootMismatch,
+    ProverUnavailable,
+    InvalidProverConfiguration,
+    ProverNotFound,
+    ProverConflict,
+    TreeStale,
+    InvalidWebhookUrl,
+    WebhooksDisabled,
+    ReplayedNonce,
+    Expired,
+    SubscriptionsDisabled,
+    TooManySubscriptions,
+    TooManyCommitments,
+    RecoveryDisabled,
+    InvalidUpgrade,
+    Unauthorized,
+    ApiKeyNotFound,
+    BatchNotFound,
+    DuplicateApiKey,
+    RateLimited,
+    NotManager,
+    Timeout,
+    Internal,
+}
+
+/// The JSON body of an error response.
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    code:    ApiError,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Value>,
+}
+
 impl Error {
+    /// Returns the stable kind of the error.
+    #[must_use]
+    pub const fn code(&self) -> ApiError {
+        match self {
+            Self::InvalidMethod => ApiError::InvalidMethod,
+            Self::InvalidPath => ApiError::InvalidPath,
+            Self::InvalidContentType => ApiError::InvalidContentType,
+            Self::PayloadTooLarge(_) => ApiError::PayloadTooLarge,
+            Self::InvalidSerialization(_) => ApiError::InvalidJson,
+            Self::InvalidGroupId => ApiError::InvalidGroupId,
+            Self::IndexOutOfBounds => ApiError::IndexOutOfBounds,
+            Self::IdentityCommitmentNotFound => ApiError::CommitmentNotFound,
+            Self::DeletionAlreadyQueued => ApiError::DeletionAlreadyQueued,
+            Self::InvalidCommitment => ApiError::InvalidCommitment,
+            Self::DuplicateCommitment => ApiError::DuplicateCommitment,
+            Self::UnreducedCommitment => ApiError::InvalidFieldElement,
+            Self::TreeFull => ApiError::TreeFull,
+            Self::InvalidRoot => ApiError::InvalidRoot,
+            Self::InvalidQuery(_) => ApiError::InvalidQuery,
+            Self::RootNotFound => ApiError::RootNotFound,
+            Self::RootMismatch => ApiError::RootMismatch,
+            Self::NoHealthyProvers | Self::ProverUpdate(UpdateError::Unreachable(_)) => {
+                ApiError::ProverUnavailable
+            }
+            Self::InvalidProverConfiguration(_) => ApiError::InvalidProverConfiguration,
+            Self::ProverUpdate(UpdateError::NotFound(_)) => ApiError::ProverNotFound,
+            Self::ProverUpdate(_) => ApiError::ProverConflict,
+            Self::Stale => ApiError::TreeStale,
+            Self::InvalidWebhookUrl(_) => ApiError::InvalidWebhookUrl,
+            Self::WebhooksDisabled => ApiError::WebhooksDisabled,
+            Self::ReplayedNonce => ApiError::ReplayedNonce,
+            Self::Expired => ApiError::Expired,
+            Self::SubscriptionsDisabled => ApiError::SubscriptionsDisabled,
+            Self::TooManySubscriptions => ApiError::TooManySubscriptions,
+            Self::TooManyCommitments(_) => ApiError::TooManyCommitments,
+            Self::RecoveryDisabled => ApiError::RecoveryDisabled,
+            Self::InvalidUpgrade => ApiError::InvalidUpgrade,
+            Self::Unauthorized => ApiError::Unauthorized,
+            Self::ApiKeyNotFound => ApiError::ApiKeyNotFound,
+            Self::BatchNotFound => ApiError::BatchNotFound,
+            Self::DuplicateApiKey => ApiError::DuplicateApiKey,
+            Self::RateLimited(_) => ApiError::RateLimited,
+            Self::NotManager => ApiError::NotManager,
+            Self::Elapsed(_) | Self::LockTimeout(_) => ApiError::Timeout,
+            Self::Database(_) | Self::Hyper(_) | Self::Http(_) | Self::Other(_) => {
+                ApiError::Internal
+            }
+        }
+    }
+
+    /// Returns the machine readable details of the error, if it has any.
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            Self::PayloadTooLarge(limit) => Some(json!({ "limitBytes": limit })),
+            Self::TooManyCommitments(limit) => Some(json!({ "maxIdentities": limit })),
+            Self::RateLimited(retry_after) => {
+                Some(json!({ "retryAfterSecs": retry_after_secs(retry_after) }))
+            }
+            Self::ProverUpdate(
+                UpdateError::InUse(batch_size)
+                | UpdateError::Duplicate(batch_size)
+                | UpdateError::NotFound(batch_size)
+                | UpdateError::Unreachable(batch_size),
+            ) => Some(json!({ "batchSize": batch_size })),
+            _ => None,
+        }
+    }
+
     fn to_response(&self) -> hyper::Response<Body> {
         #[allow(clippy::enum_glob_use)]
         use Error::*;
@@ -124,24 +687,65 @@ impl Error {
             InvalidMethod => StatusCode::METHOD_NOT_ALLOWED,
             InvalidPath => StatusCode::NOT_FOUND,
             InvalidContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
             IndexOutOfBounds
             | IdentityCommitmentNotFound
+            | DeletionAlreadyQueued
             | InvalidCommitment
+            | UnreducedCommitment
+            | InvalidRoot
+            | InvalidQuery(_)
+            | RootNotFound
             | DuplicateCommitment
+            | InvalidWebhookUrl(_)
+            | WebhooksDisabled
+            | ReplayedNonce
+            | Expired
+            | SubscriptionsDisabled
+            | TooManyCommitments(_)
+            | RecoveryDisabled
+            | InvalidUpgrade
+            | InvalidProverConfiguration(_)
+            | ProverUpdate(UpdateError::Unreachable(_))
             | InvalidSerialization(_) => StatusCode::BAD_REQUEST,
+            Unauthorized => StatusCode::UNAUTHORIZED,
+            ProverUpdate(UpdateError::NotFound(_)) | ApiKeyNotFound | BatchNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            ProverUpdate(_) | DuplicateApiKey => StatusCode::CONFLICT,
+            RateLimited(_) | TooManySubscriptions => StatusCode::TOO_MANY_REQUESTS,
+            NoHealthyProvers | Stale | TreeFull | LockTimeout(_) => StatusCode::SERVICE_UNAVAILABLE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
-        hyper::Response::builder()
+        let mut response = hyper::Response::builder()
             .status(status_code)
-            .body(hyper::Body::from(self.to_string()))
-            .expect("Failed to convert error string into hyper::Body")
+            .header(header::CONTENT_TYPE, "application/json");
+        if let RateLimited(retry_after) = self {
+            response = response.header(header::RETRY_AFTER, retry_after_secs(retry_after));
+        }
+        let body = ErrorBody {
+            code:    self.code(),
+            message: self.to_string(),
+            details: self.details(),
+        };
+        response
+            .body(hyper::Body::from(
+                serde_json::to_string(&body).expect("Failed to serialize error body"),
+            ))
+            .expect("Failed to convert error body into hyper::Body")
     }
 }
 
-/// Parse a [`Request<Body>`] as JSON using Serde and handle using the provided
-/// method.
+/// Rounds `retry_after` up to whole seconds, as sent in `Retry-After`.
+fn retry_after_secs(retry_after: &Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
+/// Parse a [`Request<Body>`] of at most `max_bytes` as JSON using Serde and
+/// handle using the provided method.
 async fn json_middleware<F, T, S, U>(
     request: Request<Body>,
+    max_bytes: usize,
     mut next: F,
 ) -> Result<Response<Body>, Error>
 where
@@ -157,21 +761,322 @@ where
     if !valid_content_type {
         return Err(Error::InvalidContentType);
     }
-    let body = hyper::body::aggregate(request).await?;
-    let request = serde_json::from_reader(body.reader())?;
+    let body = read_body(request, max_bytes).await?;
+    let request = serde_json::from_slice(&body)?;
     let response = next(request).await?;
-    let json = serde_json::to_string_pretty(&response)?;
-    let response = Response::builder()
+    json_response(&response)
+}
+
+/// Reads the body of `request`, giving up as soon as it exceeds `max_bytes`.
+async fn read_body(request: Request<Body>, max_bytes: usize) -> Result<Vec<u8>, Error> {
+    let declared_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<usize>().ok());
+    if declared_length.map_or(false, |length| length > max_bytes) {
+        return Err(Error::PayloadTooLarge(max_bytes));
+    }
+
+    let mut body = request.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(Error::PayloadTooLarge(max_bytes));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Serialize `response` as the JSON body of a [`Response<Body>`].
+fn json_response<U>(response: &U) -> Result<Response<Body>, Error>
+where
+    U: Serialize + ToResponseCode,
+{
+    let json = serde_json::to_string_pretty(response)?;
+    Response::builder()
         .status(response.to_response_code())
         .header(header::CONTENT_TYPE, CONTENT_JSON)
-        // No need to include cache-control since POST is not cached by default.
+        // No need to include cache-control since POST is not cached by default,
+        // and GET responses that can be cached add their own validators.
         .body(Body::from(json))
-        .map_err(Error::Http)?;
+        .map_err(Error::Http)
+}
+
+/// Parses the historical root requested in the `root` query parameter, if
+/// any.
+fn requested_root(request: &Request<Body>) -> Result<Option<Hash>, Error> {
+    query_param(request, "root").map_err(|_| Error::InvalidRoot)
+}
+
+/// Parses the query parameter `name`, if present.
+fn query_param<T: FromStr>(
+    request: &Request<Body>,
+    name: &'static str,
+) -> Result<Option<T>, Error> {
+    let Some(query) = request.uri().query() else {
+        return Ok(None);
+    };
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.parse().map_err(|_| Error::InvalidQuery(name)))
+        .transpose()
+}
+
+/// Lists the roots mined in the blocks from the `from` query parameter up to
+/// `to`, both inclusive, past the `after` cursor if given.
+async fn roots(request: &Request<Body>, app: &App) -> Result<Response<Body>, Error> {
+    let from_block = query_param(request, "from")?.unwrap_or_default();
+    let after = query_param(request, "after")?;
+    let to_block = query_param(request, "to")?;
+    let roots = app.roots(from_block, after, to_block).await?;
+    json_response(&roots)
+}
+
+/// Lists a page of the leaves of the tree, starting at the `offset` query
+/// parameter and holding up to `limit` leaves.
+///
+/// The page is tagged with the root of the tree it was read from, and is not
+/// sent again if the client already holds the page of that root.
+async fn leaves(request: &Request<Body>, app: &App) -> Result<Response<Body>, Error> {
+    let offset = query_param(request, "offset")?.unwrap_or_default();
+    let limit = query_param(request, "limit")?;
+    let page = app.leaves(offset, limit).await?;
+    let etag = format!("\"{:#066x}\"", page.root());
+    let mut response = if holds_etag(request, &etag) {
+        Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())?
+    } else {
+        json_response(&page)?
+    };
+    let headers = response.headers_mut();
+    headers.insert(
+        header::ETAG,
+        HeaderValue::from_str(&etag).map_err(hyper::http::Error::from)?,
+    );
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
     Ok(response)
 }
 
-#[instrument(level="info", name="api_request", skip(app), fields(http.uri=%request.uri(), http.method=%request.method()))]
-async fn route(request: Request<Body>, app: Arc<App>) -> Result<Response<Body>, hyper::Error> {
+/// Returns whether the `If-None-Match` header of `request` lists `etag`.
+fn holds_etag(request: &Request<Body>, etag: &str) -> bool {
+    request
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|tags| tags.to_str().ok())
+        .map_or(false, |tags| {
+            tags.split(',')
+                .map(str::trim)
+                .any(|tag| tag == etag || tag == "*")
+        })
+}
+
+/// Renders every registered metric in the Prometheus text format.
+fn metrics_response() -> Result<Response<Body>, Error> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&::prometheus::gather(), &mut buffer)
+        .context("Failed to encode the metrics.")?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .map_err(Error::Http)
+}
+
+/// Upgrades `request` to a WebSocket that streams the status changes of the
+/// identities its client subscribes to. Clients are told apart by their
+/// address, as for the rate limits.
+fn subscribe(
+    request: Request<Body>,
+    app: Arc<App>,
+    rate_limits: &InsertRateLimits,
+) -> Result<Response<Body>, Error> {
+    let client = client_address(&request, &rate_limits.trusted_proxies);
+    let subscriber = app.subscribe_status_changes(client)?;
+    let is_websocket = request
+        .headers()
+        .get(header::UPGRADE)
+        .map_or(false, |upgrade| {
+            upgrade.as_bytes().eq_ignore_ascii_case(b"websocket")
+        });
+    let accept = request
+        .headers()
+        .get(header::SEC_WEBSOCKET_KEY)
+        .filter(|_| is_websocket)
+        .map(|key| derive_accept_key(key.as_bytes()))
+        .ok_or(Error::InvalidUpgrade)?;
+
+    // The connection is only handed over once the response below is sent.
+    tokio::spawn(async move {
+        match hyper::upgrade::on(request).await {
+            Ok(upgraded) => {
+                let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                serve_subscription(socket, subscriber, &app).await;
+            }
+            Err(error) => warn!(%error, "Failed to upgrade a status subscription."),
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())
+        .map_err(Error::Http)
+}
+
+/// Sends the status changes in `changes` of the identities the client of
+/// `socket` subscribes to, until either side closes.
+///
+/// Every [`SubscribeRequest`] is answered with the current status of its
+/// identities that are known. Identities are followed until they are mined,
+/// and requests that would exceed the limit of the subscriber are rejected.
+async fn serve_subscription<S>(
+    mut socket: WebSocketStream<S>,
+    mut subscriber: Subscriber,
+    app: &App,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Subscriber {
+        changes,
+        subscription,
+        ..
+    } = &mut subscriber;
+    loop {
+        let outgoing = select! {
+            message = socket.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    match serde_json::from_str::<SubscribeRequest>(&text) {
+                        Ok(request) if !subscription.fits(&request.identity_commitments) => {
+                            Err(Error::TooManyCommitments(subscription.max_commitments()))
+                        }
+                        Ok(request) => {
+                            let group_id = request.group_id;
+                            let commitments = request
+                                .identity_commitments
+                                .iter()
+                                .map(|commitment| (group_id, *commitment))
+                                .collect();
+                            // The lookup rejects an invalid group before anything is followed.
+                            current_statuses(app, commitments).await.map(|changes| {
+                                subscription.register(group_id, &request.identity_commitments);
+                                follow(subscription, changes)
+                            })
+                        }
+                        Err(error) => Err(Error::InvalidSerialization(error)),
+                    }
+                }
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                // Pings are answered by the socket itself.
+                Some(Ok(_)) => continue,
+            },
+            change = changes.recv() => match change {
+                Ok(change) if subscription.accept(&change) => Ok(vec![change]),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(missed, "Status subscription fell behind, resending its statuses.");
+                    let commitments = subscription
+                        .commitments()
+                        .map(|(group_id, commitment)| (group_id, *commitment))
+                        .collect();
+                    current_statuses(app, commitments)
+                        .await
+                        .map(|changes| follow(subscription, changes))
+                }
+                Err(RecvError::Closed) => return,
+            },
+        };
+
+        let messages = match outgoing {
+            Ok(changes) => changes
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap_or_default(),
+            Err(error) => vec![serde_json::json!({ "error": error.to_string() }).to_string()],
+        };
+        for message in messages {
+            if socket.send(Message::Text(message)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Returns the current status of each of `commitments` that is known.
+async fn current_statuses(
+    app: &App,
+    commitments: Vec<(usize, Hash)>,
+) -> Result<Vec<StatusChange>, Error> {
+    let mut changes = Vec::new();
+    for (group_id, commitment) in commitments {
+        if let Some(status) = app.identity_status(group_id, &commitment).await? {
+            changes.push(StatusChange {
+                identity_commitment: commitment,
+                status,
+            });
+        }
+    }
+    Ok(changes)
+}
+
+/// Passes `changes` through `subscription`, so that mined identities are no
+/// longer followed, and returns them.
+fn follow(subscription: &mut Subscription, changes: Vec<StatusChange>) -> Vec<StatusChange> {
+    for change in &changes {
+        subscription.accept(change);
+    }
+    changes
+}
+
+/// Handle `GET`, `PUT` and `DELETE` on `/admin/provers/{batch_size}`.
+async fn route_admin_prover(
+    request: Request<Body>,
+    app: Arc<App>,
+    max_request_bytes: usize,
+) -> Result<Response<Body>, Error> {
+    let batch_size: usize = request
+        .uri()
+        .path()
+        .strip_prefix(ADMIN_PROVERS_PREFIX)
+        .and_then(|batch_size| batch_size.parse().ok())
+        .ok_or(Error::InvalidPath)?;
+    match *request.method() {
+        Method::GET => json_response(&app.get_prover(batch_size).await?),
+        Method::PUT => {
+            json_middleware(request, max_request_bytes, |request: AddProverRequest| {
+                let app = app.clone();
+                async move {
+                    app.add_prover(&request.into_configuration(batch_size))
+                        .await
+                }
+            })
+            .await
+        }
+        Method::DELETE => {
+            app.remove_prover(batch_size).await?;
+            Response::builder()
+                .status(StatusCode::OK)
+                .body(Body::empty())
+                .map_err(Error::Http)
+        }
+        _ => Err(Error::InvalidMethod),
+    }
+}
+
+#[instrument(level="info", name="api_request", skip(app, rate_limits), fields(http.uri=%request.uri(), http.method=%request.method()))]
+async fn route(
+    request: Request<Body>,
+    app: Arc<App>,
+    max_request_bytes: usize,
+    rate_limits: &InsertRateLimits,
+) -> Result<Response<Body>, hyper::Error> {
     trace_from_headers(request.headers());
 
     // Measure and log request
@@ -181,25 +1086,197 @@ async fn route(request: Request<Body>, app: Arc<App>) -> Result<Response<Body>,
 
     // Route requests
     let result = match (request.method(), request.uri().path()) {
-        (&Method::POST, "/inclusionProof") => {
-            json_middleware(request, |request: InclusionProofRequest| {
-                let app = app.clone();
-                async move {
-                    app.inclusion_proof(request.group_id, &request.identity_commitment)
-                        .await
+        (..) if authorize_admin(&request, app.admin_key()).is_err() => Err(Error::Unauthorized),
+        (&Method::POST, "/inclusionProof") => match requested_root(&request) {
+            Ok(root) => {
+                json_middleware(
+                    request,
+                    max_request_bytes,
+                    |request: InclusionProofRequest| {
+                        let app = app.clone();
+                        async move {
+                            app.inclusion_proof(
+                                request.group_id,
+                                &request.identity_commitment,
+                                root,
+                                request.compressed,
+                            )
+                            .await
+                        }
+                    },
+                )
+                .await
+            }
+            Err(error) => Err(error),
+        },
+        (&Method::POST, "/insertIdentity") => {
+            match check_insertion(&request, &app, rate_limits).await {
+                Err(error) => Err(error),
+                Ok(api_key) => {
+                    let _timer = INSERT_LATENCY.start_timer();
+                    json_middleware(
+                        request,
+                        max_request_bytes,
+                        |request: InsertCommitmentRequest| {
+                            let app = app.clone();
+                            let api_key = api_key.clone();
+                            async move {
+                                app.insert_identity(
+                                    request.group_id,
+                                    request.identity_commitment,
+                                    request.priority,
+                                    // Only trusted clients may shrink the batches of others.
+                                    request.batch_size_hint.filter(|_| api_key.is_some()),
+                                    request.webhook_url.as_deref(),
+                                    request.keyed_nonce(api_key.as_deref())?,
+                                    request.expires_at,
+                                )
+                                .await
+                            }
+                        },
+                    )
+                    .await
                 }
-            })
+            }
+        }
+        (&Method::POST, "/verifySemaphoreCommitment") => {
+            json_middleware(
+                request,
+                max_request_bytes,
+                |request: VerifyCommitmentRequest| {
+                    let app = app.clone();
+                    async move {
+                        app.verify_commitment(request.group_id, request.identity_commitment)
+                            .await
+                    }
+                },
+            )
             .await
         }
-        (&Method::POST, "/insertIdentity") => {
-            json_middleware(request, |request: InsertCommitmentRequest| {
-                let app = app.clone();
-                async move {
-                    app.insert_identity(request.group_id, request.identity_commitment)
-                        .await
+        (&Method::POST, "/deleteIdentity") => {
+            match check_deletion(&request, &app, rate_limits).await {
+                Err(error) => Err(error),
+                Ok(()) => {
+                    json_middleware(
+                        request,
+                        max_request_bytes,
+                        |request: DeleteCommitmentRequest| {
+                            let app = app.clone();
+                            async move {
+                                app.delete_identity(request.group_id, request.identity_commitment)
+                                    .await
+                            }
+                        },
+                    )
+                    .await
                 }
-            })
+            }
+        }
+        (&Method::POST, "/recoverIdentity") => {
+            match check_deletion(&request, &app, rate_limits).await {
+                Err(error) => Err(error),
+                Ok(()) => {
+                    json_middleware(request, max_request_bytes, |request: RecoveryRequest| {
+                        let app = app.clone();
+                        async move {
+                            app.recover_identity(
+                                request.group_id,
+                                request.previous_identity_commitment,
+                                request.new_identity_commitment,
+                            )
+                            .await
+                        }
+                    })
+                    .await
+                }
+            }
+        }
+        (&Method::GET, "/metrics") => metrics_response(),
+        (&Method::GET, "/subscribe") => subscribe(request, app.clone(), rate_limits),
+        (&Method::GET, "/ready") => app.readiness().and_then(|()| {
+            Response::builder()
+                .status(StatusCode::OK)
+                .body(Body::empty())
+                .map_err(Error::Http)
+        }),
+        (&Method::GET, path) if path.starts_with(IDENTITY_STATUS_PREFIX) => {
+            match path[IDENTITY_STATUS_PREFIX.len()..].parse() {
+                Ok(commitment) => app
+                    .identity_lifecycle(&commitment)
+                    .await
+                    .and_then(|lifecycle| json_response(&lifecycle)),
+                Err(_) => Err(Error::InvalidCommitment),
+            }
+        }
+        (&Method::GET, path) if path.starts_with(BATCHES_PREFIX) => {
+            match path[BATCHES_PREFIX.len()..].parse() {
+                Ok(id) => app
+                    .get_batch(id)
+                    .await
+                    .and_then(|batch| json_response(&batch)),
+                Err(_) => Err(Error::InvalidPath),
+            }
+        }
+        (&Method::GET, "/roots") => roots(&request, &app).await,
+        (&Method::GET, "/leaves") => leaves(&request, &app).await,
+        (&Method::GET, "/queueStatus") => app
+            .queue_status()
+            .await
+            .and_then(|status| json_response(&status)),
+        (&Method::GET, "/admin/provers") => app
+            .list_provers()
             .await
+            .and_then(|provers| json_response(&provers)),
+        (&Method::POST, "/addBatchSize") => {
+            json_middleware(
+                request,
+                max_request_bytes,
+                |request: AddBatchSizeRequest| {
+                    let app = app.clone();
+                    async move { app.add_prover(&request.into()).await }
+                },
+            )
+            .await
+        }
+        (&Method::DELETE, "/removeBatchSize") => {
+            json_middleware(
+                request,
+                max_request_bytes,
+                |request: RemoveBatchSizeRequest| {
+                    let app = app.clone();
+                    async move { app.remove_prover(request.batch_size).await }
+                },
+            )
+            .await
+        }
+        (&Method::GET, "/listBatchSizes") => app
+            .list_provers()
+            .await
+            .and_then(|provers| json_response(&provers)),
+        (_, path) if path.starts_with(ADMIN_PROVERS_PREFIX) => {
+            route_admin_prover(request, app.clone(), max_request_bytes).await
+        }
+        (&Method::POST, "/admin/apiKeys") => {
+            json_middleware(
+                request,
+                max_request_bytes,
+                |request: CreateApiKeyRequest| {
+                    let app = app.clone();
+                    async move { app.create_api_key(request.name).await }
+                },
+            )
+            .await
+        }
+        (&Method::DELETE, path) if path.starts_with(ADMIN_API_KEYS_PREFIX) => {
+            match percent_decode_str(&path[ADMIN_API_KEYS_PREFIX.len()..]).decode_utf8() {
+                Ok(name) => app.revoke_api_key(&name).await.and_then(|()| {
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::empty())
+                        .map_err(Error::Http)
+                }),
+                Err(_) => Err(Error::InvalidPath),
+            }
         }
         (&Method::POST, _) => Err(Error::InvalidPath),
         _ => Err(Error::InvalidMethod),
@@ -244,7 +1321,15 @@ pub async fn main(app: Arc<App>, options: Options) -> AnyhowResult<()> {
     let listener = TcpListener::bind(addr)?;
 
     let serve_timeout = Duration::from_secs(options.serve_timeout);
-    bind_from_listener(app, serve_timeout, listener).await?;
+    let rate_limits = InsertRateLimits::new(&options);
+    bind_from_listener(
+        app,
+        serve_timeout,
+        options.max_request_bytes,
+        rate_limits,
+        listener,
+    )
+    .await?;
 
     Ok(())
 }
@@ -260,27 +1345,40 @@ pub async fn main(app: Arc<App>, options: Options) -> AnyhowResult<()> {
 pub async fn bind_from_listener(
     app: Arc<App>,
     serve_timeout: Duration,
+    max_request_bytes: usize,
+    rate_limits: InsertRateLimits,
     listener: TcpListener,
 ) -> AnyhowResult<()> {
     let local_addr = listener.local_addr()?;
-    let make_svc = make_service_fn(move |_| {
+    let rate_limits = Arc::new(rate_limits);
+    let make_svc = make_service_fn(move |connection: &AddrStream| {
         // Clone here as `make_service_fn` is called for every connection
         let app = app.clone();
+        let rate_limits = rate_limits.clone();
+        let remote_addr = connection.remote_addr();
         let serve_timeout = serve_timeout;
+        let max_request_bytes = max_request_bytes;
         async move {
-            Ok::<_, hyper::Error>(service_fn(move |req| {
+            Ok::<_, hyper::Error>(service_fn(move |mut req: Request<Body>| {
                 // Clone here as `service_fn` is called for every request
                 let app = app.clone();
+                let rate_limits = rate_limits.clone();
                 let serve_timeout = serve_timeout;
+                let max_request_bytes = max_request_bytes;
+                // Rate limits are tracked by the address of the connection.
+                req.extensions_mut().insert(remote_addr);
                 async move {
-                    timeout(serve_timeout, route(req, app))
-                        .await
-                        .unwrap_or_else(|err| {
-                            error!(?err, timeout = ?serve_timeout, "Timeout while handling request");
-                            panic!("Sequencer may be stalled, terminating.");
-                            #[allow(unreachable_code)]
-                            Ok(Error::Elapsed(err).to_response())
-                        })
+                    timeout(
+                        serve_timeout,
+                        route(req, app, max_request_bytes, &rate_limits),
+                    )
+                    .await
+                    .unwrap_or_else(|err| {
+                        error!(?err, timeout = ?serve_timeout, "Timeout while handling request");
+                        panic!("Sequencer may be stalled, terminating.");
+                        #[allow(unreachable_code)]
+                        Ok(Error::Elapsed(err).to_response())
+                    })
                 }
             }))
         }
@@ -303,6 +1401,7 @@ mod test {
     use super::*;
     use hyper::{body::to_bytes, Request, StatusCode};
     use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     // TODO: Fix test
     // #[tokio::test]
@@ -322,10 +1421,314 @@ mod test {
             .header("Content-Type", "application/json")
             .body(body)
             .unwrap();
-        let res = route(request, app).await.unwrap();
+        let res = route(request, app, 4096, &InsertRateLimits::disabled())
+            .await
+            .unwrap();
         assert_eq!(res.status(), StatusCode::OK);
         // TODO deserialize proof and compare results
     }
+
+    #[test]
+    fn admin_routes_should_require_admin_key() {
+        let admin_key = AdminKey::new(Some("secret"));
+        let request = |method: &str, uri: &str, header: Option<(&str, &str)>| {
+            let mut request = Request::builder().method(method).uri(uri);
+            if let Some((name, value)) = header {
+                request = request.header(name, value);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        let admin_routes = [
+            ("POST", "/admin/apiKeys"),
+            ("DELETE", "/admin/apiKeys/name"),
+            ("GET", "/admin/provers"),
+            ("PUT", "/admin/provers/10"),
+            ("POST", "/addBatchSize"),
+            ("DELETE", "/removeBatchSize"),
+            ("GET", "/listBatchSizes"),
+        ];
+
+        for (method, uri) in admin_routes {
+            for header in [
+                None,
+                Some((ADMIN_KEY_HEADER, "guess")),
+                Some((API_KEY_HEADER, "secret")),
+            ] {
+                let error = authorize_admin(&request(method, uri, header), &admin_key).unwrap_err();
+                assert_eq!(error.to_response().status(), StatusCode::UNAUTHORIZED);
+            }
+            let authorized = request(method, uri, Some((ADMIN_KEY_HEADER, "secret")));
+            assert!(authorize_admin(&authorized, &admin_key).is_ok());
+            // Without an admin key the admin routes are closed.
+            assert!(authorize_admin(&authorized, &AdminKey::default()).is_err());
+        }
+        assert!(authorize_admin(&request("POST", "/insertIdentity", None), &admin_key).is_ok());
+    }
+
+    #[test]
+    fn deletions_should_require_a_credential() {
+        let admin_key = AdminKey::new(Some("secret"));
+        let request = |header: Option<(&str, &str)>| {
+            let mut request = Request::builder().method("POST").uri("/deleteIdentity");
+            if let Some((name, value)) = header {
+                request = request.header(name, value);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        // Rejected even though API keys are not required by default.
+        for key in [AdminKey::default(), AdminKey::new(Some("secret"))] {
+            for header in [None, Some((ADMIN_KEY_HEADER, "guess"))] {
+                let error = removal_credential(&request(header), &key).unwrap_err();
+                assert_eq!(error.to_response().status(), StatusCode::UNAUTHORIZED);
+            }
+        }
+
+        let authorized = request(Some((ADMIN_KEY_HEADER, "secret")));
+        assert_eq!(removal_credential(&authorized, &admin_key).unwrap(), None);
+        let with_api_key = request(Some((API_KEY_HEADER, "key")));
+        assert_eq!(
+            removal_credential(&with_api_key, &admin_key).unwrap(),
+            Some("key")
+        );
+    }
+
+    fn insert_request(identity_commitment: serde_json::Value) -> Request<Body> {
+        let body = json!({
+            "groupId": 1,
+            "identityCommitment": identity_commitment,
+        })
+        .to_string();
+        Request::builder()
+            .method("POST")
+            .uri("/insertIdentity")
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn oversized_commitment_should_be_rejected() {
+        let parsed = AtomicUsize::new(0);
+        let insert = |request| {
+            json_middleware(request, 4096, |_: InsertCommitmentRequest| {
+                parsed.fetch_add(1, Ordering::SeqCst);
+                async { Ok(()) }
+            })
+        };
+
+        let oversized = || insert_request(json!("F".repeat(100_000)));
+        let error = insert(oversized()).await.unwrap_err();
+        assert!(matches!(error, Error::PayloadTooLarge(4096)));
+        assert_eq!(error.to_response().status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // A declared length is rejected without reading the body.
+        let mut request = oversized();
+        request
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, 100_050_usize.into());
+        assert!(matches!(
+            insert(request).await,
+            Err(Error::PayloadTooLarge(4096))
+        ));
+
+        let response = insert(insert_request(json!(Hash::from(1_u64))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(parsed.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn insertions_should_be_limited_by_address_and_api_key() {
+        let options = Options::try_parse_from([
+            "",
+            "--insert-rate-limit-per-ip",
+            "2",
+            "--insert-rate-limit-per-api-key",
+            "1",
+        ])
+        .unwrap();
+        let rate_limits = InsertRateLimits::new(&options);
+        let check = |ip: [u8; 4], name: Option<&str>| {
+            let mut request = insert_request(json!(Hash::from(1_u64)));
+            request
+                .extensions_mut()
+                .insert(SocketAddr::from((ip, 1234)));
+            let address = rate_limits.check_address(&request)?;
+            match name {
+                Some(name) => rate_limits.check_api_key(name, address),
+                None => Ok(()),
+            }
+        };
+
+        assert!(check([10, 0, 0, 1], None).is_ok());
+        assert!(check([10, 0, 0, 1], Some("key")).is_ok());
+        let error = check([10, 0, 0, 1], None).unwrap_err();
+        let response = error.to_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+
+        // The API key is limited across addresses, and a request rejected by
+        // it does not count against the address.
+        assert!(check([10, 0, 0, 2], Some("key")).is_err());
+        assert!(check([10, 0, 0, 2], Some("other")).is_ok());
+        assert!(check([10, 0, 0, 2], None).is_ok());
+        assert!(check([10, 0, 0, 2], None).is_err());
+    }
+
+    #[test]
+    fn client_address_should_be_forwarded_by_trusted_proxies() {
+        let proxy = IpAddr::from([10, 0, 0, 1]);
+        let request = |peer: IpAddr, forwarded: Option<&str>| {
+            let mut request = insert_request(json!(Hash::from(1_u64)));
+            request
+                .extensions_mut()
+                .insert(SocketAddr::from((peer, 1234)));
+            if let Some(forwarded) = forwarded {
+                request
+                    .headers_mut()
+                    .insert(FORWARDED_FOR_HEADER, forwarded.parse().unwrap());
+            }
+            request
+        };
+        let client = IpAddr::from([203, 0, 113, 7]);
+        let spoofed = "198.51.100.1, 203.0.113.7";
+
+        assert_eq!(
+            client_address(&request(proxy, Some(spoofed)), &[proxy]),
+            Some(client)
+        );
+        // Addresses appended by further trusted proxies are skipped.
+        assert_eq!(
+            client_address(&request(proxy, Some("203.0.113.7, 10.0.0.1")), &[proxy]),
+            Some(client)
+        );
+        // Only trusted proxies can set the client address.
+        assert_eq!(
+            client_address(&request(client, Some("198.51.100.1")), &[proxy]),
+            Some(client)
+        );
+        assert_eq!(
+            client_address(&request(proxy, Some(spoofed)), &[]),
+            Some(proxy)
+        );
+        assert_eq!(
+            client_address(&request(proxy, Some("garbage")), &[proxy]),
+            Some(proxy)
+        );
+        assert_eq!(client_address(&request(proxy, None), &[proxy]), Some(proxy));
+    }
+
+    #[tokio::test]
+    async fn errors_should_be_rendered_as_json() {
+        let body = |error: Error| async move {
+            let response = error.to_response();
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+            let body = to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        assert_eq!(
+            body(Error::DuplicateCommitment).await,
+            json!({
+                "code": "duplicate_commitment",
+                "message": "provided identity commitment is already included",
+            })
+        );
+        assert_eq!(
+            body(Error::ProverUpdate(UpdateError::NotFound(10))).await,
+            json!({
+                "code": "prover_not_found",
+                "message": "No prover is registered for batch size 10.",
+                "details": { "batchSize": 10 },
+            })
+        );
+        assert_eq!(
+            Error::UnreducedCommitment.code(),
+            ApiError::InvalidFieldElement
+        );
+        assert_eq!(Error::NoHealthyProvers.code(), ApiError::ProverUnavailable);
+    }
+
+    #[test]
+    fn historical_root_should_be_parsed_from_query() {
+        let request = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        assert_eq!(requested_root(&request("/inclusionProof")).unwrap(), None);
+        assert_eq!(
+            requested_root(&request("/inclusionProof?root=0x2a")).unwrap(),
+            Some(Hash::from(42_u64))
+        );
+        assert!(matches!(
+            requested_root(&request("/inclusionProof?root=nope")),
+            Err(Error::InvalidRoot)
+        ));
+    }
+
+    #[test]
+    fn etag_should_match_if_none_match() {
+        let request = |tags: &str| {
+            Request::builder()
+                .uri("/leaves")
+                .header(header::IF_NONE_MATCH, tags)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert!(holds_etag(&request("\"0x2a\""), "\"0x2a\""));
+        assert!(holds_etag(&request("\"0x1\", \"0x2a\""), "\"0x2a\""));
+        assert!(holds_etag(&request("*"), "\"0x2a\""));
+        assert!(!holds_etag(&request("\"0x1\""), "\"0x2a\""));
+        assert!(!holds_etag(
+            &Request::builder().body(Body::empty()).unwrap(),
+            "\"0x2a\""
+        ));
+    }
+
+    #[test]
+    fn block_range_should_be_parsed_from_query() {
+        let request = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let range = request("/roots?from=5&to=9");
+        assert_eq!(query_param::<u64>(&range, "from").unwrap(), Some(5));
+        assert_eq!(query_param::<u64>(&range, "to").unwrap(), Some(9));
+        assert_eq!(
+            query_param::<u64>(&request("/roots"), "from").unwrap(),
+            None
+        );
+        assert!(matches!(
+            query_param::<u64>(&request("/roots?to=-1"), "to"),
+            Err(Error::InvalidQuery("to"))
+        ));
+        assert_eq!(
+            query_param::<database::RootCursor>(&request("/roots?after=5.1.0"), "after").unwrap(),
+            Some(database::RootCursor {
+                block_index:       5,
+                transaction_index: 1,
+                log_index:         0,
+            })
+        );
+        assert!(matches!(
+            query_param::<database::RootCursor>(&request("/roots?after=5.1"), "after"),
+            Err(Error::InvalidQuery("after"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn metrics_should_be_rendered_as_text() {
+        INSERT_LATENCY.observe(0.5);
+
+        let response = metrics_response().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("api_insert_latency_seconds_count"));
+    }
 }
 
 #[cfg(feature = "bench")]
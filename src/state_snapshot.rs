@@ -0,0 +1,222 @@
+use crate::{
+    database::{self, Database, LogRecord, StateDump},
+    identity_tree::Hash,
+};
+use anyhow::{bail, ensure, Context, Result as AnyhowResult};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+/// The version of the snapshot layout, raised on every incompatible change.
+const VERSION: u32 = 2;
+
+/// The identities, deletions, recoveries, provers, root history and tree
+/// metadata of a sequencer, to clone it into another environment or recover
+/// it.
+///
+/// The tree itself is not part of the snapshot, it is rebuilt from the events
+/// on start up.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StateSnapshot {
+    version: u32,
+    tree:    TreeMetadata,
+    #[serde(flatten)]
+    state:   StateDump,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TreeMetadata {
+    /// The number of leaves inserted.
+    leaves:     usize,
+    /// The root after the last event.
+    root:       Option<Hash>,
+    /// The block of the last event.
+    last_block: Option<i64>,
+}
+
+impl TreeMetadata {
+    fn of(logs: &[LogRecord]) -> Self {
+        Self {
+            leaves:     logs.len(),
+            root:       logs.last().map(|log| log.root),
+            last_block: logs.last().map(|log| log.block_index),
+        }
+    }
+}
+
+impl StateSnapshot {
+    /// Takes a consistent snapshot of the state in `database`, which may be in
+    /// use.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the state cannot be read.
+    pub async fn take(database: &Database) -> Result<Self, database::Error> {
+        let state = database.dump_state().await?;
+        Ok(Self {
+            version: VERSION,
+            tree: TreeMetadata::of(&state.logs),
+            state,
+        })
+    }
+
+    /// Writes the snapshot into `database`, which must not hold any
+    /// identities yet.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the snapshot is of another version or
+    /// inconsistent, the database is not fresh or the state cannot be
+    /// written.
+    pub async fn restore(&self, database: &Database) -> AnyhowResult<()> {
+        ensure!(
+            self.version == VERSION,
+            "Unsupported snapshot version {}, expected {VERSION}.",
+            self.version
+        );
+        ensure!(
+            self.tree == TreeMetadata::of(&self.state.logs),
+            "The tree metadata of the snapshot does not match its events."
+        );
+        match database.restore_state(&self.state).await {
+            Err(database::Error::NotFresh) => bail!(
+                "The database already holds identities, a snapshot can only be imported into a \
+                 fresh database."
+            ),
+            result => Ok(result?),
+        }
+    }
+}
+
+/// Writes a snapshot of the state in `database` to `path`.
+///
+/// # Errors
+///
+/// Will return `Err` if the state cannot be read or the file cannot be
+/// written.
+pub async fn export(database: &Database, path: &Path) -> AnyhowResult<()> {
+    let snapshot = StateSnapshot::take(database).await?;
+    let bytes = serde_json::to_vec(&snapshot)?;
+    let file = path.to_owned();
+    spawn_blocking(move || fs::write(file, bytes))
+        .await?
+        .with_context(|| format!("Failed to write the snapshot to {}.", path.display()))?;
+    info!(
+        leaves = snapshot.tree.leaves,
+        pending = snapshot.state.pending_identities.len(),
+        deletions = snapshot.state.pending_deletions.len(),
+        ?path,
+        "Exported state snapshot."
+    );
+    Ok(())
+}
+
+/// Restores the snapshot at `path` into `database`, which must not hold any
+/// identities yet.
+///
+/// # Errors
+///
+/// Will return `Err` if the file cannot be read, or the snapshot cannot be
+/// restored.
+pub async fn import(database: &Database, path: &Path) -> AnyhowResult<()> {
+    let file = path.to_owned();
+    let bytes = spawn_blocking(move || fs::read(file))
+        .await?
+        .with_context(|| format!("Failed to read the snapshot from {}.", path.display()))?;
+    let snapshot: StateSnapshot =
+        serde_json::from_slice(&bytes).context("Failed to parse the snapshot.")?;
+    snapshot.restore(database).await?;
+    info!(
+        leaves = snapshot.tree.leaves,
+        pending = snapshot.state.pending_identities.len(),
+        deletions = snapshot.state.pending_deletions.len(),
+        ?path,
+        "Imported state snapshot."
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        database::{mock, ConfirmedDeletionEvent, ConfirmedIdentityEvent},
+        prover::ProverConfiguration,
+    };
+
+    #[tokio::test]
+    async fn snapshot_should_restore_into_fresh_database() -> anyhow::Result<()> {
+        let source = mock::database().await;
+        for (log_index, leaf) in [1_u64, 2].into_iter().enumerate() {
+            source
+                .save_log(&ConfirmedIdentityEvent {
+                    block_index:       5,
+                    transaction_index: 0,
+                    log_index:         log_index.try_into()?,
+                    raw_log:           String::new(),
+                    leaf:              Hash::from(leaf),
+                    root:              Hash::from(leaf * 10),
+                })
+                .await?;
+        }
+        source
+            .insert_pending_identity(1, &Hash::from(3_u64), 0)
+            .await?;
+        mock::set_created_at(&source, &Hash::from(3_u64), "2020-01-01 00:00:00").await;
+        source
+            .confirm_identities(&[], &[ConfirmedDeletionEvent {
+                block_index:       6,
+                transaction_index: 0,
+                log_index:         0,
+                raw_log:           String::new(),
+                leaf:              Hash::from(1_u64),
+                leaf_index:        0,
+                root:              Hash::from(30_u64),
+            }])
+            .await?;
+        source
+            .insert_recovery(1, &Hash::from(2_u64), &Hash::from(4_u64))
+            .await?;
+        source
+            .save_prover(&ProverConfiguration {
+                url:             "http://localhost:3001".into(),
+                batch_size:      10,
+                max_concurrency: Some(2),
+                tls_client:      None,
+                region:          None,
+            })
+            .await?;
+        source.remove_prover(100).await?;
+        source.set_tree_depth(20).await?;
+
+        let snapshot = StateSnapshot::take(&source).await?;
+        assert_eq!(snapshot.tree.leaves, 2);
+        assert_eq!(snapshot.tree.root, Some(Hash::from(20_u64)));
+        assert_eq!(snapshot.state.tree_depth, Some(20));
+        assert_eq!(snapshot.state.deletion_logs.len(), 1);
+        assert_eq!(snapshot.state.pending_identities.len(), 2);
+        assert_eq!(snapshot.state.pending_deletions.len(), 1);
+        assert_eq!(snapshot.state.recoveries.len(), 1);
+        assert_eq!(snapshot.state.provers.len(), 1);
+        assert_eq!(snapshot.state.removed_provers, vec![100]);
+        let snapshot: StateSnapshot = serde_json::from_slice(&serde_json::to_vec(&snapshot)?)?;
+
+        let target = mock::database().await;
+        snapshot.restore(&target).await?;
+        assert_eq!(StateSnapshot::take(&target).await?, snapshot);
+
+        // The state would be duplicated in a database that is not fresh.
+        assert!(snapshot.restore(&target).await.is_err());
+
+        let future = StateSnapshot {
+            version: VERSION + 1,
+            ..StateSnapshot::take(&source).await?
+        };
+        assert!(future.restore(&mock::database().await).await.is_err());
+
+        Ok(())
+    }
+}
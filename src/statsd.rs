@@ -0,0 +1,376 @@
+use clap::{Parser, ValueEnum};
+use cli_batteries::await_shutdown;
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use std::{collections::HashMap, io, mem, net::UdpSocket, time::Duration};
+use tokio::{select, task::spawn_blocking, time::sleep};
+use tracing::{info, warn};
+
+/// The largest datagram pushed, which fits the MTU of common networks.
+const MAX_PACKET_BYTES: usize = 1432;
+
+/// Where metrics are reported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum MetricsBackend {
+    /// Only serve the metrics on `/metrics`, for Prometheus to scrape.
+    #[default]
+    Prometheus,
+    /// Also push them to a StatsD agent, with label values appended to the
+    /// metric names.
+    Statsd,
+    /// Also push them to a Datadog agent, with labels as DogStatsD tags.
+    Datadog,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// Where metrics are reported. They are always served on `/metrics`,
+    /// `statsd` and `datadog` also push them to a StatsD agent.
+    #[clap(long, env, value_enum, default_value = "prometheus")]
+    pub metrics_backend: MetricsBackend,
+
+    /// Address of the StatsD agent metrics are pushed to.
+    #[clap(long, env, default_value = "127.0.0.1:8125")]
+    pub statsd_address: String,
+
+    /// Prefix of the names of the pushed metrics.
+    #[clap(long, env, default_value = "signup_sequencer")]
+    pub statsd_prefix: String,
+
+    /// Interval at which metrics are pushed to the StatsD agent (seconds).
+    #[clap(long, env, default_value = "10")]
+    pub statsd_push_interval_secs: u64,
+}
+
+/// Starts pushing the registered metrics to the StatsD agent every push
+/// interval until shutdown, if a StatsD backend is selected.
+///
+/// The agent address is resolved off the runtime, and again on the next push
+/// for as long as it fails.
+pub fn start(options: &Options) {
+    let tags = match options.metrics_backend {
+        MetricsBackend::Prometheus => return,
+        MetricsBackend::Statsd => false,
+        MetricsBackend::Datadog => true,
+    };
+    let address = options.statsd_address.clone();
+    let mut encoder = Encoder::new(options.statsd_prefix.clone(), tags);
+    let interval = Duration::from_secs(options.statsd_push_interval_secs.max(1));
+    info!(
+        backend = ?options.metrics_backend,
+        %address,
+        "Pushing metrics to StatsD."
+    );
+    tokio::spawn(async move {
+        let mut socket = None;
+        loop {
+            select! {
+                _ = sleep(interval) => {}
+                _ = await_shutdown() => return,
+            }
+            if socket.is_none() {
+                let address = address.clone();
+                match spawn_blocking(move || connect(&address)).await {
+                    Ok(Ok(connected)) => socket = Some(connected),
+                    Ok(Err(error)) => {
+                        warn!(%error, %address, "Failed to connect to StatsD.");
+                        continue;
+                    }
+                    Err(error) => {
+                        warn!(%error, "StatsD connection task failed.");
+                        continue;
+                    }
+                }
+            }
+            let Some(socket) = &socket else { continue };
+            for packet in packets(&encoder.encode(&prometheus::gather())) {
+                if let Err(error) = socket.send(packet.as_bytes()) {
+                    warn!(%error, "Failed to push metrics to StatsD.");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// Connects a non-blocking UDP socket to the agent at `address`, resolving it
+/// if it is a host name.
+fn connect(address: &str) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(address)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Turns Prometheus metrics into StatsD lines.
+///
+/// Gauges are sent as they are. Counters, and the sample counts and sums of
+/// summaries, are sent as their increase since the previous push, as StatsD
+/// counters are. The samples observed by histograms since the previous push
+/// are sent as histogram samples, or distribution samples for Datadog, at the
+/// upper bound of their bucket.
+struct Encoder {
+    prefix: String,
+    tags:   bool,
+    /// The value of every counter at the previous push.
+    pushed: HashMap<String, f64>,
+}
+
+impl Encoder {
+    fn new(prefix: String, tags: bool) -> Self {
+        Self {
+            prefix,
+            tags,
+            pushed: HashMap::new(),
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn encode(&mut self, families: &[MetricFamily]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for family in families {
+            let name = format!("{}.{}", self.prefix, family.get_name());
+            for metric in family.get_metric() {
+                let labels = metric.get_label();
+                match family.get_field_type() {
+                    MetricType::GAUGE => {
+                        lines.push(self.line(&name, labels, metric.get_gauge().get_value(), "g"));
+                    }
+                    MetricType::UNTYPED => {
+                        lines.push(self.line(&name, labels, metric.get_untyped().get_value(), "g"));
+                    }
+                    MetricType::COUNTER => {
+                        lines.extend(self.increase(
+                            &name,
+                            labels,
+                            metric.get_counter().get_value(),
+                        ));
+                    }
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        // Bucket counts are cumulative, so the samples of a
+                        // bucket are those not in the buckets below it.
+                        let mut below = 0.0;
+                        let mut upper_bound = 0.0;
+                        for bucket in histogram.get_bucket() {
+                            upper_bound = bucket.get_upper_bound();
+                            let cumulative = self.delta(
+                                &format!("{name}.le{upper_bound}"),
+                                labels,
+                                bucket.get_cumulative_count() as f64,
+                            );
+                            lines.extend(self.samples(
+                                &name,
+                                labels,
+                                upper_bound,
+                                cumulative - below,
+                            ));
+                            below = cumulative;
+                        }
+                        // Samples above the largest bucket.
+                        let total = self.delta(
+                            &format!("{name}.count"),
+                            labels,
+                            histogram.get_sample_count() as f64,
+                        );
+                        lines.extend(self.samples(&name, labels, upper_bound, total - below));
+                    }
+                    MetricType::SUMMARY => {
+                        let summary = metric.get_summary();
+                        lines.extend(self.increase(
+                            &format!("{name}.count"),
+                            labels,
+                            summary.get_sample_count() as f64,
+                        ));
+                        lines.extend(self.increase(
+                            &format!("{name}.sum"),
+                            labels,
+                            summary.get_sample_sum(),
+                        ));
+                    }
+                }
+            }
+        }
+        lines
+    }
+
+    /// Returns the counter line for the increase of the counter to `value`
+    /// since the previous push, or `None` if it did not increase.
+    fn increase(&mut self, name: &str, labels: &[LabelPair], value: f64) -> Option<String> {
+        let increase = self.delta(name, labels, value);
+        (increase > 0.0).then(|| self.line(name, labels, increase, "c"))
+    }
+
+    /// Returns the increase of the counter to `value` since the previous push.
+    fn delta(&mut self, name: &str, labels: &[LabelPair], value: f64) -> f64 {
+        let previous = self.pushed.insert(key(name, labels), value).unwrap_or(0.0);
+        // A counter below its previous value was reset.
+        if value < previous {
+            value
+        } else {
+            value - previous
+        }
+    }
+
+    /// Returns the line for `count` samples of `value`, sent as one sample
+    /// with a sample rate of `1 / count`, or `None` if there are none.
+    fn samples(&self, name: &str, labels: &[LabelPair], value: f64, count: f64) -> Option<String> {
+        let kind = if self.tags { "d" } else { "h" };
+        if count < 1.0 {
+            None
+        } else if count < 2.0 {
+            Some(self.line(name, labels, value, kind))
+        } else {
+            Some(self.line(name, labels, value, &format!("{kind}|@{}", 1.0 / count)))
+        }
+    }
+
+    fn line(&self, name: &str, labels: &[LabelPair], value: f64, kind: &str) -> String {
+        if self.tags {
+            let tags = labels
+                .iter()
+                .map(|label| {
+                    format!(
+                        "{}:{}",
+                        sanitize(label.get_name()),
+                        sanitize(label.get_value())
+                    )
+                })
+                .collect::<Vec<_>>();
+            if tags.is_empty() {
+                format!("{name}:{value}|{kind}")
+            } else {
+                format!("{name}:{value}|{kind}|#{}", tags.join(","))
+            }
+        } else {
+            let mut name = name.to_owned();
+            for label in labels {
+                name.push('.');
+                name.push_str(&sanitize(label.get_value()));
+            }
+            format!("{name}:{value}|{kind}")
+        }
+    }
+}
+
+/// Identifies the series of `name` with `labels`.
+fn key(name: &str, labels: &[LabelPair]) -> String {
+    let mut key = name.to_owned();
+    for label in labels {
+        key.push_str(&format!(",{}={}", label.get_name(), label.get_value()));
+    }
+    key
+}
+
+/// Replaces the characters StatsD uses as separators.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '/') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Packs `lines` into as few datagrams as fit them.
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_BYTES {
+            packets.push(mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prometheus::{Counter, Gauge, Histogram, HistogramOpts, IntCounterVec, Opts, Registry};
+
+    #[test]
+    fn metrics_should_be_pushed_as_increases() -> anyhow::Result<()> {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(Opts::new("requests", "Requests."), &["status"])?;
+        let backlog = Gauge::new("backlog", "Backlog.")?;
+        let retries = Counter::new("retries", "Retries.")?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(backlog.clone()))?;
+        registry.register(Box::new(retries.clone()))?;
+        requests.with_label_values(&["200"]).inc_by(3);
+        backlog.set(7.0);
+
+        let mut datadog = Encoder::new("sequencer".into(), true);
+        assert_eq!(datadog.encode(&registry.gather()), vec![
+            "sequencer.backlog:7|g".to_owned(),
+            "sequencer.requests:3|c|#status:200".to_owned(),
+        ]);
+        requests.with_label_values(&["200"]).inc_by(2);
+        retries.inc();
+        assert_eq!(datadog.encode(&registry.gather()), vec![
+            "sequencer.backlog:7|g".to_owned(),
+            "sequencer.requests:2|c|#status:200".to_owned(),
+            "sequencer.retries:1|c".to_owned(),
+        ]);
+
+        let mut statsd = Encoder::new("sequencer".into(), false);
+        assert!(statsd
+            .encode(&registry.gather())
+            .contains(&"sequencer.requests.200:5|c".to_owned()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn histograms_should_be_pushed_as_samples() -> anyhow::Result<()> {
+        let registry = Registry::new();
+        let latency = Histogram::with_opts(
+            HistogramOpts::new("latency", "Latency.").buckets(vec![1.0, 10.0]),
+        )?;
+        registry.register(Box::new(latency.clone()))?;
+        for sample in [0.5, 0.7, 5.0, 20.0] {
+            latency.observe(sample);
+        }
+
+        let mut datadog = Encoder::new("sequencer".into(), true);
+        assert_eq!(datadog.encode(&registry.gather()), vec![
+            "sequencer.latency:1|d|@0.5".to_owned(),
+            "sequencer.latency:10|d".to_owned(),
+            "sequencer.latency:10|d".to_owned(),
+        ]);
+        latency.observe(3.0);
+        assert_eq!(datadog.encode(&registry.gather()), vec![
+            "sequencer.latency:10|d".to_owned()
+        ]);
+
+        let mut statsd = Encoder::new("sequencer".into(), false);
+        assert!(statsd
+            .encode(&registry.gather())
+            .contains(&"sequencer.latency:10|h|@0.5".to_owned()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn lines_should_be_packed_into_datagrams() {
+        let lines = vec!["a".repeat(1000), "b".repeat(400), "c".repeat(100)];
+        let packets = packets(&lines);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1], "c".repeat(100));
+        assert!(packets
+            .iter()
+            .all(|packet| packet.len() <= MAX_PACKET_BYTES));
+    }
+}
@@ -0,0 +1,329 @@
+use crate::{batch_events::BatchEvent, identity_tree::Hash};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+/// The stage an inserted identity has reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum IdentityStatus {
+    /// The identity is queued for a batch.
+    Pending,
+    /// The transaction of the identity's batch was included in a block.
+    Processed,
+    /// The block of the identity's batch was confirmed and the identity was
+    /// added to the tree, so its inclusion proof can be served.
+    Mined,
+}
+
+/// An identity reaching a new [`IdentityStatus`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusChange {
+    pub identity_commitment: Hash,
+    pub status:              IdentityStatus,
+}
+
+/// Broadcasts [`StatusChange`]s to every subscriber.
+///
+/// Subscribers that fall more than the capacity behind miss the oldest
+/// changes, so that a slow consumer cannot hold up batches.
+#[derive(Clone, Debug)]
+pub struct StatusChanges {
+    sender:                 broadcast::Sender<StatusChange>,
+    max_commitments:        usize,
+    max_sockets_per_client: usize,
+    /// The number of open subscribers of every client that has any.
+    sockets:                Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl StatusChanges {
+    /// Creates a bus that buffers up to `capacity` changes per subscriber.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self {
+            sender,
+            max_commitments: usize::MAX,
+            max_sockets_per_client: 0,
+            sockets: Arc::default(),
+        }
+    }
+
+    /// Limits every subscriber to following `max_commitments` identities.
+    #[must_use]
+    pub const fn with_max_commitments(mut self, max_commitments: usize) -> Self {
+        self.max_commitments = max_commitments;
+        self
+    }
+
+    /// Limits every client to `max_sockets` open subscribers at a time. Zero
+    /// disables the limit.
+    #[must_use]
+    pub const fn with_max_sockets_per_client(mut self, max_sockets: usize) -> Self {
+        self.max_sockets_per_client = max_sockets;
+        self
+    }
+
+    /// Returns a receiver of the changes emitted from now on.
+    #[must_use]
+    pub fn subscribe(&self) -> broadcast::Receiver<StatusChange> {
+        self.sender.subscribe()
+    }
+
+    /// Opens a subscriber for `client`, receiving the changes emitted from now
+    /// on, or returns `None` if the client already has as many open as
+    /// allowed. Subscribers of an unknown client are not limited.
+    #[must_use]
+    pub fn open(&self, client: Option<IpAddr>) -> Option<Subscriber> {
+        if let Some(client) = client {
+            let mut sockets = self.sockets.lock().unwrap();
+            let open = sockets.entry(client).or_default();
+            if self.max_sockets_per_client > 0 && *open >= self.max_sockets_per_client {
+                return None;
+            }
+            *open += 1;
+        }
+        Some(Subscriber {
+            changes:      self.subscribe(),
+            subscription: Subscription::new(self.max_commitments),
+            client:       client.map(|client| (client, self.sockets.clone())),
+        })
+    }
+
+    /// Sends a change of `identity_commitment` to `status` to the current
+    /// subscribers, if there are any.
+    pub fn emit(&self, identity_commitment: Hash, status: IdentityStatus) {
+        // Without subscribers there is nobody to miss the change.
+        let _ = self.sender.send(StatusChange {
+            identity_commitment,
+            status,
+        });
+    }
+}
+
+/// An open subscriber of [`StatusChanges`], which counts against the limit
+/// of its client until it is dropped.
+#[derive(Debug)]
+pub struct Subscriber {
+    pub changes:      broadcast::Receiver<StatusChange>,
+    pub subscription: Subscription,
+    client:           Option<(IpAddr, Arc<Mutex<HashMap<IpAddr, usize>>>)>,
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        if let Some((client, sockets)) = &self.client {
+            let mut sockets = sockets.lock().unwrap();
+            if let Some(open) = sockets.get_mut(client) {
+                *open -= 1;
+                if *open == 0 {
+                    sockets.remove(client);
+                }
+            }
+        }
+    }
+}
+
+/// The identities a subscriber follows, by group, up to a limit.
+///
+/// Identities are only registered once their group is known to be valid, so
+/// a commitment stands for the identity of the one group the sequencer
+/// serves.
+#[derive(Clone, Debug)]
+pub struct Subscription {
+    max_commitments: usize,
+    commitments:     HashMap<Hash, usize>,
+}
+
+impl Subscription {
+    /// Creates a subscription that follows up to `max_commitments` identities.
+    #[must_use]
+    pub fn new(max_commitments: usize) -> Self {
+        Self {
+            max_commitments,
+            commitments: HashMap::new(),
+        }
+    }
+
+    #[must_use]
+    pub const fn max_commitments(&self) -> usize {
+        self.max_commitments
+    }
+
+    /// Returns whether `commitments` can be followed without exceeding the
+    /// limit.
+    #[must_use]
+    pub fn fits(&self, commitments: &[Hash]) -> bool {
+        let added = commitments
+            .iter()
+            .filter(|commitment| !self.commitments.contains_key(commitment))
+            .count();
+        self.commitments.len() + added <= self.max_commitments
+    }
+
+    /// Follows `commitments` of `group_id` from now on, unless that would
+    /// exceed the limit. Returns whether they are followed.
+    pub fn register(&mut self, group_id: usize, commitments: &[Hash]) -> bool {
+        if !self.fits(commitments) {
+            return false;
+        }
+        self.commitments
+            .extend(commitments.iter().map(|commitment| (*commitment, group_id)));
+        true
+    }
+
+    /// Returns whether `change` is of a followed identity, and stops following
+    /// it once it is mined, which is its final status.
+    pub fn accept(&mut self, change: &StatusChange) -> bool {
+        if !self.commitments.contains_key(&change.identity_commitment) {
+            return false;
+        }
+        if change.status == IdentityStatus::Mined {
+            self.commitments.remove(&change.identity_commitment);
+        }
+        true
+    }
+
+    /// Returns the followed identities with their groups.
+    pub fn commitments(&self) -> impl Iterator<Item = (usize, &Hash)> {
+        self.commitments
+            .iter()
+            .map(|(commitment, group_id)| (*group_id, commitment))
+    }
+}
+
+/// Spawns a task that emits an [`IdentityStatus::Processed`] change for every
+/// identity of each mined batch in `batch_events` to `status_changes`, until
+/// the events end.
+pub fn forward_batch_events(
+    mut batch_events: broadcast::Receiver<BatchEvent>,
+    status_changes: StatusChanges,
+) {
+    tokio::spawn(async move {
+        let mut batches: HashMap<u64, Vec<Hash>> = HashMap::new();
+        loop {
+            match batch_events.recv().await {
+                Ok(BatchEvent::BatchAssembled {
+                    batch_id,
+                    commitments,
+                    ..
+                }) => {
+                    batches.insert(batch_id, commitments);
+                }
+                Ok(BatchEvent::BatchMined { batch_id, .. }) => {
+                    for commitment in batches.remove(&batch_id).unwrap_or_default() {
+                        status_changes.emit(commitment, IdentityStatus::Processed);
+                    }
+                }
+                Ok(
+                    BatchEvent::BatchFailed { batch_id, .. }
+                    | BatchEvent::BatchRetried { batch_id, .. },
+                ) => {
+                    batches.remove(&batch_id);
+                }
+                Ok(BatchEvent::BatchSubmitted { .. }) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(
+                        missed,
+                        "Missed batch events, their identities are not reported as processed."
+                    );
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::batch_events::BatchEvents;
+
+    #[tokio::test]
+    async fn mined_batches_should_be_reported_as_processed() {
+        let batch_events = BatchEvents::new(8);
+        let status_changes = StatusChanges::new(8);
+        let mut changes = status_changes.subscribe();
+        forward_batch_events(batch_events.subscribe(), status_changes);
+
+        let commitments = [1_u64, 2].map(Hash::from);
+        batch_events.emit(BatchEvent::BatchAssembled {
+            batch_id:    0,
+            pre_root:    None,
+            commitments: commitments.to_vec(),
+        });
+        batch_events.emit(BatchEvent::BatchAssembled {
+            batch_id:    1,
+            pre_root:    None,
+            commitments: vec![Hash::from(3_u64)],
+        });
+        batch_events.emit(BatchEvent::BatchFailed {
+            batch_id:   1,
+            batch_size: 1,
+            error:      "reverted".into(),
+        });
+        batch_events.emit(BatchEvent::BatchMined {
+            batch_id:   0,
+            batch_size: 2,
+            block:      5,
+        });
+
+        for commitment in commitments {
+            assert_eq!(changes.recv().await.unwrap(), StatusChange {
+                identity_commitment: commitment,
+                status:              IdentityStatus::Processed,
+            });
+        }
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[test]
+    fn subscription_should_follow_identities_until_mined() {
+        let change = |commitment: u64, status| StatusChange {
+            identity_commitment: Hash::from(commitment),
+            status,
+        };
+        let mut subscription = Subscription::new(2);
+        assert!(subscription.register(1, &[Hash::from(1_u64), Hash::from(2_u64)]));
+        assert!(!subscription.register(1, &[Hash::from(3_u64)]));
+
+        assert!(subscription.accept(&change(1, IdentityStatus::Processed)));
+        assert!(!subscription.accept(&change(3, IdentityStatus::Processed)));
+        assert!(subscription.accept(&change(1, IdentityStatus::Mined)));
+        assert!(!subscription.accept(&change(1, IdentityStatus::Mined)));
+        assert_eq!(subscription.commitments().collect::<Vec<_>>(), vec![(
+            1,
+            &Hash::from(2_u64)
+        )]);
+        // Identities no longer followed make room for others.
+        assert!(subscription.register(1, &[Hash::from(3_u64)]));
+    }
+
+    #[test]
+    fn subscribers_should_be_limited_per_client() {
+        let status_changes = StatusChanges::new(8).with_max_sockets_per_client(2);
+        let client = Some(IpAddr::from([10, 0, 0, 1]));
+        let other = Some(IpAddr::from([10, 0, 0, 2]));
+
+        let first = status_changes.open(client).unwrap();
+        let _second = status_changes.open(client).unwrap();
+        assert!(status_changes.open(client).is_none());
+        assert!(status_changes.open(other).is_some());
+        assert!(status_changes.open(None).is_some());
+
+        drop(first);
+        assert!(status_changes.open(client).is_some());
+        // Clients without open subscribers are forgotten.
+        assert_eq!(status_changes.sockets.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::identity_tree::Hash;
+use anyhow::anyhow;
+use std::{
+    collections::HashSet,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::watch, time::sleep};
+
+/// The shared result of a coalesced lookup, once it is known.
+type Outcome = Option<Result<Arc<HashSet<Hash>>, String>>;
+
+/// The lookups that arrived in the current window.
+struct Window {
+    commitments: Vec<Hash>,
+    outcome:     watch::Receiver<Outcome>,
+}
+
+/// Coalesces lookups of distinct commitments arriving within a short window
+/// into a single fetch, whose result is fanned back out to every lookup.
+///
+/// The first lookup of a window waits for it to close and then does the fetch
+/// for all of them.
+pub struct StatusCoalescer {
+    window: Duration,
+    open:   Mutex<Option<Window>>,
+}
+
+impl StatusCoalescer {
+    pub const fn new(window: Duration) -> Self {
+        Self {
+            window,
+            open: Mutex::new(None),
+        }
+    }
+
+    /// Returns whether `commitment` is among the commitments found by `fetch`.
+    ///
+    /// `fetch` is only called if this lookup opens a window, and is then
+    /// passed the commitments of every lookup in it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the fetch of the window fails, or is abandoned.
+    pub async fn contains<F, Fut>(&self, commitment: Hash, fetch: F) -> anyhow::Result<bool>
+    where
+        F: FnOnce(Vec<Hash>) -> Fut,
+        Fut: Future<Output = anyhow::Result<HashSet<Hash>>>,
+    {
+        let joined = {
+            let mut open = self.open.lock().unwrap();
+            match &mut *open {
+                // A window whose first lookup was dropped is never fetched.
+                Some(window) if window.outcome.has_changed().is_ok() => {
+                    window.commitments.push(commitment);
+                    Err(window.outcome.clone())
+                }
+                _ => {
+                    let (sender, outcome) = watch::channel(None);
+                    *open = Some(Window {
+                        commitments: vec![commitment],
+                        outcome,
+                    });
+                    Ok(sender)
+                }
+            }
+        };
+
+        match joined {
+            Ok(sender) => {
+                sleep(self.window).await;
+                let commitments = self
+                    .open
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .map(|window| window.commitments)
+                    .unwrap_or_default();
+                let result = fetch(commitments).await;
+                let found = result
+                    .as_ref()
+                    .map(|found| found.contains(&commitment))
+                    .map_err(|error| anyhow!("{error}"));
+                sender.send_replace(Some(result.map(Arc::new).map_err(|e| e.to_string())));
+                found
+            }
+            Err(mut outcome) => loop {
+                if let Some(result) = outcome.borrow().as_ref() {
+                    return match result {
+                        Ok(found) => Ok(found.contains(&commitment)),
+                        Err(error) => Err(anyhow!("{error}")),
+                    };
+                }
+                outcome
+                    .changed()
+                    .await
+                    .map_err(|_| anyhow!("Coalesced lookup was abandoned."))?;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn lookups_within_window_should_be_fetched_once() -> anyhow::Result<()> {
+        let coalescer = StatusCoalescer::new(Duration::from_millis(50));
+        let fetches = AtomicUsize::new(0);
+        let fetch = |commitments: Vec<Hash>| {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            async move {
+                Ok(commitments
+                    .into_iter()
+                    .filter(|commitment| commitment != &Hash::from(2_u64))
+                    .collect())
+            }
+        };
+
+        let found = join_all(
+            [1_u64, 2, 3]
+                .map(Hash::from)
+                .map(|commitment| coalescer.contains(commitment, fetch)),
+        )
+        .await
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(found, vec![true, false, true]);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        assert!(coalescer.contains(Hash::from(4_u64), fetch).await?);
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+}
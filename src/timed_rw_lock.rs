@@ -1,24 +1,108 @@
+use once_cell::sync::Lazy;
+use prometheus::{register_histogram_vec, HistogramTimer, HistogramVec};
 use std::{
     fmt::{self, Display, Formatter},
+    ops::{Deref, DerefMut},
     time::Duration,
 };
 use thiserror::Error;
 use tokio::{
     sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
-    time::timeout,
+    time::{timeout, Instant},
 };
+use tracing::{debug_span, field, warn, Instrument, Span};
 
-// FEATURE: Add tracing spans to wait and the guard.
+static HOLD_TIME: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "lock_hold_seconds",
+        "Time a lock guard was held in seconds, by operation.",
+        &["operation"]
+    )
+    .unwrap()
+});
 
 /// A read-write lock with timeout.
 ///
-/// Wraps Tokio's [`RwLock`].
+/// Wraps Tokio's [`RwLock`]. Waiting for the lock happens in a `lock_wait`
+/// span, and every guard carries a `lock_held` span that closes when it is
+/// dropped.
 #[derive(Debug)]
 pub struct TimedRwLock<T: Send + Sync> {
-    duration: Duration,
-    inner:    RwLock<T>,
+    duration:          Duration,
+    record_hold_times: bool,
+    hold_warning:      Option<Duration>,
+    inner:             RwLock<T>,
 }
 
+/// A guard of a [`TimedRwLock`].
+///
+/// If the lock records hold times, the time from acquiring the guard to
+/// dropping it is observed in `lock_hold_seconds`. If it is held for longer
+/// than the hold warning of the lock, a warning is logged when it is dropped.
+pub struct TimedGuard<G> {
+    guard:        G,
+    operation:    Operation,
+    acquired:     Instant,
+    hold_warning: Option<Duration>,
+    hold_timer:   Option<HistogramTimer>,
+    span:         Span,
+}
+
+impl<G> TimedGuard<G> {
+    fn new<T: Send + Sync>(guard: G, operation: Operation, lock: &TimedRwLock<T>) -> Self {
+        let hold_timer = lock.record_hold_times.then(|| {
+            HOLD_TIME
+                .with_label_values(&[&operation.to_string()])
+                .start_timer()
+        });
+        Self {
+            guard,
+            operation,
+            acquired: Instant::now(),
+            hold_warning: lock.hold_warning,
+            hold_timer,
+            span: debug_span!("lock_held", %operation, held_ms = field::Empty),
+        }
+    }
+
+    /// Returns `true` if the guard has been held for longer than the hold
+    /// warning of its lock.
+    fn held_too_long(&self) -> bool {
+        self.hold_warning
+            .map_or(false, |threshold| self.acquired.elapsed() > threshold)
+    }
+}
+
+impl<G> Drop for TimedGuard<G> {
+    fn drop(&mut self) {
+        let held = self.acquired.elapsed();
+        self.span.record(
+            "held_ms",
+            u64::try_from(held.as_millis()).unwrap_or(u64::MAX),
+        );
+        if self.held_too_long() {
+            warn!(operation = %self.operation, ?held, "Lock guard was held for too long.");
+        }
+    }
+}
+
+impl<G: Deref> Deref for TimedGuard<G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut> DerefMut for TimedGuard<G> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+pub type TimedReadGuard<'a, T> = TimedGuard<RwLockReadGuard<'a, T>>;
+pub type TimedWriteGuard<'a, T> = TimedGuard<RwLockWriteGuard<'a, T>>;
+
 /// Error for [`TimedRwLock`].
 #[derive(Debug, Error)]
 #[error("Timeout while waiting for lock. Duration: {duration:?}, Operation: {operation}")]
@@ -49,7 +133,35 @@ impl<T: Send + Sync> TimedRwLock<T> {
     }
 
     pub const fn from_lock(duration: Duration, inner: RwLock<T>) -> Self {
-        Self { duration, inner }
+        Self {
+            duration,
+            record_hold_times: false,
+            hold_warning: None,
+            inner,
+        }
+    }
+
+    /// Logs a warning whenever a guard is dropped after being held for longer
+    /// than `threshold`. Never warns if unset.
+    #[must_use]
+    pub const fn with_hold_warning(mut self, threshold: Option<Duration>) -> Self {
+        self.hold_warning = threshold;
+        self
+    }
+
+    pub const fn hold_warning(&self) -> Option<Duration> {
+        self.hold_warning
+    }
+
+    /// Enables recording how long each guard is held, per operation.
+    #[must_use]
+    pub fn with_hold_time_metrics(mut self, enabled: bool) -> Self {
+        self.record_hold_times = enabled;
+        self
+    }
+
+    pub const fn records_hold_times(&self) -> bool {
+        self.record_hold_times
     }
 
     #[allow(dead_code)]
@@ -57,21 +169,176 @@ impl<T: Send + Sync> TimedRwLock<T> {
         self.duration
     }
 
-    pub async fn read(&self) -> Result<RwLockReadGuard<'_, T>, Error> {
-        timeout(self.duration, self.inner.read())
+    pub async fn read(&self) -> Result<TimedReadGuard<'_, T>, Error> {
+        self.read_timeout(self.duration).await
+    }
+
+    /// Acquires a read lock, waiting up to `duration` instead of the timeout
+    /// of the lock.
+    pub async fn read_timeout(&self, duration: Duration) -> Result<TimedReadGuard<'_, T>, Error> {
+        timeout(duration, self.inner.read())
+            .instrument(wait_span(Operation::Read))
             .await
+            .map(|guard| TimedGuard::new(guard, Operation::Read, self))
+            .map_err(|_| Error {
+                operation: Operation::Read,
+                duration,
+            })
+    }
+
+    /// Acquires a read lock if it is not held for writing, without waiting.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` with a zero duration if the lock is contended.
+    pub fn try_read(&self) -> Result<TimedReadGuard<'_, T>, Error> {
+        self.inner
+            .try_read()
+            .map(|guard| TimedGuard::new(guard, Operation::Read, self))
             .map_err(|_| Error {
                 operation: Operation::Read,
-                duration:  self.duration,
+                duration:  Duration::ZERO,
             })
     }
 
-    pub async fn write(&self) -> Result<RwLockWriteGuard<'_, T>, Error> {
-        timeout(self.duration, self.inner.write())
+    /// Acquires a read lock without a timeout.
+    ///
+    /// Meant for trusted internal callers, such as consistency checks, that
+    /// must not give up on a busy lock. Everything else should use
+    /// [`Self::read`].
+    pub async fn read_uninterruptible(&self) -> TimedReadGuard<'_, T> {
+        let guard = self
+            .inner
+            .read()
+            .instrument(wait_span(Operation::Read))
+            .await;
+        TimedGuard::new(guard, Operation::Read, self)
+    }
+
+    pub async fn write(&self) -> Result<TimedWriteGuard<'_, T>, Error> {
+        self.write_timeout(self.duration).await
+    }
+
+    /// Acquires a write lock, waiting up to `duration` instead of the timeout
+    /// of the lock.
+    pub async fn write_timeout(&self, duration: Duration) -> Result<TimedWriteGuard<'_, T>, Error> {
+        timeout(duration, self.inner.write())
+            .instrument(wait_span(Operation::Write))
             .await
+            .map(|guard| TimedGuard::new(guard, Operation::Write, self))
+            .map_err(|_| Error {
+                operation: Operation::Write,
+                duration,
+            })
+    }
+
+    /// Acquires a write lock if it is not held at all, without waiting.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` with a zero duration if the lock is contended.
+    #[allow(dead_code)]
+    pub fn try_write(&self) -> Result<TimedWriteGuard<'_, T>, Error> {
+        self.inner
+            .try_write()
+            .map(|guard| TimedGuard::new(guard, Operation::Write, self))
             .map_err(|_| Error {
                 operation: Operation::Write,
-                duration:  self.duration,
+                duration:  Duration::ZERO,
             })
     }
 }
+
+fn wait_span(operation: Operation) -> Span {
+    debug_span!("lock_wait", %operation)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn uninterruptible_read_should_outlast_timeout() {
+        let lock = Arc::new(TimedRwLock::new(Duration::from_millis(50), 0));
+        let guard = lock.write().await.unwrap();
+
+        let writer = tokio::spawn(async move {
+            sleep(Duration::from_millis(200)).await;
+            drop(guard);
+        });
+
+        assert!(lock.read().await.is_err());
+        assert_eq!(*lock.read_uninterruptible().await, 0);
+
+        writer.await.unwrap();
+    }
+
+    #[test]
+    fn contended_lock_should_fail_without_waiting() {
+        let lock = TimedRwLock::new(Duration::from_secs(60), 0);
+
+        let reader = lock.try_read().unwrap();
+        assert!(lock.try_read().is_ok());
+        assert!(lock.try_write().is_err());
+        drop(reader);
+
+        let writer = lock.try_write().unwrap();
+        let error = lock.try_read().err().unwrap();
+        assert_eq!(error.duration, Duration::ZERO);
+        drop(writer);
+        assert_eq!(*lock.try_read().unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn contended_lock_should_fail_after_call_timeout() {
+        let lock = TimedRwLock::new(Duration::from_secs(60), 0);
+
+        let reader = lock.read().await.unwrap();
+        let started = Instant::now();
+        assert!(lock.write_timeout(Duration::from_millis(10)).await.is_err());
+        assert_eq!(started.elapsed(), Duration::from_millis(10));
+        drop(reader);
+
+        let writer = lock.write().await.unwrap();
+        assert!(lock.read_timeout(Duration::ZERO).await.is_err());
+        drop(writer);
+        assert_eq!(*lock.read_timeout(Duration::ZERO).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn hold_time_should_be_recorded_on_guard_drop() {
+        let samples = || {
+            HOLD_TIME
+                .with_label_values(&[&Operation::Write.to_string()])
+                .get_sample_count()
+        };
+        let lock = TimedRwLock::new(Duration::from_secs(1), 0).with_hold_time_metrics(true);
+        let before = samples();
+
+        let mut guard = lock.write().await.unwrap();
+        *guard += 1;
+        assert_eq!(samples(), before);
+
+        drop(guard);
+        assert_eq!(samples(), before + 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn guard_held_past_threshold_should_be_flagged() {
+        let lock = TimedRwLock::new(Duration::from_secs(1), 0)
+            .with_hold_warning(Some(Duration::from_millis(100)));
+
+        let guard = lock.read().await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        assert!(!guard.held_too_long());
+        sleep(Duration::from_millis(100)).await;
+        assert!(guard.held_too_long());
+
+        let unbounded = TimedRwLock::new(Duration::from_secs(1), 0);
+        let guard = unbounded.write().await.unwrap();
+        sleep(Duration::from_secs(10)).await;
+        assert!(!guard.held_too_long());
+    }
+}
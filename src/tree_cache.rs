@@ -0,0 +1,321 @@
+use crate::identity_tree::{covering, Hash, HashFunction, SharedTreeState, TreeState};
+use anyhow::{anyhow, bail, Result as AnyhowResult};
+use cli_batteries::await_shutdown;
+use ethers::utils::keccak256;
+use std::{fs, io::ErrorKind, path::PathBuf, time::Duration};
+use thiserror::Error;
+use tokio::{select, task::spawn_blocking, time::sleep};
+use tracing::{info, warn};
+
+/// Identifies a tree snapshot, and the version of its layout.
+const MAGIC: &[u8; 8] = b"SEQTREE4";
+
+/// The parts of a tree that a snapshot is made of, copied out of the tree so
+/// that it can be serialized without holding the tree lock.
+struct Parts {
+    depth:        usize,
+    initial_leaf: Hash,
+    hasher:       HashFunction,
+    nodes:        Vec<Vec<Hash>>,
+    leaves:       Vec<Hash>,
+}
+
+impl Parts {
+    fn of(tree: &TreeState) -> Self {
+        Self {
+            depth:        tree.merkle_tree.depth(),
+            initial_leaf: tree.merkle_tree.initial_leaf(),
+            hasher:       tree.merkle_tree.hasher(),
+            nodes:        tree
+                .merkle_tree
+                .kept_nodes(tree.next_leaf)
+                .into_iter()
+                .map(<[Hash]>::to_vec)
+                .collect(),
+            leaves:       tree.merkle_tree.leaves()[..tree.next_leaf].to_vec(),
+        }
+    }
+
+    /// Serializes the parts into a snapshot, see [`encode`].
+    fn encode(&self) -> Vec<u8> {
+        let hashes = self.nodes.iter().map(Vec::len).sum::<usize>() + self.leaves.len();
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 4 * 8 + (hashes + 2) * 32);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&(self.depth as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.initial_leaf.to_be_bytes::<32>());
+        bytes.extend_from_slice(&(hasher_id(self.hasher) as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.nodes.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.leaves.len() as u64).to_be_bytes());
+        for hash in self.nodes.iter().flatten().chain(&self.leaves) {
+            bytes.extend_from_slice(&hash.to_be_bytes::<32>());
+        }
+        let checksum = keccak256(&bytes);
+        bytes.extend_from_slice(&checksum);
+        bytes
+    }
+}
+
+/// Serializes `tree` into a snapshot.
+///
+/// The snapshot is a header of the depth, the initial leaf, the hash function,
+/// the number of kept levels and the next leaf, followed by the kept nodes
+/// above the leaves up to the next leaf, level by level from the root, and then
+/// those leaves. It ends with the Keccak-256 hash of everything before. Numbers
+/// are 64-bit and hashes 32 bytes, all big endian.
+#[must_use]
+pub fn encode(tree: &TreeState) -> Vec<u8> {
+    Parts::of(tree).encode()
+}
+
+/// Why a snapshot was rejected.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+pub enum Rejection {
+    #[error("it has a different layout version")]
+    Version,
+    #[error("it was taken of a different tree")]
+    OtherTree,
+    #[error("it is corrupt")]
+    Corrupt,
+}
+
+/// Deserializes a snapshot into a tree of the same depth, initial leaf, hash
+/// function and node limit as `empty`.
+///
+/// # Errors
+///
+/// Will return `Err` if the snapshot is of another layout version, does not
+/// match its checksum, is malformed or was taken of a different tree.
+pub fn decode(bytes: &[u8], empty: &TreeState) -> Result<TreeState, Rejection> {
+    if bytes.get(..MAGIC.len()) != Some(MAGIC.as_slice()) {
+        return Err(Rejection::Version);
+    }
+    let body = bytes
+        .len()
+        .checked_sub(32)
+        .filter(|&end| end >= MAGIC.len())
+        .ok_or(Rejection::Corrupt)?;
+    let (body, checksum) = bytes.split_at(body);
+    if keccak256(body) != checksum {
+        return Err(Rejection::Corrupt);
+    }
+
+    let mut reader = Reader(&body[MAGIC.len()..]);
+    if reader.usize().ok_or(Rejection::Corrupt)? != empty.merkle_tree.depth()
+        || reader.hash().ok_or(Rejection::Corrupt)? != empty.merkle_tree.initial_leaf()
+        || reader.usize().ok_or(Rejection::Corrupt)? != hasher_id(empty.merkle_tree.hasher())
+    {
+        return Err(Rejection::OtherTree);
+    }
+    let kept_levels = reader.usize().ok_or(Rejection::Corrupt)?;
+    let next_leaf = reader.usize().ok_or(Rejection::Corrupt)?;
+    if kept_levels != empty.merkle_tree.kept_levels() {
+        return Err(Rejection::OtherTree);
+    }
+    // Guards the allocations below against a corrupt header.
+    if next_leaf > empty.merkle_tree.num_leaves() {
+        return Err(Rejection::Corrupt);
+    }
+
+    let depth = empty.merkle_tree.depth();
+    let nodes = (0..kept_levels)
+        .map(|level| reader.hashes(covering(next_leaf, depth - 1 - level)))
+        .collect::<Option<Vec<_>>>()
+        .ok_or(Rejection::Corrupt)?;
+    let leaves = reader.hashes(next_leaf).ok_or(Rejection::Corrupt)?;
+    if !reader.0.is_empty() {
+        return Err(Rejection::Corrupt);
+    }
+
+    Ok(TreeState {
+        next_leaf,
+        merkle_tree: empty
+            .merkle_tree
+            .with_parts(nodes, &leaves)
+            .ok_or(Rejection::Corrupt)?,
+    })
+}
+
+/// Reads the snapshot at `path` into a tree like `empty`.
+///
+/// Returns `None` if there is no snapshot yet. If the snapshot cannot be read
+/// or is rejected, `None` is returned as well when `rebuild`, so that the tree
+/// is rebuilt from the database instead.
+///
+/// # Errors
+///
+/// Will return `Err` if the snapshot cannot be read or is rejected, unless
+/// `rebuild`.
+pub async fn restore(
+    path: PathBuf,
+    empty: &TreeState,
+    rebuild: bool,
+) -> AnyhowResult<Option<TreeState>> {
+    let read_path = path.clone();
+    let reason = match spawn_blocking(move || fs::read(read_path)).await? {
+        Ok(bytes) => match decode(&bytes, empty) {
+            Ok(tree) => return Ok(Some(tree)),
+            Err(rejection) => rejection.to_string(),
+        },
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => format!("it cannot be read: {error}"),
+    };
+    if !rebuild {
+        bail!(
+            "The tree snapshot {} is unusable, {reason}.",
+            path.display()
+        );
+    }
+    warn!(
+        ?path,
+        %reason,
+        "Rejected the tree snapshot, rebuilding the tree from the database."
+    );
+    Ok(None)
+}
+
+/// Writes a snapshot of the tree to `path`.
+///
+/// The tree is only locked while its parts are copied, they are serialized and
+/// written on a blocking thread. The snapshot is written
+/// next to `path` first and then moved over it, so that a crash cannot leave a
+/// partial snapshot behind.
+///
+/// # Errors
+///
+/// Will return `Err` if the tree lock times out or the snapshot cannot be
+/// written.
+pub async fn save(tree_state: &SharedTreeState, path: PathBuf) -> AnyhowResult<usize> {
+    let (parts, next_leaf) = {
+        let tree = tree_state.read().await?;
+        (Parts::of(&tree), tree.next_leaf)
+    };
+    spawn_blocking(move || {
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, parts.encode())?;
+        fs::rename(&temporary, &path)
+    })
+    .await?
+    .map_err(|error| anyhow!("Failed to write the tree snapshot: {error}"))?;
+    Ok(next_leaf)
+}
+
+/// Spawns a task that saves a snapshot of the tree to `path` every `interval`,
+/// and tries once more on shutdown.
+pub fn start_snapshots(tree_state: SharedTreeState, path: PathBuf, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            let shutdown = select! {
+                _ = sleep(interval) => false,
+                _ = await_shutdown() => true,
+            };
+            match save(&tree_state, path.clone()).await {
+                Ok(next_leaf) => info!(next_leaf, ?path, "Saved a tree snapshot."),
+                Err(error) => warn!(?error, "Failed to save a tree snapshot."),
+            }
+            if shutdown {
+                return;
+            }
+        }
+    });
+}
+
+/// Identifies `hasher` in snapshots.
+const fn hasher_id(hasher: HashFunction) -> usize {
+    match hasher {
+        HashFunction::Poseidon => 0,
+        HashFunction::Keccak => 1,
+    }
+}
+
+/// Reads the fields of a snapshot in order.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.0.len() < count {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn usize(&mut self) -> Option<usize> {
+        let bytes = self.take(8)?.try_into().ok()?;
+        u64::from_be_bytes(bytes).try_into().ok()
+    }
+
+    fn hash(&mut self) -> Option<Hash> {
+        Hash::try_from_be_slice(self.take(32)?)
+    }
+
+    fn hashes(&mut self, count: usize) -> Option<Vec<Hash>> {
+        (0..count).map(|_| self.hash()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snapshot_should_restore_tree() {
+        for max_node_bytes in [None, Some(3 * 32)] {
+            let empty = TreeState::with_node_cache_limit(6, Hash::from(0_u64), max_node_bytes);
+            let mut tree = empty.cleared();
+            tree.merkle_tree.set_range(0, (1..=5_u64).map(Hash::from));
+            tree.next_leaf = 5;
+
+            let bytes = encode(&tree);
+            let restored = decode(&bytes, &empty).unwrap();
+            assert_eq!(restored.next_leaf, 5);
+            assert_eq!(restored.merkle_tree.root(), tree.merkle_tree.root());
+            assert_eq!(restored.merkle_tree.leaves(), tree.merkle_tree.leaves());
+            assert_eq!(restored.merkle_tree.proof(3), tree.merkle_tree.proof(3));
+
+            // Snapshots of other trees or truncated ones are rejected.
+            let other = TreeState::with_node_cache_limit(6, Hash::from(1_u64), max_node_bytes);
+            assert_eq!(decode(&bytes, &other).err(), Some(Rejection::OtherTree));
+            assert_eq!(
+                decode(&bytes, &empty.with_hasher(HashFunction::Keccak)).err(),
+                Some(Rejection::OtherTree)
+            );
+            assert_eq!(
+                decode(&bytes[..bytes.len() - 1], &empty).err(),
+                Some(Rejection::Corrupt)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupt_snapshot_should_fall_back_to_rebuild() -> anyhow::Result<()> {
+        let empty = TreeState::new(6, Hash::from(0_u64));
+        let leaves = (1..=5_u64).map(Hash::from).collect::<Vec<_>>();
+        let mut tree = empty.cleared();
+        tree.merkle_tree.set_range(0, leaves.iter().copied());
+        tree.next_leaf = leaves.len();
+
+        let mut bytes = encode(&tree);
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        assert_eq!(decode(&bytes, &empty).err(), Some(Rejection::Corrupt));
+        let mut old = encode(&tree);
+        old[..MAGIC.len()].copy_from_slice(b"SEQTREE3");
+        assert_eq!(decode(&old, &empty).err(), Some(Rejection::Version));
+
+        let path = std::env::temp_dir().join(format!("tree-snapshot-{}", std::process::id()));
+        fs::write(&path, &bytes)?;
+        assert!(restore(path.clone(), &empty, false).await.is_err());
+        let restored = restore(path.clone(), &empty, true).await;
+        fs::remove_file(&path)?;
+        assert!(restored?.is_none());
+
+        // The tree is then rebuilt from the identities in the database.
+        let mut rebuilt = empty.cleared();
+        rebuilt.merkle_tree.set_range(0, leaves.iter().copied());
+        assert_eq!(rebuilt.merkle_tree.root(), tree.merkle_tree.root());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,373 @@
+use super::Submitter;
+use crate::ethereum::{ProviderStack, TxError};
+use async_trait::async_trait;
+use clap::Parser;
+use ethers::{
+    providers::Middleware,
+    types::{transaction::eip2718::TypedTransaction, Address, TransactionReceipt, H256, U256, U64},
+};
+use reqwest::{Client as ReqwestClient, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::Mutex,
+    time::{sleep, Instant},
+};
+use tracing::{error, info, instrument, warn};
+use url::Url;
+
+/// How long before it expires an access token is renewed.
+const TOKEN_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq, Eq, Parser)]
+#[group(skip)]
+pub struct Options {
+    /// API key of an OpenZeppelin Defender Relayer. If set, transactions are
+    /// sent through the relayer instead of being signed and sent directly,
+    /// leaving gas pricing and resubmission to Defender. The relayer must be
+    /// allowed to call the contracts.
+    #[clap(long, env)]
+    pub defender_api_key: Option<String>,
+
+    /// API secret of the OpenZeppelin Defender Relayer.
+    #[clap(long, env)]
+    pub defender_api_secret: Option<String>,
+
+    /// The OpenZeppelin Defender Relay API.
+    #[clap(long, env, default_value = "https://api.defender.openzeppelin.com")]
+    pub defender_api_url: Url,
+
+    /// The Cognito app client that relayer API keys log in with.
+    #[clap(long, env, default_value = "1bpd19lcr33qvg5cr3oi79rdap")]
+    pub defender_cognito_client_id: String,
+
+    /// The AWS region of the relayer Cognito user pool.
+    #[clap(long, env, default_value = "us-west-2")]
+    pub defender_cognito_region: String,
+
+    /// The transaction speed Defender prices relayed transactions for.
+    #[clap(long, env, default_value = "fast")]
+    pub defender_speed: String,
+
+    /// How often the status of relayed transactions is checked (seconds).
+    #[clap(long, env, default_value = "5")]
+    pub defender_poll_interval: u64,
+
+    /// Timeout for a relayed transaction to be mined (seconds).
+    #[clap(long, env, default_value = "600")]
+    pub defender_mine_timeout: u64,
+}
+
+/// A relayed transaction, as reported by Defender.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RelayedTransaction {
+    transaction_id: String,
+    /// The hash of the latest submission, which changes whenever Defender
+    /// resubmits the transaction.
+    hash:           H256,
+    status:         RelayStatus,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RelayStatus {
+    Pending,
+    Sent,
+    Submitted,
+    Inmempool,
+    Mined,
+    Confirmed,
+    Failed,
+}
+
+#[derive(Debug, Deserialize)]
+struct Relayer {
+    address: Address,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RelayRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to:        Option<Address>,
+    data:      String,
+    value:     String,
+    gas_limit: String,
+    speed:     String,
+}
+
+#[derive(Debug)]
+struct AccessToken {
+    token:   String,
+    expires: Instant,
+}
+
+/// Sends transactions through an OpenZeppelin Defender Relayer, which signs
+/// them, prices their gas and resubmits them until they are mined.
+#[derive(Debug)]
+pub struct DefenderRelay {
+    client:        ReqwestClient,
+    options:       Options,
+    api_secret:    String,
+    provider:      Arc<ProviderStack>,
+    address:       Address,
+    token:         Mutex<Option<AccessToken>>,
+    poll_interval: Duration,
+    mine_timeout:  Duration,
+}
+
+impl DefenderRelay {
+    /// Connects to the relayer configured in `options`, which waits for its
+    /// transactions to be mined on `provider`.
+    ///
+    /// Returns `None` if no relayer is configured.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the API secret is missing or the relayer cannot
+    /// be reached.
+    #[instrument(name = "DefenderRelay::new", level = "debug", skip_all)]
+    pub async fn new(
+        options: Options,
+        provider: Arc<ProviderStack>,
+    ) -> anyhow::Result<Option<Self>> {
+        if options.defender_api_key.is_none() {
+            return Ok(None);
+        }
+        let Some(api_secret) = options.defender_api_secret.clone() else {
+            anyhow::bail!("A Defender API key requires a Defender API secret.");
+        };
+        let mut relay = Self {
+            client: ReqwestClient::builder()
+                .timeout(Duration::from_secs(30))
+                .build()?,
+            api_secret,
+            provider,
+            address: Address::zero(),
+            token: Mutex::new(None),
+            poll_interval: Duration::from_secs(options.defender_poll_interval),
+            mine_timeout: Duration::from_secs(options.defender_mine_timeout),
+            options,
+        };
+        let relayer: Relayer = relay
+            .authorized(relay.client.get(relay.url("relayer")?))
+            .await?
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        relay.address = relayer.address;
+        info!(address = ?relay.address, "Connected to Defender Relayer");
+        Ok(Some(relay))
+    }
+
+    fn url(&self, path: &str) -> anyhow::Result<Url> {
+        let mut url = self.options.defender_api_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Invalid Defender API url."))?
+            .pop_if_empty()
+            .extend(path.split('/'));
+        Ok(url)
+    }
+
+    /// Adds the API key and a current access token to `request`, logging in
+    /// again once the token is about to expire.
+    async fn authorized(&self, request: RequestBuilder) -> anyhow::Result<RequestBuilder> {
+        let api_key = self.options.defender_api_key.clone().unwrap_or_default();
+        let mut token = self.token.lock().await;
+        let valid = token
+            .as_ref()
+            .filter(|token| token.expires > Instant::now() + TOKEN_MARGIN);
+        let bearer = if let Some(valid) = valid {
+            valid.token.clone()
+        } else {
+            let fresh = self.log_in(&api_key).await?;
+            let bearer = fresh.token.clone();
+            *token = Some(fresh);
+            bearer
+        };
+        Ok(request.header("X-Api-Key", api_key).bearer_auth(bearer))
+    }
+
+    async fn log_in(&self, api_key: &str) -> anyhow::Result<AccessToken> {
+        let url = format!(
+            "https://cognito-idp.{}.amazonaws.com/",
+            self.options.defender_cognito_region
+        );
+        let response: serde_json::Value = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/x-amz-json-1.1")
+            .header(
+                "X-Amz-Target",
+                "AWSCognitoIdentityProviderService.InitiateAuth",
+            )
+            .body(
+                json!({
+                    "AuthFlow": "USER_PASSWORD_AUTH",
+                    "ClientId": self.options.defender_cognito_client_id,
+                    "AuthParameters": {
+                        "USERNAME": api_key,
+                        "PASSWORD": self.api_secret,
+                    },
+                })
+                .to_string(),
+            )
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let result = &response["AuthenticationResult"];
+        let token = result["AccessToken"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Defender login returned no access token."))?;
+        let expires_in = result["ExpiresIn"].as_u64().unwrap_or_default();
+        Ok(AccessToken {
+            token:   token.to_owned(),
+            expires: Instant::now() + Duration::from_secs(expires_in),
+        })
+    }
+
+    async fn relay(&self, request: &RelayRequest) -> anyhow::Result<RelayedTransaction> {
+        Ok(self
+            .authorized(self.client.post(self.url("txs")?))
+            .await?
+            .json(request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn status(&self, transaction_id: &str) -> anyhow::Result<RelayedTransaction> {
+        Ok(self
+            .authorized(self.client.get(self.url(&format!("txs/{transaction_id}"))?))
+            .await?
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+}
+
+/// Builds the relay request for `tx`, leaving its signer, nonce and fees to
+/// Defender.
+fn relay_request(tx: &TypedTransaction, gas_limit: U256, speed: &str) -> RelayRequest {
+    RelayRequest {
+        to:        tx.to().and_then(|to| to.as_address().copied()),
+        data:      tx
+            .data()
+            .map_or_else(|| "0x".to_owned(), |data| format!("{data}")),
+        value:     tx.value().copied().unwrap_or_default().to_string(),
+        gas_limit: gas_limit.to_string(),
+        speed:     speed.to_owned(),
+    }
+}
+
+#[async_trait]
+impl Submitter for DefenderRelay {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    #[instrument(level = "info", skip_all)]
+    async fn submit(&self, mut tx: TypedTransaction) -> Result<TransactionReceipt, TxError> {
+        tx.set_from(self.address);
+        let gas_limit = self
+            .provider
+            .estimate_gas(&tx, None)
+            .await
+            .map_err(|error| TxError::Fill(Box::new(error)))?;
+        let request = relay_request(&tx, gas_limit, &self.options.defender_speed);
+        let mut relayed = self
+            .relay(&request)
+            .await
+            .map_err(|error| TxError::Send(error.into()))?;
+        info!(
+            transaction_id = %relayed.transaction_id,
+            tx_hash = ?relayed.hash,
+            "Transaction relayed"
+        );
+
+        let deadline = Instant::now() + self.mine_timeout;
+        loop {
+            match relayed.status {
+                RelayStatus::Mined | RelayStatus::Confirmed => {
+                    if let Ok(Some(receipt)) =
+                        self.provider.get_transaction_receipt(relayed.hash).await
+                    {
+                        if receipt.status != Some(U64::from(1_u64)) {
+                            return Err(TxError::Failed(Box::new(receipt)));
+                        }
+                        info!(tx_hash = ?receipt.transaction_hash, "Relayed transaction mined");
+                        return Ok(receipt);
+                    }
+                }
+                RelayStatus::Failed => {
+                    error!(transaction_id = %relayed.transaction_id, "Relayed transaction failed");
+                    return Err(TxError::Dropped(relayed.hash));
+                }
+                _ => {}
+            }
+            if Instant::now() >= deadline {
+                error!(transaction_id = %relayed.transaction_id, "Waiting for relayed transaction timed out");
+                return Err(TxError::ConfirmationTimeout);
+            }
+            sleep(self.poll_interval).await;
+            match self.status(&relayed.transaction_id).await {
+                Ok(status) => relayed = status,
+                Err(error) => {
+                    warn!(?error, transaction_id = %relayed.transaction_id, "Failed to check relayed transaction");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ethers::types::{Bytes, TransactionRequest};
+
+    #[test]
+    fn relay_request_should_leave_signing_to_defender() {
+        let to = Address::from_low_u64_be(1);
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(to)
+            .data(Bytes::from(vec![0xab, 0xcd]))
+            .nonce(7)
+            .gas_price(100)
+            .into();
+
+        let request = serde_json::to_value(relay_request(&tx, 50_000.into(), "fast")).unwrap();
+        assert_eq!(
+            request,
+            json!({
+                "to": to,
+                "data": "0xabcd",
+                "value": "0",
+                "gasLimit": "50000",
+                "speed": "fast",
+            })
+        );
+    }
+
+    #[test]
+    fn relayed_transaction_should_parse() {
+        let relayed: RelayedTransaction = serde_json::from_value(json!({
+            "transactionId": "abc",
+            "hash": H256::from_low_u64_be(2),
+            "status": "inmempool",
+            "nonce": 3,
+        }))
+        .unwrap();
+        assert_eq!(relayed.transaction_id, "abc");
+        assert_eq!(relayed.status, RelayStatus::Inmempool);
+    }
+}
@@ -5,16 +5,33 @@
 /// This is a separate module because we may eventually pull it out into a
 /// separate crate and then into an independent service. A list of goals and
 /// features can be found [here](https://www.notion.so/worldcoin/tx-sitter-8ca70eec826e4491b500f55f03ec1b43).
+pub mod defender;
+pub mod nonce_manager;
+
 use crate::ethereum::{Ethereum, TxError};
-use ethers::types::{transaction::eip2718::TypedTransaction, TransactionReceipt};
+use async_trait::async_trait;
+use ethers::types::{transaction::eip2718::TypedTransaction, Address, TransactionReceipt};
+use std::fmt::Debug;
+
+/// A backend that sends transactions in place of the Ethereum provider and
+/// waits for them to be mined.
+#[async_trait]
+pub trait Submitter: Debug + Send + Sync {
+    /// The address the transactions are sent from.
+    fn address(&self) -> Address;
+
+    async fn submit(&self, tx: TypedTransaction) -> Result<TransactionReceipt, TxError>;
+}
 
 pub struct Sitter {
     pub ethereum: Ethereum,
 }
 
 impl Sitter {
-    #[allow(clippy::unused_async)]
+    /// Waits for the transactions left in flight by a previous run before
+    /// any new ones are sent.
     pub async fn new(ethereum: Ethereum) -> Result<Self, anyhow::Error> {
+        ethereum.recover_transactions().await?;
         Ok(Self { ethereum })
     }
 
@@ -0,0 +1,72 @@
+use crate::database::{self, Database};
+use ethers::types::{Address, H256};
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::error;
+
+/// Persists the nonces of the transactions in flight along with their
+/// hashes, so that they can be monitored again after a crash instead of their
+/// nonces being spent twice.
+///
+/// A nonce of a sender is in flight from when its first transaction is sent
+/// until one of its transactions is mined or they are all dropped. A
+/// transaction replaced with higher fees is recorded under the same nonce.
+#[derive(Clone, Debug)]
+pub struct NonceManager {
+    database: Arc<Database>,
+}
+
+impl NonceManager {
+    pub const fn new(database: Arc<Database>) -> Self {
+        Self { database }
+    }
+
+    /// Records that `tx_hash` was sent by `sender` with `nonce`.
+    ///
+    /// A failure is only logged, the transaction is in the mempool either
+    /// way.
+    pub async fn sent(&self, sender: Address, nonce: u64, tx_hash: H256) {
+        if let Err(error) = self
+            .database
+            .save_transaction(&sender, nonce, &tx_hash)
+            .await
+        {
+            error!(
+                ?error,
+                ?sender,
+                nonce,
+                ?tx_hash,
+                "Failed to record in-flight transaction."
+            );
+        }
+    }
+
+    /// Records that the transactions sent by `sender` with `nonce` are
+    /// settled.
+    ///
+    /// A failure is only logged, the nonce is then checked again on the next
+    /// start up.
+    pub async fn settled(&self, sender: Address, nonce: u64) {
+        if let Err(error) = self.database.remove_transactions(&sender, nonce).await {
+            error!(
+                ?error,
+                ?sender,
+                nonce,
+                "Failed to forget settled transaction."
+            );
+        }
+    }
+
+    /// Returns the hashes of the transactions of every nonce in flight, by
+    /// sender.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the transactions cannot be read.
+    pub async fn in_flight(&self) -> Result<BTreeMap<(Address, u64), Vec<H256>>, database::Error> {
+        let mut in_flight: BTreeMap<(Address, u64), Vec<H256>> = BTreeMap::new();
+        for (sender, nonce, tx_hash) in self.database.get_transactions().await? {
+            in_flight.entry((sender, nonce)).or_default().push(tx_hash);
+        }
+        Ok(in_flight)
+    }
+}
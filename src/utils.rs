@@ -2,7 +2,7 @@ use anyhow::{Error as EyreError, Result as AnyhowResult};
 use ethers::types::U256;
 use futures::FutureExt;
 use std::future::Future;
-use tokio::task::JoinHandle;
+use tokio::{runtime::Handle, task::JoinHandle};
 use tracing::error;
 
 #[macro_export]
@@ -44,6 +44,15 @@ where
 
 /// Spawn a task and abort process if it panics or results in error.
 pub fn spawn_or_abort<F, T>(future: F) -> JoinHandle<T>
+where
+    F: Future<Output = AnyhowResult<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_or_abort_on(&Handle::current(), future)
+}
+
+/// Like [`spawn_or_abort`], but spawns the task on the runtime of `handle`.
+pub fn spawn_or_abort_on<F, T>(handle: &Handle, future: F) -> JoinHandle<T>
 where
     F: Future<Output = AnyhowResult<T>> + Send + 'static,
     T: Send + 'static,
@@ -52,7 +61,7 @@ where
     let future = std::panic::AssertUnwindSafe(future);
 
     // Run task in background, returning a handle.
-    tokio::spawn(async move {
+    handle.spawn(async move {
         let result = future.catch_unwind().await;
         match result {
             Ok(Ok(t)) => t,
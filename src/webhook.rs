@@ -0,0 +1,405 @@
+use crate::identity_tree::Hash;
+use anyhow::anyhow;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{net::lookup_host, task::JoinHandle, time::sleep};
+use tracing::{info, warn};
+use url::{Host, Url};
+
+/// The maximum amount of time to wait for a webhook to accept a notification.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The delay before the first retry of a failed delivery. It doubles with
+/// every further retry.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The state an insertion has reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Status {
+    /// The batch containing the identity was mined.
+    Mined,
+    /// The batch containing the identity failed to be submitted.
+    Failed,
+}
+
+/// The body posted to the webhook of an insertion when its status changes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Notification {
+    pub group_id:            usize,
+    pub identity_commitment: Hash,
+    pub status:              Status,
+    /// The block the identity was mined in, if it was.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block:               Option<u64>,
+}
+
+/// Returns `true` if `ip` is not reachable from the public internet, such as
+/// loopback, private, link-local and shared addresses.
+#[must_use]
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Shared address space, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.to_ipv4_mapped().map_or(false, |ip| is_private(ip.into()))
+                || ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10
+                || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+/// Checks that webhooks may be delivered to `url`: over HTTP(S), at one of
+/// `allowed_hosts` unless it is empty, and not at a private address unless
+/// `private_addresses` are allowed.
+///
+/// Host names are only resolved on delivery, where addresses are checked
+/// again.
+///
+/// # Errors
+///
+/// Will return `Err` describing why `url` is refused.
+pub fn check_url(
+    url: &Url,
+    allowed_hosts: &[String],
+    private_addresses: bool,
+) -> anyhow::Result<()> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(anyhow!("unsupported scheme {}", url.scheme()));
+    }
+    let host = url.host().ok_or_else(|| anyhow!("missing host"))?;
+    if !allowed_hosts.is_empty()
+        && !allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(url.host_str().unwrap_or_default()))
+    {
+        return Err(anyhow!("host {host} is not allowed"));
+    }
+    let ip = match host {
+        Host::Ipv4(ip) => Some(IpAddr::V4(ip)),
+        Host::Ipv6(ip) => Some(IpAddr::V6(ip)),
+        Host::Domain(_) => None,
+    };
+    if !private_addresses && ip.map_or(false, is_private) {
+        return Err(anyhow!("host {host} is a private address"));
+    }
+    Ok(())
+}
+
+/// Resolves host names to their public addresses only, so that a webhook
+/// can't reach internal services through a name pointing at them.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| !is_private(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Delivers [`Notification`]s to the webhooks of insertions.
+///
+/// Redirects are not followed, and unless allowed, no requests are sent to
+/// private addresses.
+#[derive(Clone, Debug)]
+pub struct Webhooks {
+    client:            reqwest::Client,
+    max_attempts:      usize,
+    allowed_hosts:     Vec<String>,
+    private_addresses: bool,
+}
+
+impl Webhooks {
+    /// Creates a notifier that tries every delivery up to `max_attempts`
+    /// times.
+    #[must_use]
+    pub fn new(max_attempts: usize) -> Self {
+        Self {
+            client: Self::client(false),
+            max_attempts,
+            allowed_hosts: Vec::new(),
+            private_addresses: false,
+        }
+    }
+
+    /// Only delivers to webhooks at `allowed_hosts`, unless it is empty.
+    #[must_use]
+    pub fn with_allowed_hosts(mut self, allowed_hosts: Vec<String>) -> Self {
+        self.allowed_hosts = allowed_hosts;
+        self
+    }
+
+    /// Delivers to webhooks at private addresses too, if `allowed`.
+    #[must_use]
+    pub fn with_private_addresses(mut self, allowed: bool) -> Self {
+        self.client = Self::client(allowed);
+        self.private_addresses = allowed;
+        self
+    }
+
+    /// Checks that webhooks may be delivered to `url`, see [`check_url`].
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` describing why `url` is refused.
+    pub fn check_url(&self, url: &Url) -> anyhow::Result<()> {
+        check_url(url, &self.allowed_hosts, self.private_addresses)
+    }
+
+    fn client(private_addresses: bool) -> reqwest::Client {
+        let builder = reqwest::Client::builder().redirect(Policy::none());
+        let builder = if private_addresses {
+            builder
+        } else {
+            builder.dns_resolver(Arc::new(PublicResolver))
+        };
+        builder
+            .build()
+            .expect("Webhook client has a static configuration.")
+    }
+
+    /// Posts `notification` to `url`, retrying failed deliveries with
+    /// exponential backoff.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the last attempt fails to reach the webhook or is
+    /// answered with an error status.
+    pub async fn deliver(&self, url: &Url, notification: &Notification) -> anyhow::Result<()> {
+        // The options may have changed since the webhook was recorded.
+        self.check_url(url)?;
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .client
+                .post(url.clone())
+                .timeout(DELIVERY_TIMEOUT)
+                .json(notification)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            match result {
+                Err(error) if attempt < self.max_attempts => {
+                    warn!(%error, attempt, %url, "Webhook delivery failed, retrying.");
+                    sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(error) => return Err(error.into()),
+                Ok(_) => return Ok(()),
+            }
+        }
+    }
+
+    /// Delivers `notification` to `url` in the background, logging the outcome.
+    pub fn spawn_delivery(&self, url: Url, notification: Notification) -> JoinHandle<()> {
+        let webhooks = self.clone();
+        tokio::spawn(async move {
+            let commitment = notification.identity_commitment;
+            match webhooks.deliver(&url, &notification).await {
+                Ok(()) => {
+                    info!(?commitment, status = ?notification.status, %url, "Delivered webhook.")
+                }
+                Err(error) => {
+                    warn!(?commitment, %error, %url, "Webhook delivery failed on every attempt.");
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use axum::{http::StatusCode, routing::post, Json, Router};
+    use axum_server::Handle;
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    /// A webhook receiver that records the notifications it accepts.
+    pub struct Receiver {
+        server:        Handle,
+        failing:       Arc<AtomicUsize>,
+        notifications: Arc<Mutex<Vec<Notification>>>,
+    }
+
+    impl Receiver {
+        pub fn new(addr: &str) -> anyhow::Result<Self> {
+            let failing = Arc::new(AtomicUsize::new(0));
+            let notifications = Arc::new(Mutex::new(Vec::new()));
+            let receive = {
+                let failing = failing.clone();
+                let notifications = notifications.clone();
+                move |Json(notification): Json<Notification>| {
+                    let failing = failing.clone();
+                    let notifications = notifications.clone();
+                    async move {
+                        let fail = failing
+                            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                            .is_ok();
+                        if fail {
+                            return StatusCode::SERVICE_UNAVAILABLE;
+                        }
+                        notifications.lock().unwrap().push(notification);
+                        StatusCode::OK
+                    }
+                }
+            };
+            let app = Router::new().route("/webhook", post(receive));
+
+            let addr: SocketAddr = addr.parse()?;
+            let server = Handle::new();
+            let serverside_handle = server.clone();
+            tokio::spawn(async move {
+                axum_server::bind(addr)
+                    .handle(serverside_handle)
+                    .serve(app.into_make_service())
+                    .await
+                    .unwrap();
+            });
+
+            Ok(Self {
+                server,
+                failing,
+                notifications,
+            })
+        }
+
+        /// Makes the next `count` deliveries fail as unavailable.
+        pub fn fail_next(&self, count: usize) {
+            self.failing.store(count, Ordering::SeqCst);
+        }
+
+        /// Returns the notifications accepted so far.
+        pub fn notifications(&self) -> Vec<Notification> {
+            self.notifications.lock().unwrap().clone()
+        }
+
+        pub fn stop(self) {
+            self.server.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_deliveries_should_be_retried() -> anyhow::Result<()> {
+        let receiver = mock::Receiver::new("0.0.0.0:3019")?;
+        let url: Url = "http://localhost:3019/webhook".parse()?;
+        let webhooks = Webhooks::new(3).with_private_addresses(true);
+        let notification = Notification {
+            group_id:            1,
+            identity_commitment: Hash::from(1_u64),
+            status:              Status::Failed,
+            block:               None,
+        };
+
+        receiver.fail_next(2);
+        webhooks.deliver(&url, &notification).await?;
+        assert_eq!(receiver.notifications(), vec![notification.clone()]);
+
+        receiver.fail_next(3);
+        assert!(webhooks.deliver(&url, &notification).await.is_err());
+        assert_eq!(receiver.notifications().len(), 1);
+
+        receiver.stop();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn private_addresses_should_not_be_reached() -> anyhow::Result<()> {
+        let receiver = mock::Receiver::new("0.0.0.0:3033")?;
+        let webhooks = Webhooks::new(1);
+        let notification = Notification {
+            group_id:            1,
+            identity_commitment: Hash::from(1_u64),
+            status:              Status::Mined,
+            block:               Some(1),
+        };
+
+        for url in [
+            "http://localhost:3033/webhook",
+            "http://127.0.0.1:3033/webhook",
+            "http://[::ffff:127.0.0.1]:3033/webhook",
+        ] {
+            assert!(webhooks
+                .deliver(&url.parse()?, &notification)
+                .await
+                .is_err());
+        }
+        assert!(receiver.notifications().is_empty());
+
+        receiver.stop();
+
+        Ok(())
+    }
+
+    #[test]
+    fn urls_should_be_checked() -> anyhow::Result<()> {
+        let allowed = vec!["hooks.example.com".to_string()];
+        let check = |url: &str, allowed: &[String], private: bool| -> anyhow::Result<()> {
+            check_url(&url.parse()?, allowed, private)
+        };
+
+        assert!(check("https://hooks.example.com/a", &allowed, false).is_ok());
+        assert!(check("https://other.example.com/a", &allowed, false).is_err());
+        assert!(check("ftp://hooks.example.com/a", &allowed, false).is_err());
+        assert!(check("https://other.example.com/a", &[], false).is_ok());
+        for private in [
+            "http://10.0.0.1/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/",
+            "http://[fd00::1]/",
+            "http://[fe80::1]/",
+        ] {
+            assert!(check(private, &[], false).is_err(), "{private}");
+            assert!(check(private, &[], true).is_ok(), "{private}");
+        }
+
+        Ok(())
+    }
+}
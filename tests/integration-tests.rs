@@ -52,6 +52,7 @@ async fn simulate_eth_reorg() {
     options.app.ethereum.ethereum_provider =
         Url::parse(&chain.endpoint()).expect("Failed to parse ganache endpoint");
     options.app.contracts.semaphore_address = semaphore_address;
+    options.app.contracts.tree_depth = 21;
     options.app.ethereum.signing_key = private_key;
     options.app.ethereum.confirmation_blocks_delay = 5;
     options.app.ethereum.refresh_rate = Duration::from_secs(1);
@@ -147,6 +148,7 @@ async fn insert_identity_and_proofs() {
     options.app.ethereum.ethereum_provider =
         Url::parse(&chain.endpoint()).expect("Failed to parse ganache endpoint");
     options.app.contracts.semaphore_address = semaphore_address;
+    options.app.contracts.tree_depth = 21;
     options.app.ethereum.signing_key = private_key;
     options.app.ethereum.confirmation_blocks_delay = 2;
     options.app.ethereum.refresh_rate = Duration::from_secs(1);
@@ -460,9 +462,15 @@ async fn spawn_app(options: Options) -> AnyhowResult<(JoinHandle<()>, SocketAddr
     let app = spawn({
         async move {
             info!("App thread starting");
-            server::bind_from_listener(Arc::new(app), Duration::from_secs(30), listener)
-                .await
-                .expect("Failed to bind address");
+            server::bind_from_listener(
+                Arc::new(app),
+                Duration::from_secs(30),
+                4096,
+                server::InsertRateLimits::disabled(),
+                listener,
+            )
+            .await
+            .expect("Failed to bind address");
             info!("App thread stopping");
         }
     });